@@ -1,7 +1,26 @@
-use crate::rwlock::{Locks, MutexGuardWrapper};
+use crate::rwlock::{Locks, MutexGuardWrapper, ParallelHold};
 use once_cell::sync::OnceCell;
+use parking_lot::Mutex;
 use scc::{hash_map::Entry, HashMap};
-use std::sync::atomic::AtomicU32;
+use std::{
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+    time::Duration,
+};
+
+/// The key used by attributes without one, and by functions here given `None` for the key
+///
+/// Every test without a key shares it, so they all run one at a time (apart from [parallel](macro@crate::parallel)
+/// ones, which run alongside each other).
+/// ```
+/// use serial_test::{is_locked_serially, serial, DEFAULT_KEY};
+///
+/// #[serial]
+/// fn main() {
+///     assert!(is_locked_serially(Some(DEFAULT_KEY)));
+/// }
+/// ```
+pub const DEFAULT_KEY: &str = "";
 
 #[derive(Clone)]
 pub(crate) struct UniqueReentrantMutex {
@@ -17,35 +36,144 @@ impl UniqueReentrantMutex {
         self.locks.serial()
     }
 
-    pub(crate) fn start_parallel(&self) {
-        self.locks.start_parallel();
+    pub(crate) fn lock_until(
+        &self,
+        deadline: Option<std::time::Instant>,
+    ) -> Option<MutexGuardWrapper> {
+        self.locks.serial_until(deadline)
+    }
+
+    #[cfg(feature = "tokio_serial")]
+    pub(crate) async fn lock_async(&self) -> crate::rwlock::AsyncSerialGuard<'_> {
+        self.locks.serial_async().await
     }
 
-    pub(crate) fn end_parallel(&self) {
-        self.locks.end_parallel();
+    pub(crate) fn start_parallel(&self) -> ParallelHold {
+        self.locks.start_parallel()
     }
 
-    #[cfg(test)]
     pub fn parallel_count(&self) -> u32 {
         self.locks.parallel_count()
     }
 
-    #[cfg(test)]
+    pub(crate) fn is_parallel_here(&self) -> bool {
+        self.locks.is_parallel_here()
+    }
+
+    #[cfg(any(test, feature = "diagnostics"))]
     pub fn is_locked(&self) -> bool {
         self.locks.is_locked()
     }
 
+    #[cfg(feature = "diagnostics")]
+    pub(crate) fn waiters(&self) -> u32 {
+        self.locks.waiters()
+    }
+
+    pub(crate) fn uses(&self) -> u32 {
+        self.locks.uses()
+    }
+
+    #[cfg(feature = "persistent_stats")]
+    pub(crate) fn max_wait_ms(&self) -> u64 {
+        self.locks.max_wait_ms()
+    }
+
+    pub(crate) fn description(&self) -> Option<String> {
+        self.locks.description().map(str::to_string)
+    }
+
+    pub(crate) fn register_parallel_test(&self, test_name: &'static str) {
+        self.locks.register_parallel_test(test_name)
+    }
+
+    pub(crate) fn parallel_tests(&self) -> usize {
+        self.locks.parallel_tests()
+    }
+
     pub fn is_locked_by_current_thread(&self) -> bool {
         self.locks.is_locked_by_current_thread()
     }
+
+    pub(crate) fn wait_until_idle(&self, timeout: Duration) -> bool {
+        self.locks.wait_until_idle(timeout)
+    }
+
+    pub(crate) fn released_while<R>(
+        &self,
+        guarded: &[ParallelHold],
+        function: impl FnOnce() -> R,
+    ) -> R {
+        self.locks.released_while(guarded, function)
+    }
 }
 
 #[inline]
 pub(crate) fn global_locks() -> &'static HashMap<String, UniqueReentrantMutex> {
-    #[cfg(feature = "test_logging")]
-    let _ = env_logger::builder().try_init();
     static LOCKS: OnceCell<HashMap<String, UniqueReentrantMutex>> = OnceCell::new();
-    LOCKS.get_or_init(HashMap::new)
+    LOCKS.get_or_init(|| {
+        // Only the once, as setting up the logger reads the environment, which allocates
+        #[cfg(feature = "test_logging")]
+        let _ = env_logger::builder().try_init();
+        HashMap::new()
+    })
+}
+
+// The keys each key also takes, from declare_conflicts
+static CONFLICTS: OnceCell<
+    parking_lot::RwLock<std::collections::HashMap<String, Vec<&'static str>>>,
+> = OnceCell::new();
+
+/// Makes the first key of each pair also take the second, for groups that conflict asymmetrically
+///
+/// With `declare_conflicts(&[("a", "b"), ("a", "c")])`, tests using `a` also hold `b` and `c` (serially or in
+/// parallel, as per the test), so they won't run alongside tests using either of them. Tests using `b` or `c`
+/// don't take `a`, so `b` and `c` tests can still run alongside each other. Only the declared pairs count, so if
+/// `b` also conflicts with `d`, that doesn't make `a` conflict with `d`.
+/// ````
+/// use serial_test::{declare_conflicts, is_locked_serially, with_serial};
+///
+/// declare_conflicts(&[("migrations", "users"), ("migrations", "orders")]);
+/// with_serial(["migrations"], || {
+///     assert!(is_locked_serially(Some("users")));
+///     assert!(is_locked_serially(Some("orders")));
+/// });
+/// ````
+/// The extra keys are sorted in with the test's own ones and all taken in that order, as usual, so this can't
+/// deadlock by itself. Declare the conflicts before any test using those keys starts (e.g. from a `ctor`), as tests
+/// already running won't be holding the new keys. This only applies to the in-process locks, not the file ones.
+pub fn declare_conflicts(pairs: &[(&str, &str)]) {
+    let mut conflicts = CONFLICTS.get_or_init(Default::default).write();
+    for (key, other) in pairs {
+        if key == other {
+            continue;
+        }
+        let others = conflicts.entry(key.to_string()).or_default();
+        if !others.contains(other) {
+            others.push(Box::leak(other.to_string().into_boxed_str()));
+        }
+    }
+}
+
+/// `names` plus anything they conflict with, in the order to take them
+pub(crate) fn with_conflicts(mut names: Vec<&str>) -> Vec<&str> {
+    let conflicts = match CONFLICTS.get() {
+        Some(conflicts) => conflicts.read(),
+        None => return names,
+    };
+    let extra: Vec<&'static str> = names
+        .iter()
+        .filter_map(|name| conflicts.get(*name))
+        .flatten()
+        .copied()
+        .collect();
+    if extra.is_empty() {
+        return names;
+    }
+    names.extend(extra);
+    names.sort();
+    names.dedup();
+    names
 }
 
 /// Check if the current thread is holding a serial lock
@@ -64,8 +192,6 @@ pub(crate) fn global_locks() -> &'static HashMap<String, UniqueReentrantMutex> {
 ///     // ...
 /// }
 ///
-/// #[test]
-/// # fn unused() {}
 /// #[serial]
 /// fn main() {
 ///     do_something_in_need_of_serialization();
@@ -77,8 +203,6 @@ pub(crate) fn global_locks() -> &'static HashMap<String, UniqueReentrantMutex> {
 /// ```should_panic
 /// use serial_test::{is_locked_serially, serial};
 ///
-/// #[test]
-/// # fn unused() {}
 /// // #[serial] // <-- missing
 /// fn main() {
 ///     assert!(is_locked_serially(None));
@@ -90,8 +214,6 @@ pub(crate) fn global_locks() -> &'static HashMap<String, UniqueReentrantMutex> {
 /// ```
 /// use serial_test::{is_locked_serially, serial};
 ///
-/// #[test]
-/// # fn unused() {}
 /// #[serial(some_key)]
 /// fn main() {
 ///     assert!(is_locked_serially(Some("some_key")));
@@ -100,29 +222,341 @@ pub(crate) fn global_locks() -> &'static HashMap<String, UniqueReentrantMutex> {
 /// ```
 pub fn is_locked_serially(name: Option<&str>) -> bool {
     global_locks()
-        .get(name.unwrap_or_default())
+        .get(name.unwrap_or(DEFAULT_KEY))
         .map(|lock| lock.get().is_locked_by_current_thread())
         .unwrap_or_default()
 }
 
-static MUTEX_ID: AtomicU32 = AtomicU32::new(1);
+// Only the parallel holds of the test body (or poll of an async one) running on this thread right now count
+#[doc(hidden)]
+pub fn is_locked_in_parallel_here(name: Option<&str>) -> bool {
+    global_locks()
+        .get(name.unwrap_or(DEFAULT_KEY))
+        .map(|lock| lock.get().is_parallel_here())
+        .unwrap_or_default()
+}
+
+/// What sort of lock a key is currently held with, as returned by [current_mode]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// The current thread holds the key serially
+    Serial,
+    /// The key is held by one or more parallel tests
+    Parallel,
+    /// Neither of the above
+    Unlocked,
+}
+
+/// Check how a key is currently locked, as a single value to branch on
+///
+/// This is a generalisation of [is_locked_serially]. Note that unlike [Mode::Serial], [Mode::Parallel]
+/// isn't specific to the current thread, as parallel holders aren't tracked per-thread.
+///
+/// ```
+/// use serial_test::{current_mode, parallel, Mode};
+///
+/// #[parallel(some_key)]
+/// fn main() {
+///     assert_eq!(current_mode(Some("some_key")), Mode::Parallel);
+///     assert_eq!(current_mode(None), Mode::Unlocked);
+/// }
+/// ```
+pub fn current_mode(name: Option<&str>) -> Mode {
+    let lock = global_locks()
+        .get(name.unwrap_or(DEFAULT_KEY))
+        .map(|lock| lock.get().clone());
+    match lock {
+        Some(lock) if lock.is_locked_by_current_thread() => Mode::Serial,
+        Some(lock) if lock.parallel_count() > 0 => Mode::Parallel,
+        _ => Mode::Unlocked,
+    }
+}
+
+/// Wait until nothing holds a key, either serially or in parallel
+///
+/// Blocks until there is no serial holder and no running parallel users of the key,
+/// returning `false` if that doesn't happen before `timeout`. Useful for "global setup" style
+/// code that needs a group of tests to be fully quiesced before it runs. Note that another test
+/// may well grab the key again straight afterwards, so this is a hint, not a lock.
+///
+/// ```
+/// use serial_test::wait_until_idle;
+/// use std::time::Duration;
+///
+/// assert!(wait_until_idle(Some("some_key"), Duration::from_secs(1)));
+/// ```
+///
+/// Calling this from inside a test holding the same key will always time out.
+pub fn wait_until_idle(name: Option<&str>, timeout: Duration) -> bool {
+    let lock = global_locks()
+        .get(name.unwrap_or(DEFAULT_KEY))
+        .map(|lock| lock.get().clone());
+    match lock {
+        Some(lock) => lock.wait_until_idle(timeout),
+        None => true,
+    }
+}
+
+/// List the keys that have only been locked once so far
+///
+/// A key used by a single test doesn't coordinate with anything, which usually means a typo
+/// (e.g. `#[serial(db)]` on one test and `#[parallel(database)]` on another). Call this at the end of a
+/// test run, or from a test that runs after the others, to look for them. The global key used by
+/// `#[serial]` without any keys isn't included.
+/// ```
+/// use serial_test::{serial, single_use_keys};
+///
+/// #[serial(just_the_once)]
+/// fn run() {}
+///
+/// run();
+/// assert_eq!(single_use_keys(), vec!["just_the_once".to_string()]);
+/// ```
+pub fn single_use_keys() -> Vec<String> {
+    let mut keys = Vec::new();
+    global_locks().scan(|name, lock| {
+        if !name.is_empty() && lock.uses() == 1 {
+            keys.push(name.clone());
+        }
+    });
+    keys.sort();
+    keys
+}
+
+/// The `desc` given for a key, e.g. `#[serial(db, desc = "PostgreSQL test DB")]`
+///
+/// Descriptions are recorded when a test with one starts, so this is `None` until then. If tests give the same key
+/// different descriptions, the first one to run wins.
+/// ```
+/// use serial_test::{key_description, serial};
+///
+/// #[serial(db, desc = "PostgreSQL test DB")]
+/// fn run() {}
+///
+/// run();
+/// assert_eq!(key_description(Some("db")), Some("PostgreSQL test DB".to_string()));
+/// ```
+pub fn key_description(name: Option<&str>) -> Option<String> {
+    global_locks()
+        .get(name.unwrap_or(DEFAULT_KEY))
+        .and_then(|lock| lock.get().description())
+}
+
+/// Checks two keys are the same serial group, i.e. tests using them wait for each other
+///
+/// Keys are just strings, so it's easy for them to drift apart (e.g. `#[serial(db)]` on one test and
+/// `#[serial(database)]` on another) without anything failing. Keeping the keys in `const`s and checking the
+/// attribute keys against them catches this.
+/// ```
+/// use serial_test::{assert_same_group, serial};
+///
+/// const DB: &str = "db";
+///
+/// #[serial(db)]
+/// fn run() {
+///     assert_same_group(DB, "db");
+/// }
+///
+/// run();
+/// ```
+///
+/// # Panics
+/// If the keys are different. The message mentions which of the keys haven't been used by any test yet, as that's
+/// usually the misspelt one.
+#[track_caller]
+pub fn assert_same_group(key_a: &str, key_b: &str) {
+    if key_a == key_b {
+        return;
+    }
+    let unused: Vec<String> = [key_a, key_b]
+        .iter()
+        .filter(|key| !global_locks().contains(**key))
+        .map(|key| format!("'{}'", key))
+        .collect();
+    if unused.is_empty() {
+        panic!(
+            "Keys '{}' and '{}' are different serial groups",
+            key_a, key_b
+        );
+    }
+    panic!(
+        "Keys '{}' and '{}' are different serial groups ({} not used by any test yet)",
+        key_a,
+        key_b,
+        unused.join(" and ")
+    );
+}
+
+/// Records `description` for each of the keys
+#[doc(hidden)]
+pub fn describe_keys<'a>(names: impl AsRef<[&'a str]>, description: &str) {
+    for name in names.as_ref() {
+        check_new_key(name);
+        global_locks()
+            .get(*name)
+            .expect("key to be set")
+            .get()
+            .locks
+            .set_description(description);
+    }
+}
+
+// How many `defer_start_ms` tests have started on each key, kept apart from the locks so counting doesn't
+// contend with them
+fn deferred_starts() -> &'static HashMap<String, AtomicUsize> {
+    static DEFERRED_STARTS: OnceCell<HashMap<String, AtomicUsize>> = OnceCell::new();
+    DEFERRED_STARTS.get_or_init(HashMap::new)
+}
+
+/// For `defer_start_ms`, sleeps for `defer_start_ms` times however many tests have already started this way on
+/// any of the keys (so the first doesn't wait at all), to stagger tests starting together
+#[doc(hidden)]
+pub fn defer_parallel_start<'a>(names: impl AsRef<[&'a str]>, defer_start_ms: u64) {
+    let index = names
+        .as_ref()
+        .iter()
+        .map(|name| {
+            deferred_starts()
+                .entry(name.to_string())
+                .or_insert_with(|| AtomicUsize::new(0))
+                .get()
+                .fetch_add(1, Ordering::Relaxed)
+        })
+        .max()
+        .unwrap_or_default();
+    thread::sleep(Duration::from_millis(
+        defer_start_ms.saturating_mul(index as u64),
+    ));
+}
+
+/// Keys for attributes with a `discriminator`, sorted like the compile-time ones
+#[doc(hidden)]
+pub fn discriminated_keys(names: &[&str], discriminator: &dyn std::fmt::Display) -> Vec<String> {
+    let mut keys: Vec<String> = names
+        .iter()
+        .map(|name| format!("{}::{}", name, discriminator))
+        .collect();
+    keys.sort();
+    keys
+}
+
+/// A type whose values are keys, for `key = ...` on the attributes
+///
+/// Derive it for an enum with [SerialKey](macro@crate::SerialKey), rather than implementing it by hand.
+pub trait SerialKey {
+    /// The string key for this value, which is shared with tests using it as an ordinary key
+    fn serial_key(&self) -> &'static str;
+}
+
+/// Keys for attributes with a `const_key`, which are only known once the constants are, sorted like the
+/// compile-time ones
+#[doc(hidden)]
+pub fn with_const_keys(names: &[&str], const_keys: &[&str]) -> Vec<String> {
+    let mut keys: Vec<String> = names
+        .iter()
+        .chain(const_keys)
+        .map(|key| key.to_string())
+        .collect();
+    keys.sort();
+    keys.dedup();
+    keys
+}
+
+/// Keys for attributes with `keys_from_env`, from a comma-separated list in the `var` environment variable
+#[doc(hidden)]
+pub fn keys_from_env(var: &str) -> Vec<String> {
+    keys_from(std::env::var(var).ok())
+}
+
+fn keys_from(keys: Option<String>) -> Vec<String> {
+    let mut keys: Vec<String> = keys
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|key| !key.is_empty())
+        .map(String::from)
+        .collect();
+    // Sorted like the compile-time ones, to avoid dining philosopher issues
+    keys.sort();
+    keys.dedup();
+    if keys.is_empty() {
+        keys.push(DEFAULT_KEY.to_string());
+    }
+    keys
+}
+
+// A lock rather than an atomic, so a reset can check nothing's in use without a new key getting an id meanwhile
+static MUTEX_ID: Mutex<u32> = parking_lot::const_mutex(1);
+
+/// The id the next new key will get
+///
+/// Each key gets an id when it's first used, counting up from 1 across the whole process, so which key gets which
+/// id depends on what order the tests run in. See [reset_mutex_ids] for getting the same ids each time.
+pub fn next_mutex_id() -> u32 {
+    *MUTEX_ID.lock()
+}
+
+/// Start the ids for new keys from 1 again
+///
+/// This is for tests and tooling that need reproducible ids, and only resets if nothing currently holds any key,
+/// either serially or in parallel, returning whether it did. Keys that have already been used keep their ids, so
+/// ids are only unique until the next reset.
+/// ```
+/// use serial_test::{next_mutex_id, reset_mutex_ids};
+///
+/// assert!(reset_mutex_ids());
+/// assert_eq!(next_mutex_id(), 1);
+/// ```
+pub fn reset_mutex_ids() -> bool {
+    // Gathered before taking the ids, as a new key takes them while holding its part of the map, so scanning the
+    // map with them held could deadlock
+    let ids_before = next_mutex_id();
+    let mut locks = Vec::new();
+    global_locks().scan(|_name, lock| locks.push(lock.clone()));
+    let mut next_id = MUTEX_ID.lock();
+    // No key can be set up while we hold the ids, and one that was since we looked is about to be used
+    let idle = *next_id == ids_before
+        && locks
+            .iter()
+            .all(|lock| lock.wait_until_idle(Duration::ZERO));
+    if idle {
+        *next_id = 1;
+    }
+    idle
+}
 
 impl UniqueReentrantMutex {
     fn new_mutex(name: &str) -> Self {
+        let mut next_id = MUTEX_ID.lock();
+        let id = *next_id;
+        *next_id = id.wrapping_add(1);
         Self {
             locks: Locks::new(name),
-            id: MUTEX_ID.fetch_add(1, std::sync::atomic::Ordering::SeqCst),
+            id,
         }
     }
 }
 
+/// The lock for `name`, setting it up if this is the first time it's been used
+pub(crate) fn lock_for(name: &str) -> UniqueReentrantMutex {
+    // Only needs a shared lock on the key's bucket, so looking up keys doesn't hold up anyone else doing the same
+    if let Some(lock) = global_locks().read(name, |_, lock| lock.clone()) {
+        return lock;
+    }
+    check_new_key(name);
+    global_locks()
+        .read(name, |_, lock| lock.clone())
+        .expect("key to be set")
+}
+
 pub(crate) fn check_new_key(name: &str) {
     // Check if a new key is needed. Just need a read lock, which can be done in sync with everyone else
     if global_locks().contains(name) {
         return;
     };
 
-    // This is the rare path, which avoids the multi-writer situation mostly
+    // This is the rare path, which avoids the multi-writer situation mostly. It's the only one that allocates, and
+    // nearly always goes on to insert, so it may as well take the owned key up front
     let entry = global_locks().entry(name.to_owned());
     match entry {
         Entry::Occupied(o) => o,
@@ -134,10 +568,41 @@ pub(crate) fn check_new_key(name: &str) {
 mod tests {
     use super::*;
     use crate::{local_parallel_core, local_serial_core};
+    use std::{
+        thread,
+        time::{Duration, Instant},
+    };
 
     const NAME1: &str = "NAME1";
     const NAME2: &str = "NAME2";
 
+    // Counts allocations made by threads that have asked for it, for checking the hot paths don't make any
+    struct CountingAllocator;
+
+    thread_local! {
+        static ALLOCATIONS: std::cell::Cell<Option<usize>> = const { std::cell::Cell::new(None) };
+    }
+
+    unsafe impl std::alloc::GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
+            let _ = ALLOCATIONS.try_with(|count| count.set(count.get().map(|count| count + 1)));
+            std::alloc::System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
+            std::alloc::System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    fn allocations(function: impl FnOnce()) -> usize {
+        ALLOCATIONS.with(|count| count.set(Some(0)));
+        function();
+        ALLOCATIONS.with(|count| count.take()).unwrap()
+    }
+
     #[test]
     fn assert_serially_locked_without_name() {
         local_serial_core(vec![""], None, || {
@@ -191,4 +656,187 @@ mod tests {
             .unwrap();
         });
     }
+
+    #[test]
+    fn existing_key_check_doesnt_allocate() {
+        check_new_key("existing_key_check");
+        assert_eq!(
+            allocations(|| {
+                for _ in 0..100 {
+                    check_new_key("existing_key_check");
+                }
+            }),
+            0
+        );
+    }
+
+    #[test]
+    fn lock_for_shares_the_lock_across_threads() {
+        let id = lock_for("lock_for_shared").id;
+        let ids: Vec<u32> = (0..4)
+            .map(|_| thread::spawn(|| lock_for("lock_for_shared").id))
+            .map(|handle| handle.join().unwrap())
+            .collect();
+        assert_eq!(ids, vec![id; 4]);
+    }
+
+    #[test]
+    fn current_mode_in_serial() {
+        local_serial_core(vec!["current_mode_serial"], None, || {
+            assert_eq!(current_mode(Some("current_mode_serial")), Mode::Serial);
+        });
+    }
+
+    #[test]
+    fn current_mode_in_parallel() {
+        local_parallel_core(vec!["current_mode_parallel"], None, || {
+            assert_eq!(current_mode(Some("current_mode_parallel")), Mode::Parallel);
+        });
+    }
+
+    #[test]
+    fn current_mode_without_lock() {
+        assert_eq!(current_mode(Some("current_mode_unlocked")), Mode::Unlocked);
+        local_serial_core(vec!["current_mode_unlocked"], None, || {});
+        assert_eq!(current_mode(Some("current_mode_unlocked")), Mode::Unlocked);
+    }
+
+    #[test]
+    fn single_use_key_reported() {
+        local_serial_core(vec!["single_use_key_once"], None, || {});
+        local_serial_core(vec!["single_use_key_twice"], None, || {});
+        local_parallel_core(vec!["single_use_key_twice"], None, || {});
+        let keys = single_use_keys();
+        assert!(keys.contains(&"single_use_key_once".to_string()));
+        assert!(!keys.contains(&"single_use_key_twice".to_string()));
+    }
+
+    #[test]
+    fn wait_until_idle_returns_after_release() {
+        check_new_key("wait_until_idle_release");
+        let lock = global_locks()
+            .get("wait_until_idle_release")
+            .unwrap()
+            .get()
+            .clone();
+        let guard = lock.lock();
+        let waiter = thread::spawn(|| {
+            let start = Instant::now();
+            let idle = wait_until_idle(Some("wait_until_idle_release"), Duration::from_secs(10));
+            (idle, start.elapsed())
+        });
+        thread::sleep(Duration::from_millis(100));
+        drop(guard);
+        let (idle, elapsed) = waiter.join().unwrap();
+        assert!(idle);
+        assert!(elapsed < Duration::from_secs(1), "elapsed = {:?}", elapsed);
+    }
+
+    #[test]
+    fn wait_until_idle_times_out_with_parallels() {
+        local_parallel_core(vec!["wait_until_idle_parallel"], None, || {
+            assert!(!wait_until_idle(
+                Some("wait_until_idle_parallel"),
+                Duration::from_millis(100)
+            ));
+        });
+        assert!(wait_until_idle(
+            Some("wait_until_idle_parallel"),
+            Duration::from_millis(100)
+        ));
+    }
+
+    #[test]
+    fn wait_until_idle_without_deadline() {
+        check_new_key("wait_until_idle_forever");
+        let lock = global_locks()
+            .get("wait_until_idle_forever")
+            .unwrap()
+            .get()
+            .clone();
+        let guard = lock.lock();
+        let waiter =
+            thread::spawn(|| wait_until_idle(Some("wait_until_idle_forever"), Duration::MAX));
+        thread::sleep(Duration::from_millis(100));
+        drop(guard);
+        assert!(waiter.join().unwrap());
+    }
+
+    #[test]
+    fn wait_until_idle_unknown_key() {
+        assert!(wait_until_idle(
+            Some("wait_until_idle_no_such_key"),
+            Duration::from_millis(1)
+        ));
+    }
+
+    #[test]
+    fn keys_registered_on_first_use() {
+        assert!(!global_locks().contains("registered_on_first_use"));
+        local_serial_core(vec!["registered_on_first_use"], None, || {});
+        assert!(global_locks().contains("registered_on_first_use"));
+    }
+
+    #[test]
+    fn same_group() {
+        assert_same_group("group_key", "group_key");
+        assert_same_group("", "");
+    }
+
+    #[test]
+    #[should_panic(expected = "Keys 'group_db' and 'group_database' are different serial groups")]
+    fn different_groups() {
+        assert_same_group("group_db", "group_database");
+    }
+
+    #[test]
+    #[should_panic(expected = "('group_database' not used by any test yet)")]
+    fn different_groups_unused_key() {
+        check_new_key("group_used_db");
+        assert_same_group("group_used_db", "group_database");
+    }
+
+    #[test]
+    fn described_keys() {
+        assert_eq!(key_description(Some("described")), None);
+        describe_keys(["described"], "first");
+        describe_keys(["described"], "second");
+        assert_eq!(
+            key_description(Some("described")),
+            Some("first".to_string())
+        );
+        assert_eq!(key_description(Some("not_described")), None);
+    }
+
+    #[test]
+    fn const_keys_sorted_with_the_others() {
+        assert_eq!(
+            with_const_keys(&["queue"], &["db", "queue", "cache"]),
+            vec!["cache", "db", "queue"]
+        );
+    }
+
+    #[test]
+    fn keys_from_env_var() {
+        assert_eq!(
+            keys_from(Some("queue, db,,queue".to_string())),
+            vec!["db", "queue"]
+        );
+        assert_eq!(keys_from(Some(String::new())), vec![DEFAULT_KEY]);
+        assert_eq!(keys_from(None), vec![DEFAULT_KEY]);
+    }
+
+    #[test]
+    fn deferred_starts_are_staggered() {
+        let timed = |names: &[&str]| {
+            let started = Instant::now();
+            defer_parallel_start(names, 50);
+            started.elapsed()
+        };
+        assert!(timed(&["deferred_a"]) < Duration::from_millis(50));
+        assert!(timed(&["deferred_a"]) >= Duration::from_millis(50));
+        // Goes by whichever key's had the most
+        assert!(timed(&["deferred_a", "deferred_b"]) >= Duration::from_millis(100));
+        assert!(timed(&["deferred_b"]) >= Duration::from_millis(50));
+    }
 }
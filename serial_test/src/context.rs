@@ -0,0 +1,92 @@
+use std::{any::Any, cell::RefCell, rc::Rc};
+
+thread_local! {
+    // Values from `with_context`, for each test this thread is in that has one (innermost last)
+    static CONTEXTS: RefCell<Vec<Rc<dyn Any>>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Keeps a `with_context` value available to [context] until dropped
+#[doc(hidden)]
+pub struct ContextGuard;
+
+impl Drop for ContextGuard {
+    fn drop(&mut self) {
+        CONTEXTS.with(|contexts| contexts.borrow_mut().pop());
+    }
+}
+
+/// Makes `value` available to [context] for the rest of the test
+#[doc(hidden)]
+pub fn set_context<T: 'static>(value: T) -> ContextGuard {
+    CONTEXTS.with(|contexts| contexts.borrow_mut().push(Rc::new(value)));
+    ContextGuard
+}
+
+/// The value made by the `with_context` function of the current test
+///
+/// `#[serial(key, with_context = "make_context")]` calls `make_context()` once the lock's been taken, and keeps
+/// what it returns until the test finishes, without having to change the test's signature.
+/// ````
+/// use serial_test::{context, serial};
+///
+/// struct Pool {
+///     size: usize,
+/// }
+///
+/// fn make_pool() -> Pool {
+///     Pool { size: 4 }
+/// }
+///
+/// #[serial(db, with_context = "make_pool")]
+/// fn run() {
+///     assert_eq!(context::<Pool>().size, 4);
+/// }
+///
+/// run();
+/// ````
+/// The value belongs to the thread running the test, so async tests can only see it on a single-threaded runtime
+/// (e.g. the default for `#[tokio::test]`). With nested tests, this is the innermost one's.
+///
+/// # Panics
+/// If the current test doesn't have a `with_context` (or this isn't in a test at all), or its value isn't a `T`.
+pub fn context<T: 'static>() -> Rc<T> {
+    let value = CONTEXTS
+        .with(|contexts| contexts.borrow().last().cloned())
+        .expect("context needs to be called from inside a test with a with_context");
+    value.downcast::<T>().unwrap_or_else(|_| {
+        panic!(
+            "The with_context value isn't a {}",
+            std::any::type_name::<T>()
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{context, set_context};
+
+    #[test]
+    fn nested_contexts() {
+        let outer = set_context(1u32);
+        assert_eq!(*context::<u32>(), 1);
+        {
+            let _inner = set_context("inner");
+            assert_eq!(*context::<&str>(), "inner");
+        }
+        assert_eq!(*context::<u32>(), 1);
+        drop(outer);
+    }
+
+    #[test]
+    #[should_panic(expected = "The with_context value isn't a u64")]
+    fn wrong_type() {
+        let _context = set_context(1u32);
+        context::<u64>();
+    }
+
+    #[test]
+    #[should_panic(expected = "context needs to be called from inside a test with a with_context")]
+    fn no_context() {
+        context::<u32>();
+    }
+}
@@ -0,0 +1,450 @@
+use crate::{
+    file_lock::{
+        check_path, file_identity, gen_holder_file, write_holder, FileSystem, LockType,
+        REAL_FILE_SYSTEM,
+    },
+    os_lock::{self, OsLock},
+};
+#[cfg(feature = "logging")]
+use log::{debug, warn};
+#[cfg(feature = "notify_watcher")]
+use notify::{RecursiveMode, Watcher};
+#[cfg(feature = "notify_watcher")]
+use std::sync::mpsc;
+use std::{
+    fs,
+    path::Path,
+    thread,
+    time::{Duration, SystemTime},
+};
+
+// Wakes a waiting serial user when the count file's replaced, rather than it only checking every second
+#[cfg(feature = "notify_watcher")]
+struct CountWatcher {
+    _watcher: notify::RecommendedWatcher,
+    changes: mpsc::Receiver<()>,
+}
+
+#[cfg(feature = "notify_watcher")]
+impl CountWatcher {
+    fn new(path: &str) -> Option<CountWatcher> {
+        let count_file = Path::new(&Lock::gen_count_file(path)).to_path_buf();
+        // The count file's renamed into place, so watch the directory, as a watch on the file itself would be lost
+        let dir = match count_file.parent() {
+            Some(dir) if dir != Path::new("") => dir.to_path_buf(),
+            _ => Path::new(".").to_path_buf(),
+        };
+        let (sender, changes) = mpsc::channel();
+        let mut watcher =
+            notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+                if let Ok(event) = event {
+                    if event
+                        .paths
+                        .iter()
+                        .any(|path| path.file_name() == count_file.file_name())
+                    {
+                        let _ = sender.send(());
+                    }
+                }
+            })
+            .ok()?;
+        watcher.watch(&dir, RecursiveMode::NonRecursive).ok()?;
+        Some(CountWatcher {
+            _watcher: watcher,
+            changes,
+        })
+    }
+
+    // Still gives up after a second, in case the change is never seen (e.g. on a network filesystem)
+    fn wait(&self) {
+        let _ = self.changes.recv_timeout(Duration::from_secs(1));
+    }
+}
+
+#[cfg(not(feature = "notify_watcher"))]
+struct CountWatcher;
+
+#[cfg(not(feature = "notify_watcher"))]
+impl CountWatcher {
+    fn new(_path: &str) -> Option<CountWatcher> {
+        None
+    }
+
+    fn wait(&self) {}
+}
+
+pub(crate) struct Lock {
+    lockfile: Box<dyn OsLock>,
+    pub(crate) parallel_count: u32,
+    path: String,
+    // Which file we locked, as if someone deletes it (e.g. a cleanup script), anyone opening the path after that
+    // gets a new file that isn't locked. Always None for open_exclusive, which doesn't lock the file itself
+    identity: Option<(u64, u64)>,
+    lock_type: LockType,
+    // Everything but the lock file itself, which fslock needs to be real
+    fs: &'static dyn FileSystem,
+}
+
+impl Lock {
+    // Can't use the same file as fslock truncates it, so this is only ever touched with the main lock held
+    fn gen_count_file(path: &str) -> String {
+        format!("{}-count", path)
+    }
+
+    fn read_parallel_count(self: &Lock) -> u32 {
+        assert!(
+            self.lockfile.owns_lock(),
+            "Reading count for {:?} without the lock",
+            self.path
+        );
+        let parallel_count = match self.fs.read(&Lock::gen_count_file(&self.path)) {
+            Ok(contents) => match contents[..] {
+                [a, b, c, d, ..] => u32::from_ne_bytes([a, b, c, d]),
+                _ => {
+                    #[cfg(feature = "logging")]
+                    debug!(
+                        "Error loading count file: only {} bytes long",
+                        contents.len()
+                    );
+                    0u32
+                }
+            },
+            Err(_) => 0,
+        };
+
+        #[cfg(feature = "logging")]
+        debug!("Parallel count for {:?} is {}", self.path, parallel_count);
+        parallel_count
+    }
+
+    pub(crate) fn new(path: &str, lock_type: LockType) -> Lock {
+        Lock::with_fs(path, lock_type, &REAL_FILE_SYSTEM)
+    }
+
+    pub(crate) fn with_fs(path: &str, lock_type: LockType, fs: &'static dyn FileSystem) -> Lock {
+        check_path(path);
+        let mut lock = Lock {
+            lockfile: Lock::open(path, lock_type),
+            parallel_count: 0,
+            path: String::from(path),
+            identity: None,
+            lock_type,
+            fs,
+        };
+        lock.lock();
+        lock
+    }
+
+    fn open(path: &str, lock_type: LockType) -> Box<dyn OsLock> {
+        if !Path::new(path).exists() {
+            fs::write(path, "").unwrap_or_else(|_| panic!("Lock file path was {:?}", path))
+        }
+        os_lock::open(path, lock_type)
+    }
+
+    fn lock(self: &mut Lock) {
+        self.lock_opened();
+        // The file we opened may have been deleted or replaced before we got it locked, in which case we've locked
+        // a file no-one else will, so start again with whatever's there now
+        while let Some(identity) = self.lockfile.identity() {
+            if file_identity(&self.path) == Some(identity) {
+                self.identity = Some(identity);
+                break;
+            }
+            #[cfg(feature = "logging")]
+            debug!("{:?} was replaced while we waited for it", self.path);
+            self.lockfile.unlock().unwrap();
+            self.lockfile = Lock::open(&self.path, self.lock_type);
+            self.lock_opened();
+        }
+
+        // Anything we knew about the count from before we had the lock is stale
+        self.parallel_count = self.read_parallel_count();
+    }
+
+    fn lock_opened(self: &mut Lock) {
+        if !self.lockfile.try_lock().unwrap() {
+            #[cfg(feature = "logging")]
+            match self.fs.read(&gen_holder_file(&self.path)) {
+                Ok(holder) => debug!(
+                    "Waiting on {:?}, currently held by {}",
+                    self.path,
+                    String::from_utf8_lossy(&holder)
+                ),
+                Err(_) => debug!("Waiting on {:?}", self.path),
+            }
+
+            self.lockfile.lock().unwrap();
+        }
+
+        #[cfg(feature = "logging")]
+        debug!("Locked for {:?}", self.path);
+    }
+
+    // A parallel user that died without finishing leaves its count behind, and the count file's only written when
+    // a parallel user starts or finishes, so if it's been longer than the expiry it's assumed to be stale
+    fn count_expired(self: &Lock, expiry: Duration) -> bool {
+        self.fs
+            .modified(&Lock::gen_count_file(&self.path))
+            .ok()
+            .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+            .map(|age| age > expiry)
+            .unwrap_or(false)
+    }
+
+    pub(crate) fn start_serial(self: &mut Lock, expiry: Option<Duration>, watch: bool) {
+        let mut watcher = None;
+        loop {
+            if self.parallel_count == 0 {
+                return;
+            }
+            if matches!(expiry, Some(expiry) if self.count_expired(expiry)) {
+                #[cfg(feature = "logging")]
+                debug!(
+                    "Parallel count of {} for {:?} has expired, so resetting it",
+                    self.parallel_count, self.path
+                );
+                self.parallel_count = 0;
+                self.write_parallel();
+                return;
+            }
+            #[cfg(feature = "logging")]
+            debug!("Waiting because parallel count is {}", self.parallel_count);
+            // Made while we've still got the lock, so it sees every change after we let go
+            if watch && watcher.is_none() {
+                watcher = CountWatcher::new(&self.path);
+            }
+            // unlock here is safe because we re-lock before returning
+            self.unlock();
+            match &watcher {
+                Some(watcher) => watcher.wait(),
+                None => thread::sleep(Duration::from_secs(1)),
+            }
+            self.lock();
+        }
+    }
+
+    fn unlock(self: &mut Lock) {
+        #[cfg(feature = "logging")]
+        debug!("Unlocking {}", self.path);
+        let replaced = matches!(self.identity.take(), Some(identity) if file_identity(&self.path) != Some(identity));
+        self.lockfile.unlock().unwrap();
+        if replaced {
+            #[cfg(feature = "logging")]
+            warn!(
+                "Lock file {:?} was deleted or replaced while we had it locked",
+                self.path
+            );
+            // Panicking again while already unwinding would abort, and the first panic's the one that matters
+            if !thread::panicking() {
+                panic!(
+                    "Lock file {:?} was deleted or replaced while locked, so anything that locked it since could have run at the same time",
+                    self.path
+                );
+            }
+        }
+    }
+
+    pub(crate) fn set_holder(self: &Lock, holder: &str) {
+        assert!(
+            self.lockfile.owns_lock(),
+            "Writing holder for {:?} without the lock",
+            self.path
+        );
+        write_holder(self.fs, &self.path, holder);
+    }
+
+    pub(crate) fn end_serial(mut self: Lock) {
+        let _ = self.fs.remove_file(&gen_holder_file(&self.path));
+        self.unlock();
+    }
+
+    fn write_parallel(self: &Lock) {
+        assert!(
+            self.lockfile.owns_lock(),
+            "Writing count for {:?} without the lock",
+            self.path
+        );
+        // Write then rename, so the count file is never seen half-written
+        let count_file = Lock::gen_count_file(&self.path);
+        let temp_count_file = format!("{}.tmp", count_file);
+        self.fs
+            .write(&temp_count_file, &self.parallel_count.to_ne_bytes())
+            .unwrap();
+        self.fs.rename(&temp_count_file, &count_file).unwrap();
+    }
+
+    pub(crate) fn start_parallel(self: &mut Lock, max_parallel: Option<u32>) {
+        if let Some(max_parallel) = max_parallel {
+            while self.parallel_count >= max_parallel {
+                #[cfg(feature = "logging")]
+                debug!(
+                    "Waiting because parallel count is {} (max {})",
+                    self.parallel_count, max_parallel
+                );
+                // unlock here is safe because we re-lock before carrying on
+                self.unlock();
+                thread::sleep(Duration::from_secs(1));
+                self.lock();
+            }
+        }
+        self.parallel_count = self
+            .parallel_count
+            .checked_add(1)
+            .expect("parallel count overflow");
+        self.write_parallel();
+        self.unlock();
+    }
+
+    pub(crate) fn end_parallel(mut self: Lock) {
+        // start_parallel let go, so someone else may have changed the count since
+        if !self.lockfile.owns_lock() {
+            self.lock();
+        }
+        assert!(
+            self.parallel_count > 0,
+            "parallel count underflow for lock file {:?} — end_parallel called more times than start_parallel",
+            self.path
+        );
+        self.parallel_count -= 1;
+        self.write_parallel();
+        self.unlock();
+    }
+}
+
+pub(crate) fn is_serially_locked(path: &str, lock_type: LockType) -> bool {
+    os_lock::open(path, lock_type).is_locked().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_serially_locked, Lock};
+    use crate::file_lock::{path_for_name, FileSystem, LockType};
+    use parking_lot::Mutex;
+    use std::{collections::HashMap, io, path::Path, time::SystemTime};
+
+    // Keeps everything in memory, optionally failing all the writes
+    #[derive(Default)]
+    struct FakeFileSystem {
+        files: Mutex<HashMap<String, Vec<u8>>>,
+        fail_writes: bool,
+    }
+
+    impl FakeFileSystem {
+        fn leak(self) -> &'static FakeFileSystem {
+            Box::leak(Box::new(self))
+        }
+    }
+
+    fn not_found() -> io::Error {
+        io::Error::new(io::ErrorKind::NotFound, "no such fake file")
+    }
+
+    impl FileSystem for FakeFileSystem {
+        fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+            self.files.lock().get(path).cloned().ok_or_else(not_found)
+        }
+
+        fn write(&self, path: &str, contents: &[u8]) -> io::Result<()> {
+            if self.fail_writes {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "simulated write failure",
+                ));
+            }
+            self.files
+                .lock()
+                .insert(path.to_string(), contents.to_vec());
+            Ok(())
+        }
+
+        fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+            let mut files = self.files.lock();
+            let contents = files.remove(from).ok_or_else(not_found)?;
+            files.insert(to.to_string(), contents);
+            Ok(())
+        }
+
+        fn remove_file(&self, path: &str) -> io::Result<()> {
+            self.files
+                .lock()
+                .remove(path)
+                .map(|_| ())
+                .ok_or_else(not_found)
+        }
+
+        fn modified(&self, path: &str) -> io::Result<SystemTime> {
+            self.files
+                .lock()
+                .get(path)
+                .map(|_| SystemTime::now())
+                .ok_or_else(not_found)
+        }
+    }
+
+    #[test]
+    fn corrupt_count_is_treated_as_zero() {
+        let path = path_for_name("fake_fs_corrupt_count");
+        let count_file = Lock::gen_count_file(&path);
+        let fs = FakeFileSystem::default().leak();
+        fs.files.lock().insert(count_file.clone(), vec![1, 0]);
+
+        let mut lock = Lock::with_fs(&path, LockType::Flock, fs);
+        assert_eq!(lock.parallel_count, 0);
+        lock.start_parallel(None);
+        assert_eq!(fs.read(&count_file).unwrap(), 1u32.to_ne_bytes());
+        lock.end_parallel();
+        assert_eq!(fs.read(&count_file).unwrap(), 0u32.to_ne_bytes());
+        assert!(!Path::new(&count_file).exists());
+    }
+
+    #[test]
+    #[should_panic(expected = "simulated write failure")]
+    fn failed_count_write_panics() {
+        let path = path_for_name("fake_fs_failed_count_write");
+        let fs = FakeFileSystem {
+            fail_writes: true,
+            ..Default::default()
+        }
+        .leak();
+        Lock::with_fs(&path, LockType::Flock, fs).start_parallel(None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    #[should_panic(expected = "was deleted or replaced while locked")]
+    fn replaced_lock_file_is_noticed() {
+        let path = path_for_name("replaced_lock_file_is_noticed");
+        let lock = Lock::new(&path, LockType::Flock);
+        std::fs::remove_file(&path).unwrap();
+        std::fs::write(&path, "").unwrap();
+        lock.end_serial();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn lock_file_replaced_while_waiting_is_reopened() {
+        let path = path_for_name("lock_file_replaced_while_waiting");
+        let first = Lock::new(&path, LockType::Flock);
+        let waiting = {
+            let path = path.clone();
+            std::thread::spawn(move || Lock::new(&path, LockType::Flock))
+        };
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        std::fs::remove_file(&path).unwrap();
+        std::fs::write(&path, "").unwrap();
+        assert!(
+            std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| first.end_serial())).is_err()
+        );
+        // It's got the file that's there now, not the one it was waiting on
+        let second = waiting.join().unwrap();
+        assert!(is_serially_locked(&path, LockType::Flock));
+        second.end_serial();
+    }
+
+    #[test]
+    fn unchanged_lock_file_is_fine() {
+        let path = path_for_name("unchanged_lock_file_is_fine");
+        Lock::new(&path, LockType::Flock).end_serial();
+    }
+}
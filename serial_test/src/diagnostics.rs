@@ -0,0 +1,311 @@
+use crate::{code_lock::global_locks, lock_order::held_keys};
+use once_cell::sync::OnceCell;
+use parking_lot::Mutex;
+use std::{
+    collections::HashMap,
+    thread::{self, ThreadId},
+};
+
+struct Waiter {
+    waiting_for: String,
+    holding: Vec<String>,
+}
+
+// Threads currently waiting for a serial key, and the serial keys they're holding meanwhile
+fn waiting_threads() -> &'static Mutex<HashMap<ThreadId, Waiter>> {
+    static WAITING: OnceCell<Mutex<HashMap<ThreadId, Waiter>>> = OnceCell::new();
+    WAITING.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Records that the current thread is waiting for `name`, until dropped
+pub(crate) struct Waiting;
+
+impl Waiting {
+    pub(crate) fn new(name: &str) -> Waiting {
+        let holding = held_keys()
+            .into_iter()
+            .filter(|held| held != name)
+            .collect();
+        waiting_threads().lock().insert(
+            thread::current().id(),
+            Waiter {
+                waiting_for: name.to_string(),
+                holding,
+            },
+        );
+        Waiting
+    }
+}
+
+impl Drop for Waiting {
+    fn drop(&mut self) {
+        waiting_threads().lock().remove(&thread::current().id());
+    }
+}
+
+/// State of a single key in a [LockGraph]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LockState {
+    /// Whether any thread holds the key serially
+    pub serial_held: bool,
+    /// How many parallel users the key has
+    pub parallel_count: u32,
+    /// How many threads are blocked waiting for the key, serially or in parallel
+    pub waiters: u32,
+}
+
+/// A key in a [LockGraph]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LockNode {
+    /// The key, with `""` being the global key used by attributes without one
+    pub key: String,
+    /// From `desc` on the attributes, if any
+    pub description: Option<String>,
+    /// What's happening with the key
+    pub state: LockState,
+}
+
+/// Snapshot of the in-process locks, from [lock_graph]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LockGraph {
+    /// Every key that's been used so far, sorted by key
+    pub nodes: Vec<LockNode>,
+    /// `(a, b)` means a thread holding `a` serially is waiting for `b` serially
+    pub edges: Vec<(String, String)>,
+}
+
+/// Takes a snapshot of all the in-process keys (not [file_serial](macro@crate::file_serial) ones), for diagnosing
+/// deadlocks or documenting how a suite uses its keys
+///
+/// Each key is read separately while other tests carry on, so the snapshot isn't guaranteed to be consistent.
+/// Edges are only tracked for serial locks, as parallel users aren't tracked per-thread.
+/// ````
+/// use serial_test::{lock_graph, with_serial};
+///
+/// with_serial(["db"], || {
+///     let graph = lock_graph();
+///     let db = graph.nodes.iter().find(|node| node.key == "db").unwrap();
+///     assert!(db.state.serial_held);
+/// });
+/// ````
+pub fn lock_graph() -> LockGraph {
+    let mut nodes = Vec::new();
+    global_locks().scan(|key, lock| {
+        nodes.push(LockNode {
+            key: key.clone(),
+            description: lock.description(),
+            state: LockState {
+                serial_held: lock.is_locked(),
+                parallel_count: lock.parallel_count(),
+                waiters: lock.waiters(),
+            },
+        });
+    });
+    nodes.sort_by(|a, b| a.key.cmp(&b.key));
+    let mut edges: Vec<(String, String)> = waiting_threads()
+        .lock()
+        .values()
+        .flat_map(|waiter| {
+            waiter
+                .holding
+                .iter()
+                .map(move |held| (held.clone(), waiter.waiting_for.clone()))
+        })
+        .collect();
+    edges.sort();
+    edges.dedup();
+    LockGraph { nodes, edges }
+}
+
+fn lock_state_table(graph: &LockGraph) -> String {
+    let key_width = graph
+        .nodes
+        .iter()
+        .map(|node| format!("{:?}", node.key).len())
+        .max()
+        .unwrap_or(0)
+        .max("key".len());
+    let mut table = format!(
+        "{:key_width$} | serial_held | parallel_count | waiter_count\n",
+        "key"
+    );
+    for node in &graph.nodes {
+        table.push_str(&format!(
+            "{:key_width$} | {:11} | {:14} | {:12}\n",
+            format!("{:?}", node.key),
+            node.state.serial_held,
+            node.state.parallel_count,
+            node.state.waiters
+        ));
+    }
+    table
+}
+
+/// Prints a table of all the in-process keys and their state to stderr, for working out why a suite has hung
+///
+/// The table has the same information as the nodes from [lock_graph], one row per key. It's meant to be hooked up
+/// to a signal, so you can ask a stuck test run what it's doing, e.g. with the `signal-hook` crate
+/// ````no_run
+/// # #[cfg(unix)]
+/// // Unsafe as print_lock_state will run inside the signal handler, see below
+/// unsafe {
+///     signal_hook::low_level::register(signal_hook::consts::SIGUSR1, serial_test::print_lock_state)
+///         .expect("to register the signal handler");
+/// }
+/// ````
+/// and then `kill -USR1 <pid>`. Note that this allocates and takes locks, so isn't async-signal-safe. That's
+/// normally fine for debugging a hung process, but it can deadlock if the signal arrives while the same thread is
+/// in the middle of updating the lock state.
+#[allow(clippy::print_stderr)]
+pub fn print_lock_state() {
+    eprint!("{}", lock_state_table(&lock_graph()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{lock_graph, lock_state_table, LockGraph, LockNode, LockState};
+    use crate::{
+        code_lock::{check_new_key, global_locks},
+        serial_code_lock::with_serial,
+    };
+    use std::{
+        sync::{Arc, Barrier},
+        thread,
+        time::Duration,
+    };
+
+    fn node(graph: &LockGraph, key: &str) -> LockNode {
+        graph
+            .nodes
+            .iter()
+            .find(|node| node.key == key)
+            .unwrap()
+            .clone()
+    }
+
+    // Other tests may be nesting locks at the same time, so only look at ours
+    fn our_edges(graph: &LockGraph) -> Vec<(String, String)> {
+        graph
+            .edges
+            .iter()
+            .filter(|(from, _)| from.starts_with("graph_"))
+            .cloned()
+            .collect()
+    }
+
+    #[test]
+    fn graph_of_waiting_threads() {
+        check_new_key("graph_c");
+        let parallel = global_locks().get("graph_c").unwrap().get().clone();
+        let parallel_hold = parallel.start_parallel();
+        let held = Arc::new(Barrier::new(2));
+        let release = Arc::new(Barrier::new(2));
+        let holder = {
+            let (held, release) = (held.clone(), release.clone());
+            thread::spawn(move || {
+                with_serial(["graph_b"], || {
+                    held.wait();
+                    release.wait();
+                })
+            })
+        };
+        held.wait();
+        let waiter = thread::spawn(|| with_serial(["graph_a"], || with_serial(["graph_b"], || {})));
+        let graph = loop {
+            let graph = lock_graph();
+            if node(&graph, "graph_b").state.waiters == 1 && !our_edges(&graph).is_empty() {
+                break graph;
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+        assert_eq!(
+            our_edges(&graph),
+            vec![("graph_a".to_string(), "graph_b".to_string())]
+        );
+        assert_eq!(
+            node(&graph, "graph_a").state,
+            LockState {
+                serial_held: true,
+                parallel_count: 0,
+                waiters: 0
+            }
+        );
+        assert_eq!(
+            node(&graph, "graph_b").state,
+            LockState {
+                serial_held: true,
+                parallel_count: 0,
+                waiters: 1
+            }
+        );
+        assert_eq!(
+            node(&graph, "graph_c").state,
+            LockState {
+                serial_held: false,
+                parallel_count: 1,
+                waiters: 0
+            }
+        );
+        release.wait();
+        holder.join().unwrap();
+        waiter.join().unwrap();
+        drop(parallel_hold);
+        let graph = lock_graph();
+        assert!(our_edges(&graph).is_empty());
+        for key in ["graph_a", "graph_b", "graph_c"] {
+            assert_eq!(
+                node(&graph, key).state,
+                LockState {
+                    serial_held: false,
+                    parallel_count: 0,
+                    waiters: 0
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn graph_has_descriptions() {
+        crate::describe_keys(["graph_described"], "Described in the graph");
+        let graph = lock_graph();
+        assert_eq!(
+            node(&graph, "graph_described").description,
+            Some("Described in the graph".to_string())
+        );
+    }
+
+    #[test]
+    fn lock_state_as_table() {
+        let graph = LockGraph {
+            nodes: vec![
+                LockNode {
+                    key: "".to_string(),
+                    description: None,
+                    state: LockState {
+                        serial_held: true,
+                        parallel_count: 0,
+                        waiters: 2,
+                    },
+                },
+                LockNode {
+                    key: "db".to_string(),
+                    description: None,
+                    state: LockState {
+                        serial_held: false,
+                        parallel_count: 3,
+                        waiters: 0,
+                    },
+                },
+            ],
+            edges: vec![],
+        };
+        assert_eq!(
+            lock_state_table(&graph),
+            concat!(
+                "key  | serial_held | parallel_count | waiter_count\n",
+                "\"\"   | true        |              0 |            2\n",
+                "\"db\" | false       |              3 |            0\n",
+            )
+        );
+    }
+}
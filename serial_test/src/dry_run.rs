@@ -0,0 +1,43 @@
+use once_cell::sync::OnceCell;
+use std::env;
+
+// Only `SERIAL_TEST_DRY_RUN=1` counts, so e.g. `0` doesn't switch it on
+fn dry_run_from(dry_run: Option<String>) -> bool {
+    dry_run.as_deref() == Some("1")
+}
+
+// Checked once, so a test can't switch it on or off partway through a run
+fn dry_run() -> bool {
+    static DRY_RUN: OnceCell<bool> = OnceCell::new();
+    *DRY_RUN.get_or_init(|| dry_run_from(env::var("SERIAL_TEST_DRY_RUN").ok()))
+}
+
+/// With `SERIAL_TEST_DRY_RUN=1`, logs that the test would `plan` its `names`, and returns true so the caller runs
+/// the test without locking anything
+#[allow(unused_variables)]
+pub(crate) fn skip_locking(plan: &str, names: &[&str]) -> bool {
+    if !dry_run() {
+        return false;
+    }
+    // The test harness names each test's thread after it, which is what the plan's for
+    let thread = std::thread::current();
+    let test = thread.name().unwrap_or("<unnamed>");
+    #[cfg(feature = "logging")]
+    log::info!("{} would {} {:?}", test, plan, names);
+    #[cfg(feature = "tracing")]
+    tracing::info!("{} would {} {:?}", test, plan, names);
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::dry_run_from;
+
+    #[test]
+    fn dry_run_needs_one() {
+        assert!(dry_run_from(Some("1".into())));
+        assert!(!dry_run_from(Some("0".into())));
+        assert!(!dry_run_from(Some("".into())));
+        assert!(!dry_run_from(None));
+    }
+}
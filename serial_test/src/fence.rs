@@ -0,0 +1,256 @@
+use crate::{code_lock::global_locks, rwlock::ParallelHold, ParallelGuard};
+use parking_lot::{Condvar, Mutex};
+use std::time::Instant;
+
+// How many have got to a rendezvous, and how many times it's let everyone go
+#[derive(Default)]
+struct Arrivals {
+    arrived: usize,
+    generation: u64,
+}
+
+/// Where a group waits for all of its members to turn up, shared by [LockFence] and
+/// [parallel_rendezvous](crate::parallel_rendezvous)
+#[derive(Default)]
+pub(crate) struct Rendezvous {
+    arrivals: Mutex<Arrivals>,
+    everyone_here: Condvar,
+}
+
+impl Rendezvous {
+    /// Waits for `size` arrivals, including this one, giving up at `deadline` if there is one
+    ///
+    /// Returns false if it gave up, in which case this arrival doesn't count towards the next ones to turn up.
+    pub(crate) fn wait(&self, size: usize, deadline: Option<Instant>) -> bool {
+        let mut arrivals = self.arrivals.lock();
+        let generation = arrivals.generation;
+        arrivals.arrived += 1;
+        if arrivals.arrived >= size {
+            arrivals.arrived = 0;
+            arrivals.generation += 1;
+            self.everyone_here.notify_all();
+            return true;
+        }
+        loop {
+            let timed_out = match deadline {
+                Some(deadline) => self
+                    .everyone_here
+                    .wait_until(&mut arrivals, deadline)
+                    .timed_out(),
+                None => {
+                    self.everyone_here.wait(&mut arrivals);
+                    false
+                }
+            };
+            // Waits can end spuriously, so only the generation changing counts
+            if arrivals.generation != generation {
+                return true;
+            }
+            if timed_out {
+                arrivals.arrived -= 1;
+                return false;
+            }
+        }
+    }
+
+    #[cfg(test)]
+    fn arrived(&self) -> usize {
+        self.arrivals.lock().arrived
+    }
+}
+
+/// A rendezvous point for tests sharing a key, serial or parallel
+///
+/// Each of the `count` participants calls [wait](LockFence::wait), which blocks until all of them have. Unlike
+/// [std::sync::Barrier], a participant holding the key lets go of it while waiting, so the others can get into their
+/// tests and reach the fence as well, whether it's held serially or in parallel. It takes the key back before
+/// returning, so a serial participant still waits for anyone else using it to finish first. Once everyone's
+/// through, the fence can be used again.
+/// ````
+/// use serial_test::{parallel, serial, LockFence};
+/// use std::thread;
+///
+/// static FENCE: once_cell::sync::Lazy<LockFence> = once_cell::sync::Lazy::new(|| LockFence::new("server", 2));
+///
+/// #[serial(server)]
+/// fn start_server() {
+///     // Set things up, then wait for the client to turn up
+///     FENCE.wait();
+/// }
+///
+/// #[parallel(server)]
+/// fn client() {
+///     FENCE.wait();
+/// }
+///
+/// let server = thread::spawn(start_server);
+/// client();
+/// server.join().unwrap();
+/// ````
+/// Only the holds on the key taken for the code that's waiting are let go of, i.e. by the
+/// [serial](macro@crate::serial) or [parallel](macro@crate::parallel) test (or [with_serial](crate::with_serial)
+/// and the like) it's running in, plus the guard given to [wait_with](LockFence::wait_with). Anything else holding
+/// the key, e.g. a [serial_lock_async](crate::serial_lock_async) guard or another task on the same thread, still
+/// keeps everyone else out.
+pub struct LockFence {
+    key: String,
+    count: usize,
+    rendezvous: Rendezvous,
+}
+
+impl LockFence {
+    /// A fence for `count` participants using `key`
+    ///
+    /// # Panics
+    /// If `count` is zero.
+    pub fn new(key: &str, count: usize) -> LockFence {
+        assert!(count > 0, "A LockFence needs at least one participant");
+        LockFence {
+            key: key.to_string(),
+            count,
+            rendezvous: Rendezvous::default(),
+        }
+    }
+
+    /// Blocks until all the participants have called this, letting go of the key while waiting if the test
+    /// calling it holds it
+    pub fn wait(&self) {
+        self.wait_releasing(&[]);
+    }
+
+    /// Like [wait](LockFence::wait), but also lets go of `guard`'s hold on the key while waiting
+    /// ````
+    /// use serial_test::{parallel_lock, LockFence};
+    ///
+    /// let fence = LockFence::new("server", 1);
+    /// let guard = parallel_lock(Some("server"));
+    /// fence.wait_with(&guard);
+    /// ````
+    pub fn wait_with(&self, guard: &ParallelGuard) {
+        self.wait_releasing(guard.holds());
+    }
+
+    fn wait_releasing(&self, guarded: &[ParallelHold]) {
+        // Cloned out first, as holding the map entry while waiting would block anyone else looking up the key
+        let lock = global_locks().get(&self.key).map(|lock| lock.get().clone());
+        match lock {
+            Some(lock) => lock.released_while(guarded, || self.rendezvous()),
+            // No-one's used the key yet, so there's nothing to let go of
+            None => self.rendezvous(),
+        }
+    }
+
+    fn rendezvous(&self) {
+        self.rendezvous.wait(self.count, None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LockFence;
+    use crate::{is_locked_serially, local_parallel_core, local_serial_core, parallel_lock};
+    use std::{sync::Arc, thread};
+
+    #[test]
+    fn serial_tests_meet() {
+        let fence = Arc::new(LockFence::new("fence_serial_tests_meet", 2));
+        let threads: Vec<_> = (0..2)
+            .map(|_| {
+                let fence = fence.clone();
+                thread::spawn(move || {
+                    local_serial_core(vec!["fence_serial_tests_meet"], None, || {
+                        fence.wait();
+                        assert!(is_locked_serially(Some("fence_serial_tests_meet")));
+                    })
+                })
+            })
+            .collect();
+        threads
+            .into_iter()
+            .for_each(|thread| thread.join().unwrap());
+    }
+
+    #[test]
+    fn serial_and_parallel_meet() {
+        let fence = Arc::new(LockFence::new("fence_serial_and_parallel_meet", 3));
+        // Whichever gets the key first, the others have to get in for it to get past the fence
+        let serial = {
+            let fence = fence.clone();
+            thread::spawn(move || {
+                local_serial_core(vec!["fence_serial_and_parallel_meet"], None, || {
+                    fence.wait();
+                })
+            })
+        };
+        let parallels: Vec<_> = (0..2)
+            .map(|_| {
+                let fence = fence.clone();
+                thread::spawn(move || {
+                    local_parallel_core(vec!["fence_serial_and_parallel_meet"], None, || {
+                        fence.wait();
+                    })
+                })
+            })
+            .collect();
+        parallels
+            .into_iter()
+            .for_each(|thread| thread.join().unwrap());
+        serial.join().unwrap();
+    }
+
+    #[test]
+    fn parallel_waits_for_serial() {
+        let fence = Arc::new(LockFence::new("fence_parallel_waits_for_serial", 2));
+        let parallel = {
+            let fence = fence.clone();
+            thread::spawn(move || {
+                local_parallel_core(vec!["fence_parallel_waits_for_serial"], None, || {
+                    fence.wait();
+                })
+            })
+        };
+        // The serial test can only get in once the parallel one's let go of the key at the fence
+        while fence.rendezvous.arrived() == 0 {
+            thread::yield_now();
+        }
+        local_serial_core(vec!["fence_parallel_waits_for_serial"], None, || {
+            fence.wait();
+        });
+        parallel.join().unwrap();
+    }
+
+    #[test]
+    fn wait_with_lets_go_of_guard() {
+        let fence = Arc::new(LockFence::new("fence_wait_with_lets_go_of_guard", 2));
+        let guard = parallel_lock(Some("fence_wait_with_lets_go_of_guard"));
+        // Can only get in once the guard's let go of the key at the fence
+        let serial = {
+            let fence = fence.clone();
+            thread::spawn(move || {
+                local_serial_core(vec!["fence_wait_with_lets_go_of_guard"], None, || {
+                    fence.wait();
+                })
+            })
+        };
+        fence.wait_with(&guard);
+        drop(guard);
+        serial.join().unwrap();
+    }
+
+    #[test]
+    fn reusable() {
+        let fence = Arc::new(LockFence::new("fence_reusable", 2));
+        let other = {
+            let fence = fence.clone();
+            thread::spawn(move || (0..3).for_each(|_| fence.wait()))
+        };
+        (0..3).for_each(|_| fence.wait());
+        other.join().unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "A LockFence needs at least one participant")]
+    fn no_participants() {
+        LockFence::new("fence_no_participants", 0);
+    }
+}
@@ -1,136 +1,402 @@
-use fslock::LockFile;
-#[cfg(feature = "logging")]
-use log::debug;
+use once_cell::sync::OnceCell;
+use parking_lot::Mutex;
 use std::{
     env,
-    fs::{self, File},
-    io::{Read, Write},
-    path::Path,
-    thread,
-    time::Duration,
+    ffi::OsString,
+    fs, io,
+    path::{Path, PathBuf},
+    process,
+    time::SystemTime,
 };
 
-pub(crate) struct Lock {
-    lockfile: LockFile,
-    pub(crate) parallel_count: u32,
-    path: String,
-}
-
-impl Lock {
-    // Can't use the same file as fslock truncates it
-    fn gen_count_file(path: &str) -> String {
-        format!("{}-count", path)
-    }
-
-    fn read_parallel_count(path: &str) -> u32 {
-        let parallel_count = match File::open(Lock::gen_count_file(path)) {
-            Ok(mut file) => {
-                let mut count_buf = [0; 4];
-                match file.read_exact(&mut count_buf) {
-                    Ok(_) => u32::from_ne_bytes(count_buf),
-                    Err(_err) => {
-                        #[cfg(feature = "logging")]
-                        debug!("Error loading count file: {}", _err);
-                        0u32
-                    }
-                }
-            }
-            Err(_) => 0,
-        };
-
-        #[cfg(feature = "logging")]
-        debug!("Parallel count for {:?} is {}", path, parallel_count);
-        parallel_count
-    }
+// Both backends lock the lock file itself with flock(2) on Unix (directly or via fs2), so other programs can join
+// in with flock(1). This is documented, so changing it breaks anyone doing that.
+#[cfg(not(feature = "shared_file_locks"))]
+pub(crate) use crate::count_file_lock::{is_serially_locked, Lock};
+#[cfg(feature = "shared_file_locks")]
+pub(crate) use crate::shared_file_lock::{is_serially_locked, Lock};
 
-    pub(crate) fn new(path: &str) -> Lock {
-        if !Path::new(path).exists() {
-            fs::write(path, "").unwrap_or_else(|_| panic!("Lock file path was {:?}", path))
-        }
-        let mut lockfile = LockFile::open(path).unwrap();
+/// Which OS primitive locks the lock file, from `lock_type` on [file_serial](macro@crate::file_serial) and
+/// [file_parallel](macro@crate::file_parallel). Everyone using a lock file needs to agree on this, as the different
+/// kinds don't see each other's locks.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LockType {
+    /// flock(2) on Unix, LockFileEx on Windows
+    #[default]
+    Flock,
+    /// fcntl(2) record locks, Unix only
+    Fcntl,
+    /// Creating a marker file with `O_CREAT|O_EXCL`, for filesystems without working locks
+    OpenExclusive,
+}
 
-        #[cfg(feature = "logging")]
-        debug!("Waiting on {:?}", path);
+/// The file operations the lock files need, apart from the locking itself (which the OS does), so tests can
+/// swap in a fake to see what happens when they go wrong
+// The shared lock files only use this for the holder file
+#[cfg_attr(feature = "shared_file_locks", allow(dead_code))]
+pub(crate) trait FileSystem: Sync {
+    fn read(&self, path: &str) -> io::Result<Vec<u8>>;
+    fn write(&self, path: &str, contents: &[u8]) -> io::Result<()>;
+    fn rename(&self, from: &str, to: &str) -> io::Result<()>;
+    fn remove_file(&self, path: &str) -> io::Result<()>;
+    fn modified(&self, path: &str) -> io::Result<SystemTime>;
+}
 
-        lockfile.lock().unwrap();
+pub(crate) struct RealFileSystem;
 
-        #[cfg(feature = "logging")]
-        debug!("Locked for {:?}", path);
+pub(crate) static REAL_FILE_SYSTEM: RealFileSystem = RealFileSystem;
 
-        Lock {
-            lockfile,
-            parallel_count: Lock::read_parallel_count(path),
-            path: String::from(path),
-        }
+impl FileSystem for RealFileSystem {
+    fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        fs::read(path)
     }
 
-    pub(crate) fn start_serial(self: &mut Lock) {
-        loop {
-            if self.parallel_count == 0 {
-                return;
-            }
-            #[cfg(feature = "logging")]
-            debug!("Waiting because parallel count is {}", self.parallel_count);
-            // unlock here is safe because we re-lock before returning
-            self.unlock();
-            thread::sleep(Duration::from_secs(1));
-            self.lockfile.lock().unwrap();
-            #[cfg(feature = "logging")]
-            debug!("Locked for {:?}", self.path);
-            self.parallel_count = Lock::read_parallel_count(&self.path)
-        }
+    fn write(&self, path: &str, contents: &[u8]) -> io::Result<()> {
+        fs::write(path, contents)
     }
 
-    fn unlock(self: &mut Lock) {
-        #[cfg(feature = "logging")]
-        debug!("Unlocking {}", self.path);
-        self.lockfile.unlock().unwrap();
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        fs::rename(from, to)
     }
 
-    pub(crate) fn end_serial(mut self: Lock) {
-        self.unlock();
+    fn remove_file(&self, path: &str) -> io::Result<()> {
+        fs::remove_file(path)
     }
 
-    fn write_parallel(self: &Lock) {
-        let mut file = File::create(&Lock::gen_count_file(&self.path)).unwrap();
-        file.write_all(&self.parallel_count.to_ne_bytes()).unwrap();
+    fn modified(&self, path: &str) -> io::Result<SystemTime> {
+        fs::metadata(path)?.modified()
     }
+}
 
-    pub(crate) fn start_parallel(self: &mut Lock) {
-        self.parallel_count += 1;
-        self.write_parallel();
-        self.unlock();
+/// Which file is at `path` (device and inode), so we can tell if it's been deleted or replaced since. There's no
+/// equivalent elsewhere, so this is always `None` there.
+// Only the count lock files check this
+#[cfg(unix)]
+#[cfg_attr(feature = "shared_file_locks", allow(dead_code))]
+pub(crate) fn file_identity(path: &str) -> Option<(u64, u64)> {
+    fs::metadata(path).ok().map(metadata_identity)
+}
+
+/// Which file `file` is, as per [file_identity], even if it's not at the path it was opened from any more
+#[cfg(unix)]
+#[cfg_attr(feature = "shared_file_locks", allow(dead_code))]
+pub(crate) fn open_file_identity(file: &fs::File) -> Option<(u64, u64)> {
+    file.metadata().ok().map(metadata_identity)
+}
+
+#[cfg(unix)]
+#[cfg_attr(feature = "shared_file_locks", allow(dead_code))]
+fn metadata_identity(metadata: fs::Metadata) -> (u64, u64) {
+    use std::os::unix::fs::MetadataExt;
+
+    (metadata.dev(), metadata.ino())
+}
+
+#[cfg(not(unix))]
+#[cfg_attr(feature = "shared_file_locks", allow(dead_code))]
+pub(crate) fn file_identity(_path: &str) -> Option<(u64, u64)> {
+    None
+}
+
+// Who's got the lock, for logging by anyone waiting on it. Only ever written with the serial lock held
+pub(crate) fn gen_holder_file(path: &str) -> String {
+    format!("{}-holder", path)
+}
+
+/// Records `holder` (the test name) and our PID, for as long as we've got the serial lock
+pub(crate) fn write_holder(fs: &dyn FileSystem, path: &str, holder: &str) {
+    let holder_file = gen_holder_file(path);
+    let temp_holder_file = format!("{}.tmp", holder_file);
+    fs.write(
+        &temp_holder_file,
+        format!("process {} ({})", process::id(), holder).as_bytes(),
+    )
+    .unwrap();
+    fs.rename(&temp_holder_file, &holder_file).unwrap();
+}
+
+// The OS errors for these are pretty unhelpful, so check first
+pub(crate) fn check_path(path: &str) {
+    let lock_path = Path::new(path);
+    if lock_path.is_dir() {
+        panic!(
+            "Lock file path {:?} is a directory, but needs to be a file (e.g. {:?})",
+            path,
+            lock_path.join("serial-test.lock")
+        );
     }
+    if let Some(file_ancestor) = lock_path
+        .ancestors()
+        .skip(1)
+        .find(|ancestor| ancestor.is_file())
+    {
+        panic!(
+            "Lock file path {:?} is inside {:?}, which is a file, not a directory",
+            path, file_ancestor
+        );
+    }
+}
 
-    pub(crate) fn end_parallel(mut self: Lock) {
-        assert!(self.parallel_count > 0);
-        self.parallel_count -= 1;
-        self.write_parallel();
-        self.unlock();
+struct LockPath {
+    path: PathBuf,
+    in_use: bool,
+}
+
+fn global_lock_path() -> &'static Mutex<LockPath> {
+    static LOCK_PATH: OnceCell<Mutex<LockPath>> = OnceCell::new();
+    LOCK_PATH.get_or_init(|| {
+        Mutex::new(LockPath {
+            path: env::temp_dir(),
+            in_use: false,
+        })
+    })
+}
+
+/// Sets the directory that [file_serial](macro@crate::file_serial)/[file_parallel](macro@crate::file_parallel)
+/// put their lock files in when not given an explicit `path`
+///
+/// This defaults to [env::temp_dir], which can collide when that's shared between e.g. CI shards.
+/// It needs to be called before any file locks are taken (e.g. from a `ctor` or custom test harness setup),
+/// and will panic if a lock file has already been created in the old location.
+pub fn set_global_file_lock_path(path: PathBuf) {
+    let mut lock_path = global_lock_path().lock();
+    if lock_path.in_use {
+        panic!("file lock path already in use");
     }
+    lock_path.path = path;
 }
 
 pub(crate) fn path_for_name(name: &str) -> String {
-    let mut pathbuf = env::temp_dir();
-    pathbuf.push(format!("serial-test-{}", name));
+    let mut pathbuf = {
+        let mut lock_path = global_lock_path().lock();
+        lock_path.in_use = true;
+        lock_path.path.clone()
+    };
+    pathbuf.push(file_name_for(name));
     pathbuf.into_os_string().into_string().unwrap()
 }
 
-fn make_lock_for_name_and_path(name: &str, path: Option<&str>) -> Lock {
-    if let Some(opt_path) = path {
-        Lock::new(opt_path)
-    } else {
-        let default_path = path_for_name(name);
-        Lock::new(&default_path)
+// Most filesystems allow 255 bytes in a file name, and this leaves room for the "-holder.tmp" etc. suffixes
+const MAX_FILE_NAME: usize = 200;
+
+// Long names keep a readable prefix, with a hash of the full name on the end so they stay distinct
+fn file_name_for(name: &str) -> String {
+    let file_name = format!("serial-test-{}", name);
+    if file_name.len() <= MAX_FILE_NAME {
+        return file_name;
+    }
+    let mut prefix_len = MAX_FILE_NAME - 17;
+    while !file_name.is_char_boundary(prefix_len) {
+        prefix_len -= 1;
+    }
+    format!("{}-{:016x}", &file_name[..prefix_len], name_hash(name))
+}
+
+// FNV-1a, as lock files are shared between processes (and builds), so this can't change between them
+fn name_hash(name: &str) -> u64 {
+    name.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Check if a [file_serial](macro@crate::file_serial) lock is currently held, by anyone
+///
+/// This tries to take the lock for the key (at the default path), and immediately lets go if that works. That's
+/// with the default `lock_type`, so use [is_file_locked_with] for keys locked any other way.
+/// The answer can be out of date by the time you look at it, so this is only really useful for
+/// diagnostic assertions in test helpers, not for making decisions about whether to go ahead.
+/// ````no_run
+/// use serial_test::{file_serial, is_file_locked};
+///
+/// #[file_serial(some_key)]
+/// fn main() {
+///     assert!(is_file_locked(Some("some_key")));
+/// }
+/// ````
+pub fn is_file_locked(name: Option<&str>) -> bool {
+    is_file_locked_with(name, LockType::Flock)
+}
+
+/// Like [is_file_locked], for keys locked with the given `lock_type`
+///
+/// The different kinds of lock don't see each other, so this has to check the same way the tests lock it.
+/// ````no_run
+/// use serial_test::{file_serial, is_file_locked_with, LockType};
+///
+/// #[file_serial(some_key, lock_type = "fcntl")]
+/// fn main() {
+///     assert!(is_file_locked_with(Some("some_key"), LockType::Fcntl));
+/// }
+/// ````
+/// On Unix other than Linux and Android, `fcntl` locks belong to the whole process, so one held elsewhere in this
+/// process isn't seen.
+pub fn is_file_locked_with(name: Option<&str>, lock_type: LockType) -> bool {
+    let path = path_for_name(name.unwrap_or(crate::code_lock::DEFAULT_KEY));
+    if !Path::new(&path).exists() {
+        return false;
+    }
+    is_serially_locked(&path, lock_type)
+}
+
+// What relative paths are from: the crate's manifest dir, which cargo (and nextest) set when running its tests,
+// or the current dir otherwise
+fn relative_base_from(manifest_dir: Option<OsString>) -> PathBuf {
+    manifest_dir
+        .map(PathBuf::from)
+        .unwrap_or_else(|| env::current_dir().unwrap_or_default())
+}
+
+// Worked out once, so a test changing the current dir later doesn't move everyone else's lock files
+fn relative_base() -> &'static Path {
+    static RELATIVE_BASE: OnceCell<PathBuf> = OnceCell::new();
+    RELATIVE_BASE.get_or_init(|| relative_base_from(env::var_os("CARGO_MANIFEST_DIR")))
+}
+
+fn lock_path_for_name_and_path(name: &str, path: Option<&str>) -> String {
+    match path {
+        // Absolute paths replace the base when joined, so are used as-is
+        Some(opt_path) => relative_base()
+            .join(opt_path)
+            .into_os_string()
+            .into_string()
+            .unwrap(),
+        None => path_for_name(name),
+    }
+}
+
+/// Either a path shared by all the keys, or one path per key
+#[doc(hidden)]
+pub enum LockPaths<'a> {
+    Shared(Option<&'a str>),
+    PerKey(Vec<Option<&'a str>>),
+}
+
+impl<'a> From<Option<&'a str>> for LockPaths<'a> {
+    fn from(path: Option<&'a str>) -> Self {
+        LockPaths::Shared(path)
     }
 }
 
-pub(crate) fn get_locks(names: &Vec<&str>, path: Option<&str>) -> Vec<Lock> {
-    if names.len() > 1 && path.is_some() {
-        panic!("Can't do file_parallel with both more than one name _and_ a specific path");
+impl<'a> From<Vec<Option<&'a str>>> for LockPaths<'a> {
+    fn from(paths: Vec<Option<&'a str>>) -> Self {
+        LockPaths::PerKey(paths)
+    }
+}
+
+/// Lock file path for each of the keys
+pub(crate) fn lock_paths(names: &[&str], paths: &LockPaths) -> Vec<String> {
+    match paths {
+        LockPaths::Shared(path) => {
+            if names.len() > 1 && path.is_some() {
+                panic!("Can't do file_parallel with both more than one name _and_ a specific path");
+            }
+            names
+                .iter()
+                .map(|name| lock_path_for_name_and_path(name, *path))
+                .collect::<Vec<_>>()
+        }
+        LockPaths::PerKey(paths) => {
+            if names.len() != paths.len() {
+                panic!(
+                    "Need one path per key, but got {} keys and {} paths",
+                    names.len(),
+                    paths.len()
+                );
+            }
+            names
+                .iter()
+                .zip(paths.iter())
+                .map(|(name, path)| lock_path_for_name_and_path(name, *path))
+                .collect::<Vec<_>>()
+        }
     }
-    names
+}
+
+pub(crate) fn get_locks(names: &Vec<&str>, paths: &LockPaths, lock_type: LockType) -> Vec<Lock> {
+    lock_paths(names, paths)
         .iter()
-        .map(|name| make_lock_for_name_and_path(name, path))
-        .collect::<Vec<_>>()
+        .map(|path| Lock::new(path, lock_type))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        lock_path_for_name_and_path, path_for_name, relative_base_from, Lock, LockType,
+        MAX_FILE_NAME,
+    };
+    use std::{
+        env, fs,
+        path::{Path, PathBuf},
+    };
+
+    #[test]
+    fn short_names_are_readable() {
+        let path = path_for_name("short_name");
+        assert!(path.ends_with("serial-test-short_name"));
+    }
+
+    #[test]
+    fn long_names_are_shortened() {
+        let name = "a".repeat(500);
+        let path = path_for_name(&name);
+        let file_name = Path::new(&path).file_name().unwrap().to_str().unwrap();
+        assert!(file_name.len() <= MAX_FILE_NAME);
+        assert!(file_name.starts_with("serial-test-aaaa"));
+        assert_eq!(path, path_for_name(&name));
+
+        // Only differs after the truncation point
+        let other_name = format!("{}b", "a".repeat(499));
+        assert_ne!(path, path_for_name(&other_name));
+
+        let lock = Lock::new(&path, LockType::Flock);
+        assert!(Path::new(&path).exists());
+        drop(lock);
+    }
+
+    #[test]
+    fn long_unicode_names_are_shortened() {
+        let path = path_for_name(&"é".repeat(500));
+        let file_name = Path::new(&path).file_name().unwrap().to_str().unwrap();
+        assert!(file_name.len() <= MAX_FILE_NAME);
+    }
+
+    #[test]
+    #[should_panic(expected = "is a directory, but needs to be a file")]
+    fn lock_path_is_directory() {
+        let path = path_for_name("lock_path_is_directory");
+        fs::create_dir_all(&path).unwrap();
+        Lock::new(&path, LockType::Flock);
+    }
+
+    #[test]
+    #[should_panic(expected = "which is a file, not a directory")]
+    fn lock_path_parent_is_file() {
+        let parent = path_for_name("lock_path_parent_is_file");
+        fs::write(&parent, "").unwrap();
+        Lock::new(&format!("{}/lock", parent), LockType::Flock);
+    }
+
+    #[test]
+    fn relative_base() {
+        assert_eq!(
+            relative_base_from(Some("/workspace/crate".into())),
+            PathBuf::from("/workspace/crate")
+        );
+        assert_eq!(relative_base_from(None), env::current_dir().unwrap());
+    }
+
+    #[test]
+    fn relative_paths_are_resolved() {
+        let path = lock_path_for_name_and_path("relative", Some("locks/relative"));
+        assert!(Path::new(&path).is_absolute());
+        assert!(path.ends_with("relative"));
+
+        let absolute = env::temp_dir().join("absolute");
+        let absolute = absolute.to_str().unwrap();
+        assert_eq!(
+            lock_path_for_name_and_path("absolute", Some(absolute)),
+            absolute
+        );
+    }
 }
@@ -0,0 +1,141 @@
+use std::{any::Any, cell::RefCell, panic, thread};
+
+type Join = Box<dyn FnOnce() -> thread::Result<()>>;
+
+thread_local! {
+    // Threads to join, for each serial section this thread is in (innermost last)
+    static TO_JOIN: RefCell<Vec<Vec<Join>>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Joins the threads registered during a serial section, before its locks are released
+pub(crate) struct JoinOnRelease;
+
+impl JoinOnRelease {
+    pub(crate) fn new() -> Self {
+        TO_JOIN.with(|to_join| to_join.borrow_mut().push(Vec::new()));
+        JoinOnRelease
+    }
+}
+
+impl Drop for JoinOnRelease {
+    fn drop(&mut self) {
+        let joins = TO_JOIN.with(|to_join| to_join.borrow_mut().pop().unwrap_or_default());
+        let mut first_panic: Option<Box<dyn Any + Send>> = None;
+        for join in joins {
+            if let Err(err) = join() {
+                first_panic.get_or_insert(err);
+            }
+        }
+        // Don't panic again if the test already is
+        if let Some(err) = first_panic {
+            if !thread::panicking() {
+                panic::resume_unwind(err);
+            }
+        }
+    }
+}
+
+/// Makes the current serial section wait for `handle` to finish before it releases its keys
+///
+/// A thread spawned by a [serial](macro@crate::serial) test can easily outlive it, and carry on using the shared
+/// resource while the next test with the same key runs. Registering the thread means it's joined once the test
+/// finishes, but before the key is released. If the thread panicked, so does the test (unless it's already
+/// panicking).
+/// ````
+/// use serial_test::{register_for_join, with_serial};
+/// use std::thread;
+///
+/// with_serial(["db"], || {
+///     register_for_join(thread::spawn(|| {
+///         // Background work with the database
+///     }));
+/// });
+/// // The thread has finished by now
+/// ````
+/// This works for [with_serial](crate::with_serial) and [serial](macro@crate::serial) tests, including async ones
+/// as long as they stay on the thread they started on.
+///
+/// # Panics
+/// If the current thread isn't in a serial section.
+pub fn register_for_join<T: 'static>(handle: thread::JoinHandle<T>) {
+    TO_JOIN.with(|to_join| {
+        to_join
+            .borrow_mut()
+            .last_mut()
+            .expect("register_for_join needs to be called from inside a serial test or with_serial")
+            .push(Box::new(move || handle.join().map(|_| ())));
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::register_for_join;
+    use crate::with_serial;
+    use std::{
+        panic,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        thread,
+        time::Duration,
+    };
+
+    #[test]
+    fn joined_before_release() {
+        let finished = Arc::new(AtomicBool::new(false));
+        let thread_finished = finished.clone();
+        with_serial(["joined_before_release"], || {
+            register_for_join(thread::spawn(move || {
+                thread::sleep(Duration::from_millis(200));
+                thread_finished.store(true, Ordering::SeqCst);
+            }));
+        });
+        // The next user of the key doesn't see the thread still running
+        with_serial(["joined_before_release"], || {
+            assert!(finished.load(Ordering::SeqCst));
+        });
+    }
+
+    #[test]
+    fn nested_sections_join_their_own() {
+        let finished = Arc::new(AtomicBool::new(false));
+        let thread_finished = finished.clone();
+        with_serial(["nested_join_outer"], || {
+            with_serial(["nested_join_inner"], || {
+                register_for_join(thread::spawn(move || {
+                    thread::sleep(Duration::from_millis(100));
+                    thread_finished.store(true, Ordering::SeqCst);
+                }));
+            });
+            assert!(finished.load(Ordering::SeqCst));
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "Deliberate thread panic")]
+    fn thread_panic_fails_section() {
+        with_serial(["thread_panic_fails_section"], || {
+            register_for_join(thread::spawn(|| panic!("Deliberate thread panic")));
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "register_for_join needs to be called from inside a serial test")]
+    fn outside_serial_section() {
+        register_for_join(thread::spawn(|| {}));
+    }
+
+    #[test]
+    fn released_after_test_panic() {
+        let _ = panic::catch_unwind(|| {
+            with_serial(["released_after_test_panic"], || {
+                register_for_join(thread::spawn(|| panic!("Deliberate thread panic")));
+                panic!("Deliberate test panic");
+            })
+        });
+        assert!(!crate::is_locked_serially(Some(
+            "released_after_test_panic"
+        )));
+    }
+}
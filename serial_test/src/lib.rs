@@ -6,7 +6,7 @@
 //! # serial_test
 //! `serial_test` allows for the creation of serialised Rust tests using the [serial](macro@serial) attribute
 //! e.g.
-//! ````
+//! ````no_run
 //! #[test]
 //! #[serial]
 //! fn test_serial_one() {
@@ -33,8 +33,9 @@
 //! For cases like doctests and integration tests where the tests are run as separate processes, we also support
 //! [file_serial](macro@file_serial)/[file_parallel](macro@file_parallel), with similar properties but based off file locking. Note that there are no
 //! guarantees about one test with [serial](macro@serial)/[parallel](macro@parallel) and another with [file_serial](macro@file_serial)/[file_parallel](macro@file_parallel)
-//! as they lock using different methods.
-//! ````
+//! as they lock using different methods. [dual_serial](macro@dual_serial) takes both kinds of lock, for tests that need
+//! keeping apart from both.
+//! ````no_run
 //! #[test]
 //! #[file_serial]
 //! fn test_serial_three() {
@@ -43,7 +44,7 @@
 //! ````
 //!
 //! All of the attributes can also be applied at a `mod` level and will be automagically applied to all test functions in that block
-//! ````
+//! ````no_run
 //! #[cfg(test)]
 //! #[serial]
 //! mod serial_attr_tests {
@@ -59,6 +60,13 @@
 //!}
 //! ````
 //!
+//! ## Dry runs
+//! To see how a suite's tests are meant to coordinate, set `SERIAL_TEST_DRY_RUN=1` when running them. Rather than
+//! locking, each test then logs what it would have done (e.g. `tests::test_db would acquire serial key(s) ["db"]` or
+//! `tests::test_other would start parallel on ["db"]`) at info level, via the `logging` or `tracing` features, and
+//! runs straight away. The test's name comes from its thread, which the test harness names after it. Nothing is
+//! actually kept apart, so only use it for auditing, not real runs. It's read once, when the first test starts.
+//!
 //! ## Feature flags
 #![cfg_attr(
     feature = "docsrs",
@@ -66,16 +74,48 @@
 )]
 
 mod code_lock;
+mod context;
+mod dry_run;
+mod fence;
+mod join;
+mod lock_order;
+pub mod macros;
 mod parallel_code_lock;
 mod rwlock;
 mod serial_code_lock;
+mod single_thread;
+
+#[cfg(feature = "tracing")]
+mod telemetry;
 
+#[cfg(feature = "measure_overhead")]
+mod overhead;
+
+#[cfg(feature = "runner")]
+mod runner;
+
+#[cfg(feature = "diagnostics")]
+mod diagnostics;
+
+#[cfg(feature = "persistent_stats")]
+mod stats;
+
+#[cfg(all(feature = "file_locks", not(feature = "shared_file_locks")))]
+mod count_file_lock;
 #[cfg(feature = "file_locks")]
 mod file_lock;
 #[cfg(feature = "file_locks")]
+mod nextest;
+#[cfg(all(feature = "file_locks", not(feature = "shared_file_locks")))]
+mod os_lock;
+#[cfg(feature = "file_locks")]
 mod parallel_file_lock;
 #[cfg(feature = "file_locks")]
 mod serial_file_lock;
+#[cfg(feature = "shared_file_locks")]
+mod shared_file_lock;
+#[cfg(feature = "file_locks")]
+mod shared_keys;
 
 #[cfg(feature = "async")]
 #[doc(hidden)]
@@ -86,10 +126,18 @@ pub use parallel_code_lock::{local_parallel_core, local_parallel_core_with_retur
 
 #[cfg(feature = "async")]
 #[doc(hidden)]
-pub use serial_code_lock::{local_async_serial_core, local_async_serial_core_with_return};
+pub use serial_code_lock::{
+    local_async_serial_core, local_async_serial_core_with_return, run_async_with_max_hold,
+};
+
+#[cfg(feature = "tokio_serial")]
+#[doc(hidden)]
+pub use serial_code_lock::{local_tokio_serial_core, local_tokio_serial_core_with_return};
 
 #[doc(hidden)]
-pub use serial_code_lock::{local_serial_core, local_serial_core_with_return};
+pub use serial_code_lock::{
+    local_serial_core, local_serial_core_with_return, run_with_max_hold, OnTimeout,
+};
 
 #[cfg(all(feature = "file_locks", feature = "async"))]
 #[doc(hidden)]
@@ -97,7 +145,10 @@ pub use serial_file_lock::{fs_async_serial_core, fs_async_serial_core_with_retur
 
 #[cfg(feature = "file_locks")]
 #[doc(hidden)]
-pub use serial_file_lock::{fs_serial_core, fs_serial_core_with_return};
+pub use serial_file_lock::{fs_serial_core, fs_serial_core_with_return, FileSerialOptions};
+
+#[cfg(feature = "file_locks")]
+pub use serial_file_lock::with_file_serial;
 
 #[cfg(all(feature = "file_locks", feature = "async"))]
 #[doc(hidden)]
@@ -107,10 +158,71 @@ pub use parallel_file_lock::{fs_async_parallel_core, fs_async_parallel_core_with
 #[doc(hidden)]
 pub use parallel_file_lock::{fs_parallel_core, fs_parallel_core_with_return};
 
+#[cfg(feature = "tracing")]
+#[doc(hidden)]
+pub use telemetry::telemetry_span;
+
+#[cfg(all(feature = "tracing", feature = "async"))]
+#[doc(hidden)]
+pub use telemetry::telemetry_instrument;
+
+#[cfg(feature = "measure_overhead")]
+#[doc(hidden)]
+pub use overhead::{report_overhead, report_suite_overhead, start_overhead_timer};
+
+#[doc(hidden)]
+pub use code_lock::{
+    defer_parallel_start, describe_keys, discriminated_keys, is_locked_in_parallel_here,
+    keys_from_env, with_const_keys,
+};
+
+#[doc(hidden)]
+pub use single_thread::require_single_thread;
+
+#[doc(hidden)]
+pub use lock_order::deny_reentry;
+
 // Re-export #[serial/parallel].
-pub use serial_test_derive::{parallel, serial};
+pub use serial_test_derive::{
+    parallel, require_feature, serial, serial_block, serial_suite, SerialKey,
+};
+
+// Even without file_locks, so using them says which feature's missing rather than that they don't exist
+pub use serial_test_derive::{dual_serial, file_parallel, file_serial};
 
 #[cfg(feature = "file_locks")]
-pub use serial_test_derive::{file_parallel, file_serial};
+#[doc(hidden)]
+pub use file_lock::LockType;
+#[cfg(feature = "file_locks")]
+pub use file_lock::{is_file_locked, is_file_locked_with, set_global_file_lock_path};
+
+pub use context::{context, set_context, ContextGuard};
+pub use fence::LockFence;
+pub use join::register_for_join;
+pub use parallel_code_lock::{
+    clear_parallel_panic_hook, parallel_group_size, parallel_lock, parallel_lock_keys,
+    parallel_rendezvous, set_parallel_panic_hook, ParallelGuard, ParallelPanicHook,
+};
+
+#[doc(hidden)]
+pub use parallel_code_lock::register_parallel_test;
+pub use serial_code_lock::{
+    try_with_serial_lock, with_serial, with_serial_keys_ordered, SerialLock,
+};
+
+#[cfg(feature = "runner")]
+pub use runner::{run_test_serially, TestResult};
+
+#[cfg(feature = "diagnostics")]
+pub use diagnostics::{lock_graph, print_lock_state, LockGraph, LockNode, LockState};
+
+#[cfg(feature = "persistent_stats")]
+pub use stats::{global_stats_path, save_global_stats, GlobalLockStats, KeyStats};
+
+#[cfg(feature = "async")]
+pub use serial_code_lock::{serial_lock_async, SerialAsyncGuard};
 
-pub use code_lock::is_locked_serially;
+pub use code_lock::{
+    assert_same_group, current_mode, declare_conflicts, is_locked_serially, key_description,
+    next_mutex_id, reset_mutex_ids, single_use_keys, wait_until_idle, Mode, SerialKey, DEFAULT_KEY,
+};
@@ -0,0 +1,117 @@
+use once_cell::sync::OnceCell;
+use parking_lot::Mutex;
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+};
+
+thread_local! {
+    // Serial keys held by this thread, in the order they were taken
+    static HELD: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+}
+
+// Every "took key B while holding key A" seen so far, as A -> B
+fn observed_order() -> &'static Mutex<HashMap<String, HashSet<String>>> {
+    static ORDER: OnceCell<Mutex<HashMap<String, HashSet<String>>>> = OnceCell::new();
+    ORDER.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+fn find_path(
+    order: &HashMap<String, HashSet<String>>,
+    from: &str,
+    to: &str,
+) -> Option<Vec<String>> {
+    let mut stack = vec![vec![from.to_string()]];
+    let mut visited = HashSet::new();
+    while let Some(path) = stack.pop() {
+        let last = path.last().unwrap();
+        if last == to {
+            return Some(path);
+        }
+        if !visited.insert(last.clone()) {
+            continue;
+        }
+        if let Some(nexts) = order.get(last) {
+            for next in nexts {
+                let mut next_path = path.clone();
+                next_path.push(next.clone());
+                stack.push(next_path);
+            }
+        }
+    }
+    None
+}
+
+/// Serial keys held by the current thread, in the order they were taken
+#[cfg(feature = "diagnostics")]
+pub(crate) fn held_keys() -> Vec<String> {
+    HELD.with(|held| held.borrow().clone())
+}
+
+/// Panics if the current thread already holds any of `names` serially
+#[doc(hidden)]
+pub fn deny_reentry<'a>(names: impl AsRef<[&'a str]>, test: &str) {
+    HELD.with(|held| {
+        let held = held.borrow();
+        for name in names.as_ref() {
+            if held.iter().any(|held_name| held_name == name) {
+                panic!(
+                    "{} is non_reentrant, but this thread already holds its key '{}' (e.g. it was called from another serial test)",
+                    test, name
+                );
+            }
+        }
+    });
+}
+
+/// Marks a serial key as held by the current thread, until dropped
+pub(crate) struct HeldKey {
+    name: String,
+}
+
+impl HeldKey {
+    /// Call before actually locking `name`, so an inversion panics rather than possibly hanging
+    pub(crate) fn acquire(name: &str) -> HeldKey {
+        HELD.with(|held| {
+            let mut held = held.borrow_mut();
+            // Re-entering a key we've already got can't deadlock
+            if !held.iter().any(|held_name| held_name == name) {
+                let mut order = observed_order().lock();
+                for held_name in held.iter() {
+                    if let Some(path) = find_path(&order, name, held_name) {
+                        panic!(
+                            "Lock order inversion: taking '{}' while holding '{}', but elsewhere {} were taken in that order. This can deadlock, so take them in a consistent order (e.g. both keys on one attribute)",
+                            name,
+                            held_name,
+                            path.iter()
+                                .map(|key| format!("'{}'", key))
+                                .collect::<Vec<_>>()
+                                .join(" -> ")
+                        );
+                    }
+                }
+                for held_name in held.iter() {
+                    order
+                        .entry(held_name.clone())
+                        .or_default()
+                        .insert(name.to_string());
+                }
+            }
+            held.push(name.to_string());
+        });
+        HeldKey {
+            name: name.to_string(),
+        }
+    }
+}
+
+impl Drop for HeldKey {
+    fn drop(&mut self) {
+        HELD.with(|held| {
+            let mut held = held.borrow_mut();
+            if let Some(index) = held.iter().rposition(|held_name| *held_name == self.name) {
+                held.remove(index);
+            }
+        });
+    }
+}
@@ -0,0 +1,211 @@
+//! Declarative macros for common test setups
+
+/// Runs a group of functions as [parallel](macro@crate::parallel) tests sharing a key, and checks they
+/// really do run at the same time
+///
+/// Each function gets a test in a module of the same name (e.g. `fn_one::parallel`), which calls it
+/// with the key held in parallel and then waits on a barrier shared by the whole group. That means all
+/// of the group need to be running at once for any of them to finish, so you need at least as many test
+/// threads as functions, and filtering down to only some of them will hang.
+/// ````no_run
+/// use serial_test::parallel_test_set;
+///
+/// fn fn_one() {}
+/// fn fn_two() {}
+/// fn fn_three() {}
+///
+/// parallel_test_set!("ordering_key", [fn_one, fn_two, fn_three]);
+/// ````
+/// With the `async` feature, `async` functions can be used as well, and each test blocks on its function.
+/// ````no_run
+/// use serial_test::parallel_test_set;
+///
+/// async fn fn_one() {}
+/// async fn fn_two() {}
+///
+/// parallel_test_set!("ordering_key", async [fn_one, fn_two]);
+/// ````
+#[macro_export]
+macro_rules! parallel_test_set {
+    ($key:expr, [$first:ident $(, $rest:ident)* $(,)?]) => {
+        $crate::parallel_test_set!(@set $key, sync, $first, [$($rest),*]);
+    };
+    ($key:expr, async [$first:ident $(, $rest:ident)* $(,)?]) => {
+        $crate::parallel_test_set!(@set $key, async, $first, [$($rest),*]);
+    };
+    (@set $key:expr, $mode:ident, $first:ident, [$($rest:ident),*]) => {
+        mod $first {
+            pub(super) fn __parallel_test_set_barrier() -> &'static ::std::sync::Barrier {
+                static BARRIER: $crate::macros::__private::OnceCell<::std::sync::Barrier> =
+                    $crate::macros::__private::OnceCell::new();
+                BARRIER.get_or_init(|| {
+                    ::std::sync::Barrier::new([stringify!($first) $(, stringify!($rest))*].len())
+                })
+            }
+
+            $crate::parallel_test_set!(@test $key, $mode, $first, $first);
+        }
+        $(
+            mod $rest {
+                $crate::parallel_test_set!(@test $key, $mode, $first, $rest);
+            }
+        )*
+    };
+    (@test $key:expr, sync, $first:ident, $function:ident) => {
+        #[test]
+        fn parallel() {
+            $crate::local_parallel_core(vec![$key], None, || {
+                super::$function();
+                super::$first::__parallel_test_set_barrier().wait();
+            });
+        }
+    };
+    (@test $key:expr, async, $first:ident, $function:ident) => {
+        #[test]
+        fn parallel() {
+            $crate::macros::__private::block_on($crate::local_async_parallel_core(
+                vec![$key],
+                None,
+                async {
+                    super::$function().await;
+                    super::$first::__parallel_test_set_barrier().wait();
+                },
+            ));
+        }
+    };
+}
+
+/// Panics if the current thread holds a key serially, e.g. inside a [serial](macro@crate::serial) test using it
+///
+/// For code that mustn't be called with the key held, e.g. because it takes the key itself on another thread,
+/// which would wait forever. No key means the global key used by a plain `#[serial]`.
+/// ````
+/// use serial_test::assert_not_serial;
+///
+/// fn reset_database() {
+///     assert_not_serial!("db");
+///     // Do things
+/// }
+///
+/// reset_database();
+/// ````
+#[macro_export]
+macro_rules! assert_not_serial {
+    () => {
+        $crate::assert_not_serial!($crate::DEFAULT_KEY)
+    };
+    ($key:expr) => {{
+        let key: &str = $key;
+        assert!(
+            !$crate::is_locked_serially(::std::option::Option::Some(key)),
+            "Mustn't be called while holding {:?} serially",
+            key
+        );
+    }};
+}
+
+/// Panics if the current thread holds a key in parallel, e.g. inside a [parallel](macro@crate::parallel) test using it
+///
+/// The parallel version of [assert_not_serial]. Only the parallel holds of the test that's calling it count, as
+/// other tests holding the key in parallel don't stop it doing anything. For async tests, that's still the case
+/// when they move threads, and other tasks sharing the thread don't count either.
+/// ````
+/// use serial_test::assert_not_parallel;
+///
+/// fn reset_database() {
+///     assert_not_parallel!("db");
+///     // Do things
+/// }
+///
+/// reset_database();
+/// ````
+#[macro_export]
+macro_rules! assert_not_parallel {
+    () => {
+        $crate::assert_not_parallel!($crate::DEFAULT_KEY)
+    };
+    ($key:expr) => {{
+        let key: &str = $key;
+        assert!(
+            !$crate::is_locked_in_parallel_here(::std::option::Option::Some(key)),
+            "Mustn't be called while holding {:?} in parallel",
+            key
+        );
+    }};
+}
+
+/// Runs a block holding a key serially, with some environment variables set or removed for just that block
+///
+/// The environment is global to the whole process, so changing it in tests that might run alongside each other
+/// is a race. This takes the key as per [serial](macro@crate::serial), remembers the current values of all the
+/// variables in `set` and `unset`, changes them, runs the block and then puts them all back, even if the block
+/// panics. Both `set` and `unset` are optional, and the block can use local variables and return a value.
+/// ````
+/// use serial_test::env_lock;
+///
+/// let name = "world";
+/// let greeting = env_lock!("env", set = [("GREETING", "hello")], unset = ["NAME"], {
+///     assert!(std::env::var("NAME").is_err());
+///     format!("{} {}", std::env::var("GREETING").unwrap(), name)
+/// });
+/// assert_eq!(greeting, "hello world");
+/// ````
+/// Note that this only protects against other code that takes the same key, so everything that touches those
+/// variables needs to use it (or [serial](macro@crate::serial) with that key).
+#[macro_export]
+macro_rules! env_lock {
+    ($key:expr, $(set = [$(($var:expr, $value:expr)),* $(,)?],)? $(unset = [$($unset:expr),* $(,)?],)? $body:block) => {
+        $crate::with_serial([$key], || {
+            $crate::macros::__private::with_env(
+                &[
+                    $($(($var, ::std::option::Option::Some($value)),)*)?
+                    $($(($unset, ::std::option::Option::None),)*)?
+                ],
+                || $body,
+            )
+        })
+    };
+}
+
+pub use crate::assert_not_parallel;
+pub use crate::assert_not_serial;
+pub use crate::env_lock;
+pub use crate::parallel_test_set;
+pub use serial_test_derive::serial_suite;
+
+#[doc(hidden)]
+pub mod __private {
+    #[cfg(feature = "async")]
+    pub use futures::executor::block_on;
+    pub use once_cell::sync::OnceCell;
+    use std::{
+        env,
+        panic::{self, AssertUnwindSafe},
+    };
+
+    // Applies `changes` (a value to set, or `None` to remove) for the duration of `function`
+    pub fn with_env<R>(changes: &[(&str, Option<&str>)], function: impl FnOnce() -> R) -> R {
+        let originals: Vec<_> = changes
+            .iter()
+            .map(|(name, _)| (*name, env::var_os(name)))
+            .collect();
+        for (name, value) in changes {
+            match value {
+                Some(value) => env::set_var(name, value),
+                None => env::remove_var(name),
+            }
+        }
+        let result = panic::catch_unwind(AssertUnwindSafe(function));
+        // Backwards, so a variable that's listed twice ends up with its real original value
+        for (name, original) in originals.into_iter().rev() {
+            match original {
+                Some(original) => env::set_var(name, original),
+                None => env::remove_var(name),
+            }
+        }
+        match result {
+            Ok(result) => result,
+            Err(err) => panic::resume_unwind(err),
+        }
+    }
+}
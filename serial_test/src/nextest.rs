@@ -0,0 +1,217 @@
+use crate::{
+    code_lock::global_locks,
+    file_lock::{get_locks, lock_paths, Lock, LockPaths, LockType},
+    serial_file_lock::SerialLocks,
+    shared_keys::{is_shared, shared_path},
+};
+use once_cell::sync::OnceCell;
+use parking_lot::Mutex;
+use std::{
+    collections::HashMap,
+    env,
+    thread::{self, ThreadId},
+};
+
+// Whether each test is in its own process, like nextest does by default
+fn is_process_per_test(var: impl Fn(&str) -> Option<String>) -> bool {
+    var("NEXTEST_TEST_NAME").is_some()
+        || var("NEXTEST_EXECUTION_MODE").as_deref() == Some("process-per-test")
+}
+
+fn process_per_test() -> bool {
+    static PROCESS_PER_TEST: OnceCell<bool> = OnceCell::new();
+    *PROCESS_PER_TEST.get_or_init(|| is_process_per_test(|name| env::var(name).ok()))
+}
+
+// File locks aren't reentrant, so leave out anything this thread already holds (e.g. a nested serial test),
+// as we've already got the file lock for those
+fn not_held_here<'a>(names: &[&'a str]) -> Vec<&'a str> {
+    names
+        .iter()
+        .filter(|name| {
+            !global_locks()
+                .get(**name)
+                .map(|lock| lock.get().is_locked_by_current_thread())
+                .unwrap_or(false)
+        })
+        .copied()
+        .collect()
+}
+
+// Keys that need file locks, i.e. all of them for process-per-test, otherwise just the ones from SERIAL_TEST_CONFIG
+fn file_locked<'a>(names: &[&'a str]) -> Vec<&'a str> {
+    let all = process_per_test();
+    names
+        .iter()
+        .filter(|name| all || is_shared(name))
+        .copied()
+        .collect()
+}
+
+fn locks_for(names: &[&str]) -> Vec<Lock> {
+    let names = not_held_here(names);
+    let paths = names.iter().map(|name| shared_path(name)).collect();
+    get_locks(&names, &LockPaths::PerKey(paths), LockType::Flock)
+}
+
+// Lock file paths held for serial tests, and which thread took them
+fn held_paths() -> &'static Mutex<HashMap<String, ThreadId>> {
+    static HELD_PATHS: OnceCell<Mutex<HashMap<String, ThreadId>>> = OnceCell::new();
+    HELD_PATHS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Whether this thread's already got the file lock at `path` from a serial test it's inside of (e.g. for
+/// [dual_serial](macro@crate::dual_serial)), as taking it again would deadlock
+pub(crate) fn held_by_current_thread(path: &str) -> bool {
+    held_paths().lock().get(path) == Some(&thread::current().id())
+}
+
+// Releases on drop, so we also let go if the test panics
+pub(crate) struct HeldSerialLocks {
+    paths: Vec<String>,
+    _locks: SerialLocks,
+}
+
+impl Drop for HeldSerialLocks {
+    fn drop(&mut self) {
+        let mut held_paths = held_paths().lock();
+        self.paths.iter().for_each(|path| {
+            held_paths.remove(path);
+        });
+    }
+}
+
+fn take_serial(names: &[&str]) -> HeldSerialLocks {
+    let names = not_held_here(names);
+    let shared_paths = names.iter().map(|name| shared_path(name)).collect();
+    let paths = lock_paths(&names, &LockPaths::PerKey(shared_paths));
+    let mut locks = SerialLocks(
+        paths
+            .iter()
+            .map(|path| Lock::new(path, LockType::Flock))
+            .collect(),
+    );
+    let holder = env::var("NEXTEST_TEST_NAME")
+        .ok()
+        .or_else(|| thread::current().name().map(str::to_string))
+        .unwrap_or_else(|| "serial_test".to_string());
+    locks.0.iter_mut().for_each(|lock| {
+        lock.start_serial(None, false);
+        lock.set_holder(&holder);
+    });
+    let mut held_paths = held_paths().lock();
+    paths.iter().for_each(|path| {
+        held_paths.insert(path.clone(), thread::current().id());
+    });
+    HeldSerialLocks {
+        paths,
+        _locks: locks,
+    }
+}
+
+/// File locks for a [serial](macro@crate::serial) test, when the in-process ones won't see other tests
+/// (i.e. they're in other processes)
+pub(crate) fn serial_file_locks(names: &[&str]) -> Option<HeldSerialLocks> {
+    let names = file_locked(names);
+    if names.is_empty() {
+        None
+    } else {
+        Some(take_serial(&names))
+    }
+}
+
+// Releases on drop, so we also let go if the test panics
+pub(crate) struct ParallelLocks(Vec<Lock>);
+
+impl Drop for ParallelLocks {
+    fn drop(&mut self) {
+        self.0.drain(..).for_each(|lock| lock.end_parallel());
+    }
+}
+
+fn take_parallel(names: &[&str]) -> ParallelLocks {
+    let mut locks = ParallelLocks(locks_for(names));
+    locks
+        .0
+        .iter_mut()
+        .for_each(|lock| lock.start_parallel(None));
+    locks
+}
+
+/// File locks for a [parallel](macro@crate::parallel) test, when the in-process ones won't see other tests
+/// (i.e. they're in other processes)
+pub(crate) fn parallel_file_locks(names: &[&str]) -> Option<ParallelLocks> {
+    let names = file_locked(names);
+    if names.is_empty() {
+        None
+    } else {
+        Some(take_parallel(&names))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_process_per_test, take_parallel, take_serial};
+    use crate::{file_lock::is_file_locked, fs_serial_core, local_serial_core, FileSerialOptions};
+
+    #[test]
+    fn detects_nextest() {
+        assert!(!is_process_per_test(|_| None));
+        assert!(is_process_per_test(|name| {
+            (name == "NEXTEST_TEST_NAME").then(|| "tests::foo".to_string())
+        }));
+        assert!(is_process_per_test(|name| {
+            (name == "NEXTEST_EXECUTION_MODE").then(|| "process-per-test".to_string())
+        }));
+        assert!(!is_process_per_test(|name| {
+            (name == "NEXTEST_EXECUTION_MODE").then(|| "something-else".to_string())
+        }));
+    }
+
+    #[test]
+    fn serial_takes_file_lock() {
+        let locks = take_serial(&["nextest_serial"]);
+        assert!(is_file_locked(Some("nextest_serial")));
+        drop(locks);
+        assert!(!is_file_locked(Some("nextest_serial")));
+    }
+
+    #[test]
+    fn file_serial_inside_serial_skips_held_path() {
+        let locks = take_serial(&["nextest_dual"]);
+        // Would deadlock if it tried to take the same file lock again
+        fs_serial_core(
+            vec!["nextest_dual"],
+            None,
+            FileSerialOptions {
+                holder: "nextest_dual",
+                ..Default::default()
+            },
+            || {
+                assert!(is_file_locked(Some("nextest_dual")));
+            },
+        );
+        assert!(is_file_locked(Some("nextest_dual")));
+        drop(locks);
+        assert!(!is_file_locked(Some("nextest_dual")));
+    }
+
+    #[test]
+    fn parallel_takes_file_lock() {
+        let first = take_parallel(&["nextest_parallel"]);
+        let second = take_parallel(&["nextest_parallel"]);
+        drop(first);
+        drop(second);
+        let serial = take_serial(&["nextest_parallel"]);
+        drop(serial);
+    }
+
+    #[test]
+    fn nested_serial_skips_held_keys() {
+        local_serial_core(vec!["nextest_nested"], None, || {
+            // Would deadlock on our own file lock if it wasn't skipped
+            let locks = take_serial(&["nextest_nested"]);
+            assert!(locks.paths.is_empty());
+        });
+    }
+}
@@ -0,0 +1,386 @@
+use crate::file_lock::LockType;
+#[cfg(not(unix))]
+use fslock::LockFile;
+use std::{
+    fs::{self, OpenOptions},
+    io, thread,
+    time::Duration,
+};
+
+/// The OS lock on a lock file that everything else about a file lock is built on
+pub(crate) trait OsLock: Send {
+    fn try_lock(&mut self) -> io::Result<bool>;
+    fn lock(&mut self) -> io::Result<()>;
+    fn unlock(&mut self) -> io::Result<()>;
+    fn owns_lock(&self) -> bool;
+    /// Whether anyone else has it locked, without keeping hold of it
+    fn is_locked(&mut self) -> io::Result<bool> {
+        if self.try_lock()? {
+            self.unlock()?;
+            Ok(false)
+        } else {
+            Ok(true)
+        }
+    }
+    /// Which file's open to be locked (as per [file_identity](crate::file_lock::file_identity)), which may not
+    /// be the one at the path any more, or `None` if that can't be told (or it's not a file that's locked)
+    fn identity(&self) -> Option<(u64, u64)>;
+}
+
+pub(crate) fn open(path: &str, lock_type: LockType) -> Box<dyn OsLock> {
+    match lock_type {
+        LockType::Flock => Box::new(FlockLock::open(path)),
+        LockType::Fcntl => Box::new(FcntlLock::open(path)),
+        LockType::OpenExclusive => Box::new(OpenExclusiveLock::new(path)),
+    }
+}
+
+// flock(2), on a file we keep open ourselves, so we know which file we locked
+#[cfg(unix)]
+struct FlockLock {
+    file: fs::File,
+    owned: bool,
+}
+
+#[cfg(unix)]
+impl FlockLock {
+    fn open(path: &str) -> FlockLock {
+        FlockLock {
+            file: OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)
+                .unwrap_or_else(|_| panic!("Lock file path was {:?}", path)),
+            owned: false,
+        }
+    }
+
+    fn flock(&self, operation: libc::c_int) -> io::Result<bool> {
+        use std::os::unix::io::AsRawFd;
+
+        loop {
+            // SAFETY: the file's open for as long as we've got it
+            if unsafe { libc::flock(self.file.as_raw_fd(), operation) } == 0 {
+                return Ok(true);
+            }
+            let error = io::Error::last_os_error();
+            match error.raw_os_error() {
+                Some(libc::EINTR) => continue,
+                Some(libc::EWOULDBLOCK) if operation & libc::LOCK_NB != 0 => return Ok(false),
+                _ => return Err(error),
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+impl OsLock for FlockLock {
+    fn try_lock(&mut self) -> io::Result<bool> {
+        self.owned = self.flock(libc::LOCK_EX | libc::LOCK_NB)?;
+        Ok(self.owned)
+    }
+
+    fn lock(&mut self) -> io::Result<()> {
+        self.owned = self.flock(libc::LOCK_EX)?;
+        Ok(())
+    }
+
+    fn unlock(&mut self) -> io::Result<()> {
+        self.flock(libc::LOCK_UN)?;
+        self.owned = false;
+        Ok(())
+    }
+
+    fn owns_lock(&self) -> bool {
+        self.owned
+    }
+
+    fn identity(&self) -> Option<(u64, u64)> {
+        crate::file_lock::open_file_identity(&self.file)
+    }
+}
+
+// LockFileEx on Windows, via fslock
+#[cfg(not(unix))]
+struct FlockLock(LockFile);
+
+#[cfg(not(unix))]
+impl FlockLock {
+    fn open(path: &str) -> FlockLock {
+        FlockLock(LockFile::open(path).unwrap())
+    }
+}
+
+#[cfg(not(unix))]
+impl OsLock for FlockLock {
+    fn try_lock(&mut self) -> io::Result<bool> {
+        self.0.try_lock()
+    }
+
+    fn lock(&mut self) -> io::Result<()> {
+        self.0.lock()
+    }
+
+    fn unlock(&mut self) -> io::Result<()> {
+        self.0.unlock()
+    }
+
+    fn owns_lock(&self) -> bool {
+        self.0.owns_lock()
+    }
+
+    fn identity(&self) -> Option<(u64, u64)> {
+        None
+    }
+}
+
+// POSIX record locks on the whole file. On Linux these are open file description locks, which (like flock) belong
+// to the file we opened, rather than the process as classic fcntl locks do, as otherwise two threads in the same
+// process would both get the lock, and closing any other handle on the file would let go of it
+#[cfg(unix)]
+struct FcntlLock {
+    file: fs::File,
+    owned: bool,
+}
+
+#[cfg(unix)]
+impl FcntlLock {
+    fn open(path: &str) -> FcntlLock {
+        FcntlLock {
+            file: OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(path)
+                .unwrap_or_else(|_| panic!("Lock file path was {:?}", path)),
+            owned: false,
+        }
+    }
+
+    // Whole file, and no pid, which OFD locks need
+    fn whole_file(lock_type: libc::c_int) -> libc::flock {
+        // SAFETY: flock is plain data, and all zeroes is a valid one
+        let mut flock: libc::flock = unsafe { std::mem::zeroed() };
+        flock.l_type = lock_type as _;
+        flock.l_whence = libc::SEEK_SET as _;
+        flock
+    }
+
+    fn set_lock(&mut self, lock_type: libc::c_int, wait: bool) -> io::Result<bool> {
+        use std::os::unix::io::AsRawFd;
+
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        let command = if wait {
+            libc::F_OFD_SETLKW
+        } else {
+            libc::F_OFD_SETLK
+        };
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        let command = if wait { libc::F_SETLKW } else { libc::F_SETLK };
+        let flock = FcntlLock::whole_file(lock_type);
+        loop {
+            // SAFETY: the file's open for as long as we've got it, and flock outlives the call
+            if unsafe { libc::fcntl(self.file.as_raw_fd(), command, &flock) } == 0 {
+                return Ok(true);
+            }
+            let error = io::Error::last_os_error();
+            match error.raw_os_error() {
+                Some(libc::EINTR) => continue,
+                Some(libc::EAGAIN) | Some(libc::EACCES) if !wait => return Ok(false),
+                _ => return Err(error),
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+impl OsLock for FcntlLock {
+    fn try_lock(&mut self) -> io::Result<bool> {
+        self.owned = self.set_lock(libc::F_WRLCK, false)?;
+        Ok(self.owned)
+    }
+
+    fn lock(&mut self) -> io::Result<()> {
+        self.owned = self.set_lock(libc::F_WRLCK, true)?;
+        Ok(())
+    }
+
+    fn unlock(&mut self) -> io::Result<()> {
+        self.set_lock(libc::F_UNLCK, false)?;
+        self.owned = false;
+        Ok(())
+    }
+
+    // Asks, rather than locking and unlocking, as for classic fcntl locks that'd let go of any this process holds
+    fn is_locked(&mut self) -> io::Result<bool> {
+        use std::os::unix::io::AsRawFd;
+
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        let command = libc::F_OFD_GETLK;
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        let command = libc::F_GETLK;
+        let mut flock = FcntlLock::whole_file(libc::F_WRLCK);
+        // SAFETY: the file's open for as long as we've got it, and flock outlives the call
+        if unsafe { libc::fcntl(self.file.as_raw_fd(), command, &mut flock) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(flock.l_type != libc::F_UNLCK as _)
+    }
+
+    fn owns_lock(&self) -> bool {
+        self.owned
+    }
+
+    fn identity(&self) -> Option<(u64, u64)> {
+        crate::file_lock::open_file_identity(&self.file)
+    }
+}
+
+#[cfg(not(unix))]
+struct FcntlLock;
+
+#[cfg(not(unix))]
+impl FcntlLock {
+    fn open(_path: &str) -> FcntlLock {
+        panic!("lock_type = \"fcntl\" is only supported on Unix");
+    }
+}
+
+#[cfg(not(unix))]
+impl OsLock for FcntlLock {
+    fn try_lock(&mut self) -> io::Result<bool> {
+        unreachable!()
+    }
+
+    fn lock(&mut self) -> io::Result<()> {
+        unreachable!()
+    }
+
+    fn unlock(&mut self) -> io::Result<()> {
+        unreachable!()
+    }
+
+    fn owns_lock(&self) -> bool {
+        unreachable!()
+    }
+
+    fn identity(&self) -> Option<(u64, u64)> {
+        unreachable!()
+    }
+}
+
+// How long to wait between attempts to create the marker file
+const OPEN_EXCLUSIVE_RETRY: Duration = Duration::from_millis(50);
+
+// Holding the lock means having created `<path>-exclusive`, which works even where the filesystem doesn't do
+// locks (e.g. some NFS setups), as creating a file that must not already exist is atomic. The OS can't clean up
+// after a process that died holding it though, so the marker file has to be deleted by hand then.
+struct OpenExclusiveLock {
+    marker: String,
+    owned: bool,
+}
+
+impl OpenExclusiveLock {
+    fn new(path: &str) -> OpenExclusiveLock {
+        OpenExclusiveLock {
+            marker: format!("{}-exclusive", path),
+            owned: false,
+        }
+    }
+}
+
+impl OsLock for OpenExclusiveLock {
+    fn try_lock(&mut self) -> io::Result<bool> {
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&self.marker)
+        {
+            Ok(_) => self.owned = true,
+            Err(error) if error.kind() == io::ErrorKind::AlreadyExists => self.owned = false,
+            Err(error) => return Err(error),
+        }
+        Ok(self.owned)
+    }
+
+    fn lock(&mut self) -> io::Result<()> {
+        while !self.try_lock()? {
+            thread::sleep(OPEN_EXCLUSIVE_RETRY);
+        }
+        Ok(())
+    }
+
+    fn unlock(&mut self) -> io::Result<()> {
+        fs::remove_file(&self.marker)?;
+        self.owned = false;
+        Ok(())
+    }
+
+    fn owns_lock(&self) -> bool {
+        self.owned
+    }
+
+    // The marker file's what's locked, and that's never replaced, only made and deleted
+    fn identity(&self) -> Option<(u64, u64)> {
+        None
+    }
+}
+
+impl Drop for OpenExclusiveLock {
+    fn drop(&mut self) {
+        if self.owned {
+            let _ = self.unlock();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::open;
+    use crate::file_lock::{path_for_name, LockType};
+    use std::fs;
+
+    fn excludes(lock_type: LockType, name: &str) {
+        let path = path_for_name(name);
+        fs::write(&path, "").unwrap();
+        let mut first = open(&path, lock_type);
+        let mut second = open(&path, lock_type);
+        assert!(!open(&path, lock_type).is_locked().unwrap());
+        assert!(first.try_lock().unwrap());
+        assert!(first.owns_lock());
+        // Checking doesn't take it, or let go of the first one's
+        assert!(open(&path, lock_type).is_locked().unwrap());
+        assert!(!second.try_lock().unwrap());
+        assert!(!second.owns_lock());
+        first.unlock().unwrap();
+        assert!(!open(&path, lock_type).is_locked().unwrap());
+        assert!(second.try_lock().unwrap());
+        second.unlock().unwrap();
+    }
+
+    #[test]
+    fn flock_excludes() {
+        excludes(LockType::Flock, "os_lock_flock");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn fcntl_excludes() {
+        excludes(LockType::Fcntl, "os_lock_fcntl");
+    }
+
+    #[test]
+    fn open_exclusive_excludes() {
+        excludes(LockType::OpenExclusive, "os_lock_open_exclusive");
+    }
+
+    #[test]
+    fn open_exclusive_lets_go_when_dropped() {
+        let path = path_for_name("os_lock_open_exclusive_drop");
+        let mut lock = open(&path, LockType::OpenExclusive);
+        lock.lock().unwrap();
+        drop(lock);
+        assert!(open(&path, LockType::OpenExclusive).try_lock().unwrap());
+    }
+}
@@ -0,0 +1,86 @@
+use std::{
+    cell::Cell,
+    time::{Duration, Instant},
+};
+
+thread_local! {
+    static LOCK_REQUESTED: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+#[doc(hidden)]
+pub fn start_overhead_timer() {
+    LOCK_REQUESTED.with(|requested| requested.set(Some(Instant::now())));
+}
+
+fn take_overhead() -> Option<Duration> {
+    LOCK_REQUESTED.with(|requested| requested.take().map(|start| start.elapsed()))
+}
+
+fn overhead_line(key: &str, wait: Duration, test: &str) -> String {
+    format!(
+        "serial_test_overhead key={} wait_ms={} test={}",
+        key,
+        wait.as_millis(),
+        test
+    )
+}
+
+/// Prints the time since [start_overhead_timer], in a format that's easy to pick out of CI logs.
+/// Async tests that got moved to another thread while waiting don't have a start time, so are skipped.
+#[doc(hidden)]
+#[allow(clippy::print_stdout)]
+pub fn report_overhead(key: &str, test: &str) {
+    if let Some(wait) = take_overhead() {
+        println!("{}", overhead_line(key, wait, test));
+    }
+}
+
+// Quoted, as suite names are for people, so often have spaces in
+fn suite_overhead_line(key: &str, wait: Duration, test: &str, suite: &str) -> String {
+    format!("{} suite={:?}", overhead_line(key, wait, test), suite)
+}
+
+/// [report_overhead], but grouped under `suite` (from `report_as`) for CI reports
+#[doc(hidden)]
+#[allow(clippy::print_stdout)]
+pub fn report_suite_overhead(key: &str, test: &str, suite: &str) {
+    if let Some(wait) = take_overhead() {
+        println!("{}", suite_overhead_line(key, wait, test, suite));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{overhead_line, start_overhead_timer, suite_overhead_line, take_overhead};
+    use std::{thread, time::Duration};
+
+    #[test]
+    fn overhead_is_time_since_start() {
+        assert_eq!(take_overhead(), None);
+        start_overhead_timer();
+        thread::sleep(Duration::from_millis(20));
+        assert!(take_overhead().unwrap() >= Duration::from_millis(20));
+        assert_eq!(take_overhead(), None);
+    }
+
+    #[test]
+    fn overhead_format() {
+        assert_eq!(
+            overhead_line("db", Duration::from_millis(123), "my_test_fn"),
+            "serial_test_overhead key=db wait_ms=123 test=my_test_fn"
+        );
+    }
+
+    #[test]
+    fn suite_overhead_format() {
+        assert_eq!(
+            suite_overhead_line(
+                "db",
+                Duration::from_millis(123),
+                "my_test_fn",
+                "Database tests"
+            ),
+            "serial_test_overhead key=db wait_ms=123 test=my_test_fn suite=\"Database tests\""
+        );
+    }
+}
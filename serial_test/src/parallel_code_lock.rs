@@ -1,56 +1,208 @@
 #![allow(clippy::await_holding_lock)]
 
-use crate::code_lock::{check_new_key, global_locks};
+use crate::{
+    code_lock::{global_locks, lock_for},
+    fence::Rendezvous,
+    rwlock::{ParallelHold, Running},
+};
 #[cfg(feature = "async")]
 use futures::FutureExt;
-use std::panic;
+use once_cell::sync::OnceCell;
+use parking_lot::{Mutex, RwLock};
+use std::{
+    any::Any,
+    collections::HashMap,
+    panic,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-fn get_locks(names: Vec<&str>) -> Vec<crate::code_lock::UniqueReentrantMutex> {
-    names
-        .into_iter()
-        .map(|name| {
-            check_new_key(name);
-            global_locks()
-                .get(name)
-                .expect("key to be set")
-                .get()
-                .clone()
+#[doc(hidden)]
+pub fn register_parallel_test<'a>(names: impl AsRef<[&'a str]>, test_name: &'static str) {
+    for name in names.as_ref() {
+        lock_for(name).register_parallel_test(test_name);
+    }
+}
+
+/// How many different [parallel](macro@crate::parallel) tests have used a key so far
+///
+/// Each test is counted the first time it starts, so this only reaches the full size of the group once all of
+/// them have started, and before that it's however many have got going. That makes it useful for checking up on a
+/// group afterwards (e.g. from a test that runs after the others), or sizing something that's made after they've
+/// all started, but not for a barrier the first test needs straight away.
+/// ````
+/// use serial_test::{parallel, parallel_group_size};
+///
+/// #[parallel(group)]
+/// fn first() {}
+///
+/// #[parallel(group)]
+/// fn second() {}
+///
+/// first();
+/// second();
+/// first();
+/// assert_eq!(parallel_group_size(Some("group")), 2);
+/// ````
+pub fn parallel_group_size(name: Option<&str>) -> usize {
+    global_locks()
+        .read(name.unwrap_or(crate::code_lock::DEFAULT_KEY), |_, lock| {
+            lock.parallel_tests()
         })
+        .unwrap_or_default()
+}
+
+fn rendezvous_points() -> &'static Mutex<HashMap<String, Arc<Rendezvous>>> {
+    static RENDEZVOUS_POINTS: OnceCell<Mutex<HashMap<String, Arc<Rendezvous>>>> = OnceCell::new();
+    RENDEZVOUS_POINTS.get_or_init(Default::default)
+}
+
+/// Waits until `size` [parallel](macro@crate::parallel) tests have got to the rendezvous for a key, for tests that
+/// need to be running at the same time (e.g. to check they don't get in each other's way)
+///
+/// Returns true once they're all there, or false if they haven't all turned up within `timeout` (e.g. because some
+/// of them were filtered out of this run, or there aren't enough test threads to run them all at once), rather
+/// than waiting forever like a [Barrier](std::sync::Barrier) would. The group's size has to be given, as tests
+/// only join a group when they start (see [parallel_group_size]). Once everyone's there, the rendezvous can be used
+/// again.
+/// ````
+/// use serial_test::{parallel, parallel_rendezvous};
+/// use std::{thread, time::Duration};
+///
+/// #[parallel(group)]
+/// fn meet() {
+///     assert!(parallel_rendezvous(Some("group"), 2, Duration::from_secs(60)));
+/// }
+///
+/// let other = thread::spawn(meet);
+/// meet();
+/// other.join().unwrap();
+/// ````
+pub fn parallel_rendezvous(name: Option<&str>, size: usize, timeout: Duration) -> bool {
+    let name = name.unwrap_or(crate::code_lock::DEFAULT_KEY);
+    // A timeout too long to add up to an Instant is as good as waiting forever
+    let deadline = Instant::now().checked_add(timeout);
+    // Cloned out first, so waiting doesn't block other keys' rendezvous
+    let point = rendezvous_points()
+        .lock()
+        .entry(name.to_string())
+        .or_default()
+        .clone();
+    point.wait(size, deadline)
+}
+
+/// A hook for [set_parallel_panic_hook]
+pub type ParallelPanicHook =
+    dyn Fn(&[&str], Box<dyn Any + Send>) -> Box<dyn Any + Send> + Send + Sync;
+
+static PANIC_HOOK: OnceCell<RwLock<Option<Box<ParallelPanicHook>>>> = OnceCell::new();
+
+/// Sets a function to see each panic from a [parallel](macro@crate::parallel) test, before it carries on
+///
+/// The hook gets the test's keys and the panic's payload, once the keys have been let go, and returns the payload
+/// to carry on with. That can be the same one, or a different one (e.g. with the keys added to the message), for
+/// custom harnesses that turn panics into their own failure reports. It replaces any earlier hook, and applies to
+/// every parallel test in the process from then on, but not to [file_parallel](macro@crate::file_parallel) ones.
+/// ````
+/// use serial_test::{parallel, set_parallel_panic_hook};
+///
+/// set_parallel_panic_hook(|keys, payload| {
+///     let message = payload.downcast_ref::<&str>().copied().unwrap_or("unknown panic");
+///     Box::new(format!("{} (holding {:?})", message, keys))
+/// });
+///
+/// #[parallel(db)]
+/// fn broken() {
+///     panic!("Lost the connection");
+/// }
+///
+/// let payload = std::panic::catch_unwind(broken).unwrap_err();
+/// assert_eq!(
+///     payload.downcast_ref::<String>().unwrap(),
+///     "Lost the connection (holding [\"db\"])"
+/// );
+/// ````
+pub fn set_parallel_panic_hook(
+    hook: impl Fn(&[&str], Box<dyn Any + Send>) -> Box<dyn Any + Send> + Send + Sync + 'static,
+) {
+    *PANIC_HOOK.get_or_init(Default::default).write() = Some(Box::new(hook));
+}
+
+/// Removes the hook from [set_parallel_panic_hook], so panics carry on as they are again
+pub fn clear_parallel_panic_hook() {
+    if let Some(hook) = PANIC_HOOK.get() {
+        *hook.write() = None;
+    }
+}
+
+// Carries on with a parallel test's panic, via the hook if there is one
+fn resume_panic(names: &[&str], err: Box<dyn Any + Send>) -> ! {
+    let err = match PANIC_HOOK.get().map(RwLock::read).as_deref() {
+        Some(Some(hook)) => hook(names, err),
+        _ => err,
+    };
+    panic::resume_unwind(err)
+}
+
+fn get_locks(names: &[&str]) -> Vec<crate::code_lock::UniqueReentrantMutex> {
+    crate::code_lock::with_conflicts(names.to_vec())
+        .into_iter()
+        .map(lock_for)
         .collect::<Vec<_>>()
 }
 
+fn start_parallel(locks: &[crate::code_lock::UniqueReentrantMutex]) -> Vec<ParallelHold> {
+    locks.iter().map(|lock| lock.start_parallel()).collect()
+}
+
 #[doc(hidden)]
+#[inline(always)]
 pub fn local_parallel_core_with_return<E>(
     names: Vec<&str>,
     _path: Option<&str>,
-    function: fn() -> Result<(), E>,
+    function: impl FnOnce() -> Result<(), E>,
 ) -> Result<(), E> {
-    let locks = get_locks(names);
+    if crate::dry_run::skip_locking("start parallel on", &names) {
+        return function();
+    }
+    #[cfg(feature = "file_locks")]
+    let _file_locks = crate::nextest::parallel_file_locks(&names);
+    let locks = get_locks(&names);
 
-    locks.iter().for_each(|lock| lock.start_parallel());
-    let res = panic::catch_unwind(function);
-    locks.iter().for_each(|lock| lock.end_parallel());
+    let holds = start_parallel(&locks);
+    // The panic is always resumed once the locks are released, so nothing sees any broken state
+    let res = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+        Running::parallel(&holds).run(function)
+    }));
+    drop(holds);
     match res {
         Ok(ret) => ret,
-        Err(err) => {
-            panic::resume_unwind(err);
-        }
+        Err(err) => resume_panic(&names, err),
     }
 }
 
 #[doc(hidden)]
-pub fn local_parallel_core(names: Vec<&str>, _path: Option<&str>, function: fn()) {
-    let locks = get_locks(names);
-    locks.iter().for_each(|lock| lock.start_parallel());
-    let res = panic::catch_unwind(|| {
-        function();
-    });
-    locks.iter().for_each(|lock| lock.end_parallel());
+#[inline(always)]
+pub fn local_parallel_core(names: Vec<&str>, _path: Option<&str>, function: impl FnOnce()) {
+    if crate::dry_run::skip_locking("start parallel on", &names) {
+        return function();
+    }
+    #[cfg(feature = "file_locks")]
+    let _file_locks = crate::nextest::parallel_file_locks(&names);
+    let locks = get_locks(&names);
+    let holds = start_parallel(&locks);
+    let res = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+        Running::parallel(&holds).run(function)
+    }));
+    drop(holds);
     if let Err(err) = res {
-        panic::resume_unwind(err);
+        resume_panic(&names, err);
     }
 }
 
+// The async cores deliberately don't require Send. Test attributes like #[tokio::test] block on the test's future
+// from the test thread, whatever the runtime flavor, so non-Send futures are fine and a Send bound would reject
+// working tests. Anything that's actually spawned onto another thread gets the runtime's own Send error.
 #[doc(hidden)]
 #[cfg(feature = "async")]
 pub async fn local_async_parallel_core_with_return<E>(
@@ -58,15 +210,18 @@ pub async fn local_async_parallel_core_with_return<E>(
     _path: Option<&str>,
     fut: impl std::future::Future<Output = Result<(), E>> + panic::UnwindSafe,
 ) -> Result<(), E> {
-    let locks = get_locks(names);
-    locks.iter().for_each(|lock| lock.start_parallel());
-    let res = fut.catch_unwind().await;
-    locks.iter().for_each(|lock| lock.end_parallel());
+    if crate::dry_run::skip_locking("start parallel on", &names) {
+        return fut.await;
+    }
+    #[cfg(feature = "file_locks")]
+    let _file_locks = crate::nextest::parallel_file_locks(&names);
+    let locks = get_locks(&names);
+    let holds = start_parallel(&locks);
+    let res = Running::parallel(&holds).poll(fut.catch_unwind()).await;
+    drop(holds);
     match res {
         Ok(ret) => ret,
-        Err(err) => {
-            panic::resume_unwind(err);
-        }
+        Err(err) => resume_panic(&names, err),
     }
 }
 
@@ -77,22 +232,162 @@ pub async fn local_async_parallel_core(
     _path: Option<&str>,
     fut: impl std::future::Future<Output = ()> + panic::UnwindSafe,
 ) {
-    let locks = get_locks(names);
-    locks.iter().for_each(|lock| lock.start_parallel());
-    let res = fut.catch_unwind().await;
-    locks.iter().for_each(|lock| lock.end_parallel());
+    if crate::dry_run::skip_locking("start parallel on", &names) {
+        return fut.await;
+    }
+    #[cfg(feature = "file_locks")]
+    let _file_locks = crate::nextest::parallel_file_locks(&names);
+    let locks = get_locks(&names);
+    let holds = start_parallel(&locks);
+    let res = Running::parallel(&holds).poll(fut.catch_unwind()).await;
+    drop(holds);
     if let Err(err) = res {
-        panic::resume_unwind(err);
+        resume_panic(&names, err);
+    }
+}
+
+/// Holds keys in parallel until dropped, see [parallel_lock] and [parallel_lock_keys]
+pub struct ParallelGuard {
+    // Before the file locks, so those are let go of last, as the attributes do
+    holds: Vec<ParallelHold>,
+    #[cfg(feature = "file_locks")]
+    _file_locks: Option<crate::nextest::ParallelLocks>,
+}
+
+impl ParallelGuard {
+    pub(crate) fn holds(&self) -> &[ParallelHold] {
+        &self.holds
+    }
+}
+
+/// Joins a key's parallel group, until the returned guard is dropped
+///
+/// This is for code outside of a [parallel](macro@crate::parallel) test that needs to run alongside the
+/// parallel tests for a key, but not alongside any [serial](macro@crate::serial) ones. As the guard lets go
+/// when it's dropped, that includes while unwinding from a panic.
+/// ````
+/// use serial_test::{current_mode, parallel_lock, Mode};
+///
+/// let guard = parallel_lock(Some("db"));
+/// assert_eq!(current_mode(Some("db")), Mode::Parallel);
+/// drop(guard);
+/// assert_eq!(current_mode(Some("db")), Mode::Unlocked);
+/// ````
+/// Like [parallel](macro@crate::parallel) tests, this waits for a serial holder of the key, so don't call it
+/// while this thread holds the same key serially.
+pub fn parallel_lock(name: Option<&str>) -> ParallelGuard {
+    parallel_lock_keys(&[name.unwrap_or(crate::code_lock::DEFAULT_KEY)])
+}
+
+/// Joins the parallel groups of several keys at once, as per [parallel_lock]
+///
+/// Handy for helpers that e.g. make requests to several services, so can be called from any of the
+/// [parallel](macro@crate::parallel) tests using them. The keys are taken in sorted order, like the attributes do.
+/// ````
+/// use serial_test::{current_mode, parallel_lock_keys, Mode};
+///
+/// let _guard = parallel_lock_keys(&["web_server", "db"]);
+/// assert_eq!(current_mode(Some("web_server")), Mode::Parallel);
+/// assert_eq!(current_mode(Some("db")), Mode::Parallel);
+/// ````
+/// No keys means the same global key as a plain `#[parallel]`.
+pub fn parallel_lock_keys<I>(names: I) -> ParallelGuard
+where
+    I: IntoIterator,
+    I::Item: AsRef<str>,
+{
+    let keys: Vec<I::Item> = names.into_iter().collect();
+    let mut names: Vec<&str> = keys.iter().map(AsRef::as_ref).collect();
+    if names.is_empty() {
+        names.push(crate::code_lock::DEFAULT_KEY);
+    }
+    names.sort();
+    names.dedup();
+    #[cfg(feature = "file_locks")]
+    let _file_locks = crate::nextest::parallel_file_locks(&names);
+    ParallelGuard {
+        holds: start_parallel(&get_locks(&names)),
+        #[cfg(feature = "file_locks")]
+        _file_locks,
     }
 }
 
 #[cfg(test)]
+#[allow(clippy::assertions_on_constants)]
 mod tests {
     #[cfg(feature = "async")]
     use crate::{local_async_parallel_core, local_async_parallel_core_with_return};
 
+    use super::{parallel_lock, parallel_lock_keys, parallel_rendezvous};
     use crate::{code_lock::global_locks, local_parallel_core, local_parallel_core_with_return};
-    use std::{io::Error, panic};
+    use std::{io::Error, panic, thread, time::Duration};
+
+    fn parallel_count(name: &str) -> u32 {
+        global_locks().get(name).unwrap().get().parallel_count()
+    }
+
+    #[test]
+    fn rendezvous_gives_up_on_missing_tests() {
+        assert!(!parallel_rendezvous(
+            Some("rendezvous_timeout"),
+            2,
+            Duration::from_millis(50)
+        ));
+        // The one that gave up isn't still counted, so it takes two more to meet
+        let other = thread::spawn(|| {
+            parallel_rendezvous(Some("rendezvous_timeout"), 2, Duration::from_secs(60))
+        });
+        assert!(parallel_rendezvous(
+            Some("rendezvous_timeout"),
+            2,
+            Duration::from_secs(60)
+        ));
+        assert!(other.join().unwrap());
+    }
+
+    #[test]
+    fn rendezvous_without_deadline() {
+        let other = thread::spawn(|| parallel_rendezvous(Some("rendezvous_max"), 2, Duration::MAX));
+        assert!(parallel_rendezvous(
+            Some("rendezvous_max"),
+            2,
+            Duration::MAX
+        ));
+        assert!(other.join().unwrap());
+    }
+
+    #[test]
+    fn parallel_guard_counts() {
+        let first = parallel_lock(Some("parallel_guard_counts"));
+        assert_eq!(parallel_count("parallel_guard_counts"), 1);
+        let second = parallel_lock(Some("parallel_guard_counts"));
+        assert_eq!(parallel_count("parallel_guard_counts"), 2);
+        drop(first);
+        assert_eq!(parallel_count("parallel_guard_counts"), 1);
+        drop(second);
+        assert_eq!(parallel_count("parallel_guard_counts"), 0);
+    }
+
+    #[test]
+    fn parallel_guard_released_on_panic() {
+        let _ = panic::catch_unwind(|| {
+            let _guard = parallel_lock(Some("parallel_guard_released_on_panic"));
+            assert_eq!(parallel_count("parallel_guard_released_on_panic"), 1);
+            panic!("Deliberate panic");
+        });
+        assert_eq!(parallel_count("parallel_guard_released_on_panic"), 0);
+    }
+
+    #[test]
+    fn parallel_guard_multiple_keys() {
+        let guard =
+            parallel_lock_keys(&["parallel_guard_b", "parallel_guard_a", "parallel_guard_b"]);
+        assert_eq!(parallel_count("parallel_guard_a"), 1);
+        assert_eq!(parallel_count("parallel_guard_b"), 1);
+        drop(guard);
+        assert_eq!(parallel_count("parallel_guard_a"), 0);
+        assert_eq!(parallel_count("parallel_guard_b"), 0);
+    }
 
     #[test]
     fn unlock_on_assert_sync_without_return() {
@@ -3,37 +3,49 @@ use std::panic;
 #[cfg(feature = "async")]
 use futures::FutureExt;
 
-use crate::file_lock::get_locks;
+use crate::file_lock::{get_locks, LockPaths, LockType};
 
 #[doc(hidden)]
-pub fn fs_parallel_core(names: Vec<&str>, path: Option<&str>, function: fn()) {
-    get_locks(&names, path)
+pub fn fs_parallel_core<'a>(
+    names: Vec<&str>,
+    path: impl Into<LockPaths<'a>>,
+    max_parallel: Option<u32>,
+    lock_type: LockType,
+    function: impl FnOnce(),
+) {
+    if crate::dry_run::skip_locking("start file parallel on", &names) {
+        return function();
+    }
+    let paths = path.into();
+    let mut locks = get_locks(&names, &paths, lock_type);
+    locks
         .iter_mut()
-        .for_each(|lock| lock.start_parallel());
-    let res = panic::catch_unwind(|| {
-        function();
-    });
-    get_locks(&names, path)
-        .into_iter()
-        .for_each(|lock| lock.end_parallel());
+        .for_each(|lock| lock.start_parallel(max_parallel));
+    let res = panic::catch_unwind(panic::AssertUnwindSafe(function));
+    locks.into_iter().for_each(|lock| lock.end_parallel());
     if let Err(err) = res {
         panic::resume_unwind(err);
     }
 }
 
 #[doc(hidden)]
-pub fn fs_parallel_core_with_return<E>(
+pub fn fs_parallel_core_with_return<'a, E>(
     names: Vec<&str>,
-    path: Option<&str>,
-    function: fn() -> Result<(), E>,
+    path: impl Into<LockPaths<'a>>,
+    max_parallel: Option<u32>,
+    lock_type: LockType,
+    function: impl FnOnce() -> Result<(), E>,
 ) -> Result<(), E> {
-    get_locks(&names, path)
+    if crate::dry_run::skip_locking("start file parallel on", &names) {
+        return function();
+    }
+    let paths = path.into();
+    let mut locks = get_locks(&names, &paths, lock_type);
+    locks
         .iter_mut()
-        .for_each(|lock| lock.start_parallel());
-    let res = panic::catch_unwind(function);
-    get_locks(&names, path)
-        .into_iter()
-        .for_each(|lock| lock.end_parallel());
+        .for_each(|lock| lock.start_parallel(max_parallel));
+    let res = panic::catch_unwind(panic::AssertUnwindSafe(function));
+    locks.into_iter().for_each(|lock| lock.end_parallel());
     match res {
         Ok(ret) => ret,
         Err(err) => {
@@ -44,18 +56,23 @@ pub fn fs_parallel_core_with_return<E>(
 
 #[doc(hidden)]
 #[cfg(feature = "async")]
-pub async fn fs_async_parallel_core_with_return<E>(
+pub async fn fs_async_parallel_core_with_return<'a, E>(
     names: Vec<&str>,
-    path: Option<&str>,
+    path: impl Into<LockPaths<'a>>,
+    max_parallel: Option<u32>,
+    lock_type: LockType,
     fut: impl std::future::Future<Output = Result<(), E>> + panic::UnwindSafe,
 ) -> Result<(), E> {
-    get_locks(&names, path)
+    if crate::dry_run::skip_locking("start file parallel on", &names) {
+        return fut.await;
+    }
+    let paths = path.into();
+    let mut locks = get_locks(&names, &paths, lock_type);
+    locks
         .iter_mut()
-        .for_each(|lock| lock.start_parallel());
+        .for_each(|lock| lock.start_parallel(max_parallel));
     let res = fut.catch_unwind().await;
-    get_locks(&names, path)
-        .into_iter()
-        .for_each(|lock| lock.end_parallel());
+    locks.into_iter().for_each(|lock| lock.end_parallel());
     match res {
         Ok(ret) => ret,
         Err(err) => {
@@ -66,38 +83,75 @@ pub async fn fs_async_parallel_core_with_return<E>(
 
 #[doc(hidden)]
 #[cfg(feature = "async")]
-pub async fn fs_async_parallel_core(
+pub async fn fs_async_parallel_core<'a>(
     names: Vec<&str>,
-    path: Option<&str>,
+    path: impl Into<LockPaths<'a>>,
+    max_parallel: Option<u32>,
+    lock_type: LockType,
     fut: impl std::future::Future<Output = ()> + panic::UnwindSafe,
 ) {
-    get_locks(&names, path)
+    if crate::dry_run::skip_locking("start file parallel on", &names) {
+        return fut.await;
+    }
+    let paths = path.into();
+    let mut locks = get_locks(&names, &paths, lock_type);
+    locks
         .iter_mut()
-        .for_each(|lock| lock.start_parallel());
+        .for_each(|lock| lock.start_parallel(max_parallel));
 
     let res = fut.catch_unwind().await;
-    get_locks(&names, path)
-        .into_iter()
-        .for_each(|lock| lock.end_parallel());
+    locks.into_iter().for_each(|lock| lock.end_parallel());
     if let Err(err) = res {
         panic::resume_unwind(err);
     }
 }
 
 #[cfg(test)]
+#[allow(clippy::assertions_on_constants)]
 mod tests {
     #[cfg(feature = "async")]
     use crate::{fs_async_parallel_core, fs_async_parallel_core_with_return};
 
+    #[cfg(not(feature = "shared_file_locks"))]
+    use crate::file_lock::Lock;
+
     use crate::{
-        file_lock::{path_for_name, Lock},
+        file_lock::{path_for_name, LockType},
         fs_parallel_core, fs_parallel_core_with_return,
     };
-    use std::{io::Error, panic};
+    use std::{
+        io::Error,
+        panic,
+        sync::atomic::{AtomicU32, Ordering},
+        thread,
+        time::Duration,
+    };
 
     fn unlock_ok(lock_path: &str) {
-        let lock = Lock::new(lock_path);
-        assert_eq!(lock.parallel_count, 0);
+        #[cfg(not(feature = "shared_file_locks"))]
+        assert_eq!(Lock::new(lock_path, LockType::Flock).parallel_count, 0);
+        #[cfg(feature = "shared_file_locks")]
+        assert!(fslock::LockFile::open(lock_path)
+            .unwrap()
+            .try_lock()
+            .unwrap());
+    }
+
+    #[test]
+    #[cfg(not(feature = "shared_file_locks"))]
+    #[should_panic(expected = "parallel count overflow")]
+    fn start_parallel_overflow() {
+        let mut lock = Lock::new(&path_for_name("start_parallel_overflow"), LockType::Flock);
+        lock.parallel_count = u32::MAX;
+        lock.start_parallel(None);
+    }
+
+    #[test]
+    #[cfg(not(feature = "shared_file_locks"))]
+    #[should_panic(expected = "end_parallel called more times than start_parallel")]
+    fn end_parallel_underflow() {
+        let lock = Lock::new(&path_for_name("end_parallel_underflow"), LockType::Flock);
+        lock.end_parallel();
     }
 
     #[test]
@@ -106,7 +160,9 @@ mod tests {
         let _ = panic::catch_unwind(|| {
             fs_parallel_core(
                 vec!["parallel_unlock_on_assert_sync_without_return"],
-                Some(&lock_path),
+                Some(lock_path.as_str()),
+                None,
+                LockType::Flock,
                 || {
                     assert!(false);
                 },
@@ -121,7 +177,9 @@ mod tests {
         let _ = panic::catch_unwind(|| {
             fs_parallel_core_with_return(
                 vec!["unlock_on_assert_sync_with_return"],
-                Some(&lock_path),
+                Some(lock_path.as_str()),
+                None,
+                LockType::Flock,
                 || -> Result<(), Error> {
                     assert!(false);
                     Ok(())
@@ -141,7 +199,9 @@ mod tests {
         async fn call_serial_test_fn(lock_path: &str) {
             fs_async_parallel_core(
                 vec!["unlock_on_assert_async_without_return"],
-                Some(&lock_path),
+                Some(lock_path),
+                None,
+                LockType::Flock,
                 demo_assert(),
             )
             .await
@@ -167,7 +227,9 @@ mod tests {
         async fn call_serial_test_fn(lock_path: &str) {
             fs_async_parallel_core_with_return(
                 vec!["unlock_on_assert_async_with_return"],
-                Some(&lock_path),
+                Some(lock_path),
+                None,
+                LockType::Flock,
                 demo_assert(),
             )
             .await;
@@ -178,4 +240,36 @@ mod tests {
         });
         unlock_ok(&lock_path);
     }
+
+    static INSIDE: AtomicU32 = AtomicU32::new(0);
+    static PEAK: AtomicU32 = AtomicU32::new(0);
+
+    #[test]
+    fn max_parallel_limits_users() {
+        let lock_path = path_for_name("max_parallel_limits_users");
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let lock_path = lock_path.clone();
+                thread::spawn(move || {
+                    fs_parallel_core(
+                        vec!["max_parallel_limits_users"],
+                        Some(lock_path.as_str()),
+                        Some(2),
+                        LockType::Flock,
+                        || {
+                            let inside = INSIDE.fetch_add(1, Ordering::SeqCst) + 1;
+                            PEAK.fetch_max(inside, Ordering::SeqCst);
+                            thread::sleep(Duration::from_millis(300));
+                            INSIDE.fetch_sub(1, Ordering::SeqCst);
+                        },
+                    )
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(PEAK.load(Ordering::SeqCst), 2);
+        unlock_ok(&lock_path);
+    }
 }
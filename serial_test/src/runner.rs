@@ -0,0 +1,88 @@
+use crate::serial_code_lock::with_serial;
+use std::panic::{self, AssertUnwindSafe};
+
+/// Outcome of [run_test_serially]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TestResult {
+    /// The test returned normally
+    Passed,
+    /// The test panicked, with the panic message
+    Failed(String),
+}
+
+/// Runs `test` holding `key` serially, exactly as a [serial](macro@crate::serial) test with that key would, for use
+/// from custom harnesses (e.g. `libtest_mimic`) where the attributes aren't available
+///
+/// A panic in `test` is caught and returned as [TestResult::Failed], and the lock is released either way. `name` is
+/// only used to describe panics that don't carry a message.
+/// ````
+/// use serial_test::{run_test_serially, TestResult};
+///
+/// fn fails() {
+///     panic!("nope");
+/// }
+///
+/// assert_eq!(run_test_serially("db", "passes", || {}), TestResult::Passed);
+/// assert_eq!(run_test_serially("db", "fails", fails), TestResult::Failed("nope".to_string()));
+/// ````
+/// With `libtest_mimic`, that maps straight onto a trial:
+/// ````ignore
+/// Trial::test("db_test", || match run_test_serially("db", "db_test", db_test) {
+///     TestResult::Passed => Ok(()),
+///     TestResult::Failed(message) => Err(message.into()),
+/// })
+/// ````
+pub fn run_test_serially(key: &str, name: &str, test: fn()) -> TestResult {
+    with_serial([key], || {
+        match panic::catch_unwind(AssertUnwindSafe(test)) {
+            Ok(()) => TestResult::Passed,
+            Err(payload) => TestResult::Failed(
+                payload
+                    .downcast_ref::<&str>()
+                    .map(|message| message.to_string())
+                    .or_else(|| payload.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| format!("{} panicked", name)),
+            ),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{run_test_serially, TestResult};
+    use crate::code_lock::is_locked_serially;
+    use std::panic;
+
+    fn checks_lock() {
+        assert!(is_locked_serially(Some("runner")));
+    }
+
+    fn fails() {
+        panic!("failed with {}", 42);
+    }
+
+    fn fails_without_message() {
+        panic::panic_any(42);
+    }
+
+    #[test]
+    fn passed() {
+        assert_eq!(
+            run_test_serially("runner", "checks_lock", checks_lock),
+            TestResult::Passed
+        );
+    }
+
+    #[test]
+    fn failed_releases_lock() {
+        assert_eq!(
+            run_test_serially("runner", "fails", fails),
+            TestResult::Failed("failed with 42".to_string())
+        );
+        assert!(!is_locked_serially(Some("runner")));
+        assert_eq!(
+            run_test_serially("runner", "fails_without_message", fails_without_message),
+            TestResult::Failed("fails_without_message panicked".to_string())
+        );
+    }
+}
@@ -1,16 +1,65 @@
 #[cfg(feature = "logging")]
 use log::debug;
-use parking_lot::{Condvar, Mutex, ReentrantMutex, ReentrantMutexGuard};
-use std::{sync::Arc, time::Duration};
+use once_cell::sync::OnceCell;
+use parking_lot::{
+    ArcReentrantMutexGuard, Condvar, Mutex, MutexGuard, RawMutex, RawThreadId, ReentrantMutex,
+};
+use std::{
+    cell::RefCell,
+    collections::HashSet,
+    rc::Rc,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
 struct LockState {
     parallels: u32,
+    // Threads blocked waiting for this lock, serial or parallel
+    waiters: u32,
+    // Threads waiting to lock this serially. While there are any, new parallel users wait behind them (for up to
+    // SERIAL_PRIORITY), so a steady stream of overlapping parallel users can't starve them
+    serial_waiters: u32,
 }
 
+// How long a new parallel user waits behind a serial one before joining the existing parallel users anyway. This
+// only needs to give the existing ones time to finish, and it's not forever, as the one we're waiting on might be
+// a parallel user that's waiting for us (e.g. a parallel test joining a thread that's taking the key in parallel).
+const SERIAL_PRIORITY: Duration = Duration::from_secs(1);
+
+thread_local! {
+    // The holds belonging to whatever's running on this thread right now (a test body, or one poll of an async one).
+    // `Running::run` only adds them for that long, so they can't be left behind by a test that's moved threads, or
+    // be mistaken for the holds of another task sharing the thread. Code running inside a parallel hold can always
+    // join the same lock again, even if a serial user is waiting, as otherwise it'd be waiting on itself.
+    static RUNNING: RefCell<Vec<Hold>> = const { RefCell::new(Vec::new()) };
+}
+
+type SerialGuard = ArcReentrantMutexGuard<RawMutex, RawThreadId, ()>;
+
 struct LockData {
     mutex: Mutex<LockState>,
-    serial: ReentrantMutex<()>,
+    // In its own Arc, so the guards for it can be let go of and taken again without borrowing from anything
+    serial: Arc<ReentrantMutex<()>>,
     condvar: Condvar,
+    // How many times this has been locked, serial or parallel
+    uses: AtomicU32,
+    // Longest anyone's waited for this, serial or parallel
+    #[cfg(feature = "persistent_stats")]
+    max_wait_ms: std::sync::atomic::AtomicU64,
+    // From `desc`, so people can tell what the key is for
+    description: OnceCell<String>,
+    // The parallel tests that have used this so far, for `parallel_group_size`
+    parallel_tests: Mutex<HashSet<&'static str>>,
+    // Only one tokio_serial task at a time gets to wait for `serial`, as tasks sharing a thread would all get the
+    // re-entrant lock
+    #[cfg(feature = "tokio_serial")]
+    tasks: tokio::sync::Mutex<()>,
+    // Wakes tasks waiting for `serial`, whenever it or a parallel user lets go
+    #[cfg(feature = "tokio_serial")]
+    released: tokio::sync::Notify,
 }
 
 #[derive(Clone)]
@@ -21,17 +70,154 @@ pub(crate) struct Locks {
     pub(crate) name: String,
 }
 
-pub(crate) struct MutexGuardWrapper<'a> {
-    #[allow(dead_code)] // need it around to get dropped
-    mutex_guard: ReentrantMutexGuard<'a, ()>,
+// Shared with RUNNING while the code it belongs to is running, so `released_while` can let go of it for a bit
+struct SerialHold {
+    mutex_guard: RefCell<Option<SerialGuard>>,
     locks: Locks,
 }
 
-impl<'a> Drop for MutexGuardWrapper<'a> {
+pub(crate) struct MutexGuardWrapper {
+    hold: Rc<SerialHold>,
+}
+
+impl Drop for MutexGuardWrapper {
     fn drop(&mut self) {
         #[cfg(feature = "logging")]
         debug!("End serial");
-        self.locks.arc.condvar.notify_one();
+        // Release before waking anyone, or they'll just find it still locked
+        self.hold.mutex_guard.borrow_mut().take();
+        self.hold.locks.notify_released();
+    }
+}
+
+/// A serial hold from `serial_async`, which also keeps other tokio_serial tasks out
+#[cfg(feature = "tokio_serial")]
+pub(crate) struct AsyncSerialGuard<'a> {
+    pub(crate) serial: MutexGuardWrapper,
+    _task: tokio::sync::MutexGuard<'a, ()>,
+}
+
+// Counts a task as waiting for the serial lock, until it's got it or been cancelled
+#[cfg(feature = "tokio_serial")]
+struct SerialWaiting<'a>(&'a Locks);
+
+#[cfg(feature = "tokio_serial")]
+impl<'a> SerialWaiting<'a> {
+    fn new(locks: &'a Locks) -> Self {
+        let mut lock_state = locks.arc.mutex.lock();
+        lock_state.waiters += 1;
+        lock_state.serial_waiters += 1;
+        SerialWaiting(locks)
+    }
+}
+
+#[cfg(feature = "tokio_serial")]
+impl<'a> Drop for SerialWaiting<'a> {
+    fn drop(&mut self) {
+        let mut lock_state = self.0.arc.mutex.lock();
+        lock_state.waiters -= 1;
+        lock_state.serial_waiters -= 1;
+    }
+}
+
+/// One parallel use of a lock, which ends when this is dropped
+pub(crate) struct ParallelHold {
+    locks: Locks,
+}
+
+impl Drop for ParallelHold {
+    fn drop(&mut self) {
+        self.locks.end_parallel();
+    }
+}
+
+#[derive(Clone)]
+enum Hold {
+    Serial(Rc<SerialHold>),
+    Parallel(Locks),
+}
+
+impl Hold {
+    fn locks(&self) -> &Locks {
+        match self {
+            Hold::Serial(serial) => &serial.locks,
+            Hold::Parallel(locks) => locks,
+        }
+    }
+}
+
+/// The holds a test took, to mark as belonging to its body while that's running
+pub(crate) struct Running(Vec<Hold>);
+
+// Takes off RUNNING again, even if what was running panicked
+struct Ran(usize);
+
+impl Drop for Ran {
+    fn drop(&mut self) {
+        RUNNING.with(|running| running.borrow_mut().truncate(self.0));
+    }
+}
+
+impl Running {
+    pub(crate) fn parallel(holds: &[ParallelHold]) -> Running {
+        Running(
+            holds
+                .iter()
+                .map(|hold| Hold::Parallel(hold.locks.clone()))
+                .collect(),
+        )
+    }
+
+    pub(crate) fn serial<'a>(guards: impl IntoIterator<Item = &'a MutexGuardWrapper>) -> Running {
+        Running(
+            guards
+                .into_iter()
+                .map(|guard| Hold::Serial(guard.hold.clone()))
+                .collect(),
+        )
+    }
+
+    /// Runs `function` as the code these holds belong to
+    pub(crate) fn run<R>(&self, function: impl FnOnce() -> R) -> R {
+        let _ran = RUNNING.with(|running| {
+            let mut running = running.borrow_mut();
+            let ran = Ran(running.len());
+            running.extend(self.0.iter().cloned());
+            ran
+        });
+        function()
+    }
+
+    /// Like `run`, but for each poll of `fut`, as it can move threads, or share one with other tasks
+    #[cfg(feature = "async")]
+    pub(crate) async fn poll<F: std::future::Future>(&self, fut: F) -> F::Output {
+        futures::pin_mut!(fut);
+        std::future::poll_fn(|cx| self.run(|| fut.as_mut().poll(cx))).await
+    }
+}
+
+// Takes back what `released_while` let go of
+struct Relock<'a> {
+    locks: &'a Locks,
+    released: Vec<Hold>,
+    guarded: usize,
+}
+
+impl<'a> Drop for Relock<'a> {
+    fn drop(&mut self) {
+        for hold in &self.released {
+            match hold {
+                Hold::Serial(serial) => {
+                    *serial.mutex_guard.borrow_mut() = Some(self.locks.acquire_serial());
+                }
+                // The holds these came from will end them
+                Hold::Parallel(locks) => locks.join_parallel(),
+            }
+        }
+        for _ in 0..self.guarded {
+            self.locks.join_parallel();
+        }
+        RUNNING.with(|running| running.borrow_mut().append(&mut self.released));
     }
 }
 
@@ -40,16 +226,45 @@ impl Locks {
     pub fn new(name: &str) -> Locks {
         Locks {
             arc: Arc::new(LockData {
-                mutex: Mutex::new(LockState { parallels: 0 }),
+                mutex: Mutex::new(LockState {
+                    parallels: 0,
+                    waiters: 0,
+                    serial_waiters: 0,
+                }),
                 condvar: Condvar::new(),
                 serial: Default::default(),
+                uses: AtomicU32::new(0),
+                #[cfg(feature = "persistent_stats")]
+                max_wait_ms: std::sync::atomic::AtomicU64::new(0),
+                description: OnceCell::new(),
+                parallel_tests: Default::default(),
+                #[cfg(feature = "tokio_serial")]
+                tasks: tokio::sync::Mutex::new(()),
+                #[cfg(feature = "tokio_serial")]
+                released: tokio::sync::Notify::new(),
             }),
             #[cfg(feature = "logging")]
             name: name.to_owned(),
         }
     }
 
-    #[cfg(test)]
+    // Name plus description, for log messages people might have to puzzle over
+    #[cfg(feature = "logging")]
+    fn described_name(&self) -> String {
+        match self.description() {
+            Some(description) => format!("{} ({})", self.name, description),
+            None => self.name.clone(),
+        }
+    }
+
+    fn name_or_unknown(&self) -> &str {
+        #[cfg(feature = "logging")]
+        return &self.name;
+        #[cfg(not(feature = "logging"))]
+        "<unknown>"
+    }
+
+    #[cfg(any(test, feature = "diagnostics"))]
     pub fn is_locked(&self) -> bool {
         self.arc.serial.is_locked()
     }
@@ -61,35 +276,203 @@ impl Locks {
     pub fn serial(&self) -> MutexGuardWrapper {
         #[cfg(feature = "logging")]
         debug!("Get serial lock '{}'", self.name);
+        self.arc.uses.fetch_add(1, Ordering::Relaxed);
+        self.wrap(self.acquire_serial())
+    }
+
+    fn wrap(&self, mutex_guard: SerialGuard) -> MutexGuardWrapper {
+        MutexGuardWrapper {
+            hold: Rc::new(SerialHold {
+                mutex_guard: RefCell::new(Some(mutex_guard)),
+                locks: self.clone(),
+            }),
+        }
+    }
+
+    fn notify_released(&self) {
+        // Taking the state lock means no waiter can be between checking and waiting
+        let _lock_state = self.arc.mutex.lock();
+        self.arc.condvar.notify_all();
+        #[cfg(feature = "tokio_serial")]
+        self.arc.released.notify_waiters();
+    }
+
+    /// Like `serial`, but waits by yielding to the executor rather than blocking the thread
+    #[cfg(feature = "tokio_serial")]
+    pub async fn serial_async(&self) -> AsyncSerialGuard<'_> {
+        #[cfg(feature = "logging")]
+        debug!("Get async serial lock '{}'", self.name);
+        #[cfg(feature = "persistent_stats")]
+        let started = Instant::now();
+        let task = self.arc.tasks.lock().await;
+        self.arc.uses.fetch_add(1, Ordering::Relaxed);
+        let waiting = SerialWaiting::new(self);
+        loop {
+            // Registered before checking, so a release in between still wakes us
+            let mut released = std::pin::pin!(self.arc.released.notified());
+            released.as_mut().enable();
+            if let Some(serial) = self.try_serial() {
+                drop(waiting);
+                #[cfg(feature = "persistent_stats")]
+                self.record_wait(started);
+                return AsyncSerialGuard {
+                    serial,
+                    _task: task,
+                };
+            }
+            #[cfg(feature = "logging")]
+            debug!("Async serial waiting '{}'", self.described_name());
+            released.await;
+        }
+    }
+
+    #[cfg(feature = "tokio_serial")]
+    fn try_serial(&self) -> Option<MutexGuardWrapper> {
+        let lock_state = self.arc.mutex.lock();
+        if lock_state.parallels > 0 {
+            return None;
+        }
+        let mutex_guard = self.arc.serial.try_lock_arc()?;
+        #[cfg(feature = "logging")]
+        debug!("Got async serial '{}'", self.name);
+        Some(self.wrap(mutex_guard))
+    }
+
+    /// Like `serial`, but gives up at `deadline` rather than waiting forever, or never does if it's `None`
+    pub fn serial_until(&self, deadline: Option<Instant>) -> Option<MutexGuardWrapper> {
+        #[cfg(feature = "logging")]
+        debug!("Try serial lock '{}'", self.name);
+        let mutex_guard = self.acquire_serial_until(deadline)?;
+        self.arc.uses.fetch_add(1, Ordering::Relaxed);
+        Some(self.wrap(mutex_guard))
+    }
+
+    fn acquire_serial(&self) -> SerialGuard {
+        self.acquire_serial_until(None)
+            .expect("no deadline to give up at")
+    }
+
+    fn acquire_serial_until(&self, deadline: Option<Instant>) -> Option<SerialGuard> {
+        #[cfg(feature = "persistent_stats")]
+        let started = Instant::now();
         let mut lock_state = self.arc.mutex.lock();
         loop {
             #[cfg(feature = "logging")]
             debug!("Serial acquire {} {}", lock_state.parallels, self.name);
             // If all the things we want are true, try to lock out serial
             if lock_state.parallels == 0 {
-                let possible_serial_lock = self.arc.serial.try_lock();
+                let possible_serial_lock = self.arc.serial.try_lock_arc();
                 if let Some(serial_lock) = possible_serial_lock {
                     #[cfg(feature = "logging")]
                     debug!("Got serial '{}'", self.name);
-                    return MutexGuardWrapper {
-                        mutex_guard: serial_lock,
-                        locks: self.clone(),
-                    };
+                    #[cfg(feature = "persistent_stats")]
+                    self.record_wait(started);
+                    return Some(serial_lock);
                 } else {
                     #[cfg(feature = "logging")]
-                    debug!("Someone else has serial '{}'", self.name);
+                    debug!("Someone else has serial '{}'", self.described_name());
                 }
             }
 
-            self.arc
-                .condvar
-                .wait_for(&mut lock_state, Duration::from_secs(1));
+            let wait = match deadline {
+                Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                    Some(left) if !left.is_zero() => left.min(Duration::from_secs(1)),
+                    _ => return None,
+                },
+                None => Duration::from_secs(1),
+            };
+            lock_state.waiters += 1;
+            lock_state.serial_waiters += 1;
+            self.arc.condvar.wait_for(&mut lock_state, wait);
+            lock_state.waiters -= 1;
+            lock_state.serial_waiters -= 1;
+        }
+    }
+
+    /// Runs `function` with the running code's holds on this lock (see `Running::run`) and any of `guarded` for
+    /// it let go, taking them back again afterwards, even if `function` panics
+    ///
+    /// Only those are let go of, so anything else holding it (e.g. a guard that's not for what's running, or
+    /// another task's hold on the same thread) still does.
+    pub(crate) fn released_while<R>(
+        &self,
+        guarded: &[ParallelHold],
+        function: impl FnOnce() -> R,
+    ) -> R {
+        let address = self.address();
+        let released = RUNNING.with(|running| {
+            let mut running = running.borrow_mut();
+            let (mine, others) = running
+                .drain(..)
+                .partition::<Vec<_>, _>(|hold| hold.locks().address() == address);
+            *running = others;
+            mine
+        });
+        let guarded = guarded
+            .iter()
+            .filter(|hold| hold.locks.address() == address)
+            .count();
+        let mut parallel = guarded;
+        for hold in &released {
+            match hold {
+                // Just one level of the re-entrant lock, so it stays locked if anything else here holds it too
+                Hold::Serial(serial) => drop(serial.mutex_guard.borrow_mut().take()),
+                // All still alive, as they're for what's running, so all counted in parallels
+                Hold::Parallel(_) => parallel += 1,
+            }
         }
+        if parallel > 0 {
+            let mut lock_state = self.arc.mutex.lock();
+            lock_state.parallels = lock_state
+                .parallels
+                .checked_sub(parallel as u32)
+                .expect("parallel count underflow");
+        }
+        if !released.is_empty() || guarded > 0 {
+            self.notify_released();
+        }
+        let _relock = Relock {
+            locks: self,
+            released,
+            guarded,
+        };
+        function()
+    }
+
+    fn address(&self) -> usize {
+        Arc::as_ptr(&self.arc) as usize
+    }
+
+    /// Whether the code running on this thread holds this lock in parallel, see `Running::run`
+    pub(crate) fn is_parallel_here(&self) -> bool {
+        let address = self.address();
+        RUNNING.with(|running| {
+            running.borrow().iter().any(|hold| match hold {
+                Hold::Parallel(locks) => locks.address() == address,
+                Hold::Serial(_) => false,
+            })
+        })
+    }
+
+    fn joined_parallel(&self, mut lock_state: MutexGuard<'_, LockState>) {
+        lock_state.parallels = lock_state
+            .parallels
+            .checked_add(1)
+            .expect("parallel count overflow");
     }
 
-    pub fn start_parallel(&self) {
+    pub fn start_parallel(&self) -> ParallelHold {
         #[cfg(feature = "logging")]
         debug!("Get parallel lock '{}'", self.name);
+        self.arc.uses.fetch_add(1, Ordering::Relaxed);
+        self.join_parallel();
+        ParallelHold {
+            locks: self.clone(),
+        }
+    }
+
+    fn join_parallel(&self) {
+        let started = Instant::now();
         let mut lock_state = self.arc.mutex.lock();
         loop {
             #[cfg(feature = "logging")]
@@ -98,41 +481,256 @@ impl Locks {
                 lock_state.parallels, self.name
             );
             if lock_state.parallels > 0 {
-                // fast path, as someone else already has it locked
-                lock_state.parallels += 1;
-                return;
-            }
-
-            let possible_serial_lock = self.arc.serial.try_lock();
-            if possible_serial_lock.is_some() {
-                #[cfg(feature = "logging")]
-                debug!("Parallel first '{}'", self.name);
-                // We now know no-one else has the serial lock, so we can add to parallel
-                lock_state.parallels = 1; // Had to have been 0 before, as otherwise we'd have hit the fast path
-                return;
+                // fast path, as someone else already has it locked, unless a serial user's been waiting for them
+                if lock_state.serial_waiters == 0
+                    || self.is_parallel_here()
+                    || started.elapsed() >= SERIAL_PRIORITY
+                {
+                    #[cfg(feature = "persistent_stats")]
+                    self.record_wait(started);
+                    self.joined_parallel(lock_state);
+                    return;
+                }
+            } else {
+                let possible_serial_lock = self.arc.serial.try_lock();
+                if possible_serial_lock.is_some() {
+                    #[cfg(feature = "logging")]
+                    debug!("Parallel first '{}'", self.name);
+                    // We now know no-one else has the serial lock, so we can add to parallel
+                    drop(possible_serial_lock);
+                    #[cfg(feature = "persistent_stats")]
+                    self.record_wait(started);
+                    self.joined_parallel(lock_state);
+                    return;
+                }
             }
 
             #[cfg(feature = "logging")]
-            debug!("Parallel waiting '{}'", self.name);
+            debug!("Parallel waiting '{}'", self.described_name());
+            lock_state.waiters += 1;
             self.arc
                 .condvar
                 .wait_for(&mut lock_state, Duration::from_secs(1));
+            lock_state.waiters -= 1;
         }
     }
 
-    pub fn end_parallel(&self) {
+    fn end_parallel(&self) {
         #[cfg(feature = "logging")]
         debug!("End parallel '{}", self.name);
         let mut lock_state = self.arc.mutex.lock();
-        assert!(lock_state.parallels > 0);
+        assert!(
+            lock_state.parallels > 0,
+            "parallel count underflow for lock '{}' — end_parallel called more times than start_parallel",
+            self.name_or_unknown()
+        );
         lock_state.parallels -= 1;
         drop(lock_state);
-        self.arc.condvar.notify_one();
+        self.arc.condvar.notify_all();
+        #[cfg(feature = "tokio_serial")]
+        self.arc.released.notify_waiters();
+    }
+
+    pub fn wait_until_idle(&self, timeout: Duration) -> bool {
+        #[cfg(feature = "logging")]
+        debug!("Wait until idle '{}'", self.name);
+        // A timeout too long to add up to an Instant is as good as waiting forever
+        let deadline = Instant::now().checked_add(timeout);
+        let mut lock_state = self.arc.mutex.lock();
+        loop {
+            if lock_state.parallels == 0 && !self.arc.serial.is_locked() {
+                return true;
+            }
+            match deadline {
+                Some(deadline) => {
+                    if self
+                        .arc
+                        .condvar
+                        .wait_until(&mut lock_state, deadline)
+                        .timed_out()
+                    {
+                        return lock_state.parallels == 0 && !self.arc.serial.is_locked();
+                    }
+                }
+                None => self.arc.condvar.wait(&mut lock_state),
+            }
+        }
+    }
+
+    pub fn description(&self) -> Option<&str> {
+        self.arc.description.get().map(String::as_str)
+    }
+
+    /// The first description for a key wins
+    pub fn set_description(&self, description: &str) {
+        let _ = self.arc.description.set(description.to_string());
+    }
+
+    pub fn register_parallel_test(&self, test_name: &'static str) {
+        self.arc.parallel_tests.lock().insert(test_name);
+    }
+
+    pub fn parallel_tests(&self) -> usize {
+        self.arc.parallel_tests.lock().len()
+    }
+
+    pub fn uses(&self) -> u32 {
+        self.arc.uses.load(Ordering::Relaxed)
+    }
+
+    #[cfg(feature = "persistent_stats")]
+    fn record_wait(&self, started: Instant) {
+        let waited = started.elapsed().as_millis() as u64;
+        self.arc.max_wait_ms.fetch_max(waited, Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "persistent_stats")]
+    pub fn max_wait_ms(&self) -> u64 {
+        self.arc.max_wait_ms.load(Ordering::Relaxed)
     }
 
-    #[cfg(test)]
     pub fn parallel_count(&self) -> u32 {
         let lock_state = self.arc.mutex.lock();
         lock_state.parallels
     }
+
+    #[cfg(feature = "diagnostics")]
+    pub fn waiters(&self) -> u32 {
+        let lock_state = self.arc.mutex.lock();
+        lock_state.waiters
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Locks, Running, SERIAL_PRIORITY};
+    use parking_lot::Mutex;
+    use std::{sync::Arc, thread, time::Instant};
+
+    fn has_waiters(locks: &Locks, serial_waiters: u32, waiters: u32) -> bool {
+        let lock_state = locks.arc.mutex.lock();
+        lock_state.serial_waiters == serial_waiters && lock_state.waiters == waiters
+    }
+
+    fn wait_until(condition: impl Fn() -> bool) {
+        while !condition() {
+            thread::yield_now();
+        }
+    }
+
+    #[test]
+    fn waiting_serial_goes_before_new_parallel() {
+        // Same result every time, however the threads get scheduled
+        for _ in 0..20 {
+            let locks = Locks::new("waiting_serial_goes_before_new_parallel");
+            let order = Arc::new(Mutex::new(Vec::new()));
+            let hold = locks.start_parallel();
+            let serial = {
+                let (locks, order) = (locks.clone(), order.clone());
+                thread::spawn(move || {
+                    let _guard = locks.serial();
+                    order.lock().push("serial");
+                })
+            };
+            wait_until(|| has_waiters(&locks, 1, 1));
+            let parallel = {
+                let (locks, order) = (locks.clone(), order.clone());
+                thread::spawn(move || {
+                    let _hold = locks.start_parallel();
+                    order.lock().push("parallel");
+                })
+            };
+            // Or until it's overtaken the serial one, so this fails rather than hangs if it does
+            wait_until(|| has_waiters(&locks, 1, 2) || !order.lock().is_empty());
+            drop(hold);
+            serial.join().unwrap();
+            parallel.join().unwrap();
+            assert_eq!(*order.lock(), vec!["serial", "parallel"]);
+        }
+    }
+
+    #[test]
+    fn nested_parallel_overtakes_waiting_serial() {
+        let locks = Locks::new("nested_parallel_overtakes_waiting_serial");
+        let hold = locks.start_parallel();
+        let serial = {
+            let locks = locks.clone();
+            thread::spawn(move || drop(locks.serial()))
+        };
+        wait_until(|| has_waiters(&locks, 1, 1));
+        // Already a parallel user in what's running, so waiting for the serial user would be waiting for ourselves
+        let started = Instant::now();
+        Running::parallel(std::slice::from_ref(&hold)).run(|| drop(locks.start_parallel()));
+        assert!(started.elapsed() < SERIAL_PRIORITY);
+        drop(hold);
+        serial.join().unwrap();
+    }
+
+    #[test]
+    fn parallel_here_only_while_running() {
+        let locks = Locks::new("parallel_here_only_while_running");
+        let hold = locks.start_parallel();
+        assert!(!locks.is_parallel_here());
+        Running::parallel(std::slice::from_ref(&hold)).run(|| {
+            assert!(locks.is_parallel_here());
+            let other = locks.clone();
+            assert!(!thread::spawn(move || other.is_parallel_here())
+                .join()
+                .unwrap());
+        });
+        assert!(!locks.is_parallel_here());
+        // Ending on another thread, like an async test that's moved, leaves nothing behind
+        thread::spawn(move || drop(hold)).join().unwrap();
+        assert_eq!(locks.parallel_count(), 0);
+        assert!(!locks.is_parallel_here());
+    }
+
+    #[test]
+    fn released_while_only_lets_go_of_running() {
+        let locks = Locks::new("released_while_only_lets_go_of_running");
+        let guard = locks.serial();
+        let running = Running::serial([&guard]);
+        running.run(|| {
+            locks.released_while(&[], || {
+                assert!(!locks.is_locked());
+                // Someone else can get in while it's let go of
+                let other = locks.clone();
+                thread::spawn(move || drop(other.serial())).join().unwrap();
+            });
+            assert!(locks.is_locked_by_current_thread());
+        });
+        // Not running, so not let go of, even though it's this thread's
+        locks.released_while(&[], || assert!(locks.is_locked_by_current_thread()));
+        drop(guard);
+        assert!(!locks.is_locked());
+    }
+
+    #[test]
+    fn released_while_lets_go_of_guarded() {
+        let locks = Locks::new("released_while_lets_go_of_guarded");
+        let hold = locks.start_parallel();
+        locks.released_while(&[], || assert_eq!(locks.parallel_count(), 1));
+        locks.released_while(std::slice::from_ref(&hold), || {
+            assert_eq!(locks.parallel_count(), 0)
+        });
+        assert_eq!(locks.parallel_count(), 1);
+        drop(hold);
+        assert_eq!(locks.parallel_count(), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "parallel count overflow")]
+    fn start_parallel_overflow() {
+        let locks = Locks::new("start_parallel_overflow");
+        locks.arc.mutex.lock().parallels = u32::MAX - 1;
+        let _first = locks.start_parallel();
+        assert_eq!(locks.parallel_count(), u32::MAX);
+        let _second = locks.start_parallel();
+    }
+
+    #[test]
+    #[should_panic(expected = "end_parallel called more times than start_parallel")]
+    fn end_parallel_underflow() {
+        Locks::new("end_parallel_underflow").end_parallel();
+    }
 }
@@ -1,39 +1,91 @@
 #![allow(clippy::await_holding_lock)]
 
-use crate::code_lock::{check_new_key, global_locks};
+use crate::{
+    code_lock::lock_for,
+    lock_order::HeldKey,
+    rwlock::{Locks, Running},
+};
+use once_cell::sync::OnceCell;
+
+// Releases the keys in the opposite order to taking them
+struct Guards<T>(Vec<T>);
+
+impl<T> Drop for Guards<T> {
+    fn drop(&mut self) {
+        while self.0.pop().is_some() {}
+    }
+}
 
 #[doc(hidden)]
 macro_rules! core_internal {
-    ($names: ident) => {
+    ($names: ident, $running: ident) => {
+        let $names = crate::code_lock::with_conflicts($names);
         let unlocks: Vec<_> = $names
             .into_iter()
-            .map(|name| {
-                check_new_key(name);
-                global_locks()
-                    .get(name)
-                    .expect("key to be set")
-                    .get()
-                    .clone()
-            })
+            .map(|name| (name, lock_for(name)))
             .collect();
-        let _guards: Vec<_> = unlocks.iter().map(|unlock| unlock.lock()).collect();
+        let _guards = Guards(
+            unlocks
+                .iter()
+                .map(|(name, unlock)| {
+                    let held = HeldKey::acquire(name);
+                    #[cfg(feature = "diagnostics")]
+                    let _waiting = crate::diagnostics::Waiting::new(name);
+                    (unlock.lock(), held)
+                })
+                .collect(),
+        );
+        // Dropped first, so registered threads finish before the keys are released
+        let _joins = crate::join::JoinOnRelease::new();
+        let $running = Running::serial(_guards.0.iter().map(|(guard, _)| guard));
     };
 }
 
+// Like core_internal!, but waits for each key by yielding to the executor rather than blocking the thread. Keys
+// held this way aren't tracked for lock order checks, as those are per-thread, and tasks can share a thread.
+#[cfg(feature = "tokio_serial")]
+macro_rules! tokio_core_internal {
+    ($names: ident, $running: ident) => {
+        let $names = crate::code_lock::with_conflicts($names);
+        let unlocks: Vec<_> = $names.into_iter().map(lock_for).collect();
+        let mut guards = Vec::with_capacity(unlocks.len());
+        for unlock in &unlocks {
+            guards.push(unlock.lock_async().await);
+        }
+        let _guards = Guards(guards);
+        // Dropped first, so registered threads finish before the keys are released
+        let _joins = crate::join::JoinOnRelease::new();
+        let $running = Running::serial(_guards.0.iter().map(|guard| &guard.serial));
+    };
+}
+
+// Always inlined, so benchmarks calling this in a tight loop don't pay for the call
 #[doc(hidden)]
+#[inline(always)]
 pub fn local_serial_core_with_return<E>(
     names: Vec<&str>,
     _path: Option<String>,
-    function: fn() -> Result<(), E>,
+    function: impl FnOnce() -> Result<(), E>,
 ) -> Result<(), E> {
-    core_internal!(names);
-    function()
+    if crate::dry_run::skip_locking("acquire serial key(s)", &names) {
+        return function();
+    }
+    #[cfg(feature = "file_locks")]
+    let _file_locks = crate::nextest::serial_file_locks(&names);
+    core_internal!(names, running);
+    running.run(function)
 }
 
 #[doc(hidden)]
-pub fn local_serial_core(names: Vec<&str>, _path: Option<&str>, function: fn()) {
-    core_internal!(names);
-    function();
+#[inline(always)]
+pub fn local_serial_core(names: Vec<&str>, _path: Option<&str>, function: impl FnOnce()) {
+    if crate::dry_run::skip_locking("acquire serial key(s)", &names) {
+        return function();
+    }
+    #[cfg(feature = "file_locks")]
+    let _file_locks = crate::nextest::serial_file_locks(&names);
+    core_internal!(names, running);
+    running.run(function);
 }
 
 #[doc(hidden)]
@@ -43,8 +95,13 @@ pub async fn local_async_serial_core_with_return<E>(
     _path: Option<&str>,
     fut: impl std::future::Future<Output = Result<(), E>> + std::marker::Send,
 ) -> Result<(), E> {
-    core_internal!(names);
-    fut.await
+    if crate::dry_run::skip_locking("acquire serial key(s)", &names) {
+        return fut.await;
+    }
+    #[cfg(feature = "file_locks")]
+    let _file_locks = crate::nextest::serial_file_locks(&names);
+    core_internal!(names, running);
+    running.poll(fut).await
 }
 
 #[doc(hidden)]
@@ -54,25 +111,405 @@ pub async fn local_async_serial_core(
     _path: Option<&str>,
     fut: impl std::future::Future<Output = ()>,
 ) {
-    core_internal!(names);
-    fut.await;
+    if crate::dry_run::skip_locking("acquire serial key(s)", &names) {
+        return fut.await;
+    }
+    #[cfg(feature = "file_locks")]
+    let _file_locks = crate::nextest::serial_file_locks(&names);
+    core_internal!(names, running);
+    running.poll(fut).await;
+}
+
+#[doc(hidden)]
+#[cfg(feature = "tokio_serial")]
+pub async fn local_tokio_serial_core_with_return<E>(
+    names: Vec<&str>,
+    _path: Option<&str>,
+    fut: impl std::future::Future<Output = Result<(), E>> + std::marker::Send,
+) -> Result<(), E> {
+    if crate::dry_run::skip_locking("acquire serial key(s)", &names) {
+        return fut.await;
+    }
+    #[cfg(feature = "file_locks")]
+    let _file_locks = crate::nextest::serial_file_locks(&names);
+    tokio_core_internal!(names, running);
+    running.poll(fut).await
+}
+
+#[doc(hidden)]
+#[cfg(feature = "tokio_serial")]
+pub async fn local_tokio_serial_core(
+    names: Vec<&str>,
+    _path: Option<&str>,
+    fut: impl std::future::Future<Output = ()>,
+) {
+    if crate::dry_run::skip_locking("acquire serial key(s)", &names) {
+        return fut.await;
+    }
+    #[cfg(feature = "file_locks")]
+    let _file_locks = crate::nextest::serial_file_locks(&names);
+    tokio_core_internal!(names, running);
+    running.poll(fut).await;
+}
+
+/// Runs `function` with the given keys held serially, exactly as a [serial](macro@crate::serial) test with those
+/// keys would
+///
+/// This is for code that can't use the attribute, e.g. benchmarks (`#[bench]` or criterion) that share a resource
+/// with tests. It uses the same keys as [serial](macro@crate::serial) and [parallel](macro@crate::parallel), so it
+/// contends with them whenever they're in the same process. Separate processes (e.g. `cargo bench` and `cargo test`
+/// running at once) don't share these locks, so use [file_serial](macro@crate::file_serial) for those instead.
+/// ````
+/// use serial_test::{is_locked_serially, with_serial};
+///
+/// let value = with_serial(["db"], || {
+///     assert!(is_locked_serially(Some("db")));
+///     42
+/// });
+/// assert_eq!(value, 42);
+/// ````
+/// No keys means the same global key as a plain `#[serial]`.
+///
+/// It's also the way to serialize each case of a property test (e.g. proptest or quickcheck) separately, so other
+/// tests' [parallel](macro@crate::parallel) sections can run in between cases. Leave `#[serial]` off the test, and
+/// wrap the body of each case instead.
+/// ````
+/// use serial_test::{is_locked_serially, with_serial};
+///
+/// for case in 0..100 {
+///     with_serial(["db"], || {
+///         assert!(is_locked_serially(Some("db")));
+///         // Check the property for `case`
+///     });
+///     assert!(!is_locked_serially(Some("db")));
+/// }
+/// ````
+/// Calling this inside a [serial](macro@crate::serial) test that already holds the key is fine, as the keys are
+/// re-entrant on the same thread, but then the key is held for the whole test, not just each case. Cases run
+/// on other threads (e.g. in parallel, or while shrinking) can't take a key the test itself holds, as they'd wait
+/// for the test to finish.
+///
+/// The keys can be anything that iterates over strings, e.g. `["db"]`, `&["db", "queue"]` or a `Vec<String>`.
+pub fn with_serial<I, R>(names: I, function: impl FnOnce() -> R) -> R
+where
+    I: IntoIterator,
+    I::Item: AsRef<str>,
+{
+    with_serial_keys_ordered(names, function)
+}
+
+/// Runs `function` with all of `names` held serially, taking them in sorted order and releasing them in reverse
+///
+/// Taking several keys one at a time can deadlock if two callers take them in different orders, so this always
+/// uses the same order as the attributes do, whatever order they're passed in. Duplicate keys are only taken once.
+/// ````
+/// use serial_test::{is_locked_serially, with_serial_keys_ordered};
+///
+/// with_serial_keys_ordered(&["queue", "db", "queue"], || {
+///     assert!(is_locked_serially(Some("db")));
+///     assert!(is_locked_serially(Some("queue")));
+/// });
+/// ````
+pub fn with_serial_keys_ordered<I, R>(names: I, function: impl FnOnce() -> R) -> R
+where
+    I: IntoIterator,
+    I::Item: AsRef<str>,
+{
+    let keys: Vec<I::Item> = names.into_iter().collect();
+    let mut names: Vec<&str> = keys.iter().map(AsRef::as_ref).collect();
+    if names.is_empty() {
+        names.push(crate::code_lock::DEFAULT_KEY);
+    }
+    // Same order as the attribute, to avoid dining philosopher issues
+    names.sort();
+    names.dedup();
+    core_internal!(names, running);
+    running.run(function)
+}
+
+/// Like [with_serial], but gives up if it can't get all of `names` within `timeout`
+///
+/// Returns whether `function` ran, so the caller can decide what to do instead (e.g. log it, skip the check,
+/// or try again later), rather than waiting for however long the current holder takes.
+/// ````
+/// use serial_test::{try_with_serial_lock, with_serial};
+/// use std::time::Duration;
+///
+/// assert!(try_with_serial_lock(["db"], Duration::from_millis(10), || {}));
+///
+/// let (holding, held) = std::sync::mpsc::channel();
+/// let (done, finish) = std::sync::mpsc::channel::<()>();
+/// let holder = std::thread::spawn(move || {
+///     with_serial(["db"], || {
+///         holding.send(()).unwrap();
+///         finish.recv().unwrap();
+///     })
+/// });
+/// held.recv().unwrap();
+/// assert!(!try_with_serial_lock(["db"], Duration::from_millis(10), || unreachable!()));
+/// done.send(()).unwrap();
+/// holder.join().unwrap();
+/// ````
+/// The keys are taken in sorted order, like [with_serial_keys_ordered] does, and any already taken are let go
+/// again if a later one can't be had in time. If `function` panics, the keys are let go before the panic carries on.
+pub fn try_with_serial_lock<I>(
+    names: I,
+    timeout: std::time::Duration,
+    function: impl FnOnce(),
+) -> bool
+where
+    I: IntoIterator,
+    I::Item: AsRef<str>,
+{
+    // A timeout too long to add up to an Instant is as good as waiting forever
+    let deadline = std::time::Instant::now().checked_add(timeout);
+    let keys: Vec<I::Item> = names.into_iter().collect();
+    let mut names: Vec<&str> = keys.iter().map(AsRef::as_ref).collect();
+    if names.is_empty() {
+        names.push(crate::code_lock::DEFAULT_KEY);
+    }
+    names.sort();
+    names.dedup();
+    let names = crate::code_lock::with_conflicts(names);
+    let unlocks: Vec<_> = names
+        .into_iter()
+        .map(|name| (name, lock_for(name)))
+        .collect();
+    let mut guards = Guards(Vec::with_capacity(unlocks.len()));
+    for (name, unlock) in &unlocks {
+        let held = HeldKey::acquire(name);
+        match unlock.lock_until(deadline) {
+            Some(guard) => guards.0.push((guard, held)),
+            None => return false,
+        }
+    }
+    // Dropped first, so registered threads finish before the keys are released
+    let _joins = crate::join::JoinOnRelease::new();
+    Running::serial(guards.0.iter().map(|(guard, _)| guard)).run(function);
+    true
+}
+
+/// What `run_async_with_max_hold` does when the test takes too long
+#[doc(hidden)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OnTimeout {
+    /// Fails the test, letting the rest of the suite carry on
+    Panic,
+    /// Aborts the whole test process, for `abort_on_timeout`
+    Abort,
+}
+
+/// Runs `function`, aborting the test process if it takes longer than `max_hold_ms`
+///
+/// For `max_hold` on sync tests, which is for diagnosing hangs. The body runs on the test thread as usual, keeping
+/// its locks (and whatever else is tied to the thread), and there's no safe way to stop it or take the keys off it
+/// part way through. Failing the test once it finishes would leave a body that's hung for good holding its keys
+/// forever, so a watchdog thread aborts the whole process as soon as it's over time instead. That lets go of
+/// everything and fails the run straight away, at the cost of the rest of that run's results.
+#[doc(hidden)]
+pub fn run_with_max_hold<R>(max_hold_ms: u64, test_name: &str, function: impl FnOnce() -> R) -> R {
+    // Dropped when we're done, even by a panic, which stops the watchdog early
+    let (_finished, finished) = std::sync::mpsc::channel::<()>();
+    let watched = test_name.to_string();
+    std::thread::Builder::new()
+        .name(format!("{} max_hold", test_name))
+        .spawn(move || {
+            if let Err(std::sync::mpsc::RecvTimeoutError::Timeout) =
+                finished.recv_timeout(std::time::Duration::from_millis(max_hold_ms))
+            {
+                timed_out(max_hold_ms, &watched, OnTimeout::Abort);
+            }
+        })
+        .expect("Couldn't start a thread for the max_hold watchdog");
+    function()
+}
+
+/// Runs `fut`, failing the test if it takes longer than `max_hold_ms`
+///
+/// For `max_hold` on async parallel tests. Unlike [run_with_max_hold], the test is stopped by dropping `fut`, so
+/// nothing's left running once the parallel core lets go of its keys. That doesn't need any particular runtime, as
+/// the time's kept by a thread that wakes this up.
+#[doc(hidden)]
+#[cfg(feature = "async")]
+pub fn run_async_with_max_hold<R>(
+    max_hold_ms: u64,
+    test_name: &str,
+    on_timeout: OnTimeout,
+    fut: impl std::future::Future<Output = R>,
+) -> impl std::future::Future<Output = R> + std::panic::UnwindSafe {
+    let test_name = test_name.to_string();
+    // The parallel core only carries on with a panic once it's let go of its keys, so nothing sees broken state
+    std::panic::AssertUnwindSafe(async move {
+        let (expired, expiry) = futures::channel::oneshot::channel::<()>();
+        // Dropped when we're done, which stops the timer thread early
+        let (_finished, finished) = std::sync::mpsc::channel::<()>();
+        std::thread::Builder::new()
+            .name(format!("{} max_hold", test_name))
+            .spawn(move || {
+                if let Err(std::sync::mpsc::RecvTimeoutError::Timeout) =
+                    finished.recv_timeout(std::time::Duration::from_millis(max_hold_ms))
+                {
+                    let _ = expired.send(());
+                }
+            })
+            .expect("Couldn't start a thread for the max_hold timer");
+        futures::pin_mut!(fut);
+        match futures::future::select(fut, expiry).await {
+            futures::future::Either::Left((ret, _)) => ret,
+            futures::future::Either::Right(_) => timed_out(max_hold_ms, &test_name, on_timeout),
+        }
+    })
+}
+
+// Aborting writes straight to stderr, as the logger might not get to write anything out before we go
+#[allow(clippy::print_stderr)]
+fn timed_out(max_hold_ms: u64, test_name: &str, on_timeout: OnTimeout) -> ! {
+    if on_timeout == OnTimeout::Abort {
+        eprintln!(
+            "{} held its lock for more than {}ms (max_hold), so aborting",
+            test_name, max_hold_ms
+        );
+        std::process::abort();
+    }
+    panic!(
+        "{} timed out after holding its lock for more than {}ms (max_hold)",
+        test_name, max_hold_ms
+    );
+}
+
+/// A serial lock of its own, rather than one looked up by key
+///
+/// Only callers sharing the same [SerialLock] are kept apart, and it's not one of the keys the attributes use, so
+/// it doesn't exclude (or get excluded by) any [serial](macro@crate::serial) or [parallel](macro@crate::parallel)
+/// tests. Skipping the key lookup makes it cheaper for hot paths, and it's handy for a test module that wants to own
+/// its lock as a `static`.
+/// ````
+/// use serial_test::SerialLock;
+///
+/// static DB: SerialLock = SerialLock::new();
+///
+/// let value = DB.with(|| {
+///     // Do things
+///     42
+/// });
+/// assert_eq!(value, 42);
+/// ````
+/// As with the keys, it's re-entrant on the same thread.
+pub struct SerialLock {
+    // Created on first use, so `new` can be const
+    locks: OnceCell<Locks>,
+}
+
+impl SerialLock {
+    /// Makes a new lock, unrelated to any other
+    pub const fn new() -> SerialLock {
+        SerialLock {
+            locks: OnceCell::new(),
+        }
+    }
+
+    /// Runs `function` holding this lock
+    pub fn with<R>(&self, function: impl FnOnce() -> R) -> R {
+        let _guard = self
+            .locks
+            .get_or_init(|| Locks::new("<SerialLock>"))
+            .serial();
+        function()
+    }
+}
+
+impl Default for SerialLock {
+    fn default() -> Self {
+        SerialLock::new()
+    }
+}
+
+/// Holds a serial key until dropped, see [serial_lock_async]
+#[cfg(feature = "async")]
+pub struct SerialAsyncGuard {
+    release: Option<std::sync::mpsc::Sender<()>>,
+    holder: Option<std::thread::JoinHandle<()>>,
+}
+
+#[cfg(feature = "async")]
+impl Drop for SerialAsyncGuard {
+    fn drop(&mut self) {
+        // Dropping the sender wakes up the holder, and joining means the key's free once we return
+        self.release.take();
+        if let Some(holder) = self.holder.take() {
+            let _ = holder.join();
+        }
+    }
+}
+
+/// Acquires a serial key from async code, holding it until the returned guard is dropped
+///
+/// This is for shared async setup that needs to be part of a serial group, and can be held across `.await`s.
+/// It excludes [serial](macro@crate::serial) and [parallel](macro@crate::parallel) tests with the same key
+/// as usual. As async tasks can move between threads on multi-threaded runtimes, the key is actually held
+/// by a separate thread on our behalf, which has a couple of consequences
+/// * It's not re-entrant, so don't call this from inside a test already holding the same key, or it'll deadlock
+/// * [is_locked_serially](crate::is_locked_serially) doesn't see it, as that's about the current thread
+/// * It needs threads, so won't work on `wasm32-unknown-unknown`
+/// ````
+/// use serial_test::serial_lock_async;
+///
+/// # futures::executor::block_on(async {
+/// let guard = serial_lock_async(Some("db")).await;
+/// // Do async setup
+/// drop(guard);
+/// # });
+/// ````
+#[cfg(feature = "async")]
+pub async fn serial_lock_async(name: Option<&str>) -> SerialAsyncGuard {
+    let name = name.unwrap_or(crate::code_lock::DEFAULT_KEY).to_owned();
+    let (acquired_sender, acquired) = futures::channel::oneshot::channel();
+    let (release, release_receiver) = std::sync::mpsc::channel::<()>();
+    let holder = std::thread::spawn(move || {
+        // Plus anything it conflicts with, in the same order the attributes take them
+        let locks: Vec<_> = crate::code_lock::with_conflicts(vec![name.as_str()])
+            .into_iter()
+            .map(lock_for)
+            .collect();
+        let _guards = Guards(locks.iter().map(|lock| lock.lock()).collect());
+        // If we got cancelled before getting the lock, there's no-one to wait for
+        if acquired_sender.send(()).is_ok() {
+            let _ = release_receiver.recv();
+        }
+    });
+    acquired.await.expect("serial lock holder to not panic");
+    SerialAsyncGuard {
+        release: Some(release),
+        holder: Some(holder),
+    }
 }
 
 #[cfg(test)]
-#[allow(clippy::print_stdout)]
+#[allow(clippy::print_stdout, clippy::assertions_on_constants)]
 mod tests {
-    use super::local_serial_core;
-    use crate::code_lock::{check_new_key, global_locks};
+    use super::{
+        local_serial_core, run_with_max_hold, try_with_serial_lock, with_serial,
+        with_serial_keys_ordered, SerialLock,
+    };
+    use crate::code_lock::{
+        check_new_key, global_locks, is_locked_serially, next_mutex_id, wait_until_idle,
+    };
     use itertools::Itertools;
     use parking_lot::RwLock;
     use std::{
-        sync::{Arc, Barrier},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Barrier,
+        },
         thread,
         time::Duration,
     };
 
     #[test]
     fn test_hammer_check_new_key() {
+        // Other tests are setting up keys alongside this one, so the ids can't be reset, but the key's id has to be
+        // one handed out while it ran
+        let first_id = next_mutex_id();
         let ptrs = Arc::new(RwLock::new(Vec::new()));
         let mut threads = Vec::new();
 
@@ -85,9 +522,11 @@ mod tests {
             let c = barrier.clone();
             threads.push(thread::spawn(move || {
                 c.wait();
-                check_new_key("foo");
+                check_new_key("hammer_check_new_key");
                 {
-                    let unlock = local_locks.get("foo").expect("read didn't work");
+                    let unlock = local_locks
+                        .get("hammer_check_new_key")
+                        .expect("read didn't work");
                     let mutex = unlock.get();
 
                     let mut ptr_guard = local_ptrs
@@ -108,6 +547,7 @@ mod tests {
         assert_eq!(ptrs_read_lock.len(), count);
         println!("{:?}", ptrs_read_lock);
         assert_eq!(ptrs_read_lock.iter().unique().count(), 1);
+        assert!((first_id..next_mutex_id()).contains(&ptrs_read_lock[0]));
     }
 
     #[test]
@@ -119,4 +559,352 @@ mod tests {
         });
         assert!(!global_locks().get("assert").unwrap().get().is_locked());
     }
+
+    #[test]
+    #[cfg(feature = "async")]
+    fn serial_lock_async_excludes_serial() {
+        use super::serial_lock_async;
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        static RAN: AtomicBool = AtomicBool::new(false);
+        let guard = futures::executor::block_on(async {
+            let guard = serial_lock_async(Some("serial_lock_async")).await;
+            futures::future::ready(()).await;
+            guard
+        });
+        let serial = thread::spawn(|| {
+            local_serial_core(vec!["serial_lock_async"], None, || {
+                RAN.store(true, Ordering::SeqCst);
+            })
+        });
+        thread::sleep(Duration::from_millis(100));
+        assert!(!RAN.load(Ordering::SeqCst));
+        drop(guard);
+        serial.join().unwrap();
+        assert!(RAN.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    #[cfg(feature = "async")]
+    fn serial_lock_async_takes_conflicts() {
+        use super::serial_lock_async;
+
+        crate::declare_conflicts(&[("async_conflict_a", "async_conflict_b")]);
+        let guard = futures::executor::block_on(serial_lock_async(Some("async_conflict_a")));
+        assert!(!try_with_serial_lock(
+            ["async_conflict_b"],
+            Duration::from_millis(50),
+            || {}
+        ));
+        drop(guard);
+        assert!(try_with_serial_lock(
+            ["async_conflict_b"],
+            Duration::from_millis(50),
+            || {}
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "tokio_serial")]
+    fn tokio_serial_waits_for_serial() {
+        use super::local_tokio_serial_core;
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        static RAN: AtomicBool = AtomicBool::new(false);
+        let started = Arc::new(Barrier::new(2));
+        let serial = {
+            let started = started.clone();
+            thread::spawn(move || {
+                local_serial_core(vec!["tokio_serial_waits"], None, || {
+                    started.wait();
+                    thread::sleep(Duration::from_millis(100));
+                    assert!(!RAN.load(Ordering::SeqCst));
+                })
+            })
+        };
+        started.wait();
+        futures::executor::block_on(local_tokio_serial_core(
+            vec!["tokio_serial_waits"],
+            None,
+            async {
+                RAN.store(true, Ordering::SeqCst);
+            },
+        ));
+        serial.join().unwrap();
+        assert!(!global_locks()
+            .get("tokio_serial_waits")
+            .unwrap()
+            .get()
+            .is_locked());
+    }
+
+    #[test]
+    fn lock_order_inversion_panics() {
+        thread::spawn(|| {
+            local_serial_core(vec!["inversion_a"], None, || {
+                local_serial_core(vec!["inversion_b"], None, || {});
+            });
+        })
+        .join()
+        .unwrap();
+        let err = thread::spawn(|| {
+            local_serial_core(vec!["inversion_b"], None, || {
+                local_serial_core(vec!["inversion_a"], None, || {});
+            });
+        })
+        .join()
+        .unwrap_err();
+        let message = err.downcast_ref::<String>().unwrap();
+        assert!(
+            message.starts_with("Lock order inversion: taking 'inversion_a' while holding 'inversion_b', but elsewhere 'inversion_a' -> 'inversion_b' were taken in that order"),
+            "{}",
+            message
+        );
+        // Everything got released on the way out
+        local_serial_core(vec!["inversion_a", "inversion_b"], None, || {});
+    }
+
+    #[test]
+    fn lock_order_reentrant_and_consistent() {
+        local_serial_core(vec!["consistent_a"], None, || {
+            local_serial_core(vec!["consistent_a", "consistent_b"], None, || {
+                local_serial_core(vec!["consistent_b"], None, || {});
+            });
+        });
+        local_serial_core(vec!["consistent_a", "consistent_b"], None, || {});
+    }
+
+    #[test]
+    fn with_serial_keys_ordered_out_of_order() {
+        let orders = [
+            ["ordered_c", "ordered_a", "ordered_b"],
+            ["ordered_b", "ordered_c", "ordered_a"],
+            ["ordered_a", "ordered_b", "ordered_c"],
+        ];
+        let barrier = Arc::new(Barrier::new(orders.len()));
+        let threads: Vec<_> = orders
+            .iter()
+            .copied()
+            .map(|order| {
+                let barrier = barrier.clone();
+                thread::spawn(move || {
+                    barrier.wait();
+                    for _ in 0..100 {
+                        with_serial_keys_ordered(&order, || {
+                            for key in order {
+                                assert!(is_locked_serially(Some(key)));
+                            }
+                        });
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        for key in ["ordered_a", "ordered_b", "ordered_c"] {
+            assert!(wait_until_idle(Some(key), Duration::from_millis(1)));
+        }
+    }
+
+    #[test]
+    fn with_serial_takes_any_strings() {
+        let owned = vec!["owned_b".to_string(), "owned_a".to_string()];
+        with_serial(&owned, || {
+            assert!(is_locked_serially(Some("owned_a")));
+            assert!(is_locked_serially(Some("owned_b")));
+        });
+        with_serial(owned, || {
+            assert!(is_locked_serially(Some("owned_a")));
+        });
+        with_serial(&["borrowed_a", "borrowed_b"], || {
+            assert!(is_locked_serially(Some("borrowed_b")));
+        });
+        with_serial([String::from("single")], || {
+            assert!(is_locked_serially(Some("single")));
+        });
+        with_serial(Some(String::from("optional")), || {
+            assert!(is_locked_serially(Some("optional")));
+        });
+    }
+
+    #[test]
+    fn serial_lock_excludes_its_users() {
+        static LOCK: SerialLock = SerialLock::new();
+        static RUNNING: AtomicUsize = AtomicUsize::new(0);
+        let barrier = Arc::new(Barrier::new(4));
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let barrier = barrier.clone();
+                thread::spawn(move || {
+                    barrier.wait();
+                    for _ in 0..10 {
+                        LOCK.with(|| {
+                            assert_eq!(RUNNING.fetch_add(1, Ordering::SeqCst), 0);
+                            thread::sleep(Duration::from_millis(1));
+                            RUNNING.fetch_sub(1, Ordering::SeqCst);
+                        });
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert!(!global_locks().contains("<SerialLock>"));
+    }
+
+    #[test]
+    fn declared_conflicts() {
+        crate::declare_conflicts(&[("conflict_a", "conflict_b"), ("conflict_a", "conflict_c")]);
+        // Whether a thread taking `key` has to wait while this one holds `held`
+        let blocks = |held: &'static str, key: &'static str| {
+            with_serial_keys_ordered(&[held], || {
+                let waiter = thread::spawn(move || with_serial_keys_ordered(&[key], || {}));
+                thread::sleep(Duration::from_millis(100));
+                let blocked = !waiter.is_finished();
+                (blocked, waiter)
+            })
+            .0
+        };
+        assert!(blocks("conflict_a", "conflict_b"));
+        assert!(blocks("conflict_a", "conflict_c"));
+        assert!(blocks("conflict_b", "conflict_a"));
+        assert!(!blocks("conflict_b", "conflict_c"));
+        assert!(!blocks("conflict_c", "conflict_b"));
+    }
+
+    #[test]
+    fn serial_lock_is_reentrant() {
+        let lock = SerialLock::new();
+        assert_eq!(lock.with(|| lock.with(|| 42)), 42);
+    }
+
+    #[test]
+    fn max_hold_body_keeps_the_lock() {
+        local_serial_core(vec!["max_hold_keeps_lock"], None, || {
+            run_with_max_hold(10000, "max_hold_keeps_lock", || {
+                assert!(is_locked_serially(Some("max_hold_keeps_lock")));
+                // Re-entrant as usual, as it's still on the thread holding the key
+                with_serial(["max_hold_keeps_lock"], || {});
+            })
+        });
+        assert!(wait_until_idle(
+            Some("max_hold_keeps_lock"),
+            Duration::from_millis(1)
+        ));
+    }
+
+    #[test]
+    fn try_with_serial_lock_gives_up() {
+        let (holding, held) = std::sync::mpsc::channel();
+        let (done, finish) = std::sync::mpsc::channel::<()>();
+        let holder = thread::spawn(move || {
+            with_serial(["try_serial_b"], || {
+                holding.send(()).unwrap();
+                finish.recv().unwrap();
+            })
+        });
+        held.recv().unwrap();
+        assert!(!try_with_serial_lock(
+            ["try_serial_a", "try_serial_b"],
+            Duration::from_millis(50),
+            || unreachable!()
+        ));
+        // The key it did get has been let go again
+        assert!(wait_until_idle(
+            Some("try_serial_a"),
+            Duration::from_millis(1)
+        ));
+        done.send(()).unwrap();
+        holder.join().unwrap();
+
+        let mut ran = false;
+        assert!(try_with_serial_lock(
+            ["try_serial_a", "try_serial_b"],
+            Duration::from_millis(50),
+            || {
+                assert!(is_locked_serially(Some("try_serial_a")));
+                assert!(is_locked_serially(Some("try_serial_b")));
+                ran = true;
+            }
+        ));
+        assert!(ran);
+    }
+
+    #[test]
+    fn try_with_serial_lock_without_deadline() {
+        let mut ran = false;
+        assert!(try_with_serial_lock(
+            ["try_serial_max"],
+            Duration::MAX,
+            || ran = true
+        ));
+        assert!(ran);
+    }
+
+    #[test]
+    fn try_with_serial_lock_lets_go_on_panic() {
+        assert!(std::panic::catch_unwind(|| {
+            try_with_serial_lock(["try_serial_panic"], Duration::from_millis(50), || {
+                panic!("Deliberate panic")
+            })
+        })
+        .is_err());
+        assert!(wait_until_idle(
+            Some("try_serial_panic"),
+            Duration::from_millis(1)
+        ));
+    }
+
+    #[test]
+    fn max_hold_within_budget() {
+        assert_eq!(run_with_max_hold(10000, "within_budget", || 42), 42);
+    }
+
+    #[test]
+    #[should_panic(expected = "Deliberate panic")]
+    fn max_hold_passes_on_panics() {
+        run_with_max_hold(10000, "passes_on_panics", || panic!("Deliberate panic"));
+    }
+
+    #[test]
+    fn overlapping_key_sets_dont_deadlock() {
+        let keys = ["overlap_a", "overlap_b", "overlap_c"];
+        let threads = 50;
+        let (done, finished) = std::sync::mpsc::channel();
+        let barrier = Arc::new(Barrier::new(threads));
+        for index in 0..threads {
+            let (done, barrier) = (done.clone(), barrier.clone());
+            thread::spawn(move || {
+                // Cheap xorshift, so each thread picks its own sequence of subsets
+                let mut state = (index as u32).wrapping_mul(2654435761) | 1;
+                let mut next = move || {
+                    state ^= state << 13;
+                    state ^= state >> 17;
+                    state ^= state << 5;
+                    state as usize
+                };
+                barrier.wait();
+                for _ in 0..20 {
+                    let first = next() % keys.len();
+                    let second = (first + 1 + next() % (keys.len() - 1)) % keys.len();
+                    let mut names = vec![keys[first], keys[second]];
+                    // As the attributes do
+                    names.sort();
+                    local_serial_core(names.clone(), None, || {
+                        for name in &names {
+                            assert!(is_locked_serially(Some(name)));
+                        }
+                    });
+                }
+                done.send(()).unwrap();
+            });
+        }
+        for _ in 0..threads {
+            finished
+                .recv_timeout(Duration::from_secs(60))
+                .expect("Threads taking overlapping keys deadlocked");
+        }
+    }
 }
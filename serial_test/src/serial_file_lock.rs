@@ -1,27 +1,83 @@
-use std::panic;
+use once_cell::sync::OnceCell;
+use std::{env, panic, time::Duration};
 
-use crate::file_lock::get_locks;
+use crate::{
+    file_lock::{lock_paths, Lock, LockPaths, LockType},
+    nextest::held_by_current_thread,
+    serial_code_lock::with_serial_keys_ordered,
+    single_thread::test_threads,
+};
 
-#[doc(hidden)]
-pub fn fs_serial_core(names: Vec<&str>, path: Option<&str>, function: fn()) {
-    let mut locks = get_locks(&names, path);
-    locks.iter_mut().for_each(|lock| lock.start_serial());
-    let res = panic::catch_unwind(function);
-    locks.into_iter().for_each(|lock| lock.end_serial());
-    if let Err(err) = res {
-        panic::resume_unwind(err);
-    }
+// Only `SERIAL_TEST_SINGLE_PROCESS=1` counts, so e.g. `0` doesn't switch it on
+fn single_process_from(single_process: Option<String>, threads: Option<usize>) -> bool {
+    single_process.as_deref() == Some("1") && threads == Some(1)
+}
+
+// With a single test thread, and the user's word that no other process uses the lock files, the in-memory lock
+// does the same job without touching the filesystem. Neither can change during a run, so it's only checked once.
+fn single_process() -> bool {
+    static SINGLE_PROCESS: OnceCell<bool> = OnceCell::new();
+    *SINGLE_PROCESS.get_or_init(|| {
+        single_process_from(env::var("SERIAL_TEST_SINGLE_PROCESS").ok(), test_threads())
+    })
 }
 
+// Leaves out any this thread already holds as part of a serial test (e.g. for dual_serial under nextest),
+// as file locks aren't reentrant
+fn serial_locks(names: &[&str], paths: &LockPaths, lock_type: LockType) -> Vec<Lock> {
+    lock_paths(names, paths)
+        .iter()
+        .filter(|path| !held_by_current_thread(path))
+        .map(|path| Lock::new(path, lock_type))
+        .collect()
+}
+
+/// What a [file_serial](macro@crate::file_serial) test's arguments say about taking its locks
 #[doc(hidden)]
-pub fn fs_serial_core_with_return<E>(
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FileSerialOptions<'a> {
+    /// Recorded alongside the lock file, for anyone else waiting on it
+    pub holder: &'a str,
+    /// From `expiry_ms`
+    pub expiry_ms: Option<u64>,
+    /// From `watch_path`
+    pub watch_path: bool,
+    /// From `lock_type`
+    pub lock_type: LockType,
+}
+
+fn start_serial_locks(
+    names: &[&str],
+    paths: &LockPaths,
+    options: FileSerialOptions<'_>,
+) -> Vec<Lock> {
+    let mut locks = serial_locks(names, paths, options.lock_type);
+    locks.iter_mut().for_each(|lock| {
+        lock.start_serial(
+            options.expiry_ms.map(Duration::from_millis),
+            options.watch_path,
+        );
+        lock.set_holder(options.holder);
+    });
+    locks
+}
+
+// The sync cores, with whether to skip the file locks (as per single_process) passed in
+fn run_serial<'a, R>(
+    single_process: bool,
     names: Vec<&str>,
-    path: Option<&str>,
-    function: fn() -> Result<(), E>,
-) -> Result<(), E> {
-    let mut locks = get_locks(&names, path);
-    locks.iter_mut().for_each(|lock| lock.start_serial());
-    let res = panic::catch_unwind(function);
+    path: impl Into<LockPaths<'a>>,
+    options: FileSerialOptions<'_>,
+    function: impl FnOnce() -> R,
+) -> R {
+    if crate::dry_run::skip_locking("acquire file serial key(s)", &names) {
+        return function();
+    }
+    if single_process {
+        return with_serial_keys_ordered(&names, function);
+    }
+    let locks = start_serial_locks(&names, &path.into(), options);
+    let res = panic::catch_unwind(panic::AssertUnwindSafe(function));
     locks.into_iter().for_each(|lock| lock.end_serial());
     match res {
         Ok(ret) => ret,
@@ -31,45 +87,190 @@ pub fn fs_serial_core_with_return<E>(
     }
 }
 
+#[doc(hidden)]
+pub fn fs_serial_core<'a>(
+    names: Vec<&str>,
+    path: impl Into<LockPaths<'a>>,
+    options: FileSerialOptions<'_>,
+    function: impl FnOnce(),
+) {
+    run_serial(single_process(), names, path, options, function)
+}
+
+#[doc(hidden)]
+pub fn fs_serial_core_with_return<'a, E>(
+    names: Vec<&str>,
+    path: impl Into<LockPaths<'a>>,
+    options: FileSerialOptions<'_>,
+    function: impl FnOnce() -> Result<(), E>,
+) -> Result<(), E> {
+    run_serial(single_process(), names, path, options, function)
+}
+
 #[doc(hidden)]
 #[cfg(feature = "async")]
-pub async fn fs_async_serial_core_with_return<E>(
+pub async fn fs_async_serial_core_with_return<'a, E>(
     names: Vec<&str>,
-    path: Option<&str>,
+    path: impl Into<LockPaths<'a>>,
+    options: FileSerialOptions<'_>,
     fut: impl std::future::Future<Output = Result<(), E>>,
 ) -> Result<(), E> {
-    let mut locks = get_locks(&names, path);
-    locks.iter_mut().for_each(|lock| lock.start_serial());
-    let ret: Result<(), E> = fut.await;
-    locks.into_iter().for_each(|lock| lock.end_serial());
-    ret
+    if crate::dry_run::skip_locking("acquire file serial key(s)", &names) {
+        return fut.await;
+    }
+    let _locks = SerialLocks(start_serial_locks(&names, &path.into(), options));
+    fut.await
 }
 
 #[doc(hidden)]
 #[cfg(feature = "async")]
-pub async fn fs_async_serial_core(
+pub async fn fs_async_serial_core<'a>(
     names: Vec<&str>,
-    path: Option<&str>,
+    path: impl Into<LockPaths<'a>>,
+    options: FileSerialOptions<'_>,
     fut: impl std::future::Future<Output = ()>,
 ) {
-    let mut locks = get_locks(&names, path);
-    locks.iter_mut().for_each(|lock| lock.start_serial());
+    if crate::dry_run::skip_locking("acquire file serial key(s)", &names) {
+        return fut.await;
+    }
+    let _locks = SerialLocks(start_serial_locks(&names, &path.into(), options));
     fut.await;
-    locks.into_iter().for_each(|lock| lock.end_serial());
+}
+
+// Releases on drop, so we also let go if the function panics, or an async test is cancelled. Which thread
+// that happens on doesn't matter, as file locks belong to the open file, not the thread
+pub(crate) struct SerialLocks(pub(crate) Vec<Lock>);
+
+impl Drop for SerialLocks {
+    fn drop(&mut self) {
+        self.0.drain(..).for_each(|lock| lock.end_serial());
+    }
+}
+
+/// Runs `function` holding the given keys as per [file_serial](macro@crate::file_serial)
+///
+/// This is mainly for processes that aren't tests, but need to be part of a group of
+/// [file_serial](macro@crate::file_serial) tests, e.g. child processes spawned by a test.
+/// `path` is as for [file_serial](macro@crate::file_serial), so to share a lock with a parent process,
+/// have the parent pass its lock path down (an environment variable is the easy way) and
+/// use that here.
+/// ````no_run
+/// use serial_test::with_file_serial;
+///
+/// let path = std::env::var("MY_LOCK_PATH").ok();
+/// with_file_serial(["db"], path.as_deref(), || {
+///     // Do things
+/// });
+/// ````
+/// Note that the parent can't be holding the lock itself while waiting for the child, or they'll deadlock.
+pub fn with_file_serial<I, R>(names: I, path: Option<&str>, function: impl FnOnce() -> R) -> R
+where
+    I: IntoIterator,
+    I::Item: AsRef<str>,
+{
+    let keys: Vec<I::Item> = names.into_iter().collect();
+    let mut names: Vec<&str> = keys.iter().map(AsRef::as_ref).collect();
+    if names.is_empty() {
+        names.push("");
+    }
+    names.sort();
+    let holder = format!(
+        "with_file_serial in {}",
+        std::env::args().next().unwrap_or_default()
+    );
+    let _locks = SerialLocks(start_serial_locks(
+        &names,
+        &LockPaths::Shared(path),
+        FileSerialOptions {
+            holder: &holder,
+            ..Default::default()
+        },
+    ));
+    function()
 }
 
 #[cfg(test)]
+#[allow(clippy::assertions_on_constants)]
 mod tests {
     use std::panic;
 
     use fslock::LockFile;
 
-    use super::fs_serial_core;
-    use crate::file_lock::path_for_name;
+    use super::{fs_serial_core, run_serial, single_process_from, FileSerialOptions};
+    use crate::file_lock::{gen_holder_file, is_file_locked, path_for_name};
+
+    #[test]
+    fn single_process_needs_both_signals() {
+        assert!(single_process_from(Some("1".into()), Some(1)));
+        assert!(!single_process_from(Some("1".into()), Some(4)));
+        assert!(!single_process_from(Some("0".into()), Some(1)));
+        assert!(!single_process_from(None, Some(1)));
+        assert!(!single_process_from(Some("1".into()), None));
+    }
+
+    #[test]
+    fn single_process_skips_file_locks() {
+        let lock_path = path_for_name("single_process_skips_file_locks");
+        let _ = std::fs::remove_file(&lock_path);
+        run_serial(
+            single_process_from(Some("1".into()), Some(1)),
+            vec!["single_process_skips_file_locks"],
+            Some(lock_path.as_str()),
+            FileSerialOptions {
+                holder: "single_process_skips_file_locks",
+                ..Default::default()
+            },
+            || {
+                assert!(crate::is_locked_serially(Some(
+                    "single_process_skips_file_locks"
+                )));
+            },
+        );
+        assert!(!std::path::Path::new(&lock_path).exists());
+    }
 
     #[test]
     fn test_serial() {
-        fs_serial_core(vec!["test"], None, || {});
+        fs_serial_core(
+            vec!["test"],
+            None,
+            FileSerialOptions {
+                holder: "test_serial",
+                ..Default::default()
+            },
+            || {},
+        );
+    }
+
+    #[test]
+    fn test_serial_with_paths() {
+        let first_path = path_for_name("serial_with_paths_first");
+        let second_path = path_for_name("serial_with_paths_second");
+        fs_serial_core(
+            vec!["first", "second"],
+            vec![Some(first_path.as_str()), Some(second_path.as_str())],
+            FileSerialOptions {
+                holder: "test_serial_with_paths",
+                ..Default::default()
+            },
+            || {},
+        );
+        assert!(LockFile::open(&first_path).unwrap().try_lock().unwrap());
+        assert!(LockFile::open(&second_path).unwrap().try_lock().unwrap());
+    }
+
+    #[test]
+    #[should_panic(expected = "Need one path per key, but got 2 keys and 1 paths")]
+    fn test_serial_with_too_few_paths() {
+        fs_serial_core(
+            vec!["first", "second"],
+            vec![Some("first")],
+            FileSerialOptions {
+                holder: "test_serial_with_too_few_paths",
+                ..Default::default()
+            },
+            || {},
+        );
     }
 
     #[test]
@@ -78,7 +279,11 @@ mod tests {
         let _ = panic::catch_unwind(|| {
             fs_serial_core(
                 vec!["serial_unlock_on_assert_sync_without_return"],
-                Some(&lock_path),
+                Some(lock_path.as_str()),
+                FileSerialOptions {
+                    holder: "unlock_on_assert_sync_without_return",
+                    ..Default::default()
+                },
                 || {
                     assert!(false);
                 },
@@ -87,4 +292,167 @@ mod tests {
         let mut lockfile = LockFile::open(&lock_path).unwrap();
         assert!(lockfile.try_lock().unwrap());
     }
+
+    #[test]
+    fn test_holder_recorded_while_locked() {
+        let lock_path = path_for_name("holder_recorded_while_locked");
+        fs_serial_core(
+            vec!["holder_recorded_while_locked"],
+            Some(lock_path.as_str()),
+            FileSerialOptions {
+                holder: "serial_test::tests::holder",
+                ..Default::default()
+            },
+            || {
+                let lock_path = path_for_name("holder_recorded_while_locked");
+                let holder = std::fs::read_to_string(gen_holder_file(&lock_path)).unwrap();
+                assert_eq!(
+                    holder,
+                    format!(
+                        "process {} (serial_test::tests::holder)",
+                        std::process::id()
+                    )
+                );
+            },
+        );
+        assert!(!std::path::Path::new(&gen_holder_file(&lock_path)).exists());
+    }
+
+    #[test]
+    fn test_is_file_locked() {
+        assert!(!is_file_locked(Some("is_file_locked")));
+        fs_serial_core(
+            vec!["is_file_locked"],
+            None,
+            FileSerialOptions {
+                holder: "test_is_file_locked",
+                ..Default::default()
+            },
+            || {
+                assert!(is_file_locked(Some("is_file_locked")));
+            },
+        );
+        assert!(!is_file_locked(Some("is_file_locked")));
+    }
+
+    #[test]
+    #[cfg(not(feature = "shared_file_locks"))]
+    fn test_expired_parallel_count_is_reset() {
+        use crate::file_lock::{Lock, LockType};
+        use std::{thread, time::Duration};
+
+        let lock_path = path_for_name("expired_parallel_count");
+        // A parallel user that never finishes, like one in a process that died
+        Lock::new(&lock_path, LockType::Flock).start_parallel(None);
+        thread::sleep(Duration::from_millis(200));
+        fs_serial_core(
+            vec!["expired_parallel_count"],
+            Some(lock_path.as_str()),
+            FileSerialOptions {
+                holder: "test_expired_parallel_count_is_reset",
+                expiry_ms: Some(100),
+                ..Default::default()
+            },
+            || {},
+        );
+        assert_eq!(Lock::new(&lock_path, LockType::Flock).parallel_count, 0);
+    }
+
+    #[test]
+    #[cfg(all(feature = "notify_watcher", not(feature = "shared_file_locks")))]
+    fn test_watch_path_wakes_serial_early() {
+        use crate::file_lock::{Lock, LockType};
+        use std::{
+            thread,
+            time::{Duration, Instant},
+        };
+
+        let lock_path = path_for_name("watch_path_wakes");
+        let mut parallel = Lock::new(&lock_path, LockType::Flock);
+        parallel.start_parallel(None);
+        let finisher = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            parallel.end_parallel();
+        });
+        let start = Instant::now();
+        fs_serial_core(
+            vec!["watch_path_wakes"],
+            Some(lock_path.as_str()),
+            FileSerialOptions {
+                holder: "test_watch_path_wakes_serial_early",
+                watch_path: true,
+                ..Default::default()
+            },
+            || {},
+        );
+        // Polling wouldn't have looked again until a second in
+        assert!(start.elapsed() < Duration::from_millis(900));
+        finisher.join().unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_waits_for_external_flock() {
+        use std::{
+            fs::File,
+            os::unix::io::AsRawFd,
+            sync::{
+                atomic::{AtomicBool, Ordering},
+                Arc,
+            },
+            thread,
+            time::Duration,
+        };
+
+        let lock_path = path_for_name("external_flock");
+        // As `flock <lock path> <command>` would
+        let external = File::create(&lock_path).unwrap();
+        assert_eq!(
+            unsafe { libc::flock(external.as_raw_fd(), libc::LOCK_EX) },
+            0
+        );
+
+        let ran = Arc::new(AtomicBool::new(false));
+        let test = {
+            let (lock_path, ran) = (lock_path.clone(), ran.clone());
+            thread::spawn(move || {
+                fs_serial_core(
+                    vec!["external_flock"],
+                    Some(lock_path.as_str()),
+                    FileSerialOptions {
+                        holder: "test_waits_for_external_flock",
+                        ..Default::default()
+                    },
+                    || ran.store(true, Ordering::SeqCst),
+                );
+            })
+        };
+        thread::sleep(Duration::from_millis(200));
+        assert!(!ran.load(Ordering::SeqCst));
+
+        drop(external);
+        test.join().unwrap();
+        assert!(ran.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    #[cfg(feature = "async")]
+    fn test_async_cancelled_releases_lock() {
+        use futures::FutureExt;
+
+        let lock_path = path_for_name("async_cancelled");
+        let never_finishes = super::fs_async_serial_core(
+            vec!["async_cancelled"],
+            Some(lock_path.as_str()),
+            FileSerialOptions {
+                holder: "test_async_cancelled_releases_lock",
+                ..Default::default()
+            },
+            std::future::pending(),
+        );
+        // Polls once, so we're holding the lock, then drops it
+        assert!(never_finishes.now_or_never().is_none());
+        assert!(!is_file_locked(Some("async_cancelled")));
+        assert!(!std::path::Path::new(&gen_holder_file(&lock_path)).exists());
+    }
 }
@@ -0,0 +1,131 @@
+use crate::file_lock::{check_path, gen_holder_file, write_holder, LockType, REAL_FILE_SYSTEM};
+use fs2::FileExt;
+#[cfg(feature = "logging")]
+use log::debug;
+use std::{
+    fs::{self, File, OpenOptions},
+    thread,
+    time::Duration,
+};
+
+// Parallel users take a shared lock and serial ones an exclusive lock, so the OS does all the counting for us
+pub(crate) struct Lock {
+    file: File,
+    serial: bool,
+    path: String,
+    // With max_parallel, each parallel user also holds one of that many slot files exclusively
+    slot: Option<File>,
+}
+
+fn open_lock_file(path: &str) -> File {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .unwrap_or_else(|_| panic!("Lock file path was {:?}", path))
+}
+
+fn check_lock_type(lock_type: LockType) {
+    assert!(
+        lock_type == LockType::Flock,
+        "lock_type = {:?} isn't supported with the shared_file_locks feature",
+        lock_type
+    );
+}
+
+impl Lock {
+    pub(crate) fn new(path: &str, lock_type: LockType) -> Lock {
+        check_lock_type(lock_type);
+        check_path(path);
+        Lock {
+            file: open_lock_file(path),
+            serial: false,
+            path: String::from(path),
+            slot: None,
+        }
+    }
+
+    // The OS releases the shared locks of processes that have died, so nothing can go stale, and there's
+    // nothing for an expiry to do. The OS wakes us up when the lock's free, so there's nothing to watch either
+    pub(crate) fn start_serial(self: &mut Lock, _expiry: Option<Duration>, _watch: bool) {
+        if self.file.try_lock_exclusive().is_err() {
+            #[cfg(feature = "logging")]
+            match fs::read_to_string(gen_holder_file(&self.path)) {
+                Ok(holder) => debug!("Waiting on {:?}, currently held by {}", self.path, holder),
+                Err(_) => debug!("Waiting on {:?}", self.path),
+            }
+
+            self.file.lock_exclusive().unwrap();
+        }
+        #[cfg(feature = "logging")]
+        debug!("Locked for {:?}", self.path);
+        self.serial = true;
+    }
+
+    pub(crate) fn set_holder(self: &Lock, holder: &str) {
+        assert!(
+            self.serial,
+            "Writing holder for {:?} without the lock",
+            self.path
+        );
+        write_holder(&REAL_FILE_SYSTEM, &self.path, holder);
+    }
+
+    pub(crate) fn end_serial(self: Lock) {
+        let _ = fs::remove_file(gen_holder_file(&self.path));
+        self.unlock();
+    }
+
+    fn take_slot(self: &Lock, max_parallel: u32) -> File {
+        loop {
+            for slot in 0..max_parallel {
+                let slot_file = open_lock_file(&format!("{}-slot-{}", self.path, slot));
+                if slot_file.try_lock_exclusive().is_ok() {
+                    return slot_file;
+                }
+            }
+            #[cfg(feature = "logging")]
+            debug!(
+                "Waiting for one of the {} parallel slots for {:?}",
+                max_parallel, self.path
+            );
+            thread::sleep(Duration::from_secs(1));
+        }
+    }
+
+    pub(crate) fn start_parallel(self: &mut Lock, max_parallel: Option<u32>) {
+        if let Some(max_parallel) = max_parallel {
+            self.slot = Some(self.take_slot(max_parallel));
+        }
+        self.file.lock_shared().unwrap();
+        #[cfg(feature = "logging")]
+        debug!("Shared lock for {:?}", self.path);
+    }
+
+    pub(crate) fn end_parallel(self: Lock) {
+        self.unlock();
+        if let Some(slot) = &self.slot {
+            slot.unlock().unwrap();
+        }
+    }
+
+    fn unlock(self: &Lock) {
+        #[cfg(feature = "logging")]
+        debug!("Unlocking {}", self.path);
+        self.file.unlock().unwrap();
+    }
+}
+
+// Any parallel holders have a shared lock too, so only an exclusive holder stops us getting one
+pub(crate) fn is_serially_locked(path: &str, lock_type: LockType) -> bool {
+    check_lock_type(lock_type);
+    let file = File::open(path).unwrap();
+    if file.try_lock_shared().is_ok() {
+        file.unlock().unwrap();
+        false
+    } else {
+        true
+    }
+}
@@ -0,0 +1,82 @@
+use once_cell::sync::OnceCell;
+use std::{collections::HashMap, env, fs, path::Path};
+
+const CONFIG_ENV: &str = "SERIAL_TEST_CONFIG";
+
+// Keys listed in the SERIAL_TEST_CONFIG file, which take file locks so they're shared with other test binaries,
+// along with the lock file path if one was given
+fn shared_keys() -> &'static HashMap<String, Option<String>> {
+    static SHARED_KEYS: OnceCell<HashMap<String, Option<String>>> = OnceCell::new();
+    SHARED_KEYS.get_or_init(|| match env::var_os(CONFIG_ENV) {
+        Some(path) => load(Path::new(&path)),
+        None => HashMap::new(),
+    })
+}
+
+fn load(path: &Path) -> HashMap<String, Option<String>> {
+    let contents = fs::read_to_string(path)
+        .unwrap_or_else(|err| panic!("Couldn't read {} file {:?}: {}", CONFIG_ENV, path, err));
+    parse(&contents, path.parent().unwrap_or_else(|| Path::new("")))
+}
+
+// One key per line, optionally with "= <path>" for the lock file. Relative paths are from the config file
+fn parse(contents: &str, base: &Path) -> HashMap<String, Option<String>> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| match line.split_once('=') {
+            Some((key, path)) => {
+                let path = path.trim();
+                if path.is_empty() {
+                    panic!(
+                        "Expected a path after '=' in {} line {:?}",
+                        CONFIG_ENV, line
+                    );
+                }
+                let path = base.join(path).into_os_string().into_string().unwrap();
+                (key.trim().to_string(), Some(path))
+            }
+            None => (line.to_string(), None),
+        })
+        .collect()
+}
+
+/// Whether `name` is one of the keys from `SERIAL_TEST_CONFIG`
+pub(crate) fn is_shared(name: &str) -> bool {
+    shared_keys().contains_key(name)
+}
+
+/// The lock file path from `SERIAL_TEST_CONFIG` for `name`, if it's got one
+pub(crate) fn shared_path(name: &str) -> Option<&'static str> {
+    shared_keys().get(name).and_then(|path| path.as_deref())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse;
+    use std::path::Path;
+
+    #[test]
+    fn parses_config() {
+        let keys = parse(
+            "# Keys shared with the other test binaries\n\nexternal = /tmp/external.lock\n  db  \nrelative = locks/relative\n",
+            Path::new("/workspace"),
+        );
+        assert_eq!(keys.len(), 3);
+        assert_eq!(keys["external"].as_deref(), Some("/tmp/external.lock"));
+        assert_eq!(keys["db"], None);
+        assert_eq!(
+            keys["relative"].as_deref(),
+            Some(Path::new("/workspace/locks/relative").to_str().unwrap())
+        );
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "Expected a path after '=' in SERIAL_TEST_CONFIG line \"external =\""
+    )]
+    fn missing_path() {
+        parse("external =", Path::new(""));
+    }
+}
@@ -0,0 +1,71 @@
+use std::{env, num::NonZeroUsize, thread};
+
+// Same order as libtest: `--test-threads`, then `RUST_TEST_THREADS`, then the number of CPUs
+fn test_threads_from(args: &[String], env_threads: Option<String>) -> Option<usize> {
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--test-threads" {
+            return args.next().and_then(|threads| threads.parse().ok());
+        }
+        if let Some(threads) = arg.strip_prefix("--test-threads=") {
+            return threads.parse().ok();
+        }
+    }
+    if let Some(threads) = env_threads {
+        return threads.parse().ok();
+    }
+    thread::available_parallelism().ok().map(NonZeroUsize::get)
+}
+
+// How many threads the test binary is running tests on, if we can tell
+pub(crate) fn test_threads() -> Option<usize> {
+    let args: Vec<String> = env::args().collect();
+    test_threads_from(&args, env::var("RUST_TEST_THREADS").ok())
+}
+
+/// Panics unless the test binary is running tests one at a time
+#[doc(hidden)]
+pub fn require_single_thread(test: &str) {
+    if let Some(threads) = test_threads() {
+        if threads > 1 {
+            panic!(
+                "{} requires a single test thread, but is running with {}. Use `--test-threads 1` or RUST_TEST_THREADS=1",
+                test, threads
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::test_threads_from;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_threads_arg() {
+        assert_eq!(
+            test_threads_from(&args(&["test", "--test-threads", "1"]), Some("4".into())),
+            Some(1)
+        );
+        assert_eq!(
+            test_threads_from(&args(&["test", "--test-threads=3"]), None),
+            Some(3)
+        );
+    }
+
+    #[test]
+    fn test_threads_env() {
+        assert_eq!(
+            test_threads_from(&args(&["test"]), Some("1".into())),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn test_threads_default() {
+        assert!(test_threads_from(&args(&["test"]), None).is_some());
+    }
+}
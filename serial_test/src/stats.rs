@@ -0,0 +1,209 @@
+use crate::code_lock::global_locks;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    env,
+    ffi::OsString,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// How one key's been used, in [GlobalLockStats]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyStats {
+    /// How many times tests have taken the key, serially or in parallel
+    pub acquisitions: u64,
+    /// The longest any test has waited for the key, in milliseconds
+    pub max_wait_ms: u64,
+}
+
+/// Lock usage for each key, which can be saved and added to by later test runs, to compare contention across CI runs
+///
+/// Within a run, [GlobalLockStats::current] has what the tests so far have done. [save_global_stats] covers the
+/// usual case of adding that to a file, or [load](GlobalLockStats::load), [merge](GlobalLockStats::merge) and
+/// [save](GlobalLockStats::save) can keep them wherever's needed.
+/// ````
+/// use serial_test::{serial, GlobalLockStats};
+///
+/// #[serial(db)]
+/// fn run() {}
+///
+/// run();
+/// run();
+/// let path = std::env::temp_dir().join("serial-test-stats-doc.json");
+/// let _ = std::fs::remove_file(&path);
+/// GlobalLockStats::current().save(&path);
+///
+/// // In a later run
+/// let mut stats = GlobalLockStats::load(&path);
+/// stats.merge(&GlobalLockStats::current());
+/// assert_eq!(stats.keys["db"].acquisitions, 4);
+/// ````
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GlobalLockStats {
+    /// Stats for each key, with `""` being the global key used by attributes without one
+    pub keys: BTreeMap<String, KeyStats>,
+}
+
+impl GlobalLockStats {
+    /// The stats for this test run so far
+    pub fn current() -> GlobalLockStats {
+        let mut keys = BTreeMap::new();
+        global_locks().scan(|name, lock| {
+            keys.insert(
+                name.clone(),
+                KeyStats {
+                    acquisitions: lock.uses().into(),
+                    max_wait_ms: lock.max_wait_ms(),
+                },
+            );
+        });
+        GlobalLockStats { keys }
+    }
+
+    /// Reads stats written by [save](GlobalLockStats::save), or none at all if there's nothing at `path` yet
+    ///
+    /// # Panics
+    /// If the file can't be read, or doesn't have saved stats in.
+    pub fn load(path: &Path) -> GlobalLockStats {
+        match fs::read(path) {
+            Ok(contents) => serde_json::from_slice(&contents)
+                .unwrap_or_else(|err| panic!("{:?} doesn't have lock stats in: {}", path, err)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => GlobalLockStats::default(),
+            Err(err) => panic!("Couldn't read lock stats from {:?}: {}", path, err),
+        }
+    }
+
+    /// Adds `other`'s stats to these, summing the acquisitions and keeping the longer wait for each key
+    pub fn merge(&mut self, other: &GlobalLockStats) {
+        for (name, other) in &other.keys {
+            let stats = self.keys.entry(name.clone()).or_default();
+            stats.acquisitions = stats.acquisitions.saturating_add(other.acquisitions);
+            stats.max_wait_ms = stats.max_wait_ms.max(other.max_wait_ms);
+        }
+    }
+
+    /// Writes these stats to `path` as JSON, replacing anything already there
+    ///
+    /// # Panics
+    /// If the file can't be written.
+    pub fn save(&self, path: &Path) {
+        let contents = serde_json::to_vec_pretty(self).expect("lock stats to be serializable");
+        fs::write(path, contents)
+            .unwrap_or_else(|err| panic!("Couldn't write lock stats to {:?}: {}", path, err));
+    }
+}
+
+// SERIAL_TEST_STATS_PATH if it's set, otherwise somewhere every run can find it
+fn stats_path_from(stats_path: Option<OsString>) -> PathBuf {
+    stats_path
+        .map(PathBuf::from)
+        .unwrap_or_else(|| env::temp_dir().join("serial-test-stats.json"))
+}
+
+/// Where [save_global_stats] keeps the stats: `SERIAL_TEST_STATS_PATH` if that's set, or `serial-test-stats.json`
+/// in [env::temp_dir] otherwise
+pub fn global_stats_path() -> PathBuf {
+    stats_path_from(env::var_os("SERIAL_TEST_STATS_PATH"))
+}
+
+/// Adds this run's stats to those saved at [global_stats_path], and returns the total
+///
+/// Call this once at the end of a run (e.g. from a test that runs after the others), as each call adds everything
+/// from this run so far again. Test binaries that run at the same time (e.g. under nextest) can lose each other's
+/// stats, so give them different `SERIAL_TEST_STATS_PATH`s and [merge](GlobalLockStats::merge) them afterwards.
+pub fn save_global_stats() -> GlobalLockStats {
+    let path = global_stats_path();
+    let mut stats = GlobalLockStats::load(&path);
+    stats.merge(&GlobalLockStats::current());
+    stats.save(&path);
+    stats
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{stats_path_from, GlobalLockStats, KeyStats};
+    use crate::{local_parallel_core, local_serial_core};
+    use std::{env, fs, path::PathBuf, sync::mpsc, thread, time::Duration};
+
+    fn stats(keys: &[(&str, u64, u64)]) -> GlobalLockStats {
+        GlobalLockStats {
+            keys: keys
+                .iter()
+                .map(|(name, acquisitions, max_wait_ms)| {
+                    (
+                        name.to_string(),
+                        KeyStats {
+                            acquisitions: *acquisitions,
+                            max_wait_ms: *max_wait_ms,
+                        },
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn merge_sums_acquisitions_and_keeps_longest_wait() {
+        let mut merged = stats(&[("a", 2, 10), ("b", 1, 5)]);
+        merged.merge(&stats(&[("a", 3, 4), ("c", 1, 0)]));
+        assert_eq!(merged, stats(&[("a", 5, 10), ("b", 1, 5), ("c", 1, 0)]));
+    }
+
+    #[test]
+    fn saved_stats_load_again() {
+        let path = env::temp_dir().join("serial-test-stats-unit.json");
+        let _ = fs::remove_file(&path);
+        assert_eq!(GlobalLockStats::load(&path), GlobalLockStats::default());
+
+        let saved = stats(&[("", 7, 1), ("db", 3, 250)]);
+        saved.save(&path);
+        assert_eq!(GlobalLockStats::load(&path), saved);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "doesn't have lock stats in")]
+    fn load_rejects_other_files() {
+        let path = env::temp_dir().join("serial-test-stats-not-json.json");
+        fs::write(&path, "not stats").unwrap();
+        GlobalLockStats::load(&path);
+    }
+
+    #[test]
+    fn current_counts_both_kinds() {
+        local_serial_core(vec!["stats_current"], None, || {});
+        local_parallel_core(vec!["stats_current"], None, || {});
+        assert_eq!(
+            GlobalLockStats::current().keys["stats_current"].acquisitions,
+            2
+        );
+    }
+
+    #[test]
+    fn longest_wait_is_kept() {
+        let (holding, held) = mpsc::channel();
+        let holder = thread::spawn(move || {
+            local_serial_core(vec!["stats_wait"], None, || {
+                holding.send(()).unwrap();
+                thread::sleep(Duration::from_millis(100));
+            })
+        });
+        held.recv().unwrap();
+        local_parallel_core(vec!["stats_wait"], None, || {});
+        holder.join().unwrap();
+        assert!(GlobalLockStats::current().keys["stats_wait"].max_wait_ms >= 50);
+    }
+
+    #[test]
+    fn stats_path_defaults_to_temp_dir() {
+        assert_eq!(
+            stats_path_from(None),
+            env::temp_dir().join("serial-test-stats.json")
+        );
+        assert_eq!(
+            stats_path_from(Some("/ci/stats.json".into())),
+            PathBuf::from("/ci/stats.json")
+        );
+    }
+}
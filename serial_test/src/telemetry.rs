@@ -0,0 +1,19 @@
+use tracing::Span;
+
+/// Span wrapped around a test by the `tracing` feature. The `otel.name` field is what
+/// tracing-opentelemetry uses as the exported span name, so `telemetry_key` ends up there.
+#[doc(hidden)]
+pub fn telemetry_span(name: &str) -> Span {
+    tracing::info_span!("serial_test", otel.name = name)
+}
+
+#[cfg(feature = "async")]
+#[doc(hidden)]
+pub fn telemetry_instrument<F>(name: &str, fut: F) -> tracing::instrument::Instrumented<F>
+where
+    F: std::future::Future,
+{
+    use tracing::Instrument;
+
+    fut.instrument(telemetry_span(name))
+}
@@ -0,0 +1,47 @@
+#![cfg(feature = "logging")]
+
+use log::{LevelFilter, Log, Metadata, Record};
+use serial_test::{current_mode, local_parallel_core, local_serial_core, Mode};
+use std::{env, sync::Mutex};
+
+static PLANS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+struct PlanLogger;
+
+impl Log for PlanLogger {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        let message = record.args().to_string();
+        if message.contains(" would ") {
+            PLANS.lock().unwrap().push(message);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+// In its own binary, as the environment's only looked at the first time a test locks, and it needs its own logger
+#[test]
+fn test_dry_run_logs_without_locking() {
+    log::set_logger(&PlanLogger).unwrap();
+    log::set_max_level(LevelFilter::Info);
+    env::set_var("SERIAL_TEST_DRY_RUN", "1");
+
+    local_serial_core(vec!["dry_run"], None, || {
+        assert_eq!(current_mode(Some("dry_run")), Mode::Unlocked);
+    });
+    local_parallel_core(vec!["dry_run", "dry_run_other"], None, || {
+        assert_eq!(current_mode(Some("dry_run")), Mode::Unlocked);
+    });
+
+    assert_eq!(
+        *PLANS.lock().unwrap(),
+        vec![
+            "test_dry_run_logs_without_locking would acquire serial key(s) [\"dry_run\"]",
+            "test_dry_run_logs_without_locking would start parallel on [\"dry_run\", \"dry_run_other\"]",
+        ]
+    );
+}
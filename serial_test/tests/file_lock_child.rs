@@ -0,0 +1,53 @@
+#![cfg(feature = "file_locks")]
+
+use serial_test::with_file_serial;
+use std::{
+    env, fs,
+    path::PathBuf,
+    process::{Command, Stdio},
+    thread,
+    time::Duration,
+};
+
+const LOCK_PATH_ENV: &str = "SERIAL_TEST_CHILD_LOCK_PATH";
+
+fn marker_path() -> PathBuf {
+    let mut pathbuf = env::temp_dir();
+    pathbuf.push("serial-test-child-marker");
+    pathbuf
+}
+
+// Does nothing unless run as a child of test_child_process_shares_lock
+#[test]
+fn lock_child() {
+    let path = match env::var(LOCK_PATH_ENV) {
+        Ok(path) => path,
+        Err(_) => return,
+    };
+    with_file_serial(["child"], Some(path.as_str()), || {
+        fs::write(marker_path(), "child").unwrap();
+    });
+}
+
+#[test]
+fn test_child_process_shares_lock() {
+    let mut lock_path = env::temp_dir();
+    lock_path.push("serial-test-child-lock");
+    let lock_path = lock_path.into_os_string().into_string().unwrap();
+    let _ = fs::remove_file(marker_path());
+
+    let child = with_file_serial(["child"], Some(lock_path.as_str()), || {
+        let child = Command::new(env::current_exe().unwrap())
+            .args(["lock_child", "--exact", "--test-threads", "1"])
+            .env(LOCK_PATH_ENV, &lock_path)
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap();
+        // The child can't get the lock until we let go
+        thread::sleep(Duration::from_millis(500));
+        assert!(!marker_path().exists());
+        child
+    });
+    assert!(child.wait_with_output().unwrap().status.success());
+    assert_eq!(fs::read_to_string(marker_path()).unwrap(), "child");
+}
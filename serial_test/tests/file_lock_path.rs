@@ -0,0 +1,26 @@
+#![cfg(feature = "file_locks")]
+
+use serial_test::{fs_serial_core, set_global_file_lock_path, FileSerialOptions};
+use std::{env, fs, panic};
+
+#[test]
+fn test_custom_file_lock_path() {
+    let mut lock_dir = env::temp_dir();
+    lock_dir.push("serial-test-custom-lock-path");
+    fs::create_dir_all(&lock_dir).unwrap();
+    set_global_file_lock_path(lock_dir.clone());
+
+    fs_serial_core(
+        vec!["custom_path"],
+        None,
+        FileSerialOptions {
+            holder: "test_custom_file_lock_path",
+            ..Default::default()
+        },
+        || {},
+    );
+    assert!(lock_dir.join("serial-test-custom_path").exists());
+
+    // Too late to move it now
+    assert!(panic::catch_unwind(|| set_global_file_lock_path(env::temp_dir())).is_err());
+}
@@ -0,0 +1,116 @@
+#![cfg(feature = "file_locks")]
+
+use serial_test::{fs_parallel_core, fs_serial_core, FileSerialOptions, LockType};
+use std::{
+    env, fs,
+    path::PathBuf,
+    process::{self, Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+const CHILD_ENV: &str = "SERIAL_TEST_READERS_DIR";
+const READERS: usize = 3;
+
+fn lock_path(dir: &str) -> String {
+    format!("{}-lock", dir)
+}
+
+fn markers(dir: &str, prefix: &str) -> usize {
+    fs::read_dir(dir)
+        .unwrap()
+        .filter(|entry| {
+            entry
+                .as_ref()
+                .unwrap()
+                .file_name()
+                .to_string_lossy()
+                .starts_with(prefix)
+        })
+        .count()
+}
+
+fn reader() {
+    let dir = env::var(CHILD_ENV).unwrap();
+    let inside = format!("{}/inside-{}", dir, process::id());
+    fs::write(&inside, "").unwrap();
+    // Arrivals are never removed, so everyone sees the last reader arrive, even if others have left by then
+    fs::write(format!("{}/arrived-{}", dir, process::id()), "").unwrap();
+    // All the readers have to get in together, or this times out
+    let deadline = Instant::now() + Duration::from_secs(30);
+    while markers(&dir, "arrived-") < READERS {
+        assert!(Instant::now() < deadline, "Readers weren't concurrent");
+        assert_eq!(markers(&dir, "writer"), 0);
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(markers(&dir, "writer"), 0);
+    fs::remove_file(&inside).unwrap();
+}
+
+fn writer() {
+    let dir = env::var(CHILD_ENV).unwrap();
+    assert_eq!(markers(&dir, "inside-"), 0);
+    let marker = format!("{}/writer", dir);
+    fs::write(&marker, "").unwrap();
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(markers(&dir, "inside-"), 0);
+    fs::remove_file(&marker).unwrap();
+}
+
+// Does nothing unless run as a child of test_readers_exclude_writer
+#[test]
+fn readers_child() {
+    if let Ok(dir) = env::var(CHILD_ENV) {
+        fs_parallel_core(
+            vec!["readers"],
+            Some(lock_path(&dir).as_str()),
+            None,
+            LockType::Flock,
+            reader,
+        );
+    }
+}
+
+// Does nothing unless run as a child of test_readers_exclude_writer
+#[test]
+fn writer_child() {
+    if let Ok(dir) = env::var(CHILD_ENV) {
+        fs_serial_core(
+            vec!["readers"],
+            Some(lock_path(&dir).as_str()),
+            FileSerialOptions {
+                holder: "writer_child",
+                ..Default::default()
+            },
+            writer,
+        );
+    }
+}
+
+#[test]
+fn test_readers_exclude_writer() {
+    let mut dir = env::temp_dir();
+    dir.push(format!("serial-test-readers-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let dir = dir.into_os_string().into_string().unwrap();
+
+    let spawn = |test: &str| {
+        Command::new(env::current_exe().unwrap())
+            .args([test, "--exact", "--test-threads", "1"])
+            .env(CHILD_ENV, &dir)
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap()
+    };
+    let mut children = (0..READERS)
+        .map(|_| spawn("readers_child"))
+        .collect::<Vec<_>>();
+    children.push(spawn("writer_child"));
+    for mut child in children {
+        assert!(child.wait().unwrap().success());
+    }
+    assert_eq!(markers(&dir, "inside-"), 0);
+    assert_eq!(markers(&dir, "writer"), 0);
+    fs::remove_dir_all(PathBuf::from(&dir)).unwrap();
+}
@@ -0,0 +1,77 @@
+#![cfg(feature = "file_locks")]
+
+use serial_test::{fs_parallel_core, fs_serial_core, FileSerialOptions, LockType};
+use std::{
+    env,
+    path::PathBuf,
+    process::{Command, Stdio},
+};
+
+const CHILD_ENV: &str = "SERIAL_TEST_STRESS_CHILD";
+
+fn stress_lock_path() -> PathBuf {
+    let mut pathbuf = env::temp_dir();
+    pathbuf.push("serial-test-multi-process-stress");
+    pathbuf
+}
+
+// Does nothing unless run as a child of test_multi_process_stress
+#[test]
+fn stress_child() {
+    if env::var(CHILD_ENV).is_err() {
+        return;
+    }
+    let path = stress_lock_path().into_os_string().into_string().unwrap();
+    for i in 0..10 {
+        if i % 5 == 0 {
+            fs_serial_core(
+                vec!["stress"],
+                Some(path.as_str()),
+                FileSerialOptions {
+                    holder: "stress_child",
+                    ..Default::default()
+                },
+                || {},
+            );
+        } else {
+            // Underflowing the count panics, which fails the child
+            fs_parallel_core(
+                vec!["stress"],
+                Some(path.as_str()),
+                None,
+                LockType::Flock,
+                || {},
+            );
+        }
+    }
+}
+
+#[test]
+fn test_multi_process_stress() {
+    let children = (0..4)
+        .map(|_| {
+            Command::new(env::current_exe().unwrap())
+                .args(["stress_child", "--exact", "--test-threads", "1"])
+                .env(CHILD_ENV, "1")
+                .stdout(Stdio::null())
+                .spawn()
+                .unwrap()
+        })
+        .collect::<Vec<_>>();
+    for mut child in children {
+        assert!(child.wait().unwrap().success());
+    }
+
+    // With shared locks, there's no count file to check
+    #[cfg(not(feature = "shared_file_locks"))]
+    {
+        use std::{convert::TryInto, fs};
+
+        let mut count_path = stress_lock_path().into_os_string();
+        count_path.push("-count");
+        let count = fs::read(count_path)
+            .map(|bytes| u32::from_ne_bytes(bytes.as_slice().try_into().unwrap()))
+            .unwrap();
+        assert_eq!(count, 0);
+    }
+}
@@ -0,0 +1,41 @@
+use serial_test::serial;
+use std::{
+    env,
+    process::{Command, Stdio},
+    thread,
+    time::Duration,
+};
+
+const CHILD_ENV: &str = "SERIAL_TEST_ABORT_CHILD";
+
+// Does nothing unless run as a child of test_max_hold_aborts_hung_test
+#[test]
+fn abort_child() {
+    if env::var(CHILD_ENV).is_err() {
+        return;
+    }
+    // Caught like any other panic, if this weren't aborting
+    let _ = std::panic::catch_unwind(hangs);
+}
+
+#[serial(abort_child, max_hold = 100)]
+fn hangs() {
+    thread::sleep(Duration::from_secs(60));
+}
+
+#[test]
+fn test_max_hold_aborts_hung_test() {
+    let status = Command::new(env::current_exe().unwrap())
+        .args(["abort_child", "--exact", "--test-threads", "1"])
+        .env(CHILD_ENV, "1")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .unwrap();
+    assert!(!status.success());
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        assert_eq!(status.signal(), Some(libc::SIGABRT));
+    }
+}
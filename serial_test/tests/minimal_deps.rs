@@ -0,0 +1,57 @@
+//! Without the `logging`/`test_logging` features, neither `log` nor `env_logger` should be compiled in
+
+use std::process::Command;
+
+fn normal_dependencies(features: &str) -> Vec<String> {
+    let output = Command::new(env!("CARGO"))
+        .args([
+            "tree",
+            "--package",
+            "serial_test",
+            "--no-default-features",
+            "--features",
+            features,
+            "--edges",
+            "normal",
+            "--prefix",
+            "none",
+        ])
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "cargo tree failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .filter_map(|line| line.split(' ').next())
+        .map(String::from)
+        .collect()
+}
+
+#[test]
+fn no_logging_dependencies() {
+    for features in ["", "async", "async,file_locks,tracing"] {
+        let dependencies = normal_dependencies(features);
+        assert!(dependencies.contains(&"serial_test".to_string()));
+        for logging_dependency in ["log", "env_logger"] {
+            assert!(
+                !dependencies.contains(&logging_dependency.to_string()),
+                "{} is a dependency with features '{}'",
+                logging_dependency,
+                features
+            );
+        }
+    }
+}
+
+#[test]
+fn logging_dependencies_with_logging() {
+    // Make sure the check above would actually spot them
+    let dependencies = normal_dependencies("test_logging");
+    assert!(dependencies.contains(&"log".to_string()));
+    assert!(dependencies.contains(&"env_logger".to_string()));
+}
@@ -0,0 +1,20 @@
+//! Only one test in here, so nothing else is holding keys when the ids are reset
+
+use serial_test::{next_mutex_id, reset_mutex_ids, with_serial};
+
+#[test]
+fn ids_restart_after_reset() {
+    with_serial(["mutex_ids_a", "mutex_ids_b"], || {
+        assert!(!reset_mutex_ids());
+    });
+    assert!(next_mutex_id() > 1);
+
+    assert!(reset_mutex_ids());
+    assert_eq!(next_mutex_id(), 1);
+    with_serial(["mutex_ids_c"], || {});
+    assert_eq!(next_mutex_id(), 2);
+
+    // Keys that already have ids don't use up any more
+    with_serial(["mutex_ids_a"], || {});
+    assert_eq!(next_mutex_id(), 2);
+}
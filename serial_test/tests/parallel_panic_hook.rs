@@ -0,0 +1,72 @@
+//! Only one test in here, as the hook applies to every parallel test in the process
+
+use serial_test::{
+    clear_parallel_panic_hook, current_mode, local_parallel_core, set_parallel_panic_hook, Mode,
+};
+use std::{panic, sync::Mutex};
+
+static SEEN: Mutex<Vec<(Vec<String>, String, Mode)>> = Mutex::new(Vec::new());
+
+fn message(payload: &(dyn std::any::Any + Send)) -> String {
+    payload
+        .downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap()
+}
+
+fn panics_with(run: impl FnOnce() + panic::UnwindSafe) -> String {
+    message(&*panic::catch_unwind(run).unwrap_err())
+}
+
+#[test]
+fn hook_sees_keys_and_payload() {
+    set_parallel_panic_hook(|keys, payload| {
+        SEEN.lock().unwrap().push((
+            keys.iter().map(|key| key.to_string()).collect(),
+            message(&*payload),
+            current_mode(Some(keys[0])),
+        ));
+        Box::new(format!(
+            "{} (holding {})",
+            message(&*payload),
+            keys.join(", ")
+        ))
+    });
+
+    assert_eq!(
+        panics_with(|| local_parallel_core(vec!["hook_a", "hook_b"], None, || panic!("Broken"))),
+        "Broken (holding hook_a, hook_b)"
+    );
+    // The keys have already been let go by the time the hook sees the panic
+    assert_eq!(
+        SEEN.lock().unwrap().pop().unwrap(),
+        (
+            vec!["hook_a".to_string(), "hook_b".to_string()],
+            "Broken".to_string(),
+            Mode::Unlocked
+        )
+    );
+
+    #[cfg(feature = "async")]
+    {
+        assert_eq!(
+            panics_with(
+                || futures::executor::block_on(serial_test::local_async_parallel_core(
+                    vec!["hook_async"],
+                    None,
+                    async { panic!("Broken async") }
+                ))
+            ),
+            "Broken async (holding hook_async)"
+        );
+        assert_eq!(SEEN.lock().unwrap().pop().unwrap().1, "Broken async");
+    }
+
+    clear_parallel_panic_hook();
+    assert_eq!(
+        panics_with(|| local_parallel_core(vec!["hook_a"], None, || panic!("Broken"))),
+        "Broken"
+    );
+    assert!(SEEN.lock().unwrap().is_empty());
+}
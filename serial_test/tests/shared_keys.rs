@@ -0,0 +1,65 @@
+#![cfg(feature = "file_locks")]
+
+use serial_test::serial;
+use std::{
+    env, fs,
+    path::PathBuf,
+    process::{Command, Stdio},
+    thread,
+    time::Duration,
+};
+
+const CONFIG_ENV: &str = "SERIAL_TEST_CONFIG";
+
+fn test_dir() -> PathBuf {
+    let mut pathbuf = env::temp_dir();
+    pathbuf.push("serial-test-shared-keys");
+    pathbuf
+}
+
+// Plain #[serial], so only SERIAL_TEST_CONFIG stops the two processes running this together.
+// Does nothing unless run as a child of test_shared_key_across_processes
+#[test]
+#[serial(shared_external)]
+fn shared_key_child() {
+    if env::var_os(CONFIG_ENV).is_none() {
+        return;
+    }
+    let inside = test_dir().join("inside");
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&inside)
+        .expect("other process is running shared_key_child at the same time");
+    thread::sleep(Duration::from_millis(500));
+    fs::remove_file(&inside).unwrap();
+}
+
+#[test]
+fn test_shared_key_across_processes() {
+    let dir = test_dir();
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let config = dir.join("serial-test.conf");
+    fs::write(
+        &config,
+        "# Shared with the other process\nshared_external = shared_external.lock\n",
+    )
+    .unwrap();
+
+    let children: Vec<_> = (0..2)
+        .map(|_| {
+            Command::new(env::current_exe().unwrap())
+                .args(["shared_key_child", "--exact", "--test-threads", "1"])
+                .env(CONFIG_ENV, &config)
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .spawn()
+                .unwrap()
+        })
+        .collect();
+    for child in children {
+        assert!(child.wait_with_output().unwrap().status.success());
+    }
+    assert!(dir.join("shared_external.lock").exists());
+}
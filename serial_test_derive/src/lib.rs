@@ -7,9 +7,9 @@
 extern crate proc_macro;
 
 use proc_macro::TokenStream;
-use proc_macro2::{Literal, TokenTree};
+use proc_macro2::{Delimiter, Literal, TokenTree};
 use quote::{format_ident, quote, ToTokens, TokenStreamExt};
-use std::ops::Deref;
+use std::{cell::RefCell, collections::HashMap, ops::Deref};
 use syn::Result as SynResult;
 
 /// Allows for the creation of serialised Rust tests
@@ -68,7 +68,273 @@ use syn::Result as SynResult;
 /// `test_serial_one` and `test_serial_another` will be executed in serial, as will `test_serial_third` and `test_serial_fourth`
 /// but neither sequence will be blocked by the other. `test_serial_fifth` is blocked by tests in either sequence.
 ///
+/// Methods taking `self`, `&self` or `&mut self` work too, for test frameworks that register test structs
+/// (e.g. via `inventory`) rather than using `#[test]`. Any other arguments are dropped, as for plain functions.
+///
 /// Nested serialised tests (i.e. a [serial](macro@serial) tagged test calling another) are supported.
+/// If nesting takes two keys in opposite orders in different places (e.g. `a` then `b` in one test, `b` then `a`
+/// in another), that could deadlock, so the second one panics describing the inversion rather than risking a hang.
+///
+/// Runners that put each test in its own process (e.g. [nextest](https://nexte.st/)) stop in-process locks seeing
+/// each other at all. With the `file_locks` feature, [serial](macro@serial) and [parallel](macro@parallel) notice
+/// when they're run that way (via `NEXTEST_TEST_NAME` or `NEXTEST_EXECUTION_MODE=process-per-test`) and take the
+/// same lock files as [file_serial](macro@file_serial)/[file_parallel](macro@file_parallel) as well.
+///
+/// The same goes for a workspace with several test binaries (e.g. `tests/*.rs`, or multiple crates) that all need
+/// to take turns with something outside the process, like an external service. Rather than remembering to use
+/// [file_serial](macro@file_serial) with the same path in every one, list the keys in a file and point the
+/// `SERIAL_TEST_CONFIG` environment variable at it (e.g. from `[env]` in `.cargo/config.toml`, with
+/// `relative = true`). With the `file_locks` feature, [serial](macro@serial) and [parallel](macro@parallel) tests
+/// using those keys then take the file locks as well. Each line is a key, optionally with the lock file to use
+/// (relative paths are from the config file's directory), and `#` starts a comment.
+/// ````text
+/// # Everything talking to the staging API, across all the test binaries
+/// external = target/external.lock
+/// database
+/// ````
+///
+/// Keys aren't registered up front: each lock is created the first time a test using that key runs, so keys for
+/// tests that are filtered out or never run cost nothing.
+///
+/// Note that all tests without a key share a single global key ([DEFAULT_KEY](../serial_test/constant.DEFAULT_KEY.html)),
+/// including any [parallel](macro@parallel) ones without a key. A crate can give its keyless tests their own key
+/// instead, by setting `SERIAL_TEST_DEFAULT_KEY` when it's compiled, e.g. from its `build.rs` with
+/// `println!("cargo:rustc-env=SERIAL_TEST_DEFAULT_KEY=my_crate");`. Functions taking `None` for the key, like
+/// [is_locked_serially](../serial_test/fn.is_locked_serially.html), still mean `DEFAULT_KEY`, so pass that key
+/// explicitly instead. If you want to make sure that never happens by accident, add `strict`, which makes a missing
+/// key a compile error. `strict` works for all the attributes, and isn't itself treated as a key.
+/// ````no_run
+/// #[test]
+/// #[serial(strict, something)]
+/// fn test_serial_strict() {
+///   // Do things
+/// }
+/// ````
+///
+/// With the `tracing` feature, each test runs inside a span named `serial_lock.<keys>` (`parallel_lock.<keys>`
+/// for [parallel](macro@parallel)). Set `telemetry_key` to pick the name instead, e.g. to follow your
+/// OpenTelemetry naming conventions. It's exported via the `otel.name` field.
+/// ````ignore
+/// #[test]
+/// #[serial(db, telemetry_key = "test.db.serial")]
+/// fn test_serial_telemetry() {
+///   // Do things
+/// }
+/// ````
+///
+/// To run your own code around each test, e.g. to set up a span, a timer or an error-reporting scope,
+/// use `context` with a function that takes the keys and returns a guard. The guard is created after the
+/// lock is taken and dropped before it's released, including when the test panics, and is held across
+/// `.await`s in async tests.
+/// ````no_run
+/// struct Timer;
+///
+/// fn timed(_keys: &[&str]) -> Timer {
+///   Timer
+/// }
+///
+/// #[test]
+/// #[serial(db, context = timed)]
+/// fn test_serial_with_context() {
+///   // Do things
+/// }
+/// ````
+///
+/// To hand the test a value instead, e.g. a connection pool for the group of tests, use `with_context` with a
+/// function that takes nothing. It's called once the lock is taken, and the test can get what it returned with
+/// [context](../serial_test/fn.context.html) until it finishes.
+/// ````no_run
+/// # struct Pool;
+/// fn make_pool() -> Pool {
+///   Pool
+/// }
+///
+/// #[test]
+/// #[serial(db, with_context = "make_pool")]
+/// fn test_serial_with_pool() {
+///   let pool = serial_test::context::<Pool>();
+///   // Do things with the pool
+/// }
+/// ````
+///
+/// For diagnosing hangs, `max_hold` aborts the whole test process if the test's held the lock for more than that
+/// many milliseconds. There's no safe way to stop the test part way through, or to take the lock off it while it's
+/// still running, so this is the only way to stop a hung test holding up everything else using its keys. CI sees it
+/// die from a signal straight away, with a message on stderr saying which test it was, at the cost of the rest of
+/// that run's results. It can't be used on async tests.
+/// ````no_run
+/// #[test]
+/// #[serial(db, max_hold = 30000)]
+/// fn test_serial_might_deadlock() {
+///   // Do things
+/// }
+/// ````
+///
+/// If a key should only ever be used serially, `deny_parallel_siblings = true` makes any [parallel](macro@parallel)
+/// test with the same key a compile error. This is checked across everything in the crate being compiled, not just
+/// the current `mod`, as that's where [parallel](macro@parallel) tests can clash with it.
+/// ````no_run
+/// #[test]
+/// #[serial(db, deny_parallel_siblings = true)]
+/// fn test_serial_only() {
+///   // Do things
+/// }
+/// ````
+///
+/// A [serial](macro@serial) test can also be made to wait for [parallel](macro@parallel) tests using another key,
+/// and keep them out while it runs, with `exclusive_with`. This holds that key serially as well, so it also
+/// waits for [serial](macro@serial) tests with that key.
+/// ````no_run
+/// #[test]
+/// #[serial(db, exclusive_with = "cache")]
+/// fn test_serial_db_without_cache_readers() {
+///   // Do things
+/// }
+/// ````
+///
+/// When the resource is only known at runtime (e.g. one of several tenants), add a `discriminator` expression.
+/// It's evaluated before taking the lock, and each key becomes `<key>::<discriminator>`, so tests for the
+/// same tenant run in serial, but different tenants don't block each other.
+/// ````no_run
+/// # fn current_tenant() -> u32 { 1 }
+/// #[test]
+/// #[serial(tenant, discriminator = current_tenant())]
+/// fn test_serial_tenant() {
+///   // Do things
+/// }
+/// ````
+///
+/// For parameterised tests (e.g. with `test_case`), `group_by` works out the whole key at runtime instead, from
+/// any expression that can be turned into a string. Cases that end up with the same key run in serial, and as it
+/// gives the key, it can't be used along with other keys.
+/// ````no_run
+/// #[test]
+/// #[serial(group_by = format!("group_{}", param % 3))]
+/// fn test_serial_grouped(param: u32) {
+///   // Do things
+/// }
+/// ````
+///
+/// Keys that are already defined as `&str` constants can be used as they are with `const_key`, which can be given
+/// more than once, and along with ordinary keys.
+/// ````no_run
+/// pub const DB_LOCK: &str = "database";
+///
+/// #[test]
+/// #[serial(const_key = DB_LOCK)]
+/// fn test_serial_const_key() {
+///   // Do things
+/// }
+/// ````
+///
+/// The keys can also come from an environment variable when the test runs, e.g. to vary them across a CI matrix.
+/// `keys_from_env` takes the variable's name, and its value is a comma-separated list of keys. If it's unset or
+/// empty, the test uses the global key, as if it had no keys at all.
+/// ````no_run
+/// #[test]
+/// #[serial(keys_from_env = "SERIAL_TEST_KEYS")]
+/// fn test_serial_keys_from_env() {
+///   // Do things
+/// }
+/// ````
+///
+/// Some global state (e.g. the current directory) can be touched by code that isn't marked as [serial](macro@serial) at all.
+/// For tests that can only be trusted when running one at a time, `require_single_thread` makes them panic straight
+/// away if the test binary is using more than one test thread (via `--test-threads` or `RUST_TEST_THREADS`).
+/// ````no_run
+/// #[test]
+/// #[serial(require_single_thread)]
+/// fn test_serial_changes_directory() {
+///   // Do things
+/// }
+/// ````
+///
+/// Without a key, `scope` picks the key from where the test is, rather than everything sharing the one
+/// global key. `scope = "module"` uses the module path, so tests only wait for others in the same module,
+/// `scope = "function"` gives each test its own key, and `scope = "crate"` uses the package name.
+/// `scope` works for [serial](macro@serial) and [parallel](macro@parallel).
+/// ````no_run
+/// #[test]
+/// #[serial(scope = "module")]
+/// fn test_serial_in_this_module() {
+///   // Do things
+/// }
+/// ````
+///
+/// Keys can be given a description with `desc`, which is shown alongside the key in debug logging and
+/// available from [key_description](../serial_test/fn.key_description.html).
+/// ````no_run
+/// #[test]
+/// #[serial(db, desc = "PostgreSQL test DB")]
+/// fn test_serial_described() {
+///   // Do things
+/// }
+/// ````
+///
+/// Tests sharing a file (e.g. [insta](https://docs.rs/insta) tests asserting against the same named snapshot) can
+/// share a key, so only one at a time writes it. Anything to do before or after the test while it's still got the
+/// key (e.g. insta's `Settings::bind`) can go in the test body, which is all run under the key.
+/// ````no_run
+/// #[test]
+/// #[serial(shared_snapshot)]
+/// fn test_serial_snapshot() {
+///   // insta::assert_snapshot!("shared", ...);
+/// }
+/// ````
+///
+/// Nested serialised tests can re-enter a key they already hold, which can hide accidental recursion into
+/// serialised code. `non_reentrant` makes the test panic instead if its thread already holds any of its keys.
+/// ````no_run
+/// #[test]
+/// #[serial(db, non_reentrant)]
+/// fn test_serial_not_nested() {
+///   // Do things
+/// }
+/// ````
+///
+/// With the `measure_overhead` feature, adding `measure_overhead` prints how long the test waited for its lock,
+/// separately from how long the test itself took, e.g. `serial_test_overhead key=db wait_ms=123 test=test_serial_db`.
+/// ````ignore
+/// #[test]
+/// #[serial(db, measure_overhead)]
+/// fn test_serial_db() {
+///   // Do things
+/// }
+/// ````
+///
+/// To group these under a suite name for CI reports (e.g. JUnit XML or TestRail suites) rather than by key, add
+/// `report_as`, which adds it to the line, e.g. `serial_test_overhead key=db wait_ms=123 test=test_serial_db
+/// suite="Database tests"`.
+/// ````ignore
+/// #[test]
+/// #[serial(db, measure_overhead, report_as = "Database tests")]
+/// fn test_serial_db() {
+///   // Do things
+/// }
+/// ````
+///
+/// Async tests normally block their thread while waiting for their keys, and a task holding a key re-enters it
+/// freely from any other task on the same thread. With the `tokio_serial` feature, they wait by yielding to the
+/// executor instead, and only one of these async serial tests at a time holds each key, so tasks on a
+/// single-threaded runtime can wait for each other (e.g. two serial futures in one `tokio::join!`). This isn't
+/// re-entrant between async tests, so an async serial test awaiting another with the same key will hang. It's only
+/// between the async tests though, as the keys are still re-entrant per thread otherwise. Sync code taking the key
+/// (e.g. a sync [serial](macro@serial) function, or [with_serial](../serial_test/fn.with_serial.html)) from another
+/// task on the same thread gets straight in, even while one of these holds it.
+/// ````ignore
+/// #[serial(db)]
+/// async fn migrate() {
+///   // Do things asynchronously
+/// }
+///
+/// #[tokio::test]
+/// async fn test_migrate_twice() {
+///   tokio::join!(migrate(), migrate());
+/// }
+/// ````
+///
+/// Waiting for keys always uses real time, not the runtime's clock, so they work as usual with
+/// `#[tokio::test(start_paused = true)]`, and a paused test doesn't wait any less for a key someone else has.
 #[proc_macro_attribute]
 pub fn serial(attr: TokenStream, input: TokenStream) -> TokenStream {
     local_serial_core(attr.into(), input.into()).into()
@@ -100,6 +366,37 @@ pub fn serial(attr: TokenStream, input: TokenStream) -> TokenStream {
 /// at the same time as either of them. [parallel](macro@parallel) also takes key arguments for groups
 /// of tests as per [serial](macro@serial).
 ///
+/// Which of a [serial](macro@serial) and a [parallel](macro@parallel) test goes first when they're both waiting
+/// on the same key isn't fixed. However, once a [serial](macro@serial) test is waiting for the current
+/// [parallel](macro@parallel) ones to finish, new [parallel](macro@parallel) tests wait behind it for up to a second,
+/// rather than joining in and keeping it waiting indefinitely. Code that's already running in parallel with the key
+/// on the same thread (e.g. a helper taking the key via `parallel_lock`) doesn't wait.
+///
+/// When lots of parallel tests with a key all start at once, `defer_start_ms` staggers them, with each one
+/// sleeping for that many milliseconds times the number of `defer_start_ms` tests that have already started on
+/// its keys in this process, before it asks for the lock. The first one doesn't wait, but note that the delay
+/// keeps growing with every test that starts, so keep it small. It blocks the thread, even for async tests.
+/// ````no_run
+/// #[test]
+/// #[parallel(db, defer_start_ms = 5)]
+/// fn test_parallel_staggered() {
+///   // Do things
+/// }
+/// ````
+///
+/// A hung parallel test keeps the key's serial tests from ever running, as the parallel count never drops. For async
+/// tests, `max_hold` fails the test if it's held the lock for more than that many milliseconds, dropping its future
+/// so the key's let go of, and nothing's left running. Adding `abort_on_timeout` aborts the whole test process
+/// instead, as `max_hold` always does on [serial](macro@serial) tests. Sync bodies can't be stopped like that, so
+/// aren't supported.
+/// ````ignore
+/// #[tokio::test]
+/// #[parallel(db, max_hold = 10000)]
+/// async fn test_parallel_might_hang() {
+///   // Do things
+/// }
+/// ````
+///
 /// Note that this has zero effect on [file_serial](macro@file_serial) tests, as that uses a different
 /// serialisation mechanism. For that, you want [file_parallel](macro@file_parallel).
 #[proc_macro_attribute]
@@ -143,9 +440,90 @@ pub fn parallel(attr: TokenStream, input: TokenStream) -> TokenStream {
 /// }
 /// ````
 /// The path defaults to a reasonable temp directory for the OS if not specified. If the `path` is specified, you can only use one key.
+/// For multiple keys, use `paths` instead to give one path per key, in the same order as the keys. Relative paths are
+/// from the directory of the crate's `Cargo.toml` (as given by `CARGO_MANIFEST_DIR` when the tests run), not wherever
+/// the tests are run from. Without that, they're from the current directory the first time one's used.
+/// ````no_run
+/// #[test]
+/// #[file_serial(key_a, key_b, paths => ["/tmp/a", "/tmp/b"])]
+/// fn test_serial_third() {
+///   // Do things
+/// }
+/// ````
+///
+/// As with [serial](macro@serial), it can go on a `mod` to apply to every test in it, including the `path`.
+/// ````ignore
+/// #[file_serial(group, path => "/tmp/group")]
+/// mod group_tests {
+///   #[test]
+///   fn uses_the_group_path() {}
+/// }
+/// ````
+///
+/// Without a path, the lock file for a key is `serial-test-<key>` in [env::temp_dir](std::env::temp_dir) (or
+/// wherever [set_global_file_lock_path](../serial_test/fn.set_global_file_lock_path.html) says). The lock is an
+/// exclusive `flock(2)` on that file on Unix (`LockFileEx` on Windows), so things other than Rust tests can take it
+/// too, e.g. `flock /tmp/serial-test-key ./some-script.sh` won't run alongside `#[file_serial(key)]` tests. That
+/// doesn't wait for [file_parallel](macro@file_parallel) tests that are already running, unless the
+/// `shared_file_locks` feature is on, where they hold a shared `flock` for as long as they run.
+///
+/// If you know a run only has one test process using the lock files, and it runs tests one at a time
+/// (`--test-threads 1` or `RUST_TEST_THREADS=1`), setting `SERIAL_TEST_SINGLE_PROCESS=1` as well makes
+/// [file_serial](macro@file_serial) use the in-memory lock as per [serial](macro@serial), rather than the lock
+/// files. That saves opening and locking them for every test, but nothing stops another process using them at
+/// the same time, including any [file_parallel](macro@file_parallel) tests in other processes, so only set it
+/// when that can't happen. Async tests still use the lock files, as the in-memory lock can't be held across
+/// `.await`s on a multi-threaded runtime.
+///
+/// While a test holds the lock, its PID and name are written to `<lock path>-holder`, and any other test
+/// waiting on that lock logs who it's waiting for (with the `logging` feature).
+///
+/// If a process dies while running a [file_parallel](macro@file_parallel) test, the count of parallel users it
+/// leaves behind would stop [file_serial](macro@file_serial) tests from ever running. `expiry_ms` treats that count
+/// as stale once nothing's started or finished a parallel test for that long, so set it to comfortably more than
+/// your longest [file_parallel](macro@file_parallel) test. With the `shared_file_locks` feature the OS releases the
+/// locks of dead processes, so this isn't needed and does nothing.
+/// ````no_run
+/// #[test]
+/// #[file_serial(key, expiry_ms = 600000)]
+/// fn test_serial_with_expiry() {
+///   // Do things
+/// }
+/// ````
+///
+/// A [file_serial](macro@file_serial) test waiting for [file_parallel](macro@file_parallel) ones to finish checks
+/// every second. With the `notify_watcher` feature, `watch_path` has it watch the lock's files instead, so it starts
+/// as soon as they're done.
+/// ````no_run
+/// #[test]
+/// #[file_serial(key, watch_path)]
+/// fn test_serial_watching() {
+///   // Do things
+/// }
+/// ````
+///
+/// `lock_type` picks how the lock file's locked. The default, `"flock"`, is `flock(2)` on Unix and `LockFileEx` on
+/// Windows. `"fcntl"` uses `fcntl(2)` record locks instead (Unix only), which some network filesystems support
+/// when they don't support `flock`. On Linux these are open file description locks, but elsewhere they're
+/// per-process, so only use them there if each lock file's only used by one test at a time in each process.
+/// `"open_exclusive"` doesn't use the OS locks at all, and instead holds the lock by creating a `<path>-exclusive`
+/// file, which works wherever creating a file is atomic, but is left behind (and has to be deleted by hand) if the
+/// process holding it dies. The different kinds don't see each other's locks, so everything using a lock file
+/// needs to use the same `lock_type`, including any [file_parallel](macro@file_parallel) tests. It isn't supported
+/// with the `shared_file_locks` feature.
+/// ````no_run
+/// #[test]
+/// #[file_serial(key, path => "/mnt/nfs/serial.lock", lock_type = "open_exclusive")]
+/// fn test_serial_on_nfs() {
+///   // Do things
+/// }
+/// ````
 #[proc_macro_attribute]
 #[cfg_attr(docsrs, doc(cfg(feature = "file_locks")))]
 pub fn file_serial(attr: TokenStream, input: TokenStream) -> TokenStream {
+    if let Some(missing) = missing_feature("file_locks") {
+        return missing.into();
+    }
     fs_serial_core(attr.into(), input.into()).into()
 }
 
@@ -172,6 +550,10 @@ pub fn file_serial(attr: TokenStream, input: TokenStream) -> TokenStream {
 /// Effectively, this should behave like [parallel](macro@parallel) but for [file_serial](macro@file_serial).
 /// Note that as per [file_serial](macro@file_serial) this doesn't do anything for [serial](macro@serial)/[parallel](macro@parallel) tests.
 ///
+/// By default, the number of parallel users is kept in a count file next to the lock. With the `shared_file_locks`
+/// feature of serial_test, parallel users take a shared OS lock instead, and [file_serial](macro@file_serial) an exclusive one.
+/// All the processes using a lock need to be built the same way for this to work.
+///
 /// It also supports an optional `path` arg as well as key(s) as per [serial](macro@serial).
 /// ````no_run
 /// #[test]
@@ -186,13 +568,164 @@ pub fn file_serial(attr: TokenStream, input: TokenStream) -> TokenStream {
 ///   // Do things
 /// }
 /// ````
-/// The path defaults to a reasonable temp directory for the OS if not specified. If the `path` is specified, you can only use one key.
+/// The path defaults to a reasonable temp directory for the OS if not specified. If the `path` is specified, you can only use one key,
+/// but `paths` can be used as per [file_serial](macro@file_serial) to give one path per key.
+///
+/// To stop too many running at once (e.g. when they share a service with a connection limit), `max_parallel` caps the
+/// number of [file_parallel](macro@file_parallel) users of each key at any time, across all the processes. Anything
+/// over the limit waits for one of the others to finish.
+/// ````no_run
+/// #[test]
+/// #[file_parallel(key, max_parallel = 2)]
+/// fn test_parallel_limited() {
+///   // Do things
+/// }
+/// ````
+///
+/// `lock_type` is as per [file_serial](macro@file_serial), and needs to match whatever the
+/// [file_serial](macro@file_serial) tests using the same lock file have. `max_hold` on async tests is as per
+/// [parallel](macro@parallel).
 #[proc_macro_attribute]
 #[cfg_attr(docsrs, doc(cfg(feature = "file_locks")))]
 pub fn file_parallel(attr: TokenStream, input: TokenStream) -> TokenStream {
+    if let Some(missing) = missing_feature("file_locks") {
+        return missing.into();
+    }
     fs_parallel_core(attr.into(), input.into()).into()
 }
 
+/// Serialises a test against both [serial](macro@serial) and [file_serial](macro@file_serial) tests
+/// ````no_run
+/// #[test]
+/// #[serial(key)]
+/// fn test_serial_one() {
+///   // Do things
+/// }
+///
+/// #[test]
+/// #[file_serial(key)]
+/// fn test_serial_another() {
+///   // Do things
+/// }
+///
+/// #[test]
+/// #[dual_serial(key)]
+/// fn test_dual_serial() {
+///   // Do things
+/// }
+/// ````
+/// This is for when some tests for a resource only need to be kept apart within the process, and others
+/// across processes. A [dual_serial](macro@dual_serial) test takes the [serial](macro@serial) lock for its keys,
+/// and then the [file_serial](macro@file_serial) lock as well, so won't run at the same time as either of the
+/// others above. It supports the `path`/`paths` args as per [file_serial](macro@file_serial).
+#[proc_macro_attribute]
+#[cfg_attr(docsrs, doc(cfg(feature = "file_locks")))]
+pub fn dual_serial(attr: TokenStream, input: TokenStream) -> TokenStream {
+    if let Some(missing) = missing_feature("file_locks") {
+        return missing.into();
+    }
+    dual_serial_core(attr.into(), input.into()).into()
+}
+
+/// Gives a clear compile error if a feature of serial_test isn't enabled, rather than whatever the code needing it
+/// would fail with
+/// ````ignore
+/// #[serial_test::require_feature("file_locks")]
+/// mod file_lock_tests {
+///   // Tests using serial_test::is_file_locked etc.
+/// }
+/// ````
+/// Without the feature, this fails with `serial_test: feature 'file_locks' is required but not enabled`.
+/// [file_serial](macro@file_serial), [file_parallel](macro@file_parallel) and [dual_serial](macro@dual_serial) do
+/// this check themselves. Only the features serial_test passes on to this crate can be checked, which are `async`,
+/// `tokio_serial`, `file_locks`, `notify_watcher`, `test_logging`, `tracing` and `measure_overhead`.
+#[proc_macro_attribute]
+pub fn require_feature(attr: TokenStream, input: TokenStream) -> TokenStream {
+    require_feature_core(attr.into(), input.into()).into()
+}
+
+/// Groups existing test functions under one [serial](macro@serial) key
+/// ````no_run
+/// # use serial_test_derive::serial_suite;
+/// fn test_one() {
+///   // Do things
+/// }
+///
+/// fn test_two() {
+///   // Do things
+/// }
+///
+/// serial_suite!("my_suite", [test_one, test_two]);
+/// ````
+/// This generates `#[test]` wrappers called `suite_my_suite_test_one` and `suite_my_suite_test_two`, which
+/// act like `#[serial(my_suite)]` tests calling the originals. The original functions aren't changed, so
+/// this also works for test functions exported from another crate, e.g. `[other_crate::tests::test_one]`.
+#[proc_macro]
+pub fn serial_suite(input: TokenStream) -> TokenStream {
+    serial_suite_core(input.into()).into()
+}
+
+/// Runs a block inside a test holding some keys serially, as per [serial](macro@serial)
+///
+/// For when parts of a test (e.g. several scenarios in one test) each need a key, but the whole test doesn't.
+/// The keys are only held for the block, and it evaluates to whatever the block does.
+/// ````no_run
+/// # use serial_test_derive::serial_block;
+/// #[test]
+/// fn test_scenarios() {
+///   serial_block!("db", {
+///     // Do things with the database
+///   });
+///   let value = serial_block!("cache", "queue", {
+///     // Do things with the cache and queue
+///     42
+///   });
+/// }
+/// ````
+/// No keys means the same global key as a plain `#[serial]`. This is a macro version of
+/// [with_serial_keys_ordered](../serial_test/fn.with_serial_keys_ordered.html), and it can't be called
+/// `serial!` as that would clash with the attribute.
+#[proc_macro]
+pub fn serial_block(input: TokenStream) -> TokenStream {
+    serial_block_core(input.into()).into()
+}
+
+/// Makes an enum's values usable as keys, with `key = ...` on any of the attributes
+///
+/// Each variant's key is its name, unless it's got a `#[serial_key = "..."]`. The keys are checked by the
+/// compiler, so a typo is an error rather than a new key, and they're the same as the string keys, so
+/// `#[serial(key = Resource::Db)]` and `#[serial(db)]` below share a key. `key` can be given more than once, and
+/// along with ordinary keys, like `const_key`.
+/// ````ignore
+/// use serial_test::{serial, SerialKey};
+///
+/// #[derive(SerialKey)]
+/// enum Resource {
+///   #[serial_key = "db"]
+///   Db,
+///   Queue,
+/// }
+///
+/// #[test]
+/// #[serial(key = Resource::Db)]
+/// fn test_serial_typed_key() {
+///   // Do things
+/// }
+///
+/// #[test]
+/// #[serial(db)]
+/// fn test_serial_same_key() {
+///   // Do things
+/// }
+/// ````
+/// This implements `serial_test::SerialKey`, `AsRef<str>` and `From<Resource> for Cow<'static, str>`, so the values
+/// also work with functions taking string keys, e.g. `with_serial([Resource::Queue], || ...)`. Only enums whose
+/// variants have no fields can derive it.
+#[proc_macro_derive(SerialKey, attributes(serial_key))]
+pub fn serial_key(input: TokenStream) -> TokenStream {
+    serial_key_core(input.into()).into()
+}
+
 // Based off of https://github.com/dtolnay/quote/issues/20#issuecomment-437341743
 #[derive(Default, Debug, Clone)]
 struct QuoteOption<T>(Option<T>);
@@ -210,6 +743,32 @@ impl<T: ToTokens> ToTokens for QuoteOption<T> {
 struct Config {
     names: Vec<String>,
     path: QuoteOption<String>,
+    paths: Vec<QuoteOption<String>>,
+    strict: bool,
+    telemetry_key: Option<String>,
+    context: Option<proc_macro2::TokenStream>,
+    with_context: Option<proc_macro2::TokenStream>,
+    deny_parallel_siblings: bool,
+    measure_overhead: bool,
+    report_as: Option<String>,
+    max_parallel: Option<u32>,
+    defer_start_ms: u64,
+    expiry_ms: Option<u64>,
+    watch_path: bool,
+    lock_type: Option<String>,
+    max_hold: Option<u64>,
+    abort_on_timeout: bool,
+    exclusive_with: Vec<String>,
+    discriminator: Option<proc_macro2::TokenStream>,
+    group_by: Option<proc_macro2::TokenStream>,
+    keys_from_env: Option<String>,
+    const_keys: Vec<proc_macro2::Ident>,
+    // Values of `SerialKey` types, from `key = ...`
+    typed_keys: Vec<proc_macro2::TokenStream>,
+    require_single_thread: bool,
+    non_reentrant: bool,
+    description: Option<String>,
+    scope: Option<String>,
 }
 
 fn string_from_literal(literal: Literal) -> String {
@@ -221,21 +780,354 @@ fn string_from_literal(literal: Literal) -> String {
     string_literal[1..string_literal.len() - 1].to_string()
 }
 
+// Same as serial_test::DEFAULT_KEY, unless the crate being built overrides it
+fn default_key() -> String {
+    default_key_from(std::env::var("SERIAL_TEST_DEFAULT_KEY").ok())
+}
+
+fn default_key_from(var: Option<String>) -> String {
+    var.unwrap_or_default()
+}
+
+fn is_keyless(config: &Config) -> bool {
+    config.names == vec![default_key()]
+}
+
+fn take_arrow(attrs: &mut Vec<TokenTree>, name: &str) {
+    if attrs.len() < 3 {
+        panic!("Expected a '=> <{}>' after '{}'", name, name);
+    }
+    match attrs.remove(0) {
+        TokenTree::Punct(p) if p.as_char() == '=' => {}
+        x => {
+            panic!("Expected = after {}, not {}", name, x);
+        }
+    }
+    match attrs.remove(0) {
+        TokenTree::Punct(p) if p.as_char() == '>' => {}
+        x => {
+            panic!("Expected > after {}, not {}", name, x);
+        }
+    }
+}
+
+fn take_equals(attrs: &mut Vec<TokenTree>, name: &str) {
+    if attrs.len() < 2 {
+        panic!("Expected a '= <{}>' after '{}'", name, name);
+    }
+    match attrs.remove(0) {
+        TokenTree::Punct(p) if p.as_char() == '=' => {}
+        x => {
+            panic!("Expected = after {}, not {}", name, x);
+        }
+    }
+}
+
+fn take_until_comma(attrs: &mut Vec<TokenTree>) -> proc_macro2::TokenStream {
+    let mut tokens = proc_macro2::TokenStream::new();
+    while !attrs.is_empty() {
+        match &attrs[0] {
+            TokenTree::Punct(p) if p.as_char() == ',' => break,
+            _ => tokens.append(attrs.remove(0)),
+        }
+    }
+    tokens
+}
+
 fn get_config(attr: proc_macro2::TokenStream) -> Config {
     let mut attrs = attr.into_iter().collect::<Vec<TokenTree>>();
     let mut raw_args: Vec<String> = Vec::new();
     let mut in_path: bool = false;
+    let mut in_paths: bool = false;
     let mut path: Option<String> = None;
+    let mut paths: Vec<Option<String>> = Vec::new();
+    let mut strict = false;
+    let mut telemetry_key: Option<String> = None;
+    let mut context: Option<proc_macro2::TokenStream> = None;
+    let mut with_context: Option<proc_macro2::TokenStream> = None;
+    let mut deny_parallel_siblings = false;
+    let mut measure_overhead = false;
+    let mut report_as = None;
+    let mut max_parallel = None;
+    let mut defer_start_ms = 0;
+    let mut expiry_ms = None;
+    let mut watch_path = false;
+    let mut lock_type = None;
+    let mut max_hold = None;
+    let mut abort_on_timeout = false;
+    let mut exclusive_with: Vec<String> = Vec::new();
+    let mut discriminator: Option<proc_macro2::TokenStream> = None;
+    let mut group_by: Option<proc_macro2::TokenStream> = None;
+    let mut keys_from_env: Option<String> = None;
+    let mut const_keys = Vec::new();
+    let mut typed_keys = Vec::new();
+    let mut require_single_thread = false;
+    let mut non_reentrant = false;
+    let mut description = None;
+    let mut scope = None;
     while !attrs.is_empty() {
         match attrs.remove(0) {
             TokenTree::Ident(id) if id.to_string().eq_ignore_ascii_case("path") => {
                 in_path = true;
             }
-            TokenTree::Ident(id) => {
-                let name = id.to_string();
-                raw_args.push(name);
+            TokenTree::Ident(id) if id.to_string().eq_ignore_ascii_case("paths") => {
+                in_paths = true;
             }
-            x => {
+            TokenTree::Ident(id) if id == "strict" => {
+                strict = true;
+            }
+            TokenTree::Ident(id) if id == "watch_path" => {
+                if cfg!(all(not(feature = "notify_watcher"), not(test))) {
+                    panic!("watch_path requires the notify_watcher feature of serial_test");
+                }
+                watch_path = true;
+            }
+            TokenTree::Ident(id) if id == "scope" => {
+                take_equals(&mut attrs, "scope");
+                match attrs.remove(0) {
+                    TokenTree::Literal(literal) => {
+                        let value = string_from_literal(literal);
+                        if !["crate", "module", "function"].contains(&value.as_str()) {
+                            panic!(
+                                "Expected scope to be \"crate\", \"module\" or \"function\", not \"{}\"",
+                                value
+                            );
+                        }
+                        scope = Some(value);
+                    }
+                    x => {
+                        panic!("Expected literal as scope arg, not {}", x);
+                    }
+                }
+            }
+            TokenTree::Ident(id) if id == "defer_start_ms" => {
+                take_equals(&mut attrs, "defer_start_ms");
+                match attrs.remove(0) {
+                    TokenTree::Literal(literal) => match literal.to_string().parse::<u64>() {
+                        Ok(value) => defer_start_ms = value,
+                        _ => panic!("Expected an integer as defer_start_ms arg, not {}", literal),
+                    },
+                    x => {
+                        panic!("Expected literal as defer_start_ms arg, not {}", x);
+                    }
+                }
+            }
+            TokenTree::Ident(id) if id == "max_parallel" => {
+                take_equals(&mut attrs, "max_parallel");
+                match attrs.remove(0) {
+                    TokenTree::Literal(literal) => match literal.to_string().parse::<u32>() {
+                        Ok(value) if value > 0 => max_parallel = Some(value),
+                        _ => panic!(
+                            "Expected a positive integer as max_parallel arg, not {}",
+                            literal
+                        ),
+                    },
+                    x => {
+                        panic!("Expected literal as max_parallel arg, not {}", x);
+                    }
+                }
+            }
+            TokenTree::Ident(id) if id == "max_hold" => {
+                take_equals(&mut attrs, "max_hold");
+                match attrs.remove(0) {
+                    TokenTree::Literal(literal) => match literal.to_string().parse::<u64>() {
+                        Ok(value) if value > 0 => max_hold = Some(value),
+                        _ => panic!(
+                            "Expected a positive integer as max_hold arg, not {}",
+                            literal
+                        ),
+                    },
+                    x => {
+                        panic!("Expected literal as max_hold arg, not {}", x);
+                    }
+                }
+            }
+            TokenTree::Ident(id) if id == "expiry_ms" => {
+                take_equals(&mut attrs, "expiry_ms");
+                match attrs.remove(0) {
+                    TokenTree::Literal(literal) => match literal.to_string().parse::<u64>() {
+                        Ok(value) if value > 0 => expiry_ms = Some(value),
+                        _ => panic!(
+                            "Expected a positive integer as expiry_ms arg, not {}",
+                            literal
+                        ),
+                    },
+                    x => {
+                        panic!("Expected literal as expiry_ms arg, not {}", x);
+                    }
+                }
+            }
+            TokenTree::Ident(id) if id == "lock_type" => {
+                take_equals(&mut attrs, "lock_type");
+                match attrs.remove(0) {
+                    TokenTree::Literal(literal) => {
+                        let variant = match string_from_literal(literal).as_str() {
+                            "flock" => "Flock",
+                            "fcntl" => "Fcntl",
+                            "open_exclusive" => "OpenExclusive",
+                            x => panic!(
+                                "Expected flock, fcntl or open_exclusive as lock_type arg, not {}",
+                                x
+                            ),
+                        };
+                        lock_type = Some(variant.to_string());
+                    }
+                    x => {
+                        panic!("Expected literal as lock_type arg, not {}", x);
+                    }
+                }
+            }
+            TokenTree::Ident(id) if id == "report_as" => {
+                take_equals(&mut attrs, "report_as");
+                match attrs.remove(0) {
+                    TokenTree::Literal(literal) => {
+                        report_as = Some(string_from_literal(literal));
+                    }
+                    x => {
+                        panic!("Expected literal as report_as arg, not {}", x);
+                    }
+                }
+            }
+            TokenTree::Ident(id) if id == "desc" => {
+                take_equals(&mut attrs, "desc");
+                match attrs.remove(0) {
+                    TokenTree::Literal(literal) => {
+                        description = Some(string_from_literal(literal));
+                    }
+                    x => {
+                        panic!("Expected literal as desc arg, not {}", x);
+                    }
+                }
+            }
+            TokenTree::Ident(id) if id == "telemetry_key" => {
+                if cfg!(all(not(feature = "tracing"), not(test))) {
+                    panic!("telemetry_key requires the tracing feature of serial_test");
+                }
+                take_equals(&mut attrs, "telemetry_key");
+                match attrs.remove(0) {
+                    TokenTree::Literal(literal) => {
+                        telemetry_key = Some(string_from_literal(literal));
+                    }
+                    x => {
+                        panic!("Expected literal as telemetry_key arg, not {}", x);
+                    }
+                }
+            }
+            TokenTree::Ident(id) if id == "context" => {
+                take_equals(&mut attrs, "context");
+                let context_path: syn::Path = syn::parse2(take_until_comma(&mut attrs))
+                    .unwrap_or_else(|err| {
+                        panic!("Expected a function path as context arg: {}", err)
+                    });
+                context = Some(context_path.to_token_stream());
+            }
+            TokenTree::Ident(id) if id == "with_context" => {
+                take_equals(&mut attrs, "with_context");
+                match attrs.remove(0) {
+                    TokenTree::Literal(literal) => {
+                        let context_path: syn::Path = syn::parse_str(&string_from_literal(literal))
+                            .unwrap_or_else(|err| {
+                                panic!("Expected a function path as with_context arg: {}", err)
+                            });
+                        with_context = Some(context_path.to_token_stream());
+                    }
+                    x => {
+                        panic!("Expected literal as with_context arg, not {}", x);
+                    }
+                }
+            }
+            TokenTree::Ident(id) if id == "discriminator" => {
+                take_equals(&mut attrs, "discriminator");
+                let discriminator_expr: syn::Expr = syn::parse2(take_until_comma(&mut attrs))
+                    .unwrap_or_else(|err| {
+                        panic!("Expected an expression as discriminator arg: {}", err)
+                    });
+                discriminator = Some(discriminator_expr.to_token_stream());
+            }
+            TokenTree::Ident(id) if id == "group_by" => {
+                take_equals(&mut attrs, "group_by");
+                let group_by_expr: syn::Expr = syn::parse2(take_until_comma(&mut attrs))
+                    .unwrap_or_else(|err| {
+                        panic!("Expected an expression as group_by arg: {}", err)
+                    });
+                group_by = Some(group_by_expr.to_token_stream());
+            }
+            TokenTree::Ident(id) if id == "const_key" => {
+                take_equals(&mut attrs, "const_key");
+                match attrs.remove(0) {
+                    TokenTree::Ident(id) => {
+                        const_keys.push(id);
+                    }
+                    x => {
+                        panic!("Expected a constant's name as const_key arg, not {}", x);
+                    }
+                }
+            }
+            // Only with an `=`, as otherwise it's just a key called "key"
+            TokenTree::Ident(id)
+                if id == "key"
+                    && matches!(attrs.first(), Some(TokenTree::Punct(p)) if p.as_char() == '=') =>
+            {
+                take_equals(&mut attrs, "key");
+                let key: syn::Path = syn::parse2(take_until_comma(&mut attrs))
+                    .unwrap_or_else(|err| panic!("Expected a path as key arg: {}", err));
+                typed_keys.push(key.to_token_stream());
+            }
+            TokenTree::Ident(id) if id == "keys_from_env" => {
+                take_equals(&mut attrs, "keys_from_env");
+                match attrs.remove(0) {
+                    TokenTree::Literal(literal) => {
+                        keys_from_env = Some(string_from_literal(literal));
+                    }
+                    x => {
+                        panic!("Expected literal as keys_from_env arg, not {}", x);
+                    }
+                }
+            }
+            TokenTree::Ident(id) if id == "exclusive_with" => {
+                take_equals(&mut attrs, "exclusive_with");
+                match attrs.remove(0) {
+                    TokenTree::Literal(literal) => {
+                        exclusive_with.push(string_from_literal(literal));
+                    }
+                    x => {
+                        panic!("Expected literal as exclusive_with arg, not {}", x);
+                    }
+                }
+            }
+            TokenTree::Ident(id) if id == "require_single_thread" => {
+                require_single_thread = true;
+            }
+            TokenTree::Ident(id) if id == "non_reentrant" => {
+                non_reentrant = true;
+            }
+            TokenTree::Ident(id) if id == "abort_on_timeout" => {
+                abort_on_timeout = true;
+            }
+            TokenTree::Ident(id) if id == "measure_overhead" => {
+                if cfg!(all(not(feature = "measure_overhead"), not(test))) {
+                    panic!("measure_overhead requires the measure_overhead feature of serial_test");
+                }
+                measure_overhead = true;
+            }
+            TokenTree::Ident(id) if id == "deny_parallel_siblings" => {
+                take_equals(&mut attrs, "deny_parallel_siblings");
+                match attrs.remove(0) {
+                    TokenTree::Ident(value) if value == "true" => deny_parallel_siblings = true,
+                    TokenTree::Ident(value) if value == "false" => deny_parallel_siblings = false,
+                    x => {
+                        panic!(
+                            "Expected true or false as deny_parallel_siblings arg, not {}",
+                            x
+                        );
+                    }
+                }
+            }
+            TokenTree::Ident(id) => {
+                let name = id.to_string();
+                raw_args.push(name);
+            }
+            x => {
                 panic!(
                     "Expected literal as key args (or a 'path => '\"foo\"'), not {}",
                     x
@@ -243,30 +1135,38 @@ fn get_config(attr: proc_macro2::TokenStream) -> Config {
             }
         }
         if in_path {
-            if attrs.len() < 3 {
-                panic!("Expected a '=> <path>' after 'path'");
-            }
+            take_arrow(&mut attrs, "path");
             match attrs.remove(0) {
-                TokenTree::Punct(p) if p.as_char() == '=' => {}
-                x => {
-                    panic!("Expected = after path, not {}", x);
+                TokenTree::Literal(literal) => {
+                    path = Some(string_from_literal(literal));
                 }
-            }
-            match attrs.remove(0) {
-                TokenTree::Punct(p) if p.as_char() == '>' => {}
                 x => {
-                    panic!("Expected > after path, not {}", x);
+                    panic!("Expected literals as path arg, not {}", x);
                 }
             }
+            in_path = false;
+        }
+        if in_paths {
+            take_arrow(&mut attrs, "paths");
             match attrs.remove(0) {
-                TokenTree::Literal(literal) => {
-                    path = Some(string_from_literal(literal));
+                TokenTree::Group(group) if group.delimiter() == Delimiter::Bracket => {
+                    for token in group.stream() {
+                        match token {
+                            TokenTree::Literal(literal) => {
+                                paths.push(Some(string_from_literal(literal)));
+                            }
+                            TokenTree::Punct(p) if p.as_char() == ',' => {}
+                            x => {
+                                panic!("Expected literals as paths args, not {}", x);
+                            }
+                        }
+                    }
                 }
                 x => {
-                    panic!("Expected literals as path arg, not {}", x);
+                    panic!("Expected [\"path\", ...] after paths, not {}", x);
                 }
             }
-            in_path = false;
+            in_paths = false;
         }
         if !attrs.is_empty() {
             match attrs.remove(0) {
@@ -277,13 +1177,182 @@ fn get_config(attr: proc_macro2::TokenStream) -> Config {
             }
         }
     }
+    if path.is_some() && !paths.is_empty() {
+        panic!("Can't use both 'path' and 'paths'");
+    }
     if raw_args.is_empty() {
-        raw_args.push(String::new());
+        raw_args.push(default_key());
+    }
+    if raw_args.len() == paths.len() {
+        // Keep each path with its key through the sort below
+        let mut pairs = raw_args.into_iter().zip(paths).collect::<Vec<_>>();
+        pairs.sort();
+        (raw_args, paths) = pairs.into_iter().unzip();
     }
     raw_args.sort(); // So the keys are always requested in the same order. Avoids dining philosopher issues.
     Config {
         names: raw_args,
         path: QuoteOption(path),
+        paths: paths.into_iter().map(QuoteOption).collect(),
+        strict,
+        telemetry_key,
+        context,
+        with_context,
+        deny_parallel_siblings,
+        measure_overhead,
+        report_as,
+        max_parallel,
+        defer_start_ms,
+        expiry_ms,
+        watch_path,
+        lock_type,
+        max_hold,
+        abort_on_timeout,
+        exclusive_with,
+        discriminator,
+        group_by,
+        keys_from_env,
+        const_keys,
+        typed_keys,
+        require_single_thread,
+        non_reentrant,
+        description,
+        scope,
+    }
+}
+
+struct SuiteInput {
+    name: syn::LitStr,
+    functions: syn::punctuated::Punctuated<syn::Path, syn::Token![,]>,
+}
+
+impl syn::parse::Parse for SuiteInput {
+    fn parse(input: syn::parse::ParseStream) -> SynResult<Self> {
+        let name = input.parse()?;
+        input.parse::<syn::Token![,]>()?;
+        let content;
+        syn::bracketed!(content in input);
+        let functions = content.parse_terminated(syn::Path::parse_mod_style, syn::Token![,])?;
+        Ok(SuiteInput { name, functions })
+    }
+}
+
+struct BlockInput {
+    keys: Vec<syn::LitStr>,
+    block: syn::Block,
+}
+
+impl syn::parse::Parse for BlockInput {
+    fn parse(input: syn::parse::ParseStream) -> SynResult<Self> {
+        let mut keys = Vec::new();
+        while input.peek(syn::LitStr) {
+            keys.push(input.parse()?);
+            input.parse::<syn::Token![,]>()?;
+        }
+        let block = input.parse()?;
+        Ok(BlockInput { keys, block })
+    }
+}
+
+fn serial_key_core(input: proc_macro2::TokenStream) -> proc_macro2::TokenStream {
+    let item_enum = match syn::parse2::<syn::Item>(input) {
+        Ok(syn::Item::Enum(item_enum)) => item_enum,
+        Ok(item) => {
+            return syn::Error::new_spanned(item, "SerialKey can only be derived for enums")
+                .to_compile_error();
+        }
+        Err(err) => return err.to_compile_error(),
+    };
+    let name = &item_enum.ident;
+    let mut arms = Vec::new();
+    for variant in &item_enum.variants {
+        if !matches!(variant.fields, syn::Fields::Unit) {
+            return syn::Error::new_spanned(
+                variant,
+                "SerialKey can only be derived for enums whose variants have no fields",
+            )
+            .to_compile_error();
+        }
+        let mut key = variant.ident.to_string();
+        for attr in variant
+            .attrs
+            .iter()
+            .filter(|attr| attr.path().is_ident("serial_key"))
+        {
+            match &attr.meta {
+                syn::Meta::NameValue(syn::MetaNameValue {
+                    value:
+                        syn::Expr::Lit(syn::ExprLit {
+                            lit: syn::Lit::Str(lit),
+                            ..
+                        }),
+                    ..
+                }) => key = lit.value(),
+                _ => {
+                    return syn::Error::new_spanned(attr, "Expected #[serial_key = \"...\"]")
+                        .to_compile_error();
+                }
+            }
+        }
+        let variant = &variant.ident;
+        arms.push(quote! { #name::#variant => #key });
+    }
+    let (impl_generics, ty_generics, where_clause) = item_enum.generics.split_for_impl();
+    quote! {
+        impl #impl_generics serial_test::SerialKey for #name #ty_generics #where_clause {
+            fn serial_key(&self) -> &'static str {
+                match *self {
+                    #(#arms,)*
+                }
+            }
+        }
+
+        impl #impl_generics ::std::convert::AsRef<str> for #name #ty_generics #where_clause {
+            fn as_ref(&self) -> &str {
+                serial_test::SerialKey::serial_key(self)
+            }
+        }
+
+        impl #impl_generics ::std::convert::From<#name #ty_generics> for ::std::borrow::Cow<'static, str> #where_clause {
+            fn from(key: #name #ty_generics) -> Self {
+                ::std::borrow::Cow::Borrowed(serial_test::SerialKey::serial_key(&key))
+            }
+        }
+    }
+}
+
+fn serial_block_core(input: proc_macro2::TokenStream) -> proc_macro2::TokenStream {
+    let BlockInput { keys, block } = match syn::parse2(input) {
+        Ok(block_input) => block_input,
+        Err(err) => return err.to_compile_error(),
+    };
+    quote! {
+        serial_test::with_serial_keys_ordered(&[#(#keys),*], || #block)
+    }
+}
+
+fn serial_suite_core(input: proc_macro2::TokenStream) -> proc_macro2::TokenStream {
+    let suite: SuiteInput = match syn::parse2(input) {
+        Ok(suite) => suite,
+        Err(err) => return err.to_compile_error(),
+    };
+    let name = suite.name.value();
+    let ident_name: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    let wrappers = suite.functions.iter().map(|function| {
+        let function_name = &function.segments.last().unwrap().ident;
+        let wrapper = format_ident!("suite_{}_{}", ident_name, function_name);
+        quote! {
+            #[test]
+            fn #wrapper () {
+                serial_test::local_serial_core(vec![#name], ::std::option::Option::None, #function);
+            }
+        }
+    });
+    quote! {
+        #(#wrappers)*
     }
 }
 
@@ -311,6 +1380,14 @@ fn fs_serial_core(
     serial_setup(input, config, "fs")
 }
 
+fn dual_serial_core(
+    attr: proc_macro2::TokenStream,
+    input: proc_macro2::TokenStream,
+) -> proc_macro2::TokenStream {
+    let config = get_config(attr);
+    serial_setup(input, config, "dual")
+}
+
 fn fs_parallel_core(
     attr: proc_macro2::TokenStream,
     input: proc_macro2::TokenStream,
@@ -326,8 +1403,222 @@ fn core_setup(
     prefix: &str,
     kind: &str,
 ) -> proc_macro2::TokenStream {
+    let attr_name = attribute_name(prefix, kind);
+    if config.strict
+        && is_keyless(config)
+        && config.group_by.is_none()
+        && config.keys_from_env.is_none()
+        && config.const_keys.is_empty()
+        && config.typed_keys.is_empty()
+    {
+        return syn::Error::new(
+            proc_macro2::Span::call_site(),
+            format!(
+                "#[{attr_name}(strict)] requires an explicit key, e.g. #[{attr_name}(strict, some_key)]"
+            ),
+        )
+        .to_compile_error();
+    }
+    if !config.paths.is_empty() && config.paths.len() != config.names.len() {
+        return syn::Error::new(
+            proc_macro2::Span::call_site(),
+            format!(
+                "Need one path per key, but got {} keys and {} paths",
+                config.names.len(),
+                config.paths.len()
+            ),
+        )
+        .to_compile_error();
+    }
+    if config.deny_parallel_siblings && kind != "serial" {
+        return syn::Error::new(
+            proc_macro2::Span::call_site(),
+            format!("deny_parallel_siblings isn't supported by #[{attr_name}]"),
+        )
+        .to_compile_error();
+    }
+    if config.group_by.is_some()
+        && (!is_keyless(config)
+            || !config.exclusive_with.is_empty()
+            || config.discriminator.is_some()
+            || config.scope.is_some()
+            || config.context.is_some())
+    {
+        return syn::Error::new(
+            proc_macro2::Span::call_site(),
+            format!("#[{attr_name}(group_by = ...)] picks the key itself, so can't be used with keys, discriminator, scope or context"),
+        )
+        .to_compile_error();
+    }
+    if config.keys_from_env.is_some()
+        && (!is_keyless(config)
+            || !config.exclusive_with.is_empty()
+            || config.discriminator.is_some()
+            || config.group_by.is_some()
+            || config.scope.is_some()
+            || config.context.is_some())
+    {
+        return syn::Error::new(
+            proc_macro2::Span::call_site(),
+            format!("#[{attr_name}(keys_from_env = ...)] picks the keys itself, so can't be used with keys, discriminator, group_by, scope or context"),
+        )
+        .to_compile_error();
+    }
+    for (arg, used) in [
+        ("const_key", !config.const_keys.is_empty()),
+        ("key", !config.typed_keys.is_empty()),
+    ] {
+        if used
+            && (config.discriminator.is_some()
+                || config.group_by.is_some()
+                || config.keys_from_env.is_some()
+                || config.scope.is_some()
+                || !config.paths.is_empty())
+        {
+            return syn::Error::new(
+                proc_macro2::Span::call_site(),
+                format!("#[{attr_name}({arg} = ...)] can't be used with discriminator, group_by, keys_from_env, scope or paths"),
+            )
+            .to_compile_error();
+        }
+    }
+    if config.discriminator.is_some() && is_keyless(config) {
+        return syn::Error::new(
+            proc_macro2::Span::call_site(),
+            format!("#[{attr_name}(discriminator = ...)] needs a key to add the discriminator to"),
+        )
+        .to_compile_error();
+    }
+    if config.max_parallel.is_some() && (prefix != "fs" || kind != "parallel") {
+        return syn::Error::new(
+            proc_macro2::Span::call_site(),
+            format!("max_parallel isn't supported by #[{attr_name}]"),
+        )
+        .to_compile_error();
+    }
+    if config.defer_start_ms > 0 && kind != "parallel" {
+        return syn::Error::new(
+            proc_macro2::Span::call_site(),
+            format!("defer_start_ms isn't supported by #[{attr_name}]"),
+        )
+        .to_compile_error();
+    }
+    if config.expiry_ms.is_some() && (prefix != "fs" || kind != "serial") {
+        return syn::Error::new(
+            proc_macro2::Span::call_site(),
+            format!("expiry_ms isn't supported by #[{attr_name}]"),
+        )
+        .to_compile_error();
+    }
+    if config.watch_path && (prefix != "fs" || kind != "serial") {
+        return syn::Error::new(
+            proc_macro2::Span::call_site(),
+            format!("watch_path isn't supported by #[{attr_name}]"),
+        )
+        .to_compile_error();
+    }
+    if config.lock_type.is_some() && prefix == "local" {
+        return syn::Error::new(
+            proc_macro2::Span::call_site(),
+            format!("lock_type isn't supported by #[{attr_name}]"),
+        )
+        .to_compile_error();
+    }
+    if config.max_hold.is_some() && kind != "parallel" && (prefix != "local" || kind != "serial") {
+        return syn::Error::new(
+            proc_macro2::Span::call_site(),
+            format!("max_hold isn't supported by #[{attr_name}]"),
+        )
+        .to_compile_error();
+    }
+    if config.abort_on_timeout && config.max_hold.is_none() {
+        return syn::Error::new(
+            proc_macro2::Span::call_site(),
+            format!("abort_on_timeout is what happens when max_hold runs out, so needs #[{attr_name}(max_hold = ..., ...)]"),
+        )
+        .to_compile_error();
+    }
+    if config.report_as.is_some() && !config.measure_overhead {
+        return syn::Error::new(
+            proc_macro2::Span::call_site(),
+            format!("report_as names the suite in the overhead report, so needs #[{attr_name}(measure_overhead, ...)]"),
+        )
+        .to_compile_error();
+    }
+    if config.scope.is_some() && prefix != "local" {
+        return syn::Error::new(
+            proc_macro2::Span::call_site(),
+            format!("scope isn't supported by #[{attr_name}]"),
+        )
+        .to_compile_error();
+    }
+    if config.scope.is_some() && (!is_keyless(config) || !config.exclusive_with.is_empty()) {
+        return syn::Error::new(
+            proc_macro2::Span::call_site(),
+            format!("#[{attr_name}(scope = ...)] picks the key itself, so can't be used with keys"),
+        )
+        .to_compile_error();
+    }
+    if config.description.is_some() && prefix != "local" {
+        return syn::Error::new(
+            proc_macro2::Span::call_site(),
+            format!("desc isn't supported by #[{attr_name}]"),
+        )
+        .to_compile_error();
+    }
+    if config.non_reentrant && (prefix != "local" || kind != "serial") {
+        return syn::Error::new(
+            proc_macro2::Span::call_site(),
+            format!("non_reentrant isn't supported by #[{attr_name}]"),
+        )
+        .to_compile_error();
+    }
+    if !config.exclusive_with.is_empty() && kind != "serial" {
+        return syn::Error::new(
+            proc_macro2::Span::call_site(),
+            format!("exclusive_with isn't supported by #[{attr_name}]"),
+        )
+        .to_compile_error();
+    }
+    if !config.exclusive_with.is_empty() && !config.paths.is_empty() {
+        return syn::Error::new(
+            proc_macro2::Span::call_site(),
+            "Can't use both 'exclusive_with' and 'paths'",
+        )
+        .to_compile_error();
+    }
+    // Checked here rather than left to the runtime, so a mod-level path is checked against every fn in the mod
+    if config.path.0.is_some() && prefix != "local" && lock_names(config).len() > 1 {
+        return syn::Error::new(
+            proc_macro2::Span::call_site(),
+            format!("#[{attr_name}(path => ...)] can only be used with one key, so use 'paths' to give each key its own"),
+        )
+        .to_compile_error();
+    }
+    if let Some(key) = check_parallel_siblings(config, prefix, kind) {
+        let serial_attr = attribute_name(prefix, "serial");
+        let parallel_attr = attribute_name(prefix, "parallel");
+        return syn::Error::new(
+            proc_macro2::Span::call_site(),
+            format!(
+                "Key '{key}' is used by both #[{serial_attr}(deny_parallel_siblings = true)] and #[{parallel_attr}] tests"
+            ),
+        )
+        .to_compile_error();
+    }
     let fn_ast: SynResult<syn::ItemFn> = syn::parse2(input.clone());
     if let Ok(ast) = fn_ast {
+        if let Some(other) = ast
+            .attrs
+            .iter()
+            .find_map(|attr| other_kind_attr(attr, kind))
+        {
+            return syn::Error::new(
+                proc_macro2::Span::call_site(),
+                format!("Can't use both #[{attr_name}] and #[{other}] on the same function"),
+            )
+            .to_compile_error();
+        }
         return fn_setup(ast, config, prefix, kind);
     };
     let mod_ast: SynResult<syn::ItemMod> = syn::parse2(input);
@@ -337,18 +1628,7 @@ fn core_setup(
                 let new_items = items
                     .into_iter()
                     .map(|item| match item {
-                        syn::Item::Fn(item_fn)
-                            if item_fn.attrs.iter().any(|attr| {
-                                attr.meta
-                                    .path()
-                                    .segments
-                                    .iter()
-                                    .map(|s| s.ident.to_string())
-                                    .collect::<Vec<String>>()
-                                    .join("::")
-                                    .contains("test")
-                            }) =>
-                        {
+                        syn::Item::Fn(item_fn) if item_fn.attrs.iter().any(is_test_attr) => {
                             let tokens = fn_setup(item_fn, config, prefix, kind);
                             let token_display = format!("tokens: {tokens}");
                             syn::parse2(tokens).expect(&token_display)
@@ -361,8 +1641,9 @@ fn core_setup(
             if let Some(nc) = new_content {
                 ast.content.replace(nc);
             }
+            // Only our own attribute, so e.g. #[parallel] doesn't also take a #[serial] with it
             ast.attrs.retain(|attr| {
-                attr.meta.path().segments.first().unwrap().ident.to_string() != "serial"
+                attr.meta.path().segments.first().unwrap().ident != attr_name.as_str()
             });
             ast.into_token_stream()
         }
@@ -372,6 +1653,150 @@ fn core_setup(
     }
 }
 
+fn require_feature_core(
+    attr: proc_macro2::TokenStream,
+    input: proc_macro2::TokenStream,
+) -> proc_macro2::TokenStream {
+    let feature: syn::LitStr = match syn::parse2(attr) {
+        Ok(feature) => feature,
+        Err(err) => {
+            return syn::Error::new(
+                err.span(),
+                "Expected a feature name, e.g. #[require_feature(\"file_locks\")]",
+            )
+            .to_compile_error()
+        }
+    };
+    match missing_feature(&feature.value()) {
+        Some(missing) => quote! { #missing #input },
+        None => input,
+    }
+}
+
+/// A compile error if serial_test's `feature` isn't enabled
+fn missing_feature(feature: &str) -> Option<proc_macro2::TokenStream> {
+    let enabled = match feature {
+        "async" => cfg!(feature = "async"),
+        "tokio_serial" => cfg!(feature = "tokio_serial"),
+        "file_locks" => cfg!(feature = "file_locks"),
+        "notify_watcher" => cfg!(feature = "notify_watcher"),
+        "test_logging" => cfg!(feature = "test_logging"),
+        "tracing" => cfg!(feature = "tracing"),
+        "measure_overhead" => cfg!(feature = "measure_overhead"),
+        _ => {
+            return Some(
+                syn::Error::new(
+                    proc_macro2::Span::call_site(),
+                    format!("serial_test: can't check for feature '{feature}'"),
+                )
+                .to_compile_error(),
+            )
+        }
+    };
+    if enabled {
+        return None;
+    }
+    Some(
+        syn::Error::new(
+            proc_macro2::Span::call_site(),
+            format!("serial_test: feature '{feature}' is required but not enabled"),
+        )
+        .to_compile_error(),
+    )
+}
+
+// Name of a serial attribute for the opposite kind (i.e. parallel for serial), which can't be mixed
+fn other_kind_attr(attr: &syn::Attribute, kind: &str) -> Option<String> {
+    let ident = attr.path().segments.last()?.ident.to_string();
+    let other = if kind == "serial" {
+        ["parallel", "file_parallel"]
+    } else {
+        ["serial", "file_serial"]
+    };
+    if other.contains(&ident.as_str()) {
+        Some(ident)
+    } else {
+        None
+    }
+}
+
+fn is_test_path(path: &syn::Path) -> bool {
+    path.segments
+        .iter()
+        .map(|s| s.ident.to_string())
+        .collect::<Vec<String>>()
+        .join("::")
+        .contains("test")
+}
+
+/// Whether a fn inside a mod is a test. Attributes on the mod's contents haven't been expanded yet, so
+/// `#[cfg_attr(..., test)]` still needs looking inside.
+fn is_test_attr(attr: &syn::Attribute) -> bool {
+    if !attr.path().is_ident("cfg_attr") {
+        return is_test_path(attr.path());
+    }
+    attr.parse_args_with(syn::punctuated::Punctuated::<syn::Meta, syn::Token![,]>::parse_terminated)
+        .map(|metas| {
+            // The first one is the condition
+            metas.iter().skip(1).any(|meta| is_test_path(meta.path()))
+        })
+        .unwrap_or(false)
+}
+
+/// Keys to actually lock. `exclusive_with` keys are held serially as well, as that's what keeps out
+/// parallel tests using them, and are sorted in with the rest to avoid dining philosopher issues.
+fn lock_names(config: &Config) -> Vec<String> {
+    let mut names = config.names.clone();
+    for name in &config.exclusive_with {
+        if !names.contains(name) {
+            names.push(name.clone());
+        }
+    }
+    names.sort();
+    names
+}
+
+fn attribute_name(prefix: &str, kind: &str) -> String {
+    match prefix {
+        "fs" => format!("file_{}", kind),
+        "dual" => format!("dual_{}", kind),
+        _ => kind.to_string(),
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum LockKind {
+    Serial,
+    Parallel,
+}
+
+thread_local! {
+    // Keys seen so far in this compilation unit, as either `Serial` (with deny_parallel_siblings) or `Parallel`
+    static SEEN: RefCell<HashMap<String, LockKind>> = RefCell::new(HashMap::new());
+}
+
+/// Records the keys for this attribute, returning the first that's used by both a `deny_parallel_siblings`
+/// serial test and a parallel one. Expansion order isn't fixed, so whichever comes second reports it.
+fn check_parallel_siblings(config: &Config, prefix: &str, kind: &str) -> Option<String> {
+    let lock_kind = match kind {
+        "serial" if config.deny_parallel_siblings => LockKind::Serial,
+        "parallel" => LockKind::Parallel,
+        _ => return None,
+    };
+    SEEN.with(|seen| {
+        let mut seen = seen.borrow_mut();
+        for name in &config.names {
+            let entry = seen
+                .entry(format!("{}:{}", prefix, name))
+                .or_insert(lock_kind);
+            if *entry != lock_kind {
+                return Some(name.clone());
+            }
+        }
+        None
+    })
+}
+
 fn fn_setup(
     ast: syn::ItemFn,
     config: &Config,
@@ -383,6 +1808,11 @@ fn fn_setup(
         panic!("async testing attempted with async feature disabled in serial_test!");
     }
     let vis = ast.vis;
+    // Methods (e.g. for test frameworks registering `fn(&mut self)`s) keep their receiver, and parameterised
+    // tests (e.g. from `test_case`) their arguments
+    let inputs = ast.sig.inputs.clone();
+    // e.g. `extern "C"` for tests called from an FFI harness
+    let abi = ast.sig.abi;
     let name = ast.sig.ident;
     #[cfg(all(feature = "test_logging", not(test)))]
     let print_name = {
@@ -397,125 +1827,1564 @@ fn fn_setup(
         syn::ReturnType::Default => None,
         syn::ReturnType::Type(_rarrow, ref box_type) => Some(box_type.deref()),
     };
-    let block = ast.block;
-    let attrs: Vec<syn::Attribute> = ast.attrs.into_iter().collect();
-    let names = config.names.clone();
-    let path = config.path.clone();
-    if let Some(ret) = return_type {
-        match asyncness {
-            Some(_) => {
-                let fnname = format_ident!("{}_async_{}_core_with_return", prefix, kind);
-                let temp_fn = format_ident!("_{}_internal", name);
-                quote! {
-                    #(#attrs)
-                    *
-                    #vis async fn #name () -> #ret {
-                        async fn #temp_fn () -> #ret
-                        #block
-
-                        #print_name
-                        serial_test::#fnname(vec![#(#names ),*], #path, #temp_fn()).await
-                    }
-                }
+    // Without a key, `scope` picks one from where the test is
+    let scope_key = config.scope.as_deref().map(|scope| match scope {
+        "crate" => quote! { env!("CARGO_PKG_NAME") },
+        "module" => quote! { module_path!() },
+        _ => {
+            let fn_name = name.to_string();
+            quote! { concat!(module_path!(), "::", #fn_name) }
+        }
+    });
+    let mut block = ast.block.to_token_stream();
+    if let Some(with_context) = &config.with_context {
+        // Also inside the block, so the value's only made once we've got the lock
+        block = quote! {
+            {
+                let _serial_test_with_context = serial_test::set_context(#with_context());
+                #block
             }
-            None => {
-                let fnname = format_ident!("{}_{}_core_with_return", prefix, kind);
-                quote! {
-                    #(#attrs)
-                    *
-                    #vis fn #name () -> #ret {
-                        #print_name
-                        serial_test::#fnname(vec![#(#names ),*], #path, || #block )
-                    }
+        };
+    }
+    if let Some(context) = &config.context {
+        // Inside the block, so the guard is held (and dropped) with the lock
+        let names = match &scope_key {
+            Some(scope_key) => vec![scope_key.clone()],
+            None => config.names.iter().map(|name| quote! { #name }).collect(),
+        };
+        block = quote! {
+            {
+                let _serial_test_context = #context(&[#(#names),*]);
+                #block
+            }
+        };
+    }
+    if let Some(max_hold) = config.max_hold {
+        let test_name = name.to_string();
+        let on_timeout = if config.abort_on_timeout {
+            quote! { serial_test::OnTimeout::Abort }
+        } else {
+            quote! { serial_test::OnTimeout::Panic }
+        };
+        if kind == "parallel" {
+            // A sync body can't be stopped, and would carry on alongside the serial tests it was keeping out
+            if asyncness.is_none() {
+                return syn::Error::new(
+                    proc_macro2::Span::call_site(),
+                    format!(
+                        "max_hold is only supported by #[{}] on async tests",
+                        attribute_name(prefix, kind)
+                    ),
+                )
+                .to_compile_error();
+            }
+            block = quote! {
+                {
+                    serial_test::run_async_with_max_hold(#max_hold, concat!(module_path!(), "::", #test_name), #on_timeout, async move #block).await
                 }
+            };
+        } else {
+            // The body's run as a closure, which can't be awaited
+            if asyncness.is_some() {
+                return syn::Error::new(
+                    proc_macro2::Span::call_site(),
+                    "max_hold isn't supported for async tests",
+                )
+                .to_compile_error();
+            }
+            if config.abort_on_timeout {
+                return syn::Error::new(
+                    proc_macro2::Span::call_site(),
+                    format!(
+                        "#[{}] always aborts when max_hold runs out, so doesn't need abort_on_timeout",
+                        attribute_name(prefix, kind)
+                    ),
+                )
+                .to_compile_error();
             }
+            block = quote! {
+                {
+                    serial_test::run_with_max_hold(#max_hold, concat!(module_path!(), "::", #test_name), || #block)
+                }
+            };
         }
+    }
+    let single_thread_check = if config.require_single_thread {
+        let test_name = name.to_string();
+        Some(quote! { serial_test::require_single_thread(#test_name); })
     } else {
-        match asyncness {
-            Some(_) => {
-                let fnname = format_ident!("{}_async_{}_core", prefix, kind);
-                let temp_fn = format_ident!("_{}_internal", name);
-                quote! {
-                    #(#attrs)
-                    *
-                    #vis async fn #name () {
-                        async fn #temp_fn ()
-                        #block
-
-                        #print_name
-                        serial_test::#fnname(vec![#(#names ),*], #path, #temp_fn()).await;
-                    }
-                }
+        None
+    };
+    // Timer starts just before the lock is requested, and is reported as soon as we've got it
+    let start_timer = if config.measure_overhead {
+        let keys = config.names.join(",");
+        let test_name = name.to_string();
+        let report = match &config.report_as {
+            Some(suite) => {
+                quote! { serial_test::report_suite_overhead(#keys, #test_name, #suite); }
             }
-            None => {
-                let fnname = format_ident!("{}_{}_core", prefix, kind);
-                quote! {
-                    #(#attrs)
-                    *
-                    #vis fn #name () {
-                        #print_name
-                        serial_test::#fnname(vec![#(#names ),*], #path, || #block );
-                    }
-                }
+            None => quote! { serial_test::report_overhead(#keys, #test_name); },
+        };
+        block = quote! {
+            {
+                #report
+                #block
             }
+        };
+        Some(quote! { serial_test::start_overhead_timer(); })
+    } else {
+        None
+    };
+    let attrs: Vec<syn::Attribute> = ast.attrs.into_iter().collect();
+    let names = match &scope_key {
+        Some(scope_key) => vec![scope_key.clone()],
+        None => lock_names(config)
+            .iter()
+            .map(|name| quote! { #name })
+            .collect(),
+    };
+    // A lone const_key (or key) is the only key, so can go in as it is, without any sorting
+    let const_keys: Vec<proc_macro2::TokenStream> = config
+        .const_keys
+        .iter()
+        .map(|key| quote! { #key })
+        .chain(
+            config
+                .typed_keys
+                .iter()
+                .map(|key| quote! { serial_test::SerialKey::serial_key(&#key) }),
+        )
+        .collect();
+    let lone_const_key =
+        const_keys.len() == 1 && is_keyless(config) && config.exclusive_with.is_empty();
+    let names = if lone_const_key {
+        const_keys.clone()
+    } else {
+        names
+    };
+    // With a discriminator, group_by, keys_from_env or const_key, the keys are only known at runtime
+    let runtime_keys = match (
+        &config.discriminator,
+        &config.group_by,
+        &config.keys_from_env,
+    ) {
+        (Some(discriminator), _, _) => {
+            Some(quote! { serial_test::discriminated_keys(&[#(#names),*], &(#discriminator)) })
         }
-    }
-}
-
-fn serial_setup(
-    input: proc_macro2::TokenStream,
-    config: Config,
-    prefix: &str,
-) -> proc_macro2::TokenStream {
-    core_setup(input, &config, prefix, "serial")
-}
-
-fn parallel_setup(
-    input: proc_macro2::TokenStream,
-    config: Config,
-    prefix: &str,
-) -> proc_macro2::TokenStream {
-    core_setup(input, &config, prefix, "parallel")
-}
-
-#[cfg(test)]
-mod tests {
-    use super::{fs_serial_core, local_serial_core};
-    use proc_macro2::TokenStream;
-    use quote::quote;
+        (None, Some(group_by), _) => Some(quote! { vec![(#group_by).to_string()] }),
+        (None, None, Some(keys_from_env)) => {
+            Some(quote! { serial_test::keys_from_env(#keys_from_env) })
+        }
+        (None, None, None) if !const_keys.is_empty() && !lone_const_key => {
+            // The default key's only there because there weren't any other keys
+            let names = lock_names(config)
+                .into_iter()
+                .filter(|name| !is_keyless(config) || *name != default_key());
+            Some(quote! { serial_test::with_const_keys(&[#(#names),*], &[#(#const_keys),*]) })
+        }
+        (None, None, None) => None,
+    };
+    let (key_setup, keys) = match &runtime_keys {
+        Some(runtime_keys) => (
+            Some(quote! {
+                let _serial_test_keys = #runtime_keys;
+            }),
+            quote! { _serial_test_keys.iter().map(|key| key.as_str()).collect() },
+        ),
+        None => (None, quote! { vec![#(#names ),*] }),
+    };
+    // After key_setup, as the runtime keys are the ones actually locked
+    let key_list = match &runtime_keys {
+        Some(_) => {
+            quote! { _serial_test_keys.iter().map(|key| key.as_str()).collect::<Vec<_>>() }
+        }
+        None => quote! { [#(#names),*] },
+    };
+    let reentry_check = if config.non_reentrant {
+        let test_name = name.to_string();
+        Some(quote! { serial_test::deny_reentry(#key_list, #test_name); })
+    } else {
+        None
+    };
+    let describe = config
+        .description
+        .as_ref()
+        .map(|description| quote! { serial_test::describe_keys(#key_list, #description); });
+    // For parallel_group_size
+    let register = if prefix == "local" && kind == "parallel" {
+        let test_name = name.to_string();
+        Some(quote! {
+            serial_test::register_parallel_test(#key_list, concat!(module_path!(), "::", #test_name));
+        })
+    } else {
+        None
+    };
+    // Staggers tests starting together, before any of them asks for the lock
+    let defer_start = match config.defer_start_ms {
+        0 => None,
+        defer_start_ms => {
+            Some(quote! { serial_test::defer_parallel_start(#key_list, #defer_start_ms); })
+        }
+    };
+    let path = if config.paths.is_empty() {
+        config.path.to_token_stream()
+    } else {
+        let paths = &config.paths;
+        quote! { vec![#(#paths),*] }
+    };
+    let core_fn = |prefix: &str| {
+        // With tokio_serial, async tests wait for in-process serial keys without blocking the executor's thread
+        let async_kind = if prefix == "local"
+            && kind == "serial"
+            && cfg!(all(feature = "tokio_serial", not(test)))
+        {
+            "tokio"
+        } else {
+            "async"
+        };
+        match (asyncness.is_some(), return_type.is_some()) {
+            (true, true) => format_ident!("{}_{}_{}_core_with_return", prefix, async_kind, kind),
+            (true, false) => format_ident!("{}_{}_{}_core", prefix, async_kind, kind),
+            (false, true) => format_ident!("{}_{}_core_with_return", prefix, kind),
+            (false, false) => format_ident!("{}_{}_core", prefix, kind),
+        }
+    };
+    // dual_serial is a serial test wrapped around a file_serial one
+    let fnname = if prefix == "dual" {
+        core_fn("local")
+    } else {
+        core_fn(prefix)
+    };
+    let ret_arrow = return_type.map(|ret| quote! { -> #ret });
+    let semicolon = if return_type.is_some() {
+        quote! {}
+    } else {
+        quote! { ; }
+    };
+    let span_name = telemetry_span_name(config, kind);
+    let lock_type = format_ident!("{}", config.lock_type.as_deref().unwrap_or("Flock"));
+    let lock_type = quote! { serial_test::LockType::#lock_type };
+    // file_serial records which test holds the lock, for anyone else waiting on it
+    let holder = if prefix != "local" && kind == "serial" {
+        let holder_name = name.to_string();
+        let expiry_ms = QuoteOption(config.expiry_ms);
+        let watch_path = config.watch_path;
+        Some(quote! {
+            serial_test::FileSerialOptions {
+                holder: concat!(module_path!(), "::", #holder_name),
+                expiry_ms: #expiry_ms,
+                watch_path: #watch_path,
+                lock_type: #lock_type,
+            },
+        })
+    } else {
+        None
+    };
+    let max_parallel = if prefix == "fs" && kind == "parallel" {
+        let max_parallel = match config.max_parallel {
+            Some(max_parallel) => quote! { ::std::option::Option::Some(#max_parallel), },
+            None => quote! { ::std::option::Option::None, },
+        };
+        Some(quote! { #max_parallel #lock_type, })
+    } else {
+        None
+    };
+    // For dual_serial, the file_serial core runs inside, so gets the path and holder
+    let (inner_path, inner_holder) = (path.clone(), holder.clone());
+    let wrap_inner = |function: proc_macro2::TokenStream| {
+        if prefix == "dual" {
+            let inner_fn = core_fn("fs");
+            quote! { serial_test::#inner_fn(#keys, #inner_path, #inner_holder #function) }
+        } else {
+            function
+        }
+    };
+    let (path, holder) = if prefix == "dual" {
+        (quote! { ::std::option::Option::None }, None)
+    } else {
+        (path, holder)
+    };
+    match asyncness {
+        Some(_) => {
+            let temp_fn = format_ident!("_{}_internal", name);
+            // A nested fn can't take `self` or see the arguments, so those use an async block instead
+            let (temp_fn_def, future) = match inputs.is_empty() {
+                false => (
+                    None,
+                    quote! { ::std::panic::AssertUnwindSafe(async move #block) },
+                ),
+                true => (
+                    Some(quote! {
+                        async fn #temp_fn () #ret_arrow
+                        #block
+                    }),
+                    quote! { #temp_fn() },
+                ),
+            };
+            let future = wrap_inner(future);
+            let mut call =
+                quote! { serial_test::#fnname(#keys, #path, #holder #max_parallel #future) };
+            if let Some(span_name) = span_name {
+                call = quote! { serial_test::telemetry_instrument(#span_name, #call) };
+            }
+            quote! {
+                #(#attrs)
+                *
+                #vis async #abi fn #name (#inputs) #ret_arrow {
+                    #temp_fn_def
+
+                    #print_name
+                    #single_thread_check
+                    #key_setup
+                    #describe
+                    #register
+                    #defer_start
+                    #reentry_check
+                    #start_timer
+                    #call.await #semicolon
+                }
+            }
+        }
+        None => {
+            let function = quote! { || #block };
+            let function = if prefix == "dual" {
+                let inner = wrap_inner(function);
+                quote! { || #inner }
+            } else {
+                function
+            };
+            let span_guard = span_name.map(|span_name| {
+                quote! { let _serial_test_span = serial_test::telemetry_span(#span_name).entered(); }
+            });
+            quote! {
+                #(#attrs)
+                *
+                #vis #abi fn #name (#inputs) #ret_arrow {
+                    #print_name
+                    #single_thread_check
+                    #span_guard
+                    #key_setup
+                    #describe
+                    #register
+                    #defer_start
+                    #reentry_check
+                    #start_timer
+                    serial_test::#fnname(#keys, #path, #holder #max_parallel #function ) #semicolon
+                }
+            }
+        }
+    }
+}
+
+/// Name of the tracing span wrapped around a test, if the `tracing` feature is on.
+/// Like `print_name`, the default span is left out of our own tests so their expected output
+/// doesn't depend on features, but an explicit `telemetry_key` is always honoured.
+fn telemetry_span_name(config: &Config, kind: &str) -> Option<String> {
+    if let Some(telemetry_key) = &config.telemetry_key {
+        return Some(telemetry_key.clone());
+    }
+    if cfg!(any(not(feature = "tracing"), test)) {
+        return None;
+    }
+    Some(default_span_name(config, kind))
+}
+
+fn default_span_name(config: &Config, kind: &str) -> String {
+    let keys = config
+        .names
+        .iter()
+        .filter(|name| !name.is_empty())
+        .cloned()
+        .collect::<Vec<String>>();
+    if keys.is_empty() {
+        format!("{}_lock", kind)
+    } else {
+        format!("{}_lock.{}", kind, keys.join(","))
+    }
+}
+
+fn serial_setup(
+    input: proc_macro2::TokenStream,
+    config: Config,
+    prefix: &str,
+) -> proc_macro2::TokenStream {
+    core_setup(input, &config, prefix, "serial")
+}
+
+fn parallel_setup(
+    input: proc_macro2::TokenStream,
+    config: Config,
+    prefix: &str,
+) -> proc_macro2::TokenStream {
+    core_setup(input, &config, prefix, "parallel")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        default_key_from, default_span_name, dual_serial_core, fs_parallel_core, fs_serial_core,
+        get_config, local_parallel_core, local_serial_core, require_feature_core,
+        serial_block_core, serial_key_core, serial_suite_core,
+    };
+    use proc_macro2::TokenStream;
+    use quote::quote;
     use std::iter::FromIterator;
 
     fn init() {
         let _ = env_logger::builder().is_test(false).try_init();
     }
 
-    fn unparse(input: TokenStream) -> String {
-        let item = syn::parse2(input).unwrap();
-        let file = syn::File {
-            attrs: vec![],
-            items: vec![item],
-            shebang: None,
+    fn unparse(input: TokenStream) -> String {
+        let item = syn::parse2(input).unwrap();
+        let file = syn::File {
+            attrs: vec![],
+            items: vec![item],
+            shebang: None,
+        };
+
+        prettyplease::unparse(&file)
+    }
+
+    fn compare_streams(first: TokenStream, second: TokenStream) {
+        let f = unparse(first);
+        assert_eq!(f, unparse(second));
+    }
+
+    #[test]
+    fn test_serial() {
+        init();
+        let attrs = proc_macro2::TokenStream::new();
+        let input = quote! {
+            #[test]
+            fn foo() {}
+        };
+        let stream = local_serial_core(attrs, input);
+        let compare = quote! {
+            #[test]
+            fn foo () {
+                serial_test::local_serial_core(vec![""], ::std::option::Option::None, || {} );
+            }
+        };
+        compare_streams(compare, stream);
+    }
+
+    #[test]
+    fn test_serial_with_pub() {
+        init();
+        let attrs = proc_macro2::TokenStream::new();
+        let input = quote! {
+            #[test]
+            pub fn foo() {}
+        };
+        let stream = local_serial_core(attrs, input);
+        let compare = quote! {
+            #[test]
+            pub fn foo () {
+                serial_test::local_serial_core(vec![""], ::std::option::Option::None, || {} );
+            }
+        };
+        compare_streams(compare, stream);
+    }
+
+    #[test]
+    fn test_other_attributes() {
+        init();
+        let attrs = proc_macro2::TokenStream::new();
+        let input = quote! {
+            #[test]
+            #[ignore]
+            #[should_panic(expected = "Testing panic")]
+            #[something_else]
+            fn foo() {}
+        };
+        let stream = local_serial_core(attrs, input);
+        let compare = quote! {
+            #[test]
+            #[ignore]
+            #[should_panic(expected = "Testing panic")]
+            #[something_else]
+            fn foo () {
+                serial_test::local_serial_core(vec![""], ::std::option::Option::None, || {} );
+            }
+        };
+        compare_streams(compare, stream);
+    }
+
+    #[test]
+    fn test_serial_with_test_log_first() {
+        init();
+        let attrs = proc_macro2::TokenStream::new();
+        // What #[serial] sees after `#[test_log::test] #[serial]` has had test_log expanded
+        let input = quote! {
+            #[::core::prelude::v1::test]
+            fn foo() {
+                mod init {
+                    pub fn init() {}
+                }
+
+                init::init();
+
+                {}
+            }
+        };
+        let stream = local_serial_core(attrs, input);
+        let compare = quote! {
+            #[::core::prelude::v1::test]
+            fn foo () {
+                serial_test::local_serial_core(vec![""], ::std::option::Option::None, || {
+                    mod init {
+                        pub fn init() {}
+                    }
+
+                    init::init();
+
+                    {}
+                } );
+            }
+        };
+        compare_streams(compare, stream);
+    }
+
+    #[test]
+    fn test_serial_with_test_log_second() {
+        init();
+        let attrs = proc_macro2::TokenStream::new();
+        let input = quote! {
+            #[test_log::test]
+            fn foo() {}
+        };
+        let stream = local_serial_core(attrs, input);
+        let compare = quote! {
+            #[test_log::test]
+            fn foo () {
+                serial_test::local_serial_core(vec![""], ::std::option::Option::None, || {} );
+            }
+        };
+        compare_streams(compare, stream);
+    }
+
+    #[test]
+    #[cfg(feature = "async")]
+    fn test_serial_async() {
+        init();
+        let attrs = proc_macro2::TokenStream::new();
+        let input = quote! {
+            async fn foo() {}
+        };
+        let stream = local_serial_core(attrs, input);
+        let compare = quote! {
+            async fn foo () {
+                async fn _foo_internal () { }
+                serial_test::local_async_serial_core(vec![""], ::std::option::Option::None, _foo_internal() ).await;
+            }
+        };
+        assert_eq!(format!("{}", compare), format!("{}", stream));
+    }
+
+    #[test]
+    fn test_serial_method() {
+        init();
+        let attrs = proc_macro2::TokenStream::new();
+        let input = quote! {
+            fn foo(&mut self) { self.bar(); }
+        };
+        let stream = local_serial_core(attrs, input);
+        let compare = quote! {
+            fn foo (&mut self) {
+                serial_test::local_serial_core(vec![""], ::std::option::Option::None, || { self.bar(); } );
+            }
+        };
+        assert_eq!(format!("{}", compare), format!("{}", stream));
+    }
+
+    #[test]
+    #[cfg(feature = "async")]
+    fn test_serial_async_method() {
+        init();
+        let attrs = proc_macro2::TokenStream::new();
+        let input = quote! {
+            async fn foo(self) { self.bar().await; }
+        };
+        let stream = local_serial_core(attrs, input);
+        let compare = quote! {
+            async fn foo (self) {
+                serial_test::local_async_serial_core(vec![""], ::std::option::Option::None, ::std::panic::AssertUnwindSafe(async move { self.bar().await; }) ).await;
+            }
+        };
+        assert_eq!(format!("{}", compare), format!("{}", stream));
+    }
+
+    #[test]
+    #[cfg(feature = "async")]
+    fn test_serial_async_return() {
+        init();
+        let attrs = proc_macro2::TokenStream::new();
+        let input = quote! {
+            async fn foo() -> Result<(), ()> { Ok(()) }
+        };
+        let stream = local_serial_core(attrs, input);
+        let compare = quote! {
+            async fn foo () -> Result<(), ()> {
+                async fn _foo_internal ()  -> Result<(), ()> { Ok(()) }
+                serial_test::local_async_serial_core_with_return(vec![""], ::std::option::Option::None, _foo_internal() ).await
+            }
+        };
+        assert_eq!(format!("{}", compare), format!("{}", stream));
+    }
+
+    #[test]
+    fn test_file_serial() {
+        init();
+        let attrs: Vec<_> = quote! { foo }.into_iter().collect();
+        let input = quote! {
+            #[test]
+            fn foo() {}
+        };
+        let stream = fs_serial_core(proc_macro2::TokenStream::from_iter(attrs), input);
+        let compare = quote! {
+            #[test]
+            fn foo () {
+                serial_test::fs_serial_core(vec!["foo"], ::std::option::Option::None, serial_test::FileSerialOptions { holder: concat!(module_path!(), "::", "foo"), expiry_ms: ::std::option::Option::None, watch_path: false, lock_type: serial_test::LockType::Flock, }, || {} );
+            }
+        };
+        compare_streams(compare, stream);
+    }
+
+    #[test]
+    fn test_file_serial_no_args() {
+        init();
+        let attrs = proc_macro2::TokenStream::new();
+        let input = quote! {
+            #[test]
+            fn foo() {}
+        };
+        let stream = fs_serial_core(proc_macro2::TokenStream::from_iter(attrs), input);
+        let compare = quote! {
+            #[test]
+            fn foo () {
+                serial_test::fs_serial_core(vec![""], ::std::option::Option::None, serial_test::FileSerialOptions { holder: concat!(module_path!(), "::", "foo"), expiry_ms: ::std::option::Option::None, watch_path: false, lock_type: serial_test::LockType::Flock, }, || {} );
+            }
+        };
+        compare_streams(compare, stream);
+    }
+
+    #[test]
+    fn test_file_serial_with_path() {
+        init();
+        let attrs: Vec<_> = quote! { foo, path => "bar_path" }.into_iter().collect();
+        let input = quote! {
+            #[test]
+            fn foo() {}
+        };
+        let stream = fs_serial_core(proc_macro2::TokenStream::from_iter(attrs), input);
+        let compare = quote! {
+            #[test]
+            fn foo () {
+                serial_test::fs_serial_core(vec!["foo"], ::std::option::Option::Some("bar_path"), serial_test::FileSerialOptions { holder: concat!(module_path!(), "::", "foo"), expiry_ms: ::std::option::Option::None, watch_path: false, lock_type: serial_test::LockType::Flock, }, || {} );
+            }
+        };
+        compare_streams(compare, stream);
+    }
+
+    #[test]
+    fn test_file_serial_with_paths() {
+        init();
+        let attrs: Vec<_> = quote! { foo, bar, paths => ["foo_path", "bar_path"] }
+            .into_iter()
+            .collect();
+        let input = quote! {
+            #[test]
+            fn foo() {}
+        };
+        let stream = fs_serial_core(proc_macro2::TokenStream::from_iter(attrs), input);
+        let compare = quote! {
+            #[test]
+            fn foo () {
+                serial_test::fs_serial_core(vec!["bar", "foo"], vec![::std::option::Option::Some("bar_path"), ::std::option::Option::Some("foo_path")], serial_test::FileSerialOptions { holder: concat!(module_path!(), "::", "foo"), expiry_ms: ::std::option::Option::None, watch_path: false, lock_type: serial_test::LockType::Flock, }, || {} );
+            }
+        };
+        compare_streams(compare, stream);
+    }
+
+    #[test]
+    #[cfg(not(windows))]
+    fn test_file_serial_mod_with_path() {
+        init();
+        let input = quote! {
+            #[file_serial(group, path => "/tmp/group")]
+            mod file_serial_tests {
+                #[test]
+                fn foo() {}
+
+                #[test]
+                fn bar() {}
+            }
+        };
+        let stream = fs_serial_core(quote! { group, path => "/tmp/group" }, input);
+        let compare = quote! {
+            mod file_serial_tests {
+                #[test]
+                fn foo() {
+                    serial_test::fs_serial_core(vec!["group"], ::std::option::Option::Some("/tmp/group"), serial_test::FileSerialOptions { holder: concat!(module_path!(), "::", "foo"), expiry_ms: ::std::option::Option::None, watch_path: false, lock_type: serial_test::LockType::Flock, }, || {} );
+                }
+
+                #[test]
+                fn bar() {
+                    serial_test::fs_serial_core(vec!["group"], ::std::option::Option::Some("/tmp/group"), serial_test::FileSerialOptions { holder: concat!(module_path!(), "::", "bar"), expiry_ms: ::std::option::Option::None, watch_path: false, lock_type: serial_test::LockType::Flock, }, || {} );
+                }
+            }
+        };
+        compare_streams(compare, stream);
+    }
+
+    #[test]
+    fn test_file_serial_path_with_two_keys() {
+        init();
+        let input = quote! {
+            mod file_serial_tests {
+                #[test]
+                fn foo() {}
+            }
+        };
+        let stream = fs_serial_core(quote! { foo, bar, path => "/tmp/group" }, input);
+        let compare = quote! {
+            ::core::compile_error! {
+                "#[file_serial(path => ...)] can only be used with one key, so use 'paths' to give each key its own"
+            }
+        };
+        compare_streams(compare, stream);
+    }
+
+    #[test]
+    fn test_require_feature() {
+        init();
+        let input = quote! {
+            mod file_lock_tests {}
+        };
+        let stream = require_feature_core(quote! { "file_locks" }, input.clone());
+        let compare = if cfg!(feature = "file_locks") {
+            input
+        } else {
+            quote! {
+                ::core::compile_error! {
+                    "serial_test: feature 'file_locks' is required but not enabled"
+                }
+                mod file_lock_tests {}
+            }
+        };
+        // Two items, which compare_streams can't parse
+        assert_eq!(stream.to_string(), compare.to_string());
+    }
+
+    #[test]
+    fn test_require_unknown_feature() {
+        init();
+        let stream = require_feature_core(quote! { "runner" }, quote! { mod foo {} });
+        let compare = quote! {
+            ::core::compile_error! {
+                "serial_test: can't check for feature 'runner'"
+            }
+            mod foo {}
+        };
+        // Two items, which compare_streams can't parse
+        assert_eq!(stream.to_string(), compare.to_string());
+    }
+
+    #[test]
+    fn test_file_serial_with_wrong_number_of_paths() {
+        init();
+        let attrs: Vec<_> = quote! { foo, bar, paths => ["foo_path"] }
+            .into_iter()
+            .collect();
+        let input = quote! {
+            #[test]
+            fn foo() {}
+        };
+        let stream = fs_serial_core(proc_macro2::TokenStream::from_iter(attrs), input);
+        let compare = quote! {
+            ::core::compile_error! {
+                "Need one path per key, but got 2 keys and 1 paths"
+            }
+        };
+        compare_streams(compare, stream);
+    }
+
+    #[test]
+    fn test_single_attr() {
+        init();
+        let attrs: Vec<_> = quote! { one}.into_iter().collect();
+        let input = quote! {
+            #[test]
+            fn single() {}
+        };
+        let stream = local_serial_core(proc_macro2::TokenStream::from_iter(attrs), input);
+        let compare = quote! {
+            #[test]
+            fn single () {
+                serial_test::local_serial_core(vec!["one"], ::std::option::Option::None, || {} );
+            }
+        };
+        compare_streams(compare, stream);
+    }
+
+    #[test]
+    fn test_multiple_attr() {
+        init();
+        let attrs: Vec<_> = quote! { two, one }.into_iter().collect();
+        let input = quote! {
+            #[test]
+            fn multiple() {}
+        };
+        let stream = local_serial_core(proc_macro2::TokenStream::from_iter(attrs), input);
+        let compare = quote! {
+            #[test]
+            fn multiple () {
+                serial_test::local_serial_core(vec!["one", "two"], ::std::option::Option::None, || {} );
+            }
+        };
+        compare_streams(compare, stream);
+    }
+
+    #[test]
+    fn test_strict_with_key() {
+        init();
+        let attrs: Vec<_> = quote! { strict, one }.into_iter().collect();
+        let input = quote! {
+            #[test]
+            fn single() {}
+        };
+        let stream = local_serial_core(proc_macro2::TokenStream::from_iter(attrs), input);
+        let compare = quote! {
+            #[test]
+            fn single () {
+                serial_test::local_serial_core(vec!["one"], ::std::option::Option::None, || {} );
+            }
+        };
+        compare_streams(compare, stream);
+    }
+
+    #[test]
+    fn test_strict_without_key() {
+        init();
+        let attrs: Vec<_> = quote! { strict }.into_iter().collect();
+        let input = quote! {
+            #[test]
+            fn single() {}
+        };
+        let stream = local_serial_core(proc_macro2::TokenStream::from_iter(attrs), input);
+        let compare = quote! {
+            ::core::compile_error! {
+                "#[serial(strict)] requires an explicit key, e.g. #[serial(strict, some_key)]"
+            }
+        };
+        compare_streams(compare, stream);
+    }
+
+    #[test]
+    fn test_parallel_strict_without_key() {
+        init();
+        let attrs: Vec<_> = quote! { strict }.into_iter().collect();
+        let input = quote! {
+            #[test]
+            fn single() {}
+        };
+        let stream = local_parallel_core(proc_macro2::TokenStream::from_iter(attrs), input);
+        let compare = quote! {
+            ::core::compile_error! {
+                "#[parallel(strict)] requires an explicit key, e.g. #[parallel(strict, some_key)]"
+            }
+        };
+        compare_streams(compare, stream);
+    }
+
+    #[test]
+    fn test_telemetry_key() {
+        init();
+        let attrs: Vec<_> = quote! { db, telemetry_key = "test.db.serial" }
+            .into_iter()
+            .collect();
+        let input = quote! {
+            #[test]
+            fn foo() {}
+        };
+        let stream = local_serial_core(proc_macro2::TokenStream::from_iter(attrs), input);
+        let compare = quote! {
+            #[test]
+            fn foo () {
+                let _serial_test_span = serial_test::telemetry_span("test.db.serial").entered();
+                serial_test::local_serial_core(vec!["db"], ::std::option::Option::None, || {} );
+            }
+        };
+        compare_streams(compare, stream);
+    }
+
+    #[test]
+    #[cfg(feature = "async")]
+    fn test_telemetry_key_async() {
+        init();
+        let attrs: Vec<_> = quote! { telemetry_key = "test.db.serial", db }
+            .into_iter()
+            .collect();
+        let input = quote! {
+            async fn foo() {}
+        };
+        let stream = local_serial_core(proc_macro2::TokenStream::from_iter(attrs), input);
+        let compare = quote! {
+            async fn foo () {
+                async fn _foo_internal () { }
+                serial_test::telemetry_instrument("test.db.serial", serial_test::local_async_serial_core(vec!["db"], ::std::option::Option::None, _foo_internal() )).await;
+            }
+        };
+        assert_eq!(format!("{}", compare), format!("{}", stream));
+    }
+
+    #[test]
+    fn test_context() {
+        init();
+        let attrs: Vec<_> = quote! { db, context = my_crate::context_for }
+            .into_iter()
+            .collect();
+        let input = quote! {
+            #[test]
+            fn foo() -> Result<(), ()> { Ok(()) }
+        };
+        let stream = local_serial_core(proc_macro2::TokenStream::from_iter(attrs), input);
+        let compare = quote! {
+            #[test]
+            fn foo () -> Result<(), ()> {
+                serial_test::local_serial_core_with_return(vec!["db"], ::std::option::Option::None, || {
+                    let _serial_test_context = my_crate::context_for(&["db"]);
+                    { Ok(()) }
+                } )
+            }
+        };
+        compare_streams(compare, stream);
+    }
+
+    #[test]
+    fn test_max_hold() {
+        init();
+        let input = quote! {
+            #[test]
+            fn foo() {}
+        };
+        let stream = local_serial_core(quote! { db, max_hold = 30000 }, input.clone());
+        let compare = quote! {
+            #[test]
+            fn foo () {
+                serial_test::local_serial_core(vec!["db"], ::std::option::Option::None, || {
+                    serial_test::run_with_max_hold(30000u64, concat!(module_path!(), "::", "foo"), || {})
+                } );
+            }
+        };
+        compare_streams(compare, stream);
+
+        let stream = local_parallel_core(quote! { db, max_hold = 30000 }, input);
+        let compare = quote! {
+            ::core::compile_error! {
+                "max_hold is only supported by #[parallel] on async tests"
+            }
+        };
+        compare_streams(compare, stream);
+    }
+
+    #[test]
+    fn test_abort_on_timeout() {
+        init();
+        let input = quote! {
+            #[test]
+            fn foo() {}
+        };
+        let stream = local_serial_core(
+            quote! { db, max_hold = 30000, abort_on_timeout },
+            input.clone(),
+        );
+        let compare = quote! {
+            ::core::compile_error! {
+                "#[serial] always aborts when max_hold runs out, so doesn't need abort_on_timeout"
+            }
+        };
+        compare_streams(compare, stream);
+
+        let stream = local_serial_core(quote! { db, abort_on_timeout }, input);
+        let compare = quote! {
+            ::core::compile_error! {
+                "abort_on_timeout is what happens when max_hold runs out, so needs #[serial(max_hold = ..., ...)]"
+            }
+        };
+        compare_streams(compare, stream);
+    }
+
+    #[test]
+    #[cfg(feature = "async")]
+    fn test_max_hold_async() {
+        init();
+        let input = quote! {
+            async fn foo() {}
+        };
+        let stream = local_serial_core(quote! { db, max_hold = 30000 }, input);
+        let compare = quote! {
+            ::core::compile_error! {
+                "max_hold isn't supported for async tests"
+            }
+        };
+        assert_eq!(format!("{}", compare), format!("{}", stream));
+    }
+
+    #[test]
+    #[cfg(feature = "async")]
+    fn test_max_hold_async_parallel() {
+        init();
+        let input = quote! {
+            async fn foo() {}
+        };
+        let stream = local_parallel_core(quote! { db, max_hold = 30000 }, input.clone());
+        let compare = quote! {
+            async fn foo () {
+                async fn _foo_internal () {
+                    serial_test::run_async_with_max_hold(30000u64, concat!(module_path!(), "::", "foo"), serial_test::OnTimeout::Panic, async move {}).await
+                }
+                serial_test::register_parallel_test(["db"], concat!(module_path!(), "::", "foo"));
+                serial_test::local_async_parallel_core(vec!["db"], ::std::option::Option::None, _foo_internal() ).await;
+            }
+        };
+        compare_streams(compare, stream);
+
+        let stream = fs_parallel_core(quote! { db, max_hold = 30000, abort_on_timeout }, input);
+        let compare = quote! {
+            async fn foo () {
+                async fn _foo_internal () {
+                    serial_test::run_async_with_max_hold(30000u64, concat!(module_path!(), "::", "foo"), serial_test::OnTimeout::Abort, async move {}).await
+                }
+                serial_test::fs_async_parallel_core(vec!["db"], ::std::option::Option::None, ::std::option::Option::None, serial_test::LockType::Flock, _foo_internal() ).await;
+            }
+        };
+        compare_streams(compare, stream);
+    }
+
+    #[test]
+    fn test_with_context() {
+        init();
+        let input = quote! {
+            #[test]
+            fn foo() {}
+        };
+        let stream = local_serial_core(quote! { db, with_context = "my_crate::make_pool" }, input);
+        let compare = quote! {
+            #[test]
+            fn foo () {
+                serial_test::local_serial_core(vec!["db"], ::std::option::Option::None, || {
+                    let _serial_test_with_context = serial_test::set_context(my_crate::make_pool());
+                    { }
+                } );
+            }
+        };
+        compare_streams(compare, stream);
+    }
+
+    #[test]
+    #[should_panic(expected = "Expected a function path as with_context arg")]
+    fn test_with_context_not_a_path() {
+        init();
+        let input = quote! {
+            #[test]
+            fn foo() {}
+        };
+        local_serial_core(quote! { db, with_context = "not a path" }, input);
+    }
+
+    #[test]
+    #[cfg(feature = "async")]
+    fn test_context_async() {
+        init();
+        let attrs: Vec<_> = quote! { context = context_for }.into_iter().collect();
+        let input = quote! {
+            async fn foo() {}
+        };
+        let stream = local_serial_core(proc_macro2::TokenStream::from_iter(attrs), input);
+        let compare = quote! {
+            async fn foo () {
+                async fn _foo_internal () {
+                    let _serial_test_context = context_for(&[""]);
+                    { }
+                }
+                serial_test::local_async_serial_core(vec![""], ::std::option::Option::None, _foo_internal() ).await;
+            }
+        };
+        assert_eq!(format!("{}", compare), format!("{}", stream));
+    }
+
+    #[test]
+    fn test_deny_parallel_siblings_serial_first() {
+        init();
+        let input = quote! {
+            #[test]
+            fn foo() {}
+        };
+        local_serial_core(quote! { db, deny_parallel_siblings = true }, input.clone());
+        let stream = local_parallel_core(quote! { db }, input);
+        let compare = quote! {
+            ::core::compile_error! {
+                "Key 'db' is used by both #[serial(deny_parallel_siblings = true)] and #[parallel] tests"
+            }
+        };
+        compare_streams(compare, stream);
+    }
+
+    #[test]
+    fn test_deny_parallel_siblings_parallel_first() {
+        init();
+        let input = quote! {
+            #[test]
+            fn foo() {}
+        };
+        local_parallel_core(quote! { db }, input.clone());
+        local_serial_core(quote! { db }, input.clone());
+        let stream = local_serial_core(quote! { db, deny_parallel_siblings = true }, input);
+        let compare = quote! {
+            ::core::compile_error! {
+                "Key 'db' is used by both #[serial(deny_parallel_siblings = true)] and #[parallel] tests"
+            }
+        };
+        compare_streams(compare, stream);
+    }
+
+    #[test]
+    fn test_deny_parallel_siblings_other_key() {
+        init();
+        let input = quote! {
+            #[test]
+            fn foo() {}
+        };
+        local_parallel_core(quote! { other }, input.clone());
+        let stream = local_serial_core(quote! { db, deny_parallel_siblings = true }, input);
+        let compare = quote! {
+            #[test]
+            fn foo () {
+                serial_test::local_serial_core(vec!["db"], ::std::option::Option::None, || {} );
+            }
+        };
+        compare_streams(compare, stream);
+    }
+
+    #[test]
+    fn test_serial_suite() {
+        init();
+        let stream = serial_suite_core(quote! { "my-suite", [test_one, other::test_two] });
+        let compare = quote! {
+            #[test]
+            fn suite_my_suite_test_one () {
+                serial_test::local_serial_core(vec!["my-suite"], ::std::option::Option::None, test_one);
+            }
+
+            #[test]
+            fn suite_my_suite_test_two () {
+                serial_test::local_serial_core(vec!["my-suite"], ::std::option::Option::None, other::test_two);
+            }
+        };
+        assert_eq!(format!("{}", compare), format!("{}", stream));
+    }
+
+    #[test]
+    fn test_serial_block() {
+        init();
+        let stream = serial_block_core(quote! { "db", "cache", { foo(); 42 } });
+        let compare = quote! {
+            serial_test::with_serial_keys_ordered(&["db", "cache"], || { foo(); 42 })
+        };
+        assert_eq!(format!("{}", compare), format!("{}", stream));
+    }
+
+    #[test]
+    fn test_serial_block_without_block() {
+        init();
+        let stream = serial_block_core(quote! { "db" });
+        assert!(format!("{}", stream).contains("compile_error"));
+    }
+
+    #[test]
+    fn test_measure_overhead() {
+        init();
+        let input = quote! {
+            #[test]
+            fn foo() {}
+        };
+        let stream = local_serial_core(quote! { db, measure_overhead }, input);
+        let compare = quote! {
+            #[test]
+            fn foo () {
+                serial_test::start_overhead_timer();
+                serial_test::local_serial_core(vec!["db"], ::std::option::Option::None, || {
+                    serial_test::report_overhead("db", "foo");
+                    {}
+                } );
+            }
+        };
+        compare_streams(compare, stream);
+    }
+
+    #[test]
+    fn test_measure_overhead_report_as() {
+        init();
+        let input = quote! {
+            #[test]
+            fn foo() {}
+        };
+        let stream = local_serial_core(
+            quote! { db, measure_overhead, report_as = "Database tests" },
+            input.clone(),
+        );
+        let compare = quote! {
+            #[test]
+            fn foo () {
+                serial_test::start_overhead_timer();
+                serial_test::local_serial_core(vec!["db"], ::std::option::Option::None, || {
+                    serial_test::report_suite_overhead("db", "foo", "Database tests");
+                    {}
+                } );
+            }
+        };
+        compare_streams(compare, stream);
+
+        let stream = local_serial_core(quote! { db, report_as = "Database tests" }, input);
+        let compare = quote! {
+            ::core::compile_error! {
+                "report_as names the suite in the overhead report, so needs #[serial(measure_overhead, ...)]"
+            }
+        };
+        compare_streams(compare, stream);
+    }
+
+    #[test]
+    fn test_exclusive_with() {
+        init();
+        let input = quote! {
+            #[test]
+            fn foo() {}
+        };
+        let stream = local_serial_core(quote! { b_key, exclusive_with = "a_parallel" }, input);
+        let compare = quote! {
+            #[test]
+            fn foo () {
+                serial_test::local_serial_core(vec!["a_parallel", "b_key"], ::std::option::Option::None, || {} );
+            }
+        };
+        compare_streams(compare, stream);
+    }
+
+    #[test]
+    fn test_parallel_exclusive_with() {
+        init();
+        let input = quote! {
+            #[test]
+            fn foo() {}
+        };
+        let stream = local_parallel_core(quote! { a, exclusive_with = "b" }, input);
+        let compare = quote! {
+            ::core::compile_error! {
+                "exclusive_with isn't supported by #[parallel]"
+            }
+        };
+        compare_streams(compare, stream);
+    }
+
+    #[test]
+    fn test_discriminator() {
+        init();
+        let input = quote! {
+            #[test]
+            fn foo() {}
+        };
+        let stream = local_serial_core(quote! { tenant, discriminator = tenant_id() + 1 }, input);
+        let compare = quote! {
+            #[test]
+            fn foo () {
+                let _serial_test_keys = serial_test::discriminated_keys(&["tenant"], &(tenant_id() + 1));
+                serial_test::local_serial_core(_serial_test_keys.iter().map(|key| key.as_str()).collect(), ::std::option::Option::None, || {} );
+            }
+        };
+        compare_streams(compare, stream);
+    }
+
+    #[test]
+    fn test_group_by() {
+        init();
+        let input = quote! {
+            #[test]
+            fn foo() {}
+        };
+        let stream = local_serial_core(quote! { group_by = format!("group_{}", param % 3) }, input);
+        let compare = quote! {
+            #[test]
+            fn foo () {
+                let _serial_test_keys = vec![(format!("group_{}", param % 3)).to_string()];
+                serial_test::local_serial_core(_serial_test_keys.iter().map(|key| key.as_str()).collect(), ::std::option::Option::None, || {} );
+            }
+        };
+        compare_streams(compare, stream);
+    }
+
+    #[test]
+    fn test_const_key() {
+        init();
+        let input = quote! {
+            #[test]
+            fn foo() {}
+        };
+        let stream = local_serial_core(quote! { const_key = DB_LOCK }, input.clone());
+        let compare = quote! {
+            #[test]
+            fn foo () {
+                serial_test::local_serial_core(vec![DB_LOCK], ::std::option::Option::None, || {} );
+            }
+        };
+        compare_streams(compare, stream);
+
+        let stream = local_serial_core(
+            quote! { queue, const_key = DB_LOCK, const_key = CACHE_LOCK },
+            input.clone(),
+        );
+        let compare = quote! {
+            #[test]
+            fn foo () {
+                let _serial_test_keys = serial_test::with_const_keys(&["queue"], &[DB_LOCK, CACHE_LOCK]);
+                serial_test::local_serial_core(_serial_test_keys.iter().map(|key| key.as_str()).collect(), ::std::option::Option::None, || {} );
+            }
+        };
+        compare_streams(compare, stream);
+
+        let stream = local_serial_core(quote! { const_key = DB_LOCK, group_by = 1 }, input);
+        let compare = quote! {
+            ::core::compile_error! {
+                "#[serial(const_key = ...)] can't be used with discriminator, group_by, keys_from_env, scope or paths"
+            }
+        };
+        compare_streams(compare, stream);
+    }
+
+    #[test]
+    fn test_typed_key() {
+        init();
+        let input = quote! {
+            #[test]
+            fn foo() {}
+        };
+        let stream = local_serial_core(quote! { key = Resource::Db }, input.clone());
+        let compare = quote! {
+            #[test]
+            fn foo () {
+                serial_test::local_serial_core(vec![serial_test::SerialKey::serial_key(&Resource::Db)], ::std::option::Option::None, || {} );
+            }
+        };
+        compare_streams(compare, stream);
+
+        let stream = local_serial_core(
+            quote! { queue, key = Resource::Db, const_key = CACHE_LOCK },
+            input.clone(),
+        );
+        let compare = quote! {
+            #[test]
+            fn foo () {
+                let _serial_test_keys = serial_test::with_const_keys(&["queue"], &[CACHE_LOCK, serial_test::SerialKey::serial_key(&Resource::Db)]);
+                serial_test::local_serial_core(_serial_test_keys.iter().map(|key| key.as_str()).collect(), ::std::option::Option::None, || {} );
+            }
+        };
+        compare_streams(compare, stream);
+
+        // Without an `=`, it's still an ordinary key
+        let stream = local_serial_core(quote! { key }, input.clone());
+        let compare = quote! {
+            #[test]
+            fn foo () {
+                serial_test::local_serial_core(vec!["key"], ::std::option::Option::None, || {} );
+            }
+        };
+        compare_streams(compare, stream);
+
+        let stream = local_serial_core(quote! { key = Resource::Db, group_by = 1 }, input);
+        let compare = quote! {
+            ::core::compile_error! {
+                "#[serial(key = ...)] can't be used with discriminator, group_by, keys_from_env, scope or paths"
+            }
+        };
+        compare_streams(compare, stream);
+    }
+
+    #[test]
+    fn test_serial_key_derive() {
+        let stream = serial_key_core(quote! {
+            enum Resource {
+                #[serial_key = "db"]
+                Db,
+                Queue,
+            }
+        });
+        let compare = quote! {
+            impl serial_test::SerialKey for Resource {
+                fn serial_key(&self) -> &'static str {
+                    match *self {
+                        Resource::Db => "db",
+                        Resource::Queue => "Queue",
+                    }
+                }
+            }
+
+            impl ::std::convert::AsRef<str> for Resource {
+                fn as_ref(&self) -> &str {
+                    serial_test::SerialKey::serial_key(self)
+                }
+            }
+
+            impl ::std::convert::From<Resource> for ::std::borrow::Cow<'static, str> {
+                fn from(key: Resource) -> Self {
+                    ::std::borrow::Cow::Borrowed(serial_test::SerialKey::serial_key(&key))
+                }
+            }
+        };
+        assert_eq!(format!("{}", compare), format!("{}", stream));
+    }
+
+    #[test]
+    fn test_keys_from_env() {
+        init();
+        let input = quote! {
+            #[test]
+            fn foo() {}
+        };
+        let stream =
+            local_serial_core(quote! { keys_from_env = "SERIAL_TEST_KEYS" }, input.clone());
+        let compare = quote! {
+            #[test]
+            fn foo () {
+                let _serial_test_keys = serial_test::keys_from_env("SERIAL_TEST_KEYS");
+                serial_test::local_serial_core(_serial_test_keys.iter().map(|key| key.as_str()).collect(), ::std::option::Option::None, || {} );
+            }
+        };
+        compare_streams(compare, stream);
+
+        let stream = local_serial_core(quote! { db, keys_from_env = "SERIAL_TEST_KEYS" }, input);
+        let compare = quote! {
+            ::core::compile_error! {
+                "#[serial(keys_from_env = ...)] picks the keys itself, so can't be used with keys, discriminator, group_by, scope or context"
+            }
+        };
+        compare_streams(compare, stream);
+    }
+
+    #[test]
+    fn test_group_by_with_key() {
+        init();
+        let input = quote! {
+            #[test]
+            fn foo() {}
+        };
+        let stream = local_serial_core(quote! { db, group_by = param }, input);
+        let compare = quote! {
+            ::core::compile_error! {
+                "#[serial(group_by = ...)] picks the key itself, so can't be used with keys, discriminator, scope or context"
+            }
+        };
+        compare_streams(compare, stream);
+    }
+
+    #[test]
+    fn test_discriminator_without_key() {
+        init();
+        let input = quote! {
+            #[test]
+            fn foo() {}
+        };
+        let stream = local_serial_core(quote! { discriminator = 1 }, input);
+        let compare = quote! {
+            ::core::compile_error! {
+                "#[serial(discriminator = ...)] needs a key to add the discriminator to"
+            }
+        };
+        compare_streams(compare, stream);
+    }
+
+    #[test]
+    fn test_require_single_thread() {
+        init();
+        let input = quote! {
+            #[test]
+            fn foo() {}
+        };
+        let stream = local_serial_core(quote! { require_single_thread }, input);
+        let compare = quote! {
+            #[test]
+            fn foo () {
+                serial_test::require_single_thread("foo");
+                serial_test::local_serial_core(vec![""], ::std::option::Option::None, || {} );
+            }
+        };
+        compare_streams(compare, stream);
+    }
+
+    #[test]
+    fn test_non_reentrant() {
+        init();
+        let input = quote! {
+            #[test]
+            fn foo() {}
+        };
+        let stream = local_serial_core(quote! { db, non_reentrant }, input);
+        let compare = quote! {
+            #[test]
+            fn foo () {
+                serial_test::deny_reentry(["db"], "foo");
+                serial_test::local_serial_core(vec!["db"], ::std::option::Option::None, || {} );
+            }
+        };
+        compare_streams(compare, stream);
+    }
+
+    #[test]
+    fn test_serial_and_parallel() {
+        init();
+        let attrs = proc_macro2::TokenStream::new();
+        let input = quote! {
+            #[test]
+            #[parallel]
+            fn foo() {}
         };
-
-        prettyplease::unparse(&file)
+        let stream = local_serial_core(attrs, input);
+        let compare = quote! {
+            ::core::compile_error! {
+                "Can't use both #[serial] and #[parallel] on the same function"
+            }
+        };
+        compare_streams(compare, stream);
     }
 
-    fn compare_streams(first: TokenStream, second: TokenStream) {
-        let f = unparse(first);
-        assert_eq!(f, unparse(second));
+    #[test]
+    fn test_serial_extern_c() {
+        init();
+        let attrs = proc_macro2::TokenStream::new();
+        let input = quote! {
+            extern "C" fn test_ffi() {}
+        };
+        let stream = local_serial_core(attrs, input);
+        let compare = quote! {
+            extern "C" fn test_ffi () {
+                serial_test::local_serial_core(vec![""], ::std::option::Option::None, || {} );
+            }
+        };
+        compare_streams(compare, stream);
     }
 
     #[test]
-    fn test_serial() {
+    fn test_serial_with_test_log() {
         init();
         let attrs = proc_macro2::TokenStream::new();
         let input = quote! {
-            #[test]
+            #[test_log::test]
             fn foo() {}
         };
-        let stream = local_serial_core(attrs.into(), input);
+        let stream = local_serial_core(attrs, input);
+        // Left for test_log to expand afterwards, so its logging setup runs before we take the lock
         let compare = quote! {
-            #[test]
+            #[test_log::test]
             fn foo () {
                 serial_test::local_serial_core(vec![""], ::std::option::Option::None, || {} );
             }
@@ -524,188 +3393,337 @@ mod tests {
     }
 
     #[test]
-    fn test_serial_with_pub() {
+    fn test_dual_serial() {
         init();
-        let attrs = proc_macro2::TokenStream::new();
         let input = quote! {
             #[test]
-            pub fn foo() {}
+            fn bar() {}
         };
-        let stream = local_serial_core(attrs.into(), input);
+        let stream = dual_serial_core(quote! { foo }, input);
         let compare = quote! {
             #[test]
-            pub fn foo () {
-                serial_test::local_serial_core(vec![""], ::std::option::Option::None, || {} );
+            fn bar () {
+                serial_test::local_serial_core(vec!["foo"], ::std::option::Option::None, || serial_test::fs_serial_core(vec!["foo"], ::std::option::Option::None, serial_test::FileSerialOptions { holder: concat!(module_path!(), "::", "bar"), expiry_ms: ::std::option::Option::None, watch_path: false, lock_type: serial_test::LockType::Flock, }, || {} ) );
             }
         };
         compare_streams(compare, stream);
     }
 
     #[test]
-    fn test_other_attributes() {
+    #[cfg(feature = "async")]
+    fn test_dual_serial_async() {
+        init();
+        let input = quote! {
+            async fn bar() {}
+        };
+        let stream = dual_serial_core(quote! { foo }, input);
+        let compare = quote! {
+            async fn bar () {
+                async fn _bar_internal () { }
+                serial_test::local_async_serial_core(vec!["foo"], ::std::option::Option::None, serial_test::fs_async_serial_core(vec!["foo"], ::std::option::Option::None, serial_test::FileSerialOptions { holder: concat!(module_path!(), "::", "bar"), expiry_ms: ::std::option::Option::None, watch_path: false, lock_type: serial_test::LockType::Flock, }, _bar_internal() ) ).await;
+            }
+        };
+        compare_streams(compare, stream);
+    }
+
+    #[test]
+    fn test_file_serial_expiry() {
         init();
-        let attrs = proc_macro2::TokenStream::new();
         let input = quote! {
             #[test]
-            #[ignore]
-            #[should_panic(expected = "Testing panic")]
-            #[something_else]
             fn foo() {}
         };
-        let stream = local_serial_core(attrs.into(), input);
+        let stream = fs_serial_core(quote! { key, expiry_ms = 60000 }, input.clone());
         let compare = quote! {
             #[test]
-            #[ignore]
-            #[should_panic(expected = "Testing panic")]
-            #[something_else]
             fn foo () {
-                serial_test::local_serial_core(vec![""], ::std::option::Option::None, || {} );
+                serial_test::fs_serial_core(vec!["key"], ::std::option::Option::None, serial_test::FileSerialOptions { holder: concat!(module_path!(), "::", "foo"), expiry_ms: ::std::option::Option::Some(60000u64), watch_path: false, lock_type: serial_test::LockType::Flock, }, || {} );
+            }
+        };
+        compare_streams(compare, stream);
+
+        let stream = local_serial_core(quote! { key, expiry_ms = 60000 }, input);
+        let compare = quote! {
+            ::core::compile_error! {
+                "expiry_ms isn't supported by #[serial]"
             }
         };
         compare_streams(compare, stream);
     }
 
     #[test]
-    #[cfg(feature = "async")]
-    fn test_serial_async() {
+    fn test_file_serial_watch_path() {
         init();
-        let attrs = proc_macro2::TokenStream::new();
         let input = quote! {
-            async fn foo() {}
+            #[test]
+            fn foo() {}
         };
-        let stream = local_serial_core(attrs.into(), input);
+        let stream = fs_serial_core(quote! { key, watch_path }, input.clone());
         let compare = quote! {
-            async fn foo () {
-                async fn _foo_internal () { }
-                serial_test::local_async_serial_core(vec![""], ::std::option::Option::None, _foo_internal() ).await;
+            #[test]
+            fn foo () {
+                serial_test::fs_serial_core(vec!["key"], ::std::option::Option::None, serial_test::FileSerialOptions { holder: concat!(module_path!(), "::", "foo"), expiry_ms: ::std::option::Option::None, watch_path: true, lock_type: serial_test::LockType::Flock, }, || {} );
             }
         };
-        assert_eq!(format!("{}", compare), format!("{}", stream));
+        compare_streams(compare, stream);
+
+        let stream = fs_parallel_core(quote! { key, watch_path }, input);
+        let compare = quote! {
+            ::core::compile_error! {
+                "watch_path isn't supported by #[file_parallel]"
+            }
+        };
+        compare_streams(compare, stream);
     }
 
     #[test]
-    #[cfg(feature = "async")]
-    fn test_serial_async_return() {
+    fn test_file_lock_type() {
         init();
-        let attrs = proc_macro2::TokenStream::new();
         let input = quote! {
-            async fn foo() -> Result<(), ()> { Ok(()) }
+            #[test]
+            fn foo() {}
         };
-        let stream = local_serial_core(attrs.into(), input);
+        let stream = fs_serial_core(quote! { key, lock_type = "open_exclusive" }, input.clone());
         let compare = quote! {
-            async fn foo () -> Result<(), ()> {
-                async fn _foo_internal ()  -> Result<(), ()> { Ok(()) }
-                serial_test::local_async_serial_core_with_return(vec![""], ::std::option::Option::None, _foo_internal() ).await
+            #[test]
+            fn foo () {
+                serial_test::fs_serial_core(vec!["key"], ::std::option::Option::None, serial_test::FileSerialOptions { holder: concat!(module_path!(), "::", "foo"), expiry_ms: ::std::option::Option::None, watch_path: false, lock_type: serial_test::LockType::OpenExclusive, }, || {} );
             }
         };
-        assert_eq!(format!("{}", compare), format!("{}", stream));
+        compare_streams(compare, stream);
+
+        let stream = fs_parallel_core(quote! { key, lock_type = "fcntl" }, input.clone());
+        let compare = quote! {
+            #[test]
+            fn foo () {
+                serial_test::fs_parallel_core(vec!["key"], ::std::option::Option::None, ::std::option::Option::None, serial_test::LockType::Fcntl, || {} );
+            }
+        };
+        compare_streams(compare, stream);
+
+        let stream = local_serial_core(quote! { key, lock_type = "flock" }, input);
+        let compare = quote! {
+            ::core::compile_error! {
+                "lock_type isn't supported by #[serial]"
+            }
+        };
+        compare_streams(compare, stream);
     }
 
     #[test]
-    fn test_file_serial() {
+    #[should_panic(
+        expected = "Expected flock, fcntl or open_exclusive as lock_type arg, not lockf"
+    )]
+    fn test_file_lock_type_unknown() {
+        init();
+        fs_serial_core(
+            quote! { key, lock_type = "lockf" },
+            quote! {
+                #[test]
+                fn foo() {}
+            },
+        );
+    }
+
+    #[test]
+    fn test_file_parallel_max_parallel() {
         init();
-        let attrs: Vec<_> = quote! { foo }.into_iter().collect();
         let input = quote! {
             #[test]
             fn foo() {}
         };
-        let stream = fs_serial_core(
-            proc_macro2::TokenStream::from_iter(attrs.into_iter()),
-            input,
-        );
+        let stream = fs_parallel_core(quote! { key, max_parallel = 2 }, input.clone());
         let compare = quote! {
             #[test]
             fn foo () {
-                serial_test::fs_serial_core(vec!["foo"], ::std::option::Option::None, || {} );
+                serial_test::fs_parallel_core(vec!["key"], ::std::option::Option::None, ::std::option::Option::Some(2u32), serial_test::LockType::Flock, || {} );
+            }
+        };
+        compare_streams(compare, stream);
+
+        let stream = local_parallel_core(quote! { key, max_parallel = 2 }, input);
+        let compare = quote! {
+            ::core::compile_error! {
+                "max_parallel isn't supported by #[parallel]"
             }
         };
         compare_streams(compare, stream);
     }
 
     #[test]
-    fn test_file_serial_no_args() {
+    fn test_defer_start_ms() {
         init();
-        let attrs = proc_macro2::TokenStream::new();
         let input = quote! {
             #[test]
             fn foo() {}
         };
-        let stream = fs_serial_core(
-            proc_macro2::TokenStream::from_iter(attrs.into_iter()),
-            input,
-        );
+        let stream = local_parallel_core(quote! { key, defer_start_ms = 10 }, input.clone());
+        let compare = quote! {
+            #[test]
+            fn foo () {
+                serial_test::register_parallel_test(["key"], concat!(module_path!(), "::", "foo"));
+                serial_test::defer_parallel_start(["key"], 10u64);
+                serial_test::local_parallel_core(vec!["key"], ::std::option::Option::None, || {} );
+            }
+        };
+        compare_streams(compare, stream);
+
+        // Zero is the same as not having it
+        let stream = fs_parallel_core(quote! { key, defer_start_ms = 0 }, input.clone());
         let compare = quote! {
             #[test]
             fn foo () {
-                serial_test::fs_serial_core(vec![""], ::std::option::Option::None, || {} );
+                serial_test::fs_parallel_core(vec!["key"], ::std::option::Option::None, ::std::option::Option::None, serial_test::LockType::Flock, || {} );
+            }
+        };
+        compare_streams(compare, stream);
+
+        let stream = local_serial_core(quote! { key, defer_start_ms = 10 }, input);
+        let compare = quote! {
+            ::core::compile_error! {
+                "defer_start_ms isn't supported by #[serial]"
             }
         };
         compare_streams(compare, stream);
     }
 
     #[test]
-    fn test_file_serial_with_path() {
+    #[should_panic(expected = "Expected a positive integer as max_parallel arg, not 0")]
+    fn test_max_parallel_zero() {
+        get_config(quote! { max_parallel = 0 });
+    }
+
+    #[test]
+    fn test_default_key() {
+        assert_eq!(default_key_from(None), "");
+        assert_eq!(default_key_from(Some("my_crate".to_string())), "my_crate");
+    }
+
+    #[test]
+    fn test_scope() {
         init();
-        let attrs: Vec<_> = quote! { foo, path => "bar_path" }.into_iter().collect();
         let input = quote! {
             #[test]
             fn foo() {}
         };
-        let stream = fs_serial_core(
-            proc_macro2::TokenStream::from_iter(attrs.into_iter()),
-            input,
-        );
+        let stream = local_serial_core(quote! { scope = "module" }, input.clone());
+        let compare = quote! {
+            #[test]
+            fn foo () {
+                serial_test::local_serial_core(vec![module_path!()], ::std::option::Option::None, || {} );
+            }
+        };
+        compare_streams(compare, stream);
+        let stream = local_parallel_core(quote! { scope = "function" }, input.clone());
+        let compare = quote! {
+            #[test]
+            fn foo () {
+                serial_test::register_parallel_test([concat!(module_path!(), "::", "foo")], concat!(module_path!(), "::", "foo"));
+                serial_test::local_parallel_core(vec![concat!(module_path!(), "::", "foo")], ::std::option::Option::None, || {} );
+            }
+        };
+        compare_streams(compare, stream);
+        let stream = local_serial_core(quote! { scope = "crate" }, input);
         let compare = quote! {
             #[test]
             fn foo () {
-                serial_test::fs_serial_core(vec!["foo"], ::std::option::Option::Some("bar_path"), || {} );
+                serial_test::local_serial_core(vec![env!("CARGO_PKG_NAME")], ::std::option::Option::None, || {} );
             }
         };
         compare_streams(compare, stream);
     }
 
     #[test]
-    fn test_single_attr() {
+    fn test_scope_with_key() {
         init();
-        let attrs: Vec<_> = quote! { one}.into_iter().collect();
         let input = quote! {
             #[test]
-            fn single() {}
+            fn foo() {}
         };
-        let stream = local_serial_core(
-            proc_macro2::TokenStream::from_iter(attrs.into_iter()),
-            input,
-        );
+        let stream = local_serial_core(quote! { db, scope = "module" }, input);
+        let compare = quote! {
+            ::core::compile_error! {
+                "#[serial(scope = ...)] picks the key itself, so can't be used with keys"
+            }
+        };
+        compare_streams(compare, stream);
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "Expected scope to be \"crate\", \"module\" or \"function\", not \"file\""
+    )]
+    fn test_scope_unknown() {
+        get_config(quote! { scope = "file" });
+    }
+
+    #[test]
+    fn test_desc() {
+        init();
+        let input = quote! {
+            #[test]
+            fn foo() {}
+        };
+        let stream = local_parallel_core(quote! { db, desc = "PostgreSQL test DB" }, input);
         let compare = quote! {
             #[test]
-            fn single () {
-                serial_test::local_serial_core(vec!["one"], ::std::option::Option::None, || {} );
+            fn foo () {
+                serial_test::describe_keys(["db"], "PostgreSQL test DB");
+                serial_test::register_parallel_test(["db"], concat!(module_path!(), "::", "foo"));
+                serial_test::local_parallel_core(vec!["db"], ::std::option::Option::None, || {} );
             }
         };
         compare_streams(compare, stream);
     }
 
     #[test]
-    fn test_multiple_attr() {
+    fn test_file_serial_desc() {
         init();
-        let attrs: Vec<_> = quote! { two, one }.into_iter().collect();
         let input = quote! {
             #[test]
-            fn multiple() {}
+            fn foo() {}
         };
-        let stream = local_serial_core(
-            proc_macro2::TokenStream::from_iter(attrs.into_iter()),
-            input,
-        );
+        let stream = fs_serial_core(quote! { db, desc = "PostgreSQL test DB" }, input);
         let compare = quote! {
+            ::core::compile_error! {
+                "desc isn't supported by #[file_serial]"
+            }
+        };
+        compare_streams(compare, stream);
+    }
+
+    #[test]
+    fn test_parallel_non_reentrant() {
+        init();
+        let input = quote! {
             #[test]
-            fn multiple () {
-                serial_test::local_serial_core(vec!["one", "two"], ::std::option::Option::None, || {} );
+            fn foo() {}
+        };
+        let stream = local_parallel_core(quote! { db, non_reentrant }, input);
+        let compare = quote! {
+            ::core::compile_error! {
+                "non_reentrant isn't supported by #[parallel]"
             }
         };
         compare_streams(compare, stream);
     }
 
+    #[test]
+    fn test_default_span_name() {
+        assert_eq!(
+            default_span_name(&get_config(quote! { db }), "serial"),
+            "serial_lock.db"
+        );
+        assert_eq!(
+            default_span_name(&get_config(quote! { b, a }), "parallel"),
+            "parallel_lock.a,b"
+        );
+        assert_eq!(
+            default_span_name(&get_config(quote! {}), "serial"),
+            "serial_lock"
+        );
+    }
+
     #[test]
     fn test_mod() {
         init();
@@ -722,10 +3740,7 @@ mod tests {
                 fn bar() {}
             }
         };
-        let stream = local_serial_core(
-            proc_macro2::TokenStream::from_iter(attrs.into_iter()),
-            input,
-        );
+        let stream = local_serial_core(proc_macro2::TokenStream::from_iter(attrs), input);
         let compare = quote! {
             #[cfg(test)]
             mod serial_attr_tests {
@@ -742,6 +3757,31 @@ mod tests {
         compare_streams(compare, stream);
     }
 
+    #[test]
+    fn test_parallel_mod() {
+        init();
+        let input = quote! {
+            #[cfg(test)]
+            #[parallel]
+            mod parallel_attr_tests {
+                #[test]
+                fn bar() {}
+            }
+        };
+        let stream = local_parallel_core(TokenStream::new(), input);
+        let compare = quote! {
+            #[cfg(test)]
+            mod parallel_attr_tests {
+                #[test]
+                fn bar() {
+                    serial_test::register_parallel_test([""], concat!(module_path!(), "::", "bar"));
+                    serial_test::local_parallel_core(vec![""], ::std::option::Option::None, || {} );
+                }
+            }
+        };
+        compare_streams(compare, stream);
+    }
+
     #[test]
     fn test_later_test_mod() {
         init();
@@ -758,10 +3798,7 @@ mod tests {
                 fn bar() {}
             }
         };
-        let stream = local_serial_core(
-            proc_macro2::TokenStream::from_iter(attrs.into_iter()),
-            input,
-        );
+        let stream = local_serial_core(proc_macro2::TokenStream::from_iter(attrs), input);
         let compare = quote! {
             #[cfg(test)]
             mod serial_attr_tests {
@@ -778,6 +3815,35 @@ mod tests {
         compare_streams(compare, stream);
     }
 
+    #[test]
+    fn test_cfg_attr_test_mod() {
+        init();
+        let attrs = proc_macro2::TokenStream::new();
+        let input = quote! {
+            #[serial]
+            mod serial_attr_tests {
+                #[cfg_attr(test, test)]
+                fn foo() {}
+
+                #[cfg_attr(test, allow(dead_code))]
+                fn bar() {}
+            }
+        };
+        let stream = local_serial_core(proc_macro2::TokenStream::from_iter(attrs), input);
+        let compare = quote! {
+            mod serial_attr_tests {
+                #[cfg_attr(test, test)]
+                fn foo() {
+                    serial_test::local_serial_core(vec![""], ::std::option::Option::None, || {} );
+                }
+
+                #[cfg_attr(test, allow(dead_code))]
+                fn bar() {}
+            }
+        };
+        compare_streams(compare, stream);
+    }
+
     #[test]
     #[cfg(feature = "async")]
     fn test_mod_with_async() {
@@ -799,10 +3865,7 @@ mod tests {
                 }
             }
         };
-        let stream = local_serial_core(
-            proc_macro2::TokenStream::from_iter(attrs.into_iter()),
-            input,
-        );
+        let stream = local_serial_core(proc_macro2::TokenStream::from_iter(attrs), input);
         let compare = quote! {
             #[cfg(test)]
             mod serial_attr_tests {
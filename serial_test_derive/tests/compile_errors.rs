@@ -0,0 +1,17 @@
+// Compiler output changes between versions, so the .stderr files are only checked against current stable
+#[rustversion::attr(any(not(stable), before(1.80)), ignore)]
+#[test]
+fn compile_errors() {
+    let t = trybuild::TestCases::new();
+    t.compile_fail("tests/compile_tests/*.rs");
+    // With the feature on (e.g. from the workspace build), async tests are fine
+    if cfg!(not(feature = "async")) {
+        t.compile_fail("tests/compile_tests/async/*.rs");
+    }
+    // file_serial etc. only get as far as checking their args with file_locks on
+    if cfg!(feature = "file_locks") {
+        t.compile_fail("tests/compile_tests/with_file_locks/*.rs");
+    } else {
+        t.compile_fail("tests/compile_tests/file_locks/*.rs");
+    }
+}
@@ -0,0 +1,6 @@
+use serial_test_derive::serial;
+
+#[serial]
+async fn async_without_feature() {}
+
+fn main() {}
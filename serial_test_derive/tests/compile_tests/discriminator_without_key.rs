@@ -0,0 +1,6 @@
+use serial_test_derive::serial;
+
+#[serial(discriminator = 1)]
+fn discriminator_without_key() {}
+
+fn main() {}
@@ -0,0 +1,6 @@
+use serial_test_derive::file_serial;
+
+#[file_serial]
+fn file_serial_without_feature() {}
+
+fn main() {}
@@ -0,0 +1,6 @@
+use serial_test_derive::require_feature;
+
+#[require_feature("file_locks")]
+mod file_lock_tests {}
+
+fn main() {}
@@ -0,0 +1,6 @@
+use serial_test_derive::parallel;
+
+#[parallel(key, non_reentrant)]
+fn non_reentrant_parallel() {}
+
+fn main() {}
@@ -0,0 +1,7 @@
+use serial_test_derive::serial;
+
+#[serial]
+#[serial_test_derive::parallel]
+fn both() {}
+
+fn main() {}
@@ -0,0 +1,6 @@
+use serial_test_derive::SerialKey;
+
+#[derive(SerialKey)]
+struct Resource;
+
+fn main() {}
@@ -0,0 +1,8 @@
+use serial_test_derive::SerialKey;
+
+#[derive(SerialKey)]
+enum Resource {
+    Db(u32),
+}
+
+fn main() {}
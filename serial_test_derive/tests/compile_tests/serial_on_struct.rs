@@ -0,0 +1,6 @@
+use serial_test_derive::serial;
+
+#[serial]
+struct NotATest;
+
+fn main() {}
@@ -0,0 +1,6 @@
+use serial_test_derive::serial;
+
+#[serial(strict)]
+fn strict_without_key() {}
+
+fn main() {}
@@ -0,0 +1,6 @@
+use serial_test_derive::serial;
+
+#[serial(scope = "file")]
+fn unknown_scope() {}
+
+fn main() {}
@@ -0,0 +1,6 @@
+use serial_test_derive::file_serial;
+
+#[file_serial(key, path = "/tmp/key")]
+fn malformed_path() {}
+
+fn main() {}
@@ -0,0 +1,6 @@
+use serial_test_derive::file_serial;
+
+#[file_serial(one, two, paths => ["/tmp/one"])]
+fn paths_mismatch() {}
+
+fn main() {}
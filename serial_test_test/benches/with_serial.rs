@@ -0,0 +1,22 @@
+//! Benchmarks don't go through `#[serial]`, but can still share keys with the tests using `with_serial`.
+//! This works the same way with criterion's `b.iter(|| with_serial(...))`.
+
+use serial_test::{current_mode, is_locked_serially, with_serial, Mode};
+use std::time::Instant;
+
+#[allow(clippy::print_stdout)]
+fn main() {
+    let iterations = 1000;
+    let start = Instant::now();
+    for _ in 0..iterations {
+        with_serial(["db"], || {
+            // Same keyspace as `#[serial(db)]`, so this would block any of those tests
+            assert!(is_locked_serially(Some("db")));
+            assert_eq!(current_mode(Some("db")), Mode::Serial);
+        });
+    }
+    println!(
+        "with_serial(db): {:?} per iteration",
+        start.elapsed() / iterations
+    );
+}
@@ -92,39 +92,61 @@ mod serial_attr_tests {}
 #[parallel]
 mod parallel_attr_tests {}
 
+#[cfg(test)]
+#[cfg_attr(test, serial(cfg_attr_mod_key))]
+mod cfg_attr_serial_tests {
+    use serial_test::is_locked_serially;
+
+    #[test]
+    fn applied_to_mod() {
+        assert!(is_locked_serially(Some("cfg_attr_mod_key")));
+    }
+
+    #[cfg_attr(test, test)]
+    fn applied_to_mod_with_cfg_attr_test() {
+        assert!(is_locked_serially(Some("cfg_attr_mod_key")));
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(not(test), serial(cfg_attr_mod_key))]
+mod cfg_attr_not_serial_tests {
+    use serial_test::is_locked_serially;
+
+    #[test]
+    fn not_applied_to_mod() {
+        assert!(!is_locked_serially(Some("cfg_attr_mod_key")));
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::{init, test_fn};
     use log::info;
-    use once_cell::sync::OnceCell;
     use parking_lot::Mutex;
-    use serial_test::{parallel, serial};
-    use std::{sync::Barrier, thread, time::Duration};
+    use serial_test::{
+        assert_not_parallel, assert_not_serial, env_lock, is_locked_serially, parallel,
+        parallel_group_size, parallel_rendezvous, parallel_test_set, serial, serial_block,
+        serial_suite, with_serial, SerialKey,
+    };
+    use std::{
+        env,
+        sync::atomic::{AtomicBool, Ordering},
+        thread,
+        time::{Duration, Instant},
+    };
     #[cfg(feature = "async")]
     use wasm_bindgen_test::wasm_bindgen_test;
 
     static THREAD_ORDERINGS: Mutex<Vec<bool>> = Mutex::new(Vec::new());
 
-    #[inline]
-    fn parallel_barrier() -> &'static Barrier {
-        static PARALLEL_BARRIER: OnceCell<Barrier> = OnceCell::new();
-        PARALLEL_BARRIER.get_or_init(|| Barrier::new(3))
-    }
-
     #[cfg(feature = "file_locks")]
     static FS_THREAD_ORDERINGS: Mutex<Vec<bool>> = Mutex::new(Vec::new());
 
-    #[cfg(feature = "file_locks")]
-    #[inline]
-    fn fs_parallel_barrier() -> &'static Barrier {
-        static FS_PARALLEL_BARRIER: OnceCell<Barrier> = OnceCell::new();
-        FS_PARALLEL_BARRIER.get_or_init(|| Barrier::new(3))
-    }
-
     #[cfg(feature = "file_locks")]
     use super::fs_test_fn;
     #[cfg(feature = "file_locks")]
-    use serial_test::{file_parallel, file_serial};
+    use serial_test::{dual_serial, file_parallel, file_serial};
 
     #[test]
     #[serial]
@@ -138,6 +160,229 @@ mod tests {
         init();
     }
 
+    #[test]
+    #[serial(strict, strict_key)]
+    fn test_serial_strict() {
+        init();
+    }
+
+    static WITH_SERIAL_RAN: AtomicBool = AtomicBool::new(false);
+
+    #[test]
+    #[serial(with_serial_key)]
+    fn test_with_serial_shares_keys() {
+        init();
+        // Re-entrant from the same thread, like nested #[serial]
+        with_serial(["with_serial_key"], || {});
+        let waiter = thread::spawn(|| {
+            with_serial(["with_serial_key"], || {
+                WITH_SERIAL_RAN.store(true, Ordering::SeqCst);
+            })
+        });
+        thread::sleep(Duration::from_millis(200));
+        assert!(!WITH_SERIAL_RAN.load(Ordering::SeqCst));
+        assert!(!waiter.is_finished());
+    }
+
+    #[test]
+    #[serial(exclusive_key, exclusive_with = "exclusive_parallel_key")]
+    fn test_serial_exclusive_with() {
+        init();
+        assert!(is_locked_serially(Some("exclusive_key")));
+        assert!(is_locked_serially(Some("exclusive_parallel_key")));
+    }
+
+    fn tenant_id() -> u32 {
+        1
+    }
+
+    #[test]
+    #[serial(tenant, discriminator = tenant_id())]
+    fn test_serial_discriminator() {
+        init();
+        assert!(is_locked_serially(Some("tenant::1")));
+        // Different discriminator, so doesn't wait on us
+        thread::spawn(|| with_serial(["tenant::2"], || {}))
+            .join()
+            .unwrap();
+        // Same discriminator, so does
+        let same = thread::spawn(|| with_serial(["tenant::1"], || {}));
+        thread::sleep(Duration::from_millis(100));
+        assert!(!same.is_finished());
+    }
+
+    #[test]
+    fn test_serial_block() {
+        init();
+        serial_block!("block_first", {
+            assert!(is_locked_serially(Some("block_first")));
+            assert!(!is_locked_serially(Some("block_second")));
+        });
+        let value = serial_block!("block_second", {
+            assert!(!is_locked_serially(Some("block_first")));
+            assert!(is_locked_serially(Some("block_second")));
+            42
+        });
+        assert_eq!(value, 42);
+        assert!(!is_locked_serially(Some("block_second")));
+    }
+
+    #[serial(keys_from_env = "SERIAL_TEST_TEST_KEYS")]
+    fn serial_keys_from_env() {
+        assert!(is_locked_serially(Some("env_key_a")));
+        assert!(is_locked_serially(Some("env_key_b")));
+    }
+
+    #[test]
+    fn test_serial_keys_from_env() {
+        init();
+        env_lock!(
+            "env_lock",
+            set = [("SERIAL_TEST_TEST_KEYS", "env_key_b,env_key_a")],
+            { serial_keys_from_env() }
+        );
+    }
+
+    const CONST_KEY_A: &str = "const_key_a";
+    const CONST_KEY_B: &str = "const_key_b";
+
+    #[test]
+    #[serial(const_key = CONST_KEY_A)]
+    fn test_serial_const_key() {
+        init();
+        assert!(is_locked_serially(Some(CONST_KEY_A)));
+        assert!(!is_locked_serially(Some("CONST_KEY_A")));
+    }
+
+    #[test]
+    #[serial(const_key_c, const_key = CONST_KEY_B, const_key = CONST_KEY_A)]
+    fn test_serial_const_keys() {
+        init();
+        assert!(is_locked_serially(Some(CONST_KEY_A)));
+        assert!(is_locked_serially(Some(CONST_KEY_B)));
+        assert!(is_locked_serially(Some("const_key_c")));
+    }
+
+    #[derive(SerialKey)]
+    enum Resource {
+        #[serial_key = "typed_key_db"]
+        Db,
+        TypedKeyQueue,
+    }
+
+    #[test]
+    #[serial(key = Resource::Db)]
+    fn test_serial_typed_key() {
+        init();
+        assert!(is_locked_serially(Some("typed_key_db")));
+        assert!(!is_locked_serially(Some("Db")));
+    }
+
+    #[test]
+    #[serial(typed_key_other, key = Resource::TypedKeyQueue, key = Resource::Db)]
+    fn test_serial_typed_keys() {
+        init();
+        assert!(is_locked_serially(Some("typed_key_db")));
+        assert!(is_locked_serially(Some("TypedKeyQueue")));
+        assert!(is_locked_serially(Some("typed_key_other")));
+        // The same key as the string version
+        with_serial([Resource::Db], || {
+            assert!(is_locked_serially(Some(Resource::Db.as_ref())));
+        });
+    }
+
+    #[serial(group_by = format!("group_{}", param % 3))]
+    fn serial_grouped(param: u32) {
+        assert!(is_locked_serially(Some(&format!("group_{}", param % 3))));
+        // A different group, so doesn't wait on us
+        let other = format!("group_{}", (param + 1) % 3);
+        thread::spawn(move || with_serial([other.as_str()], || {}))
+            .join()
+            .unwrap();
+    }
+
+    #[test]
+    fn test_serial_group_by() {
+        init();
+        serial_grouped(1);
+        serial_grouped(5);
+    }
+
+    #[test]
+    #[serial(strict_key, non_reentrant)]
+    fn test_serial_non_reentrant() {
+        init();
+    }
+
+    #[test]
+    #[serial(strict_key, non_reentrant)]
+    #[should_panic(expected = "test_serial_non_reentrant is non_reentrant")]
+    fn test_serial_non_reentrant_nested() {
+        init();
+        test_serial_non_reentrant();
+    }
+
+    #[test]
+    #[serial(deny_key, deny_parallel_siblings = true)]
+    fn test_serial_deny_parallel_siblings() {
+        init();
+    }
+
+    fn suite_member_one() {
+        init();
+        assert!(is_locked_serially(Some("test_suite")));
+    }
+
+    fn suite_member_two() {
+        init();
+        assert!(is_locked_serially(Some("test_suite")));
+    }
+
+    serial_suite!("test_suite", [suite_member_one, suite_member_two]);
+
+    static CONTEXT_KEYS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    struct ContextGuard;
+
+    impl Drop for ContextGuard {
+        fn drop(&mut self) {
+            CONTEXT_KEYS.lock().clear();
+        }
+    }
+
+    fn test_context(keys: &[&str]) -> ContextGuard {
+        *CONTEXT_KEYS.lock() = keys.iter().map(|key| key.to_string()).collect();
+        ContextGuard
+    }
+
+    #[test]
+    #[serial(context_key, context = test_context)]
+    fn test_serial_context() {
+        init();
+        assert_eq!(*CONTEXT_KEYS.lock(), vec!["context_key".to_string()]);
+    }
+
+    #[cfg(feature = "measure_overhead")]
+    #[test]
+    #[serial(overhead_key, measure_overhead)]
+    fn test_serial_measure_overhead() {
+        init();
+    }
+
+    #[cfg(feature = "measure_overhead")]
+    #[test]
+    #[serial(overhead_key, measure_overhead, report_as = "Overhead tests")]
+    fn test_serial_measure_overhead_report_as() {
+        init();
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    #[serial(telemetry_key = "serial_test.telemetry")]
+    fn test_serial_telemetry_key() {
+        init();
+    }
+
     #[test]
     #[serial(alpha)]
     fn test_serial_1() {
@@ -214,6 +459,95 @@ mod tests {
         init();
     }
 
+    #[cfg(feature = "async")]
+    static ASYNC_GUARD_RAN: AtomicBool = AtomicBool::new(false);
+
+    #[cfg(feature = "async")]
+    #[serial(async_guard_key)]
+    fn async_guard_serial() {
+        ASYNC_GUARD_RAN.store(true, Ordering::SeqCst);
+    }
+
+    // #[tokio::test] runs the test's own future on the test thread, even with the multi_thread flavor, so it
+    // doesn't need to be Send
+    #[cfg(feature = "async")]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    #[serial_test::parallel(not_send_key)]
+    async fn test_async_parallel_not_send() {
+        init();
+        let not_send = std::rc::Rc::new(1);
+        tokio::task::yield_now().await;
+        assert_eq!(*not_send, 1);
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_serial_lock_async() {
+        init();
+        let guard = serial_test::serial_lock_async(Some("async_guard_key")).await;
+        let serial = thread::spawn(async_guard_serial);
+        // Give the runtime a chance to move us to another thread
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+        thread::sleep(Duration::from_millis(100));
+        assert!(!ASYNC_GUARD_RAN.load(Ordering::SeqCst));
+        drop(guard);
+        serial.join().unwrap();
+        assert!(ASYNC_GUARD_RAN.load(Ordering::SeqCst));
+    }
+
+    #[cfg(feature = "tokio_serial")]
+    #[serial(tokio_serial_key)]
+    async fn tokio_serial_yielding(running: &AtomicBool) {
+        assert!(!running.swap(true, Ordering::SeqCst));
+        tokio::task::yield_now().await;
+        running.store(false, Ordering::SeqCst);
+    }
+
+    // Both tasks are on the one thread, so without tokio_serial the second would re-enter the first's key
+    #[cfg(feature = "tokio_serial")]
+    #[tokio::test]
+    async fn test_tokio_serial_tasks_on_one_thread() {
+        init();
+        let running = AtomicBool::new(false);
+        tokio::join!(
+            tokio_serial_yielding(&running),
+            tokio_serial_yielding(&running)
+        );
+    }
+
+    #[cfg(feature = "async")]
+    static PAUSED_RUNNING: AtomicBool = AtomicBool::new(false);
+
+    // The keys are waited for in real time, so tokio's paused clock doesn't matter to them, and sleeping on the
+    // paused clock while holding a key is over as soon as the runtime's idle
+    #[cfg(feature = "async")]
+    async fn paused_serial_sleep() {
+        assert!(is_locked_serially(Some("paused_key")));
+        assert!(!PAUSED_RUNNING.swap(true, Ordering::SeqCst));
+        let started = std::time::Instant::now();
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        assert!(started.elapsed() < Duration::from_secs(10));
+        PAUSED_RUNNING.store(false, Ordering::SeqCst);
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test(start_paused = true)]
+    #[serial(paused_key)]
+    async fn test_serial_start_paused_one() {
+        init();
+        paused_serial_sleep().await;
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test(start_paused = true)]
+    #[serial(paused_key)]
+    async fn test_serial_start_paused_two() {
+        init();
+        paused_serial_sleep().await;
+    }
+
     #[cfg(feature = "async")]
     #[tokio::test]
     #[serial]
@@ -222,6 +556,75 @@ mod tests {
         Ok(())
     }
 
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    #[serial(async_panic_key)]
+    #[should_panic(expected = "Testing async panic")]
+    async fn test_async_should_panic() {
+        init();
+        tokio::task::yield_now().await;
+        panic!("Testing async panic");
+    }
+
+    #[cfg(feature = "async")]
+    #[serial(async_panic_key)]
+    async fn async_panicking() {
+        tokio::task::yield_now().await;
+        panic!("Testing async panic");
+    }
+
+    // #[should_panic] tests can't return anything, so this one's only run below
+    #[cfg(feature = "async")]
+    #[serial(async_panic_key)]
+    async fn async_panicking_with_return() -> Result<(), ()> {
+        tokio::task::yield_now().await;
+        panic!("Testing async panic");
+    }
+
+    // The panic unwinds out of the runtime, which has to let go of the key, or the next test using it would hang
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_async_panic_releases_lock() {
+        init();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        assert!(std::panic::catch_unwind(|| runtime.block_on(async_panicking())).is_err());
+        assert!(
+            std::panic::catch_unwind(|| runtime.block_on(async_panicking_with_return())).is_err()
+        );
+        let (sender, receiver) = std::sync::mpsc::channel();
+        thread::spawn(move || with_serial(["async_panic_key"], || sender.send(()).unwrap()));
+        receiver
+            .recv_timeout(Duration::from_secs(5))
+            .expect("async_panic_key to be free after the panics");
+    }
+
+    #[cfg(feature = "async")]
+    #[parallel(async_max_hold_key, max_hold = 100)]
+    async fn async_parallel_hangs() {
+        std::future::pending::<()>().await;
+    }
+
+    // The hung body gets dropped, so the parallel count goes back down, rather than keeping serial tests out for good
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_async_parallel_max_hold() {
+        init();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let err =
+            std::panic::catch_unwind(|| runtime.block_on(async_parallel_hangs())).unwrap_err();
+        assert!(err.downcast_ref::<String>().unwrap().ends_with(
+            "async_parallel_hangs timed out after holding its lock for more than 100ms (max_hold)"
+        ));
+        assert_eq!(
+            serial_test::current_mode(Some("async_max_hold_key")),
+            serial_test::Mode::Unlocked
+        );
+    }
+
     #[cfg(feature = "file_locks")]
     #[test]
     #[file_serial]
@@ -253,6 +656,80 @@ mod tests {
     #[file_serial(path => "/tmp/test")]
     fn test_file_with_path_and_no_key() {}
 
+    #[cfg(all(feature = "file_locks", not(windows)))]
+    #[test]
+    #[file_serial(test_a, test_b, paths => ["/tmp/test_a", "/tmp/test_b"])]
+    fn test_file_with_paths() {}
+
+    #[cfg(all(feature = "file_locks", feature = "async"))]
+    #[file_serial(fs_async_thread_key)]
+    async fn file_serial_across_threads() {
+        // Give the runtime a chance to move us to another thread
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+        assert!(serial_test::is_file_locked(Some("fs_async_thread_key")));
+    }
+
+    #[cfg(all(feature = "file_locks", feature = "async"))]
+    #[file_serial(fs_async_cancel_key)]
+    async fn file_serial_never_finishes() {
+        std::future::pending::<()>().await;
+    }
+
+    #[cfg(all(feature = "file_locks", feature = "async"))]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_file_serial_async_multi_thread() {
+        init();
+        tokio::spawn(file_serial_across_threads()).await.unwrap();
+        assert!(!serial_test::is_file_locked(Some("fs_async_thread_key")));
+
+        let never_finishes = tokio::spawn(file_serial_never_finishes());
+        while !serial_test::is_file_locked(Some("fs_async_cancel_key")) {
+            tokio::task::yield_now().await;
+        }
+        never_finishes.abort();
+        assert!(never_finishes.await.unwrap_err().is_cancelled());
+        assert!(!serial_test::is_file_locked(Some("fs_async_cancel_key")));
+    }
+
+    #[cfg(feature = "file_locks")]
+    static DUAL_RUNNING: AtomicBool = AtomicBool::new(false);
+
+    #[cfg(feature = "file_locks")]
+    #[test]
+    #[dual_serial(dual_key)]
+    fn test_dual_serial() {
+        init();
+        assert!(is_locked_serially(Some("dual_key")));
+        assert!(serial_test::is_file_locked(Some("dual_key")));
+        DUAL_RUNNING.store(true, Ordering::Relaxed);
+        thread::sleep(Duration::from_millis(500));
+        DUAL_RUNNING.store(false, Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "file_locks")]
+    #[test]
+    #[serial(dual_key)]
+    fn test_serial_excluded_by_dual_serial() {
+        init();
+        for _ in 0..5 {
+            assert!(!DUAL_RUNNING.load(Ordering::Relaxed));
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+
+    #[cfg(feature = "file_locks")]
+    #[test]
+    #[file_serial(dual_key)]
+    fn test_file_serial_excluded_by_dual_serial() {
+        init();
+        for _ in 0..5 {
+            assert!(!DUAL_RUNNING.load(Ordering::Relaxed));
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+
     #[test]
     #[serial(test_key)]
     fn test_with_key() {
@@ -267,36 +744,415 @@ mod tests {
         assert!(count == 0 || count == 3, "count = {}", count);
     }
 
-    #[test]
-    #[parallel(ordering_key)]
     fn parallel_with_key_1() {
         thread::sleep(Duration::from_secs(1));
-        info!("Waiting barrier 1");
-        parallel_barrier().wait();
         info!("Waiting lock 1");
         THREAD_ORDERINGS.lock().push(false);
     }
 
-    #[test]
-    #[parallel(ordering_key)]
     fn parallel_with_key_2() {
         thread::sleep(Duration::from_secs(2));
-        info!("Waiting barrier 2");
-        parallel_barrier().wait();
         info!("Waiting lock 2");
         THREAD_ORDERINGS.lock().push(false);
     }
 
-    #[test]
-    #[parallel(ordering_key)]
     fn parallel_with_key_3() {
         thread::sleep(Duration::from_secs(3));
-        info!("Waiting barrier 3");
-        parallel_barrier().wait();
         info!("Waiting lock 3");
         THREAD_ORDERINGS.lock().push(false);
     }
 
+    parallel_test_set!(
+        "ordering_key",
+        [
+            parallel_with_key_1,
+            parallel_with_key_2,
+            parallel_with_key_3
+        ]
+    );
+
+    #[cfg(feature = "async")]
+    async fn async_parallel_set_1() {
+        init();
+    }
+
+    #[cfg(feature = "async")]
+    async fn async_parallel_set_2() {
+        init();
+    }
+
+    #[cfg(feature = "async")]
+    parallel_test_set!(
+        "async_ordering_key",
+        async [async_parallel_set_1, async_parallel_set_2]
+    );
+
+    #[test]
+    #[cfg_attr(test, serial(cfg_attr_key))]
+    fn test_cfg_attr_serial() {
+        assert!(is_locked_serially(Some("cfg_attr_key")));
+    }
+
+    #[cfg_attr(test, serial(cfg_attr_key))]
+    #[test]
+    fn test_cfg_attr_serial_before_test() {
+        assert!(is_locked_serially(Some("cfg_attr_key")));
+    }
+
+    #[cfg_attr(test, test, serial(cfg_attr_key))]
+    fn test_cfg_attr_test_and_serial() {
+        assert!(is_locked_serially(Some("cfg_attr_key")));
+    }
+
+    #[test]
+    #[cfg_attr(not(test), serial(cfg_attr_key))]
+    fn test_cfg_attr_not_serial() {
+        assert!(!is_locked_serially(Some("cfg_attr_key")));
+    }
+
+    #[test]
+    #[serial(scope = "module")]
+    fn test_serial_module_scope() {
+        init();
+        assert!(is_locked_serially(Some(module_path!())));
+        assert!(!is_locked_serially(None));
+    }
+
+    #[test]
+    #[serial(scope = "function")]
+    fn test_serial_function_scope() {
+        init();
+        assert!(is_locked_serially(Some(concat!(
+            module_path!(),
+            "::test_serial_function_scope"
+        ))));
+    }
+
+    #[test]
+    #[serial]
+    fn test_serial_default_key() {
+        init();
+        assert!(is_locked_serially(Some(serial_test::DEFAULT_KEY)));
+        assert!(is_locked_serially(None));
+    }
+
+    // Like a property test, checking a bunch of cases
+    #[test]
+    #[serial(property_key)]
+    fn test_with_serial_inside_serial() {
+        init();
+        for _ in 0..10 {
+            with_serial(["property_key"], || {
+                assert!(is_locked_serially(Some("property_key")));
+            });
+            // Still held by the test
+            assert!(is_locked_serially(Some("property_key")));
+        }
+    }
+
+    #[test]
+    fn test_with_serial_per_case() {
+        init();
+        for case in 0..10 {
+            let doubled = with_serial(["property_case_key"], || {
+                assert!(is_locked_serially(Some("property_case_key")));
+                case * 2
+            });
+            assert_eq!(doubled, case * 2);
+            assert!(!is_locked_serially(Some("property_case_key")));
+        }
+    }
+
+    #[serial(max_hold_key)]
+    fn max_hold_nested(ran: &AtomicBool) {
+        ran.store(true, Ordering::SeqCst);
+    }
+
+    // Runs on the test thread, so it can borrow its arguments, and the key's held (re-entrantly) as usual
+    #[serial(max_hold_key, max_hold = 10000)]
+    fn max_hold_with_args(ran: &AtomicBool) {
+        assert!(is_locked_serially(Some("max_hold_key")));
+        max_hold_nested(ran);
+    }
+
+    #[test]
+    fn test_serial_max_hold_with_args() {
+        init();
+        let ran = AtomicBool::new(false);
+        max_hold_with_args(&ran);
+        assert!(ran.load(Ordering::SeqCst));
+    }
+
+    struct Pool {
+        connections: usize,
+    }
+
+    fn make_pool() -> Pool {
+        assert!(is_locked_serially(Some("context_key")));
+        Pool { connections: 4 }
+    }
+
+    #[test]
+    #[serial(context_key, with_context = "make_pool")]
+    fn test_serial_with_context() {
+        init();
+        assert_eq!(serial_test::context::<Pool>().connections, 4);
+    }
+
+    // As exported for a C test harness to call
+    #[serial(ffi_key)]
+    extern "C" fn ffi_check() {
+        assert!(is_locked_serially(Some("ffi_key")));
+    }
+
+    #[test]
+    fn test_serial_extern_c() {
+        init();
+        let check: extern "C" fn() = ffi_check;
+        check();
+    }
+
+    // Like the test structs that e.g. inventory-based frameworks register
+    struct MethodTests {
+        runs: u32,
+    }
+
+    impl MethodTests {
+        #[serial(method_key)]
+        fn check(&mut self) {
+            assert!(is_locked_serially(Some("method_key")));
+            self.runs += 1;
+        }
+
+        #[cfg(feature = "async")]
+        #[serial(method_key)]
+        async fn check_async(&mut self) {
+            self.runs += 1;
+        }
+    }
+
+    #[test]
+    fn test_serial_method() {
+        init();
+        let mut tests = MethodTests { runs: 0 };
+        tests.check();
+        tests.check();
+        assert_eq!(tests.runs, 2);
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn test_async_serial_method() {
+        init();
+        let mut tests = MethodTests { runs: 0 };
+        tests.check_async().await;
+        assert_eq!(tests.runs, 1);
+    }
+
+    #[test]
+    #[serial(described_key, desc = "Key with a description")]
+    fn test_serial_desc() {
+        assert_eq!(
+            serial_test::key_description(Some("described_key")),
+            Some("Key with a description".to_string())
+        );
+    }
+
+    #[test]
+    #[serial(not_serial_other)]
+    fn test_assert_not_serial_other_key() {
+        init();
+        assert_not_serial!("not_serial_key");
+        assert_not_parallel!("not_serial_other");
+    }
+
+    #[test]
+    #[serial(not_serial_key)]
+    #[should_panic(expected = "Mustn't be called while holding \"not_serial_key\" serially")]
+    fn test_assert_not_serial_held() {
+        init();
+        assert_not_serial!("not_serial_key");
+    }
+
+    #[test]
+    #[parallel(not_parallel_key)]
+    #[should_panic(expected = "Mustn't be called while holding \"not_parallel_key\" in parallel")]
+    fn test_assert_not_parallel_held() {
+        init();
+        assert_not_parallel!("not_parallel_key");
+    }
+
+    #[cfg(feature = "async")]
+    #[parallel(not_parallel_task)]
+    async fn parallel_until(done: tokio::sync::oneshot::Receiver<()>) {
+        let _ = done.await;
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn test_assert_not_parallel_other_task() {
+        init();
+        let (done, wait) = tokio::sync::oneshot::channel();
+        // Same thread as the parallel holder, but it's not the one running in parallel
+        tokio::join!(parallel_until(wait), async {
+            tokio::task::yield_now().await;
+            assert_eq!(
+                serial_test::current_mode(Some("not_parallel_task")),
+                serial_test::Mode::Parallel
+            );
+            assert_not_parallel!("not_parallel_task");
+            done.send(()).unwrap();
+        });
+    }
+
+    #[parallel(group_size)]
+    fn group_size_one() {}
+
+    #[parallel(group_size)]
+    fn group_size_two() {}
+
+    #[parallel(group_size, other_group_size)]
+    fn group_size_three() {}
+
+    #[test]
+    fn test_parallel_group_size() {
+        init();
+        assert_eq!(parallel_group_size(Some("group_size")), 0);
+        group_size_one();
+        group_size_two();
+        group_size_three();
+        // Running again doesn't count twice
+        group_size_one();
+        assert_eq!(parallel_group_size(Some("group_size")), 3);
+        assert_eq!(parallel_group_size(Some("other_group_size")), 1);
+    }
+
+    // Only passes if all three are running at once
+    #[test]
+    #[parallel(rendezvous_key)]
+    fn test_parallel_rendezvous_one() {
+        init();
+        assert!(parallel_rendezvous(
+            Some("rendezvous_key"),
+            3,
+            Duration::from_secs(60)
+        ));
+    }
+
+    #[test]
+    #[parallel(rendezvous_key)]
+    fn test_parallel_rendezvous_two() {
+        init();
+        assert!(parallel_rendezvous(
+            Some("rendezvous_key"),
+            3,
+            Duration::from_secs(60)
+        ));
+    }
+
+    #[test]
+    #[parallel(rendezvous_key)]
+    fn test_parallel_rendezvous_three() {
+        init();
+        assert!(parallel_rendezvous(
+            Some("rendezvous_key"),
+            3,
+            Duration::from_secs(60)
+        ));
+    }
+
+    #[parallel(defer_start_key, defer_start_ms = 50)]
+    fn deferred_start() {}
+
+    #[test]
+    fn test_parallel_defer_start() {
+        init();
+        let started = Instant::now();
+        deferred_start();
+        assert!(started.elapsed() < Duration::from_millis(50));
+        deferred_start();
+        deferred_start();
+        // Waits 50ms, then 100ms
+        assert!(started.elapsed() >= Duration::from_millis(150));
+    }
+
+    #[test_log::test]
+    #[serial(test_log_key)]
+    fn test_test_log_outside_serial() {
+        assert!(is_locked_serially(Some("test_log_key")));
+        info!("test_log outside serial");
+    }
+
+    #[serial(test_log_key)]
+    #[test_log::test]
+    fn test_test_log_inside_serial() {
+        assert!(is_locked_serially(Some("test_log_key")));
+        info!("test_log inside serial");
+    }
+
+    #[cfg(feature = "async")]
+    #[test_log::test(tokio::test)]
+    #[serial(test_log_key)]
+    async fn test_test_log_async_outside_serial() {
+        assert!(is_locked_serially(Some("test_log_key")));
+    }
+
+    #[cfg(feature = "async")]
+    #[serial(test_log_key)]
+    #[test_log::test(tokio::test)]
+    async fn test_test_log_async_inside_serial() {
+        assert!(is_locked_serially(Some("test_log_key")));
+    }
+
+    #[test]
+    fn test_env_lock() {
+        init();
+        // Nested, as they're re-entrant like any other key
+        env_lock!(
+            "env_lock",
+            set = [("SERIAL_TEST_ENV_LOCK_UNSET", "original")],
+            {
+                let suffix = "!";
+                let value = env_lock!(
+                    "env_lock",
+                    set = [("SERIAL_TEST_ENV_LOCK_SET", "changed")],
+                    unset = ["SERIAL_TEST_ENV_LOCK_UNSET"],
+                    {
+                        assert!(is_locked_serially(Some("env_lock")));
+                        assert!(env::var("SERIAL_TEST_ENV_LOCK_UNSET").is_err());
+                        env::var("SERIAL_TEST_ENV_LOCK_SET").unwrap() + suffix
+                    }
+                );
+                assert_eq!(value, "changed!");
+                assert!(env::var("SERIAL_TEST_ENV_LOCK_SET").is_err());
+                assert_eq!(env::var("SERIAL_TEST_ENV_LOCK_UNSET").unwrap(), "original");
+            }
+        );
+    }
+
+    #[test]
+    fn test_env_lock_restores_on_panic() {
+        init();
+        env_lock!(
+            "env_lock",
+            set = [("SERIAL_TEST_ENV_LOCK_PANIC", "original")],
+            {
+                let result = std::panic::catch_unwind(|| {
+                    env_lock!(
+                        "env_lock",
+                        set = [("SERIAL_TEST_ENV_LOCK_PANIC", "changed")],
+                        {
+                            panic!("in env_lock");
+                        }
+                    )
+                });
+                assert!(result.is_err());
+                assert_eq!(env::var("SERIAL_TEST_ENV_LOCK_PANIC").unwrap(), "original");
+            }
+        );
+        assert!(!is_locked_serially(Some("env_lock")));
+    }
+
     #[test]
     #[serial(ordering_key)]
     fn serial_with_parallel_key_2() {
@@ -330,7 +1186,11 @@ mod tests {
         init();
         thread::sleep(Duration::from_secs(1));
         info!("Waiting barrier 1");
-        fs_parallel_barrier().wait();
+        assert!(parallel_rendezvous(
+            Some("fs_ordering_key"),
+            3,
+            Duration::from_secs(60)
+        ));
         info!("Waiting lock 1");
         FS_THREAD_ORDERINGS.lock().push(false);
     }
@@ -342,7 +1202,11 @@ mod tests {
         init();
         thread::sleep(Duration::from_secs(1));
         info!("Waiting barrier 2");
-        fs_parallel_barrier().wait();
+        assert!(parallel_rendezvous(
+            Some("fs_ordering_key"),
+            3,
+            Duration::from_secs(60)
+        ));
         info!("Waiting lock 2");
         FS_THREAD_ORDERINGS.lock().push(false);
     }
@@ -354,7 +1218,11 @@ mod tests {
         init();
         thread::sleep(Duration::from_secs(1));
         info!("Waiting barrier 3");
-        fs_parallel_barrier().wait();
+        assert!(parallel_rendezvous(
+            Some("fs_ordering_key"),
+            3,
+            Duration::from_secs(60)
+        ));
         info!("Waiting lock 3");
         FS_THREAD_ORDERINGS.lock().push(false);
     }
@@ -381,6 +1249,41 @@ mod tests {
         init();
     }
 
+    #[cfg(feature = "file_locks")]
+    #[test]
+    #[file_parallel(max_parallel_key, max_parallel = 1)]
+    fn file_parallel_max_parallel() {
+        init();
+    }
+
+    #[cfg(all(feature = "file_locks", feature = "async"))]
+    #[tokio::test]
+    #[file_parallel(max_parallel_key, max_parallel = 1)]
+    async fn file_parallel_max_parallel_async() {
+        init();
+    }
+
+    #[cfg(all(feature = "file_locks", not(feature = "shared_file_locks")))]
+    #[test]
+    #[file_serial(lock_type_key, lock_type = "open_exclusive")]
+    fn file_serial_open_exclusive() {
+        init();
+    }
+
+    #[cfg(all(feature = "file_locks", not(feature = "shared_file_locks")))]
+    #[test]
+    #[file_parallel(lock_type_key, lock_type = "open_exclusive")]
+    fn file_parallel_open_exclusive() {
+        init();
+    }
+
+    #[cfg(all(unix, feature = "file_locks", not(feature = "shared_file_locks")))]
+    #[test]
+    #[file_serial(fcntl_key, lock_type = "fcntl")]
+    fn file_serial_fcntl() {
+        init();
+    }
+
     // Note, not actually a test as such, just a "can you wrap serial functions" compile-time check
     #[cfg(feature = "async")]
     #[serial]
@@ -0,0 +1,7 @@
+// The derive crate's own compile tests can't use serial_test, so ones needing its traits are here
+// Compiler output changes between versions, so the .stderr files are only checked against current stable
+#[rustversion::attr(any(not(stable), before(1.80)), ignore)]
+#[test]
+fn compile_errors() {
+    trybuild::TestCases::new().compile_fail("tests/compile_tests/*.rs");
+}
@@ -0,0 +1,12 @@
+use serial_test::serial;
+
+enum NotAKey {
+    X,
+}
+
+#[serial(key = NotAKey::X)]
+fn uses_not_a_key() {}
+
+fn main() {
+    uses_not_a_key();
+}
@@ -0,0 +1,11 @@
+//! Tests with the same name from different `include!`d files, which only need to build. The function the attribute
+//! wraps is declared inside the test's own body, so it's scoped to that test and can't clash with the other one's.
+#![cfg(feature = "async")]
+
+mod a {
+    include!("included/a.rs");
+}
+
+mod b {
+    include!("included/b.rs");
+}
@@ -0,0 +1,9 @@
+use serial_test::{parallel, serial};
+
+#[tokio::test]
+#[serial(included)]
+async fn foo() {}
+
+#[test]
+#[parallel(included)]
+fn bar() {}
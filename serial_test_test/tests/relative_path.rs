@@ -0,0 +1,44 @@
+//! In its own process, as changing the current dir affects every test running at the time
+#![cfg(feature = "file_locks")]
+
+use serial_test::file_serial;
+use std::{env, fs, path::Path};
+
+const LOCK_PATH: &str = "relative-path.lock";
+
+#[file_serial(relative_path, path => "relative-path.lock")]
+fn take_lock() {}
+
+// Along with anything else made next to it, e.g. the holder file
+fn remove_lock_files(dir: &Path) {
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path
+            .file_name()
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .starts_with(LOCK_PATH)
+        {
+            fs::remove_file(path).unwrap();
+        }
+    }
+}
+
+fn check_lock_file_made() {
+    let manifest_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+    remove_lock_files(manifest_dir);
+    take_lock();
+    assert!(manifest_dir.join(LOCK_PATH).exists());
+    remove_lock_files(manifest_dir);
+}
+
+#[test]
+fn relative_paths_are_from_the_manifest_dir() {
+    check_lock_file_made();
+    let cwd = env::temp_dir().join("serial-test-relative-path-cwd");
+    fs::create_dir_all(&cwd).unwrap();
+    env::set_current_dir(cwd).unwrap();
+    check_lock_file_made();
+    assert!(!Path::new(LOCK_PATH).exists());
+}
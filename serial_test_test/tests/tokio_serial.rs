@@ -0,0 +1,48 @@
+//! Serial futures sharing a current-thread runtime, which only works if waiting for a key yields to the executor
+#![cfg(feature = "tokio_serial")]
+
+use serial_test::serial;
+use std::{
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    time::Duration,
+};
+use tokio::sync::oneshot;
+
+#[serial(tokio_serial_progress)]
+async fn hold_until(running: &AtomicBool, go: oneshot::Receiver<()>) {
+    assert!(!running.swap(true, Ordering::SeqCst));
+    let _ = go.await;
+    running.store(false, Ordering::SeqCst);
+}
+
+#[serial(tokio_serial_progress)]
+async fn hold_briefly(running: &AtomicBool) {
+    assert!(!running.swap(true, Ordering::SeqCst));
+    tokio::task::yield_now().await;
+    running.store(false, Ordering::SeqCst);
+}
+
+#[tokio::test]
+async fn serial_futures_in_one_join() {
+    let running = AtomicBool::new(false);
+    let ticks = AtomicUsize::new(0);
+    let (go, wait) = oneshot::channel();
+    // A task of its own, which needs the thread while both serial futures are holding or waiting for the key
+    let ticker = tokio::spawn(async {
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+    });
+    tokio::join!(hold_until(&running, wait), hold_briefly(&running), async {
+        // The first holds the key until we say, so the second's left waiting on it meanwhile
+        while ticks.fetch_add(1, Ordering::SeqCst) < 10 {
+            tokio::task::yield_now().await;
+        }
+        tokio::time::timeout(Duration::from_secs(10), ticker)
+            .await
+            .expect("other tasks to carry on while the key's waited for")
+            .unwrap();
+        go.send(()).unwrap();
+    });
+    assert!(ticks.load(Ordering::SeqCst) > 10);
+}